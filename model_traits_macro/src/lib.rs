@@ -1,8 +1,9 @@
 extern crate proc_macro;
 
 use proc_macro::TokenStream;
-use quote::quote;
-use syn::{parse_macro_input, DeriveInput};
+use quote::{quote, quote_spanned};
+use syn::spanned::Spanned;
+use syn::{parse_macro_input, Data, DeriveInput, Fields};
 
 #[proc_macro_attribute]
 pub fn derive_all(_attr: TokenStream, item: TokenStream) -> TokenStream {
@@ -16,3 +17,102 @@ pub fn derive_all(_attr: TokenStream, item: TokenStream) -> TokenStream {
 
     TokenStream::from(expanded)
 }
+
+///derives instructor_rs::dsl::parallel::ParallelBase for an enum where every variant wraps one response model,
+/// the tool call name is matched against the tool_schema() name of each model.
+/// The Args of a model are only known to the compiler, so a variant whose Args differ from those of the first
+/// one is reported at the variant by a check the derive generates (see dsl::parallel::SameArgs)
+#[proc_macro_derive(ParallelBase)]
+pub fn derive_parallel_base(item: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(item as DeriveInput);
+    let name = &input.ident;
+
+    let data = match &input.data {
+        Data::Enum(data) => data,
+        _ => {
+            return syn::Error::new_spanned(name, "ParallelBase can only be derived for enums")
+                .to_compile_error()
+                .into()
+        }
+    };
+
+    let mut variants = Vec::new();
+    let mut types = Vec::new();
+    for variant in data.variants.iter() {
+        match &variant.fields {
+            Fields::Unnamed(fields) if fields.unnamed.len() == 1 => {
+                variants.push(&variant.ident);
+                types.push(&fields.unnamed.first().unwrap().ty);
+            }
+            _ => {
+                return syn::Error::new_spanned(
+                    &variant.ident,
+                    "ParallelBase variants must wrap exactly one response model, e.g. Person(Person)",
+                )
+                .to_compile_error()
+                .into()
+            }
+        }
+    }
+
+    let first_type = match types.first() {
+        Some(ty) => ty,
+        None => {
+            return syn::Error::new_spanned(name, "ParallelBase needs at least one variant")
+                .to_compile_error()
+                .into()
+        }
+    };
+
+    // the Args of every model must be the Args of the first one, the check is spanned to the variant
+    // so a model with another validation context is reported there
+    let args_checks = types.iter().map(|ty| quote_spanned! {ty.span()=>
+        same_args::<<#ty as ::validator::ValidateArgs<'static>>::Args, <#first_type as ::validator::ValidateArgs<'static>>::Args>();
+    });
+    let indices = 0..types.len();
+
+    let expanded = quote! {
+        const _: fn() = || {
+            fn same_args<T: ::instructor_rs::dsl::parallel::SameArgs<A>, A>() {}
+            #(#args_checks)*
+        };
+
+        impl ::instructor_rs::dsl::parallel::ParallelBase for #name {
+            type Args = <#first_type as ::validator::ValidateArgs<'static>>::Args;
+
+            fn tool_schemas() -> ::std::vec::Vec<::async_openai::types::FunctionObject> {
+                vec![
+                    #(<#types as ::instructor_rs::openai_schema::OpenAISchema<_, #types>>::tool_schema()),*
+                ]
+            }
+
+            fn from_tool_call(
+                name: &str,
+                arguments: &str,
+                validation_context: &Self::Args,
+            ) -> ::std::result::Result<Self, ::instructor_rs::error::Error> {
+                // the names come from the schemars schemas (a serde rename changes them), so they are built once
+                static MODEL_NAMES: ::std::sync::OnceLock<::std::vec::Vec<::std::string::String>> = ::std::sync::OnceLock::new();
+                let model_names = MODEL_NAMES.get_or_init(|| {
+                    Self::tool_schemas().into_iter().map(|schema| schema.name).collect()
+                });
+                let index = model_names.iter().position(|model_name| model_name == name);
+                #(
+                    if index == Some(#indices) {
+                        return ::instructor_rs::dsl::parallel::parse_tool_arguments::<#types, Self::Args>(
+                            arguments,
+                            validation_context,
+                        ).map(#name::#variants);
+                    }
+                )*
+                Err(::instructor_rs::error::Error::Generic(format!(
+                    "tool call name: {} does not match any of the model names: {}",
+                    name,
+                    model_names.join(", ")
+                )))
+            }
+        }
+    };
+
+    TokenStream::from(expanded)
+}
@@ -0,0 +1,352 @@
+use serde::{Deserialize, Serialize};
+use crate::error::Error;
+use crate::enums::ChatCompletionResponseWrapper;
+use crate::backend::{ChatBackend, BackendFuture, ChatRequest};
+use async_openai::error::{ApiError, OpenAIError};
+use async_openai::types::{
+    ChatCompletionMessageToolCall, ChatCompletionRequestMessage, ChatCompletionRequestMessageContentPart,
+    ChatCompletionRequestUserMessageContent, ChatCompletionToolChoiceOption, ChatCompletionToolType,
+    CreateChatCompletionRequest, FunctionCall, FunctionObject, Stop
+};
+
+pub const ANTHROPIC_API_BASE: &str = "https://api.anthropic.com/v1";
+pub const ANTHROPIC_VERSION: &str = "2023-06-01";
+/// the messages api requires max_tokens, this is used when the request does not set it
+pub const DEFAULT_MAX_TOKENS: u32 = 4096;
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AnthropicRole {
+    User,
+    Assistant,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ContentBlock {
+    Text {
+        text: String,
+    },
+    ToolUse {
+        id: String,
+        name: String,
+        input: serde_json::Value,
+    },
+    ToolResult {
+        tool_use_id: String,
+        content: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        is_error: Option<bool>,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct AnthropicMessage {
+    pub role: AnthropicRole,
+    pub content: Vec<ContentBlock>,
+}
+
+///an entry in the tools field of the messages api, the equivalent of a FunctionObject
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct AnthropicTool {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    pub input_schema: serde_json::Value,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum AnthropicToolChoice {
+    Auto,
+    Any,
+    Tool { name: String },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct MessagesRequest {
+    pub model: String,
+    pub max_tokens: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system: Option<String>,
+    pub messages: Vec<AnthropicMessage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tools: Option<Vec<AnthropicTool>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_choice: Option<AnthropicToolChoice>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_sequences: Option<Vec<String>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+pub struct AnthropicUsage {
+    pub input_tokens: u32,
+    pub output_tokens: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct MessagesResponse {
+    pub id: String,
+    pub r#type: String,
+    pub role: AnthropicRole,
+    pub content: Vec<ContentBlock>,
+    pub model: String,
+    pub stop_reason: Option<String>,
+    pub stop_sequence: Option<String>,
+    pub usage: AnthropicUsage,
+}
+
+impl MessagesResponse {
+    ///returns the tool_use blocks of the response in the same shape as openai tool calls,
+    /// the input object is serialized into the arguments string
+    pub fn tool_calls(&self) -> Vec<ChatCompletionMessageToolCall> {
+        self.content.iter().filter_map(|block| match block {
+            ContentBlock::ToolUse { id, name, input } => Some(ChatCompletionMessageToolCall {
+                id: id.clone(),
+                r#type: ChatCompletionToolType::Function,
+                function: FunctionCall {
+                    name: name.clone(),
+                    arguments: input.to_string(),
+                },
+            }),
+            _ => None,
+        }).collect()
+    }
+
+    ///returns the concatenated text blocks of the response
+    pub fn text(&self) -> String {
+        self.content.iter().filter_map(|block| match block {
+            ContentBlock::Text { text } => Some(text.as_str()),
+            _ => None,
+        }).collect::<Vec<&str>>().join("")
+    }
+}
+
+impl From<FunctionObject> for AnthropicTool {
+    fn from(function: FunctionObject) -> Self {
+        AnthropicTool {
+            name: function.name,
+            description: function.description,
+            input_schema: function.parameters.unwrap_or_else(
+                || serde_json::json!({"type": "object", "properties": {}})
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct AnthropicErrorBody {
+    error: AnthropicErrorDetail,
+}
+
+#[derive(Debug, Deserialize)]
+struct AnthropicErrorDetail {
+    r#type: String,
+    message: String,
+}
+
+impl MessagesRequest {
+    ///translates an openai chat completion request into a request for the anthropic messages api.
+    /// system messages are moved into the system field, assistant tool calls become tool_use blocks
+    /// and tool messages become tool_result blocks. Tool messages are only ever produced by re-asks
+    /// after a failed validation, so they are sent with is_error set.
+    ///
+    /// # Arguments
+    /// * `kwargs`: `&CreateChatCompletionRequest` - the request to translate
+    pub fn from_chat_request(kwargs: &CreateChatCompletionRequest) -> Result<Self, Error> {
+        let mut system: Vec<String> = Vec::new();
+        let mut messages: Vec<AnthropicMessage> = Vec::new();
+
+        for message in &kwargs.messages {
+            match message {
+                ChatCompletionRequestMessage::System(message) => {
+                    system.push(message.content.clone());
+                }
+                ChatCompletionRequestMessage::User(message) => {
+                    let blocks = match &message.content {
+                        ChatCompletionRequestUserMessageContent::Text(text) => {
+                            vec![ContentBlock::Text { text: text.clone() }]
+                        }
+                        ChatCompletionRequestUserMessageContent::Array(parts) => {
+                            parts.iter().map(|part| match part {
+                                ChatCompletionRequestMessageContentPart::Text(part) => {
+                                    Ok(ContentBlock::Text { text: part.text.clone() })
+                                }
+                                ChatCompletionRequestMessageContentPart::Image(_) => Err(Error::NotImplementedError(
+                                    "image content is not supported in anthropic_tools mode".to_string()
+                                )),
+                            }).collect::<Result<Vec<ContentBlock>, Error>>()?
+                        }
+                    };
+                    push_blocks(&mut messages, AnthropicRole::User, blocks);
+                }
+                ChatCompletionRequestMessage::Assistant(message) => {
+                    let mut blocks = Vec::new();
+                    if let Some(text) = &message.content {
+                        blocks.push(ContentBlock::Text { text: text.clone() });
+                    }
+                    for tool_call in message.tool_calls.iter().flatten() {
+                        let input = serde_json::from_str(&tool_call.function.arguments)
+                            .unwrap_or_else(|_| serde_json::Value::String(tool_call.function.arguments.clone()));
+                        blocks.push(ContentBlock::ToolUse {
+                            id: tool_call.id.clone(),
+                            name: tool_call.function.name.clone(),
+                            input,
+                        });
+                    }
+                    push_blocks(&mut messages, AnthropicRole::Assistant, blocks);
+                }
+                ChatCompletionRequestMessage::Tool(message) => {
+                    push_blocks(&mut messages, AnthropicRole::User, vec![ContentBlock::ToolResult {
+                        tool_use_id: message.tool_call_id.clone(),
+                        content: message.content.clone(),
+                        is_error: Some(true),
+                    }]);
+                }
+                ChatCompletionRequestMessage::Function(_) => {
+                    return Err(Error::NotImplementedError(
+                        "function messages are not supported in anthropic_tools mode".to_string()
+                    ));
+                }
+            }
+        }
+
+        let tool_choice = match &kwargs.tool_choice {
+            Some(ChatCompletionToolChoiceOption::Named(choice)) => Some(AnthropicToolChoice::Tool {
+                name: choice.function.name.clone(),
+            }),
+            Some(ChatCompletionToolChoiceOption::Auto) => Some(AnthropicToolChoice::Auto),
+            Some(ChatCompletionToolChoiceOption::None) | None => None,
+        };
+
+        let stop_sequences = match &kwargs.stop {
+            Some(Stop::String(stop)) => Some(vec![stop.clone()]),
+            Some(Stop::StringArray(stop)) => Some(stop.clone()),
+            None => None,
+        };
+
+        Ok(MessagesRequest {
+            model: kwargs.model.clone(),
+            max_tokens: kwargs.max_tokens.map(u32::from).unwrap_or(DEFAULT_MAX_TOKENS),
+            system: if system.is_empty() { None } else { Some(system.join("\n\n")) },
+            messages,
+            tools: kwargs.tools.as_ref().map(|tools| {
+                tools.iter().map(|tool| AnthropicTool::from(tool.function.clone())).collect()
+            }),
+            tool_choice,
+            temperature: kwargs.temperature,
+            top_p: kwargs.top_p,
+            stop_sequences,
+        })
+    }
+}
+
+///the messages api requires user and assistant turns to alternate, so consecutive blocks
+/// of the same role are merged into one message. Empty text blocks are rejected by the api and dropped.
+fn push_blocks(messages: &mut Vec<AnthropicMessage>, role: AnthropicRole, blocks: Vec<ContentBlock>) {
+    let blocks: Vec<ContentBlock> = blocks.into_iter().filter(|block| {
+        !matches!(block, ContentBlock::Text { text } if text.trim().is_empty())
+    }).collect();
+    if blocks.is_empty() {
+        return;
+    }
+    match messages.last_mut() {
+        Some(last) if last.role == role => last.content.extend(blocks),
+        _ => messages.push(AnthropicMessage { role, content: blocks }),
+    }
+}
+
+///a minimal client for the anthropic messages api, it implements ChatBackend and is meant to be used
+/// with Mode::ANTHROPIC_TOOLS
+///
+/// Example
+///
+/// let client = AnthropicClient::new() //defaults to env variable ANTHROPIC_API_KEY
+///     .with_api_base("http://localhost:8080/v1");
+/// let patched_client = Patch { client, mode: Some(Mode::ANTHROPIC_TOOLS) };
+#[derive(Debug, Clone)]
+pub struct AnthropicClient {
+    http_client: reqwest::Client,
+    api_key: String,
+    api_base: String,
+    version: String,
+}
+
+impl Default for AnthropicClient {
+    fn default() -> Self {
+        AnthropicClient {
+            http_client: reqwest::Client::new(),
+            api_key: std::env::var("ANTHROPIC_API_KEY").unwrap_or_default(),
+            api_base: ANTHROPIC_API_BASE.to_string(),
+            version: ANTHROPIC_VERSION.to_string(),
+        }
+    }
+}
+
+impl AnthropicClient {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_api_key<S: Into<String>>(mut self, api_key: S) -> Self {
+        self.api_key = api_key.into();
+        self
+    }
+
+    pub fn with_api_base<S: Into<String>>(mut self, api_base: S) -> Self {
+        self.api_base = api_base.into();
+        self
+    }
+
+    pub fn with_version<S: Into<String>>(mut self, version: S) -> Self {
+        self.version = version.into();
+        self
+    }
+
+    ///sends a request to the /messages endpoint
+    pub async fn create_message(&self, request: MessagesRequest) -> Result<MessagesResponse, OpenAIError> {
+        let response = self.http_client
+            .post(format!("{}/messages", self.api_base))
+            .header("x-api-key", &self.api_key)
+            .header("anthropic-version", &self.version)
+            .json(&request)
+            .send()
+            .await?;
+
+        let status = response.status();
+        let bytes = response.bytes().await?;
+        if !status.is_success() {
+            let error = match serde_json::from_slice::<AnthropicErrorBody>(&bytes) {
+                Ok(body) => ApiError {
+                    message: body.error.message,
+                    r#type: Some(body.error.r#type),
+                    param: None,
+                    code: Some(serde_json::json!(status.as_u16())),
+                },
+                Err(_) => ApiError {
+                    message: String::from_utf8_lossy(&bytes).to_string(),
+                    r#type: None,
+                    param: None,
+                    code: Some(serde_json::json!(status.as_u16())),
+                },
+            };
+            return Err(OpenAIError::ApiError(error));
+        }
+        serde_json::from_slice::<MessagesResponse>(&bytes).map_err(OpenAIError::JSONDeserialize)
+    }
+}
+
+impl ChatBackend for AnthropicClient {
+    fn create(&self, request: ChatRequest) -> BackendFuture<ChatCompletionResponseWrapper> {
+        let client = self.clone();
+        Box::pin(async move {
+            let request = MessagesRequest::from_chat_request(&request.request)?;
+            let res = client.create_message(request).await?;
+            Ok(ChatCompletionResponseWrapper::Anthropic(res))
+        })
+    }
+}
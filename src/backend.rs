@@ -0,0 +1,549 @@
+use crate::error::{Error, HttpError};
+use crate::enums::ChatCompletionResponseWrapper;
+use crate::completion::completion_request_from_chat;
+use async_openai::Client;
+use async_openai::config::Config;
+use async_openai::error::{ApiError, OpenAIError};
+use async_openai::types::{
+    ChatCompletionResponseStream, CreateChatCompletionRequest, CreateChatCompletionResponse,
+    CreateChatCompletionStreamResponse, ChatCompletionRequestMessage, ChatCompletionRequestAssistantMessage,
+    ChatCompletionRequestUserMessage, ChatCompletionRequestUserMessageContent, Role, CompletionUsage
+};
+use async_stream::stream;
+use futures::stream::StreamExt;
+use reqwest::header::HeaderMap;
+use reqwest_eventsource::{Event, EventSource, RequestBuilderExt};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+use std::pin::Pin;
+use std::future::Future;
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+pub type BackendFuture<T> = Pin<Box<dyn Future<Output = Result<T, Error>> + Send>>;
+
+///receives the usage of a streamed response, see ChatBackend::create_stream_with_usage
+pub type UsageSink = Arc<dyn Fn(CompletionUsage) + Send + Sync>;
+
+///the request that Patch sends to a ChatBackend, an openai style CreateChatCompletionRequest
+/// plus the body fields async_openai does not model (e.g. a json_schema response_format).
+/// Backends talking to an openai compatible api merge extra_body into the request body,
+/// backends that translate the request (like AnthropicClient) ignore it
+#[derive(Debug, Clone, Default)]
+pub struct ChatRequest {
+    pub request: CreateChatCompletionRequest,
+    pub extra_body: Map<String, Value>,
+}
+
+impl ChatRequest {
+    pub fn new(request: CreateChatCompletionRequest) -> Self {
+        ChatRequest { request, extra_body: Map::new() }
+    }
+
+    ///the json body of the request, fields in extra_body overwrite the fields of the request
+    pub fn body(&self) -> Result<Value, Error> {
+        let mut body = serde_json::to_value(&self.request).map_err(Error::SerdeError)?;
+        if let Value::Object(fields) = &mut body {
+            for (key, value) in self.extra_body.iter() {
+                fields.insert(key.clone(), value.clone());
+            }
+        }
+        Ok(body)
+    }
+}
+
+impl From<CreateChatCompletionRequest> for ChatRequest {
+    fn from(request: CreateChatCompletionRequest) -> Self {
+        ChatRequest::new(request)
+    }
+}
+
+///this is the trait that Patch uses to talk to a provider.
+/// The request is always an openai style ChatRequest (after handle_response_model has added the response model to it),
+/// it is up to the backend to translate it to whatever the provider expects.
+///
+/// async_openai::Client implements this trait, so the Patch { client: Client::new(), ... } usage is unchanged.
+/// To plug in your own gateway or a fake for testing implement `create` (and `create_stream` if you support streaming)
+///
+/// Example
+///
+/// #[derive(Clone)]
+/// struct MyGateway { ... }
+///
+/// impl ChatBackend for MyGateway {
+///     fn create(&self, request: ChatRequest) -> BackendFuture<ChatCompletionResponseWrapper> {
+///         let this = self.clone();
+///         Box::pin(async move {
+///             let response = this.send(request.body()?).await?;
+///             Ok(ChatCompletionResponseWrapper::AtOnce(response))
+///         })
+///     }
+/// }
+///
+/// let patched_client = Patch { client: MyGateway { ... }, mode: Some(Mode::TOOLS) };
+pub trait ChatBackend: Clone + Send + Sync + 'static {
+    ///sends a non streaming request, should return ChatCompletionResponseWrapper::AtOnce
+    /// or a provider specific variant like ChatCompletionResponseWrapper::Anthropic
+    fn create(&self, request: ChatRequest) -> BackendFuture<ChatCompletionResponseWrapper>;
+
+    ///sends a streaming request, the default implementation returns Error::NotImplementedError
+    fn create_stream(&self, _request: ChatRequest) -> BackendFuture<ChatCompletionResponseStream> {
+        Box::pin(async move {
+            Err(Error::NotImplementedError("this backend does not support streaming".to_string()))
+        })
+    }
+
+    ///like create_stream, but the usage of the response is passed to `usage` when it arrives.
+    /// openai compatible apis send it in a last chunk without choices when stream_options.include_usage is set,
+    /// async_openai drops it when parsing that chunk. The default implementation calls create_stream and never reports usage
+    fn create_stream_with_usage(&self, request: ChatRequest, _usage: UsageSink) -> BackendFuture<ChatCompletionResponseStream> {
+        self.create_stream(request)
+    }
+
+    ///sends the request to the legacy completions endpoint (Mode::COMPLETION), the messages are flattened
+    /// into a single prompt with completion_request_from_chat. The default implementation returns Error::NotImplementedError
+    fn complete(&self, _request: ChatRequest) -> BackendFuture<ChatCompletionResponseWrapper> {
+        Box::pin(async move {
+            Err(Error::NotImplementedError("this backend does not support the completions endpoint".to_string()))
+        })
+    }
+
+    ///dispatches to create or create_stream depending on request.stream
+    fn chat(&self, request: ChatRequest) -> BackendFuture<ChatCompletionResponseWrapper> {
+        match request.request.stream {
+            Some(true) => {
+                let stream = self.create_stream(request);
+                Box::pin(async move {
+                    Ok(ChatCompletionResponseWrapper::Stream(stream.await?))
+                })
+            }
+            Some(false) | None => self.create(request),
+        }
+    }
+}
+
+///wraps a ChatBackend and re-requests the rest of a streamed response when the connection drops in the middle of it.
+/// The text received so far is sent back as an assistant message followed by a user message asking the model
+/// to continue exactly where it stopped, the chunks of the new stream are appended to the ones already yielded.
+/// The non streaming methods are passed through unchanged.
+///
+/// * only text (delta.content) can be resumed, a stream that is cut off after a tool call fragment
+///   ends with the error because the model would start the call over
+/// * an ApiError (a refusal, a rate limit, ...) is not a dropped connection and is passed on as is
+/// * once max_reconnects is reached the error is passed on, so it arrives as an Err item of InstructorResponse::Stream
+///
+/// Example
+///
+/// let patched_client = Patch { client: ReconnectingBackend::new(Client::new(), 2), mode: Some(Mode::MD_JSON) };
+#[derive(Debug, Clone)]
+pub struct ReconnectingBackend<B: ChatBackend> {
+    pub backend: B,
+    pub max_reconnects: usize,
+}
+
+impl<B: ChatBackend> ReconnectingBackend<B> {
+    pub fn new(backend: B, max_reconnects: usize) -> Self {
+        ReconnectingBackend { backend, max_reconnects }
+    }
+
+    ///the stream of the inner backend, reporting its usage if `usage` is given
+    fn stream_of(&self, request: ChatRequest, usage: &Option<UsageSink>) -> BackendFuture<ChatCompletionResponseStream> {
+        match usage {
+            Some(usage) => self.backend.create_stream_with_usage(request, usage.clone()),
+            None => self.backend.create_stream(request),
+        }
+    }
+
+    ///the stream of create_stream and create_stream_with_usage
+    fn reconnecting_stream(&self, request: ChatRequest, usage: Option<UsageSink>) -> BackendFuture<ChatCompletionResponseStream> {
+        let this = self.clone();
+        Box::pin(async move {
+            let mut response = this.stream_of(request.clone(), &usage).await?;
+            let stream = stream! {
+                let mut text = String::new();
+                let mut tool_calls = false;
+                let mut reconnects = 0;
+                loop {
+                    let mut dropped = None;
+                    while let Some(chunk) = response.next().await {
+                        match chunk {
+                            Ok(chunk) => {
+                                for choice in chunk.choices.iter().filter(|choice| choice.index == 0) {
+                                    if let Some(content) = choice.delta.content.as_ref() {
+                                        text.push_str(content);
+                                    }
+                                    tool_calls |= choice.delta.tool_calls.as_ref().is_some_and(|calls| !calls.is_empty());
+                                }
+                                yield Ok(chunk);
+                            }
+                            Err(e) if is_dropped(&e) && !tool_calls && reconnects < this.max_reconnects => {
+                                dropped = Some(e);
+                                break;
+                            }
+                            Err(e) => yield Err(e),
+                        }
+                    }
+                    let dropped = match dropped {
+                        Some(dropped) => dropped,
+                        None => break,
+                    };
+                    reconnects += 1;
+                    match this.stream_of(continuation_request(&request, &text), &usage).await {
+                        Ok(stream) => response = stream,
+                        Err(e) => {
+                            yield Err(OpenAIError::StreamError(format!("{}, reconnecting failed: {}", dropped, e)));
+                            break;
+                        }
+                    }
+                }
+            };
+            Ok(Box::pin(stream) as ChatCompletionResponseStream)
+        })
+    }
+}
+
+///whether the stream failed because the connection was lost rather than because the provider returned an error
+fn is_dropped(error: &OpenAIError) -> bool {
+    matches!(error, OpenAIError::StreamError(_) | OpenAIError::Reqwest(_))
+}
+
+///the request for the rest of a response of which `text` has been received
+fn continuation_request(request: &ChatRequest, text: &str) -> ChatRequest {
+    let mut request = request.clone();
+    // nothing was received, the request is simply sent again
+    if text.is_empty() {
+        return request;
+    }
+    request.request.messages.push(ChatCompletionRequestMessage::Assistant(
+        ChatCompletionRequestAssistantMessage {
+            role: Role::Assistant,
+            content: Some(text.to_string()),
+            ..Default::default()
+        }
+    ));
+    request.request.messages.push(ChatCompletionRequestMessage::User(
+        ChatCompletionRequestUserMessage {
+            role: Role::User,
+            content: ChatCompletionRequestUserMessageContent::Text(
+                "Your response was cut off. Continue exactly where it stopped, do not repeat anything you already wrote".to_string()
+            ),
+            name: None,
+        }
+    ));
+    request
+}
+
+impl<B: ChatBackend> ChatBackend for ReconnectingBackend<B> {
+    fn create(&self, request: ChatRequest) -> BackendFuture<ChatCompletionResponseWrapper> {
+        self.backend.create(request)
+    }
+
+    fn complete(&self, request: ChatRequest) -> BackendFuture<ChatCompletionResponseWrapper> {
+        self.backend.complete(request)
+    }
+
+    fn create_stream(&self, request: ChatRequest) -> BackendFuture<ChatCompletionResponseStream> {
+        self.reconnecting_stream(request, None)
+    }
+
+    fn create_stream_with_usage(&self, request: ChatRequest, usage: UsageSink) -> BackendFuture<ChatCompletionResponseStream> {
+        // every reconnect is a request of its own, each of them reports its usage
+        self.reconnecting_stream(request, Some(usage))
+    }
+}
+
+///the http client of the backends made from an async_openai::Client, see OpenAIBackend
+fn default_http_client() -> &'static reqwest::Client {
+    static HTTP_CLIENT: OnceLock<reqwest::Client> = OnceLock::new();
+    HTTP_CLIENT.get_or_init(reqwest::Client::new)
+}
+
+fn chat_request_builder<C: Config>(http_client: &reqwest::Client, config: &C, body: &Value) -> reqwest::RequestBuilder {
+    http_client
+        .post(config.url("/chat/completions"))
+        .query(&config.query())
+        .headers(config.headers())
+        .json(body)
+}
+
+#[derive(Debug, Deserialize)]
+struct WrappedApiError {
+    error: ApiError,
+}
+
+///returns the refusal of the first choice, `message` for a response and `delta` for a stream chunk
+fn refusal(response: &Value, message_key: &str) -> Option<String> {
+    response["choices"][0][message_key]["refusal"].as_str().map(|refusal| refusal.to_string())
+}
+
+///the delay asked for by the retry-after-ms or Retry-After header of a response, a delay in seconds or an http date.
+/// A value that can not be read is ignored, so the backoff of the RetryPolicy is used
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let text = |name: &str| headers.get(name)?.to_str().ok().map(|value| value.trim());
+    let seconds = |value: &str| value.parse::<f64>().ok().filter(|delay| delay.is_finite() && *delay >= 0.0);
+    if let Some(millis) = text("retry-after-ms").and_then(seconds) {
+        return Some(Duration::from_secs_f64(millis / 1000.0));
+    }
+    let value = text("retry-after")?;
+    match seconds(value) {
+        Some(seconds) => Some(Duration::from_secs_f64(seconds)),
+        // a date in the past asks for no delay
+        None => http_date(value).map(|date| date.duration_since(SystemTime::now()).unwrap_or(Duration::ZERO)),
+    }
+}
+
+///parses an http date in the preferred format of RFC 9110 (IMF-fixdate), e.g. "Sun, 06 Nov 1994 08:49:37 GMT".
+/// The obsolete formats are not understood
+fn http_date(value: &str) -> Option<SystemTime> {
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let (_, date) = value.split_once(", ")?;
+    let parts: Vec<&str> = date.split(' ').collect();
+    let [day, month, year, time, "GMT"] = parts[..] else {
+        return None;
+    };
+    let day = day.parse::<i64>().ok().filter(|day| (1..=31).contains(day))?;
+    let month = MONTHS.iter().position(|name| *name == month)? as i64 + 1;
+    let year = year.parse::<i64>().ok()?;
+    let time: Vec<i64> = time.split(':').map(|part| part.parse::<i64>().ok()).collect::<Option<_>>()?;
+    let [hours @ 0..=23, minutes @ 0..=59, seconds @ 0..=60] = time[..] else {
+        return None;
+    };
+    // the days since 1970-01-01 of the proleptic gregorian calendar, with the year starting in march
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+    let seconds = days * 86400 + hours * 3600 + minutes * 60 + seconds;
+    Some(UNIX_EPOCH + Duration::from_secs(seconds.max(0) as u64))
+}
+
+///reads a response with a non success status into Error::HttpError
+async fn http_error(response: reqwest::Response) -> Error {
+    let status = response.status();
+    let retry_after = retry_after(response.headers());
+    let bytes = match response.bytes().await {
+        Ok(bytes) => bytes,
+        Err(e) => return OpenAIError::Reqwest(e).into(),
+    };
+    let error = match serde_json::from_slice::<WrappedApiError>(&bytes) {
+        Ok(wrapped) => wrapped.error,
+        Err(_) => ApiError {
+            message: String::from_utf8_lossy(&bytes).to_string(),
+            r#type: None,
+            param: None,
+            code: Some(serde_json::json!(status.as_u16())),
+        },
+    };
+    Error::HttpError(Box::new(HttpError { status: status.as_u16(), retry_after, error }))
+}
+
+async fn post_chat<C: Config>(http_client: &reqwest::Client, config: &C, request: &ChatRequest) -> Result<CreateChatCompletionResponse, Error> {
+    let response = chat_request_builder(http_client, config, &request.body()?)
+        .send()
+        .await
+        .map_err(OpenAIError::Reqwest)?;
+
+    if !response.status().is_success() {
+        return Err(http_error(response).await);
+    }
+    let bytes = response.bytes().await.map_err(OpenAIError::Reqwest)?;
+
+    let value = serde_json::from_slice::<Value>(&bytes).map_err(OpenAIError::JSONDeserialize)?;
+    if let Some(refusal) = refusal(&value, "message") {
+        return Err(Error::Refusal(refusal));
+    }
+    Ok(serde_json::from_value::<CreateChatCompletionResponse>(value).map_err(OpenAIError::JSONDeserialize)?)
+}
+
+///the stream of a refused request ends with an ApiError of type "refusal" carrying the whole refusal,
+/// extract_json_async turns it into Error::Refusal. The usage of the response is passed to `usage`.
+/// The stream is returned once the connection is open, a request that fails is returned as the error
+async fn post_chat_stream<C: Config>(
+    http_client: &reqwest::Client,
+    config: &C,
+    request: &ChatRequest,
+    usage: Option<UsageSink>
+) -> Result<ChatCompletionResponseStream, Error> {
+    let mut event_source: EventSource = chat_request_builder(http_client, config, &request.body()?)
+        .eventsource()
+        .map_err(|e| OpenAIError::StreamError(e.to_string()))?;
+    match event_source.next().await {
+        Some(Ok(Event::Open)) | None => {}
+        Some(Ok(Event::Message(_))) => {
+            return Err(OpenAIError::StreamError("a message arrived before the stream was opened".to_string()).into());
+        }
+        Some(Err(reqwest_eventsource::Error::InvalidStatusCode(_, response))) => return Err(http_error(response).await),
+        Some(Err(reqwest_eventsource::Error::Transport(e))) => return Err(OpenAIError::Reqwest(e).into()),
+        Some(Err(e)) => return Err(OpenAIError::StreamError(e.to_string()).into()),
+    }
+
+    let stream = stream! {
+        let mut refusal_text = String::new();
+        let mut finished = false;
+        while let Some(event) = event_source.next().await {
+            match event {
+                Ok(Event::Open) => continue,
+                Ok(Event::Message(message)) => {
+                    if message.data == "[DONE]" {
+                        break;
+                    }
+                    let value = match serde_json::from_str::<Value>(&message.data) {
+                        Ok(value) => value,
+                        Err(e) => {
+                            yield Err(OpenAIError::JSONDeserialize(e));
+                            break;
+                        }
+                    };
+                    if let Some(refusal) = refusal(&value, "delta") {
+                        refusal_text.push_str(&refusal);
+                    }
+                    finished |= value["choices"][0]["finish_reason"].is_string();
+                    if let Some(usage) = usage.as_ref().filter(|_| value["usage"].is_object()) {
+                        match serde_json::from_value::<CompletionUsage>(value["usage"].clone()) {
+                            Ok(completion_usage) => usage(completion_usage),
+                            Err(e) => {
+                                yield Err(OpenAIError::JSONDeserialize(e));
+                                break;
+                            }
+                        }
+                    }
+                    yield serde_json::from_value::<CreateChatCompletionStreamResponse>(value)
+                        .map_err(OpenAIError::JSONDeserialize);
+                }
+                // some servers close the connection after the last chunk instead of sending [DONE]
+                Err(reqwest_eventsource::Error::StreamEnded) if finished => break,
+                Err(e) => {
+                    yield Err(OpenAIError::StreamError(e.to_string()));
+                    break;
+                }
+            }
+        }
+        event_source.close();
+        if !refusal_text.is_empty() {
+            yield Err(OpenAIError::ApiError(ApiError {
+                message: refusal_text,
+                r#type: Some("refusal".to_string()),
+                param: None,
+                code: None,
+            }));
+        }
+    };
+    Ok(Box::pin(stream))
+}
+
+///an async_openai::Client together with the reqwest::Client its chat requests are sent with, opt in to it
+/// to send every chat request yourself rather than with client.chat(): async_openai can not send the extra body
+/// fields and drops the refusal field of the response. An async_openai::Client does not give out its http client,
+/// so a bare Client sends the requests async_openai can not send with a default reqwest::Client,
+/// use this backend to send them with your own (a proxy, custom tls, timeouts, ...)
+///
+/// Example
+///
+/// let http_client = reqwest::Client::builder().proxy(reqwest::Proxy::all("http://localhost:3128")?).build()?;
+/// let client = OpenAIBackend::new(Client::new()).with_http_client(http_client);
+/// let patched_client = Patch { client, mode: Some(Mode::TOOLS) };
+#[derive(Debug, Clone)]
+pub struct OpenAIBackend<C: Config> {
+    pub client: Client<C>,
+    pub http_client: reqwest::Client,
+}
+
+impl<C: Config> OpenAIBackend<C> {
+    pub fn new(client: Client<C>) -> Self {
+        OpenAIBackend { client, http_client: default_http_client().clone() }
+    }
+
+    ///the http client of every request, the requests async_openai sends itself (Mode::COMPLETION) included
+    pub fn with_http_client(mut self, http_client: reqwest::Client) -> Self {
+        self.client = self.client.with_http_client(http_client.clone());
+        self.http_client = http_client;
+        self
+    }
+}
+
+impl<C: Config> From<Client<C>> for OpenAIBackend<C> {
+    fn from(client: Client<C>) -> Self {
+        OpenAIBackend::new(client)
+    }
+}
+
+impl<C> ChatBackend for OpenAIBackend<C>
+where
+    C: Config + Clone + Send + Sync + 'static,
+{
+    fn create(&self, request: ChatRequest) -> BackendFuture<ChatCompletionResponseWrapper> {
+        let this = self.clone();
+        Box::pin(async move {
+            let res = post_chat(&this.http_client, this.client.config(), &request).await?;
+            Ok(ChatCompletionResponseWrapper::AtOnce(res))
+        })
+    }
+
+    fn create_stream(&self, request: ChatRequest) -> BackendFuture<ChatCompletionResponseStream> {
+        let this = self.clone();
+        Box::pin(async move {
+            post_chat_stream(&this.http_client, this.client.config(), &request, None).await
+        })
+    }
+
+    fn create_stream_with_usage(&self, request: ChatRequest, usage: UsageSink) -> BackendFuture<ChatCompletionResponseStream> {
+        let this = self.clone();
+        Box::pin(async move {
+            post_chat_stream(&this.http_client, this.client.config(), &request, Some(usage)).await
+        })
+    }
+
+    fn complete(&self, request: ChatRequest) -> BackendFuture<ChatCompletionResponseWrapper> {
+        let client = self.client.clone();
+        Box::pin(async move {
+            let request = completion_request_from_chat(&request)?;
+            let res = client.completions().create(request).await?;
+            Ok(ChatCompletionResponseWrapper::Completion(res))
+        })
+    }
+}
+
+///sends a request without extra body fields with client.chat(), so with the http client and the backoff
+/// of the async_openai::Client. The requests async_openai can not send (extra body fields, the usage of a stream)
+/// are sent as OpenAIBackend::new(client) sends them, use an OpenAIBackend to send those with your own http client
+impl<C> ChatBackend for Client<C>
+where
+    C: Config + Clone + Send + Sync + 'static,
+{
+    fn create(&self, request: ChatRequest) -> BackendFuture<ChatCompletionResponseWrapper> {
+        if !request.extra_body.is_empty() {
+            return OpenAIBackend::new(self.clone()).create(request);
+        }
+        let client = self.clone();
+        Box::pin(async move {
+            let res = client.chat().create(request.request).await?;
+            Ok(ChatCompletionResponseWrapper::AtOnce(res))
+        })
+    }
+
+    fn create_stream(&self, request: ChatRequest) -> BackendFuture<ChatCompletionResponseStream> {
+        if !request.extra_body.is_empty() {
+            return OpenAIBackend::new(self.clone()).create_stream(request);
+        }
+        let client = self.clone();
+        Box::pin(async move {
+            let stream = client.chat().create_stream(request.request).await?;
+            Ok(stream)
+        })
+    }
+
+    fn create_stream_with_usage(&self, request: ChatRequest, usage: UsageSink) -> BackendFuture<ChatCompletionResponseStream> {
+        OpenAIBackend::new(self.clone()).create_stream_with_usage(request, usage)
+    }
+
+    fn complete(&self, request: ChatRequest) -> BackendFuture<ChatCompletionResponseWrapper> {
+        let client = self.clone();
+        Box::pin(async move {
+            let request = completion_request_from_chat(&request)?;
+            let res = client.completions().create(request).await?;
+            Ok(ChatCompletionResponseWrapper::Completion(res))
+        })
+    }
+}
@@ -0,0 +1,77 @@
+use crate::error::Error;
+use crate::backend::ChatRequest;
+use async_openai::types::{
+    ChatCompletionRequestMessage, ChatCompletionRequestMessageContentPart, ChatCompletionRequestUserMessageContent,
+    CreateCompletionRequest, Prompt
+};
+
+///flattens the messages of a chat request into a single prompt for instruct models,
+/// the system messages come first and every other turn is prefixed with its role.
+/// The prompt ends with "Assistant:" so the model continues as the assistant, re-asks
+/// are appended to the messages and so end up at the end of the prompt text
+///
+/// # Example
+///
+/// system: "extract the user", user: "Jason is 25" becomes
+/// "extract the user\n\nUser: Jason is 25\n\nAssistant:"
+pub fn completion_prompt(messages: &[ChatCompletionRequestMessage]) -> Result<String, Error> {
+    let mut system: Vec<String> = Vec::new();
+    let mut turns: Vec<String> = Vec::new();
+
+    for message in messages {
+        match message {
+            ChatCompletionRequestMessage::System(message) => system.push(message.content.clone()),
+            ChatCompletionRequestMessage::User(message) => {
+                let text = match &message.content {
+                    ChatCompletionRequestUserMessageContent::Text(text) => text.clone(),
+                    ChatCompletionRequestUserMessageContent::Array(parts) => {
+                        parts.iter().map(|part| match part {
+                            ChatCompletionRequestMessageContentPart::Text(part) => Ok(part.text.clone()),
+                            ChatCompletionRequestMessageContentPart::Image(_) => Err(Error::NotImplementedError(
+                                "image content is not supported in completion mode".to_string()
+                            )),
+                        }).collect::<Result<Vec<String>, Error>>()?.join("\n")
+                    }
+                };
+                turns.push(format!("User: {}", text));
+            }
+            ChatCompletionRequestMessage::Assistant(message) => {
+                turns.push(format!("Assistant: {}", message.content.clone().unwrap_or_default()));
+            }
+            ChatCompletionRequestMessage::Tool(_) | ChatCompletionRequestMessage::Function(_) => {
+                return Err(Error::NotImplementedError(
+                    "tool and function messages are not supported in completion mode".to_string()
+                ));
+            }
+        }
+    }
+
+    let mut sections = Vec::new();
+    if !system.is_empty() {
+        sections.push(system.join("\n\n"));
+    }
+    sections.extend(turns);
+    sections.push("Assistant:".to_string());
+    Ok(sections.join("\n\n"))
+}
+
+///builds the request for the completions endpoint from a chat request,
+/// the sampling parameters are copied and the messages are flattened with completion_prompt
+pub fn completion_request_from_chat(chat_request: &ChatRequest) -> Result<CreateCompletionRequest, Error> {
+    let kwargs = &chat_request.request;
+    Ok(CreateCompletionRequest {
+        model: kwargs.model.clone(),
+        prompt: Prompt::String(completion_prompt(&kwargs.messages)?),
+        max_tokens: kwargs.max_tokens,
+        temperature: kwargs.temperature,
+        top_p: kwargs.top_p,
+        n: kwargs.n,
+        stop: kwargs.stop.clone(),
+        presence_penalty: kwargs.presence_penalty,
+        frequency_penalty: kwargs.frequency_penalty,
+        logit_bias: kwargs.logit_bias.clone(),
+        user: kwargs.user.clone(),
+        seed: kwargs.seed,
+        ..Default::default()
+    })
+}
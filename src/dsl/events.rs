@@ -0,0 +1,120 @@
+use validator::ValidateArgs;
+use crate::error::Error;
+use crate::enums::IterableOrSingle;
+use crate::mode::Mode;
+use crate::openai_schema::BaseArg;
+use crate::openai_schema::BaseSchema;
+use crate::dsl::iterable::{IterableBase, item_chunks};
+use crate::json_stream::{JsonEvent, JsonEventParser};
+use async_openai::types::ChatCompletionResponseStream;
+use futures::stream::{Stream, StreamExt};
+use async_stream::stream;
+use serde_json::Value;
+use std::pin::Pin;
+
+///an event of a streamed response, see EventsBase::events_from_streaming_response
+#[derive(Debug)]
+pub enum StreamEvent<T> {
+    ///the '{' of the object with this index was received, objects are numbered from 0
+    ObjectStarted { index: usize },
+    ///text of a string field that was just received, e.g. path "summary" or "sections[2].title"
+    FieldDelta { path: String, text: String },
+    ///the value of a field is complete, it has not been validated yet
+    FieldCompleted { path: String, value: Value },
+    ///the object is complete and valid
+    ObjectCompleted { index: usize, object: T },
+    ///the object is complete but did not parse or validate
+    ValidationFailed { index: usize, errors: Error },
+}
+
+pub type EventStream<T> = Pin<Box<dyn Stream<Item = Result<StreamEvent<T>, Error>> + Send>>;
+
+///This is the trait for following a streaming response field by field rather than object by object,
+/// e.g. to show a long text field while it is being generated and the other fields are still loading.
+/// It is implemented for every struct that implements IterableBase
+///
+/// now you can access the following methods:
+///
+/// Mystruct::events_from_streaming_response(...)
+pub trait EventsBase<Args, T>
+where
+    T: ValidateArgs<'static, Args=Args> + BaseSchema + 'static ,
+    Args: BaseArg,
+{
+    type Args : BaseArg;
+
+    ///recieves a stream of CreateChatCompletionStreamResponse and returns a stream of StreamEvent<T>,
+    /// the json text of IterableBase::extract_json_async is parsed with JsonEventParser.
+    /// Every object yields ObjectStarted, then the events of its fields, then ObjectCompleted or ValidationFailed.
+    /// Errors of the stream itself are yielded as Err, Mode::MD_YAML and Mode::XML are not json and are not supported
+    ///
+    /// # Arguments
+    ///
+    /// * `model` - The model to use, IterableOrSingle::Iterable yields an object per element
+    /// * `response` - The stream of CreateChatCompletionStreamResponse
+    /// * `validation_context` - The validation context to use for each object
+    /// * `mode` - The mode the request was made in
+    ///
+    /// # Returns
+    /// * `EventStream<T>` - A stream of Result<StreamEvent<T>, Error>
+    async fn events_from_streaming_response(
+        model: IterableOrSingle<Self>,
+        response: ChatCompletionResponseStream,
+        validation_context: &Args,
+        mode: Mode,
+    ) -> EventStream<T>
+    where
+        Self: Sized + ValidateArgs<'static> + BaseSchema;
+}
+
+impl<A, T> EventsBase<A, T> for T
+where
+    T: ValidateArgs<'static, Args=A> + BaseSchema + 'static ,
+    A: BaseArg + 'static,
+{
+    type Args = A;
+
+    async fn events_from_streaming_response(
+        model: IterableOrSingle<Self>,
+        response: ChatCompletionResponseStream,
+        validation_context: &A,
+        mode: Mode,
+    ) -> EventStream<T>
+    where
+        Self: Sized + ValidateArgs<'static> + BaseSchema,
+    {
+        if matches!(mode, Mode::MD_YAML | Mode::XML) {
+            return futures::stream::once(async move {
+                Err(Error::NotImplementedError(format!("field events are not supported in mode {}", mode)))
+            }).boxed();
+        }
+        let json_chunks = T::extract_json_async(response, mode).await;
+        let mut json_chunks = item_chunks(matches!(model, IterableOrSingle::Iterable(_)), json_chunks, mode);
+        let validation_context = validation_context.clone();
+        stream! {
+            let mut parser = JsonEventParser::new();
+            while let Some(chunk_result) = json_chunks.next().await {
+                let chunk = match chunk_result {
+                    Ok(chunk) => chunk,
+                    Err(e) => {
+                        yield Err(e);
+                        continue;
+                    }
+                };
+                for event in parser.push(&chunk) {
+                    yield Ok(match event {
+                        JsonEvent::ObjectStarted { index } => StreamEvent::ObjectStarted { index },
+                        JsonEvent::FieldDelta { path, text } => StreamEvent::FieldDelta { path, text },
+                        JsonEvent::FieldCompleted { path, value } => StreamEvent::FieldCompleted { path, value },
+                        JsonEvent::ObjectEnded { index, json } => {
+                            match T::validate_stream_item(&model, &json, &validation_context, mode) {
+                                Ok(object) => StreamEvent::ObjectCompleted { index, object },
+                                Err(errors) => StreamEvent::ValidationFailed { index, errors },
+                            }
+                        },
+                    });
+                }
+            }
+        }.boxed()
+    }
+}
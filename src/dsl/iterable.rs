@@ -4,11 +4,15 @@ use crate::enums::IterableOrSingle;
 use crate::mode::Mode;
 use crate::openai_schema::BaseArg;
 use crate::openai_schema::BaseSchema;
-use crate::openai_schema::OpenAISchema;
+use crate::openai_schema::{OpenAISchema, check_tool_call};
 use crate::enums::InstructorResponse;
-use async_openai::types::{ChatCompletionResponseStream};
+use crate::dsl::partial::PartialBase;
+use crate::dsl::yaml::{YamlBase, YamlListSplitter};
+use async_openai::types::{ChatCompletionMessageToolCall, ChatCompletionMessageToolCallChunk, ChatCompletionResponseStream};
+use async_openai::error::OpenAIError;
 use std::pin::Pin;
 use crate::types::JsonStream;
+use crate::json_stream::{JsonObjectSplitter, ToolCallSplitter, skip_to_first_array, skip_items_wrapper, extract_from_code_fence};
 use futures::stream::{Stream, StreamExt};
 use async_stream::stream;
 use pin_utils::pin_mut;
@@ -39,7 +43,8 @@ where
 {
     type Args : BaseArg;
 
-    ///recieves a stream of CreateChatCompletionStreamResponse and returns a stream of strings
+    ///recieves a stream of CreateChatCompletionStreamResponse and returns a stream of strings,
+    /// in Mode::TOOLS the arguments of a single tool call (a parallel call ends the stream with an error)
     /// 
     /// # Arguments
     /// 
@@ -58,6 +63,7 @@ where
 
     ///recieves a stream of CreateChatCompletionStreamResponse and returns a stream of parsed objects 
     /// for each object T_i in the stream T_i::model_validate_json() is called on it 
+    /// if the model is IterableOrSingle::Partial the stream yields snapshots of a single object instead (see PartialBase)
     /// 
     /// # Arguments
     /// 
@@ -80,7 +86,7 @@ where
     
     ///recieves a stream of strings(JsonStream) and returns a stream of Result<T, Error> where T is the parsed struct 
     /// for each object T_i in the stream T_i::model_validate_json() is called on it.
-    /// self.get_object() is used to collect the tokens into a string that can get parsed as json
+    /// json_stream::JsonObjectSplitter is used to collect the tokens into strings that can get parsed as json
     /// 
     /// # Arguments
     /// 
@@ -100,9 +106,148 @@ where
     where
         Self: Sized + ValidateArgs<'static> + BaseSchema;
 
+    ///recieves a stream of CreateChatCompletionStreamResponse in Mode::TOOLS and returns a stream of Result<T, Error>,
+    /// the fragments of parallel tool calls are assembled per index (see ToolCallSplitter) 
+    /// and every call is checked against the name of the model and validated as soon as its arguments are complete,
+    /// a Single model yields an error for every call after the first
+    /// 
+    /// # Arguments
+    /// 
+    /// * `model` - The model to use
+    /// * `response` - The stream of CreateChatCompletionStreamResponse
+    /// * `validation_context` - The validation context to use for each struct
+    /// 
+    /// # Returns
+    /// * `InstructorResponse::Stream(stream)` - A stream of Result<T, Error> with one item per tool call
+    fn tasks_from_tool_call_chunks(
+        model: IterableOrSingle<Self>,
+        response: ChatCompletionResponseStream,
+        validation_context: Args
+    ) -> InstructorResponse<T>
+    where
+        Self: Sized + ValidateArgs<'static> + BaseSchema;
+
+
+    ///recieves a stream of CreateChatCompletionStreamResponse for an IterableOrSingle::Iterable model and returns 
+    /// the raw text of every item as soon as it is complete, before it is validated: 
+    /// the json object, the arguments of a tool call in Mode::TOOLS or the list item in Mode::MD_YAML.
+    /// A tool call to another function is yielded as an error.
+    /// This is used to re-ask for the invalid items of a stream (see retry::reask_stream_items)
+    /// 
+    /// # Arguments
+    /// 
+    /// * `response` - The stream of CreateChatCompletionStreamResponse
+    /// * `mode` - The mode the response was requested in
+    /// 
+    /// # Returns
+    /// * `JsonStream` - A stream of the raw items
+    async fn raw_items_from_streaming_response(
+        response: ChatCompletionResponseStream,
+        mode: Mode
+    ) -> JsonStream
+    where
+        Self: Sized + ValidateArgs<'static> + BaseSchema;
+
+    ///parses and validates an item yielded by raw_items_from_streaming_response
+    fn validate_stream_item(
+        model: &IterableOrSingle<Self>,
+        item: &str,
+        validation_context: &Args,
+        mode: Mode
+    ) -> Result<T, Error>
+    where
+        Self: Sized + ValidateArgs<'static> + BaseSchema;
+
+    ///returns the first object of s from the byte offset `index` on and the text after it,
+    /// the object is found with json_stream::JsonObjectSplitter so braces inside strings do not count
     fn get_object(s: &str, index: usize) -> (Option<String>, String);
 }
 
+///the text of the items of a streamed response, the schema constrained modes stream an Iterable as {"items": [...]},
+/// in Mode::JSON and Mode::MD_JSON the llm may wrap the list in an object as well.
+/// The prose around the ```json codeblock of Mode::MD_JSON is dropped
+pub(crate) fn item_chunks(iterable: bool, json_chunks: JsonStream, mode: Mode) -> JsonStream {
+    match (iterable, mode) {
+        (true, Mode::JSON_SCHEMA | Mode::OLLAMA_JSON_SCHEMA | Mode::LLAMA_CPP_JSON_SCHEMA | Mode::LLAMA_CPP_GRAMMAR) => {
+            skip_to_first_array(json_chunks)
+        },
+        (true, Mode::JSON) => skip_items_wrapper(json_chunks),
+        (true, Mode::MD_JSON) => skip_items_wrapper(extract_from_code_fence(json_chunks)),
+        (false, Mode::MD_JSON) => extract_from_code_fence(json_chunks),
+        _ => json_chunks,
+    }
+}
+
+///the arguments of every tool call of the stream as soon as they are complete (see ToolCallSplitter),
+/// a call to another function than T is yielded as an error
+fn tool_call_arguments<T>(response: ChatCompletionResponseStream) -> JsonStream {
+    stream! {
+        pin_mut!(response);
+        let mut splitter = ToolCallSplitter::new();
+        while let Some(chunk_result) = response.next().await {
+            match chunk_result {
+                Ok(chunk) => {
+                    let tool_calls = chunk.choices.iter()
+                        .filter_map(|choice| choice.delta.tool_calls.as_ref())
+                        .flat_map(|tool_calls| splitter.push(tool_calls))
+                        .collect::<Vec<ChatCompletionMessageToolCall>>();
+                    for tool_call in tool_calls.iter() {
+                        yield check_tool_call::<T>(tool_call);
+                    }
+                },
+                // the refusal of a json_schema request arrives as an ApiError at the end of the stream
+                Err(OpenAIError::ApiError(e)) if e.r#type.as_deref() == Some("refusal") => {
+                    yield Err(Error::Refusal(e.message));
+                },
+                Err(e) => {
+                    yield Err(Error::OpenAIError(e));
+                },
+            }
+        }
+        for tool_call in splitter.finish().iter() {
+            yield check_tool_call::<T>(tool_call);
+        }
+    }.boxed()
+}
+
+///the arguments of the tool call of a Single or Partial model as they arrive, the stream ends with an error
+/// as soon as a parallel call (a second call index) appears, since only one object can be parsed from it
+fn single_tool_call_arguments(response: ChatCompletionResponseStream) -> JsonStream {
+    stream! {
+        pin_mut!(response);
+        let mut first_index = None;
+        while let Some(chunk_result) = response.next().await {
+            match chunk_result {
+                Ok(chunk) => {
+                    let calls = chunk.choices.into_iter()
+                        .filter_map(|choice| choice.delta.tool_calls)
+                        .flatten()
+                        .collect::<Vec<ChatCompletionMessageToolCallChunk>>();
+                    for call in calls {
+                        let index = *first_index.get_or_insert(call.index);
+                        if call.index != index {
+                            yield Err(Error::Generic(format!(
+                                "expected one tool call, the response made a parallel call with index {}", call.index
+                            )));
+                            return;
+                        }
+                        if let Some(arguments) = call.function.and_then(|function| function.arguments) {
+                            yield Ok(arguments);
+                        }
+                    }
+                },
+                // the refusal of a json_schema request arrives as an ApiError at the end of the stream
+                Err(OpenAIError::ApiError(e)) if e.r#type.as_deref() == Some("refusal") => {
+                    yield Err(Error::Refusal(e.message));
+                },
+                Err(e) => {
+                    yield Err(Error::OpenAIError(e));
+                },
+            }
+        }
+    }.boxed()
+}
+
 impl<A, T> IterableBase<A, T> for T
 where
     T: ValidateArgs<'static, Args=A> + BaseSchema + 'static ,
@@ -117,6 +262,9 @@ where
     where
         Self: Sized + ValidateArgs<'static> + BaseSchema
     {
+        if mode == Mode::TOOLS {
+            return single_tool_call_arguments(completion);
+        }
         let t0 = std::time::Instant::now();
         let stream = completion.filter_map(move |chunk_result| {
             async move {
@@ -124,7 +272,8 @@ where
                     Ok(chunk) => {
                         // Assuming each chunk or its relevant parts can be cloned as needed
                         match mode {
-                            Mode::JSON | Mode::MD_JSON | Mode::JSON_SCHEMA => {
+                            Mode::JSON | Mode::MD_JSON | Mode::MD_YAML | Mode::JSON_SCHEMA |
+                            Mode::OLLAMA_JSON_SCHEMA | Mode::LLAMA_CPP_JSON_SCHEMA | Mode::LLAMA_CPP_GRAMMAR => {
                                 let chunk = chunk.choices.get(0).and_then(|choice| {
                                     // Here, we clone the content of the choice, if necessary
                                     choice.delta.content.clone().map(
@@ -134,24 +283,17 @@ where
                                 println!("json: chunk: {:?} at time: {:?}", chunk, t0.elapsed());
                                 chunk
                             },
-                            Mode::TOOLS => {
-                                if let Some(choice) = chunk.choices.get(0) {
-                                    if let Some(arguments) = choice.delta.tool_calls.as_ref()
-                                        .and_then(|tc| tc.get(0))
-                                        .and_then(|call| call.function.as_ref())
-                                        .and_then(|f| f.arguments.clone()) {
-                                        println!("tools: chunk: {:?} at time: {:?}", arguments, t0.elapsed());
-                                        return Some(Ok(Ok(arguments)));
-                                    }
-                                }
-                                None // else we return None
-                            },
                             _ => Some(Err(Error::Generic(
                                 format!("Mode {:?} is not supported for MultiTask streaming", mode)
                             ))),
                         }
                     },
-                    Err(_) => None, // Consider how to handle stream errors appropriately
+                    // the refusal of a json_schema request arrives as an ApiError at the end of the stream
+                    Err(OpenAIError::ApiError(e)) if e.r#type.as_deref() == Some("refusal") => {
+                        Some(Ok(Err(Error::Refusal(e.message))))
+                    },
+                    // a dropped connection is yielded as an error rather than ending the stream early
+                    Err(e) => Some(Ok(Err(Error::OpenAIError(e)))),
                 }
             }
         }).flat_map(|option| futures::stream::iter(option.into_iter()));
@@ -167,12 +309,18 @@ where
     where
         Self: Sized + ValidateArgs<'static> + BaseSchema
     { 
+        // every parallel tool call is one item
+        if mode == Mode::TOOLS && !matches!(model, IterableOrSingle::Partial(_)) {
+            return Self::tasks_from_tool_call_chunks(model, response, validation_context.clone());
+        }
 
         let json_chunks  = Self::extract_json_async(response, mode).await;
-        //if mode == Mode::MD_JSON {
-        //    let mut json_chunks: JsonStream = extract_json_from_stream_async(response).await;
-        //} 
-        Self::tasks_from_chunks_async(model, json_chunks, validation_context.clone()).await
+        let json_chunks = item_chunks(matches!(model, IterableOrSingle::Iterable(_)), json_chunks, mode);
+        match model {
+            IterableOrSingle::Partial(_) => Self::partials_from_chunks(model, json_chunks, validation_context.clone()),
+            _ if mode == Mode::MD_YAML => Self::yaml_tasks_from_chunks(model, json_chunks, validation_context.clone()),
+            _ => Self::tasks_from_chunks_async(model, json_chunks, validation_context.clone()).await,
+        }
     }
 
     async fn tasks_from_chunks_async(
@@ -183,24 +331,13 @@ where
     where
         Self: Sized + ValidateArgs<'static> + BaseSchema,
     {
-        let mut started = false;
-        let mut potential_object = String::new();
         let stream = stream! {
             pin_mut!(json_chunks); // Ensure json_chunks is pinned for .next() in async context
+            let mut splitter = JsonObjectSplitter::new();
             while let Some(chunk_result) = json_chunks.next().await {
                 match chunk_result {
                     Ok(chunk) => {
-                        potential_object.push_str(&chunk);
-                        if !started {
-                            if let Some(index) = chunk.find('[') {
-                                started = true;
-                                potential_object = chunk[index + 1..].to_string();
-                                continue; // Continue to the next iteration of the loop
-                            }
-                        }
-                        let (task_json, new_potential_object) = Self::get_object(&potential_object, 0);
-                        potential_object = new_potential_object;
-                        if let Some(task_json) = task_json {
+                        for task_json in splitter.push(&chunk) {
                             // Ensure model_validate_json and its entire call chain are `Send`
                             match Self::model_validate_json(&model, &task_json, &validation_context) {
                                 Ok(single) => {
@@ -221,21 +358,91 @@ where
         InstructorResponse::Stream(stream)
     }
 
-    fn get_object(s: &str, mut stack: usize) -> (Option<String>, String) {
-        let start_index = s.find('{');
-        if let Some(start) = start_index {
-            for (i, c) in s.char_indices() {
-                if c == '{' {
-                    stack += 1;
-                } else if c == '}' {
-                    stack -= 1;
-                    if stack == 0 {
-                        // Adjusted slicing to handle Rust's string slice indexing
-                        return (Some(s[start..=i].to_string()), s[i+1..].to_string());
-                    }
+    fn tasks_from_tool_call_chunks(
+        model: IterableOrSingle<Self>,
+        response: ChatCompletionResponseStream,
+        validation_context: Self::Args,
+    ) -> InstructorResponse<T>
+    where
+        Self: Sized + ValidateArgs<'static> + BaseSchema,
+    {
+        let single = !matches!(model, IterableOrSingle::Iterable(_));
+        let stream = tool_call_arguments::<T>(response).enumerate().map(move |(index, arguments)| {
+            if single && index > 0 {
+                return Err(Error::Generic("expected one tool call, the response made parallel tool calls".to_string()));
+            }
+            arguments
+                .and_then(|arguments| Self::model_validate_json(&model, &arguments, &validation_context))
+                .and_then(|response| response.unwrap())
+        }).boxed();
+        InstructorResponse::Stream(stream)
+    }
+
+    async fn raw_items_from_streaming_response(
+        response: ChatCompletionResponseStream,
+        mode: Mode
+    ) -> JsonStream
+    where
+        Self: Sized + ValidateArgs<'static> + BaseSchema,
+    {
+        if mode == Mode::TOOLS {
+            return tool_call_arguments::<T>(response);
+        }
+        let json_chunks = Self::extract_json_async(response, mode).await;
+        let mut json_chunks = item_chunks(true, json_chunks, mode);
+        stream! {
+            let mut yaml_splitter = YamlListSplitter::new();
+            let mut json_splitter = JsonObjectSplitter::new();
+            while let Some(chunk_result) = json_chunks.next().await {
+                match chunk_result {
+                    Ok(chunk) if mode == Mode::MD_YAML => {
+                        for item in yaml_splitter.push(&chunk) {
+                            yield Ok(item);
+                        }
+                    },
+                    Ok(chunk) => {
+                        for item in json_splitter.push(&chunk) {
+                            yield Ok(item);
+                        }
+                    },
+                    Err(e) => {
+                        yield Err(e);
+                    },
                 }
             }
+            for item in yaml_splitter.finish() {
+                yield Ok(item);
+            }
+        }.boxed()
+    }
+
+    fn validate_stream_item(
+        model: &IterableOrSingle<Self>,
+        item: &str,
+        validation_context: &Self::Args,
+        mode: Mode
+    ) -> Result<T, Error>
+    where
+        Self: Sized + ValidateArgs<'static> + BaseSchema,
+    {
+        let inner = model.clone().unwrap().expect("IterableOrSingle::unwrap can not fail");
+        let response = match mode {
+            // a yaml list item is parsed as a list with a single element
+            Mode::MD_YAML => Self::model_validate_yaml(&IterableOrSingle::Iterable(inner), item, validation_context)?,
+            // a json item is a single object, the paths of its errors are relative to it
+            _ => Self::model_validate_json(&IterableOrSingle::Single(inner), item, validation_context)?,
+        };
+        response.unwrap()
+    }
+
+    fn get_object(s: &str, index: usize) -> (Option<String>, String) {
+        let start = match s.get(index..) {
+            Some(_) => index,
+            None => return (None, s.to_string()),
+        };
+        match JsonObjectSplitter::first_object(&s[start..]) {
+            Some((object, end)) => (Some(object), s[start + end..].to_string()),
+            None => (None, s.to_string()),
         }
-        (None, s.to_string())
     }
 }
@@ -1 +1,5 @@
 pub mod iterable;
+pub mod partial;
+pub mod parallel;
+pub mod yaml;
+pub mod events;
@@ -0,0 +1,114 @@
+use validator::ValidateArgs;
+use crate::error::{Error, DeserializeError};
+use crate::enums::ChatCompletionResponseWrapper;
+use crate::openai_schema::{BaseArg, BaseSchema, deserialize_json};
+use async_openai::types::FunctionObject;
+use std::fmt::Debug;
+
+
+///This is the trait for extracting several different response models from one response,
+/// each tool call the llm makes is dispatched to the model with the same name and validated with its own validator.
+/// It is usually derived on an enum with one unnamed field per variant
+///
+/// Example
+///
+/// #[derive(ParallelBase, Debug)]
+/// enum Entity {
+///     Person(Person),
+///     Company(Company),
+/// }
+///
+/// now you can access the following methods:
+///
+/// Entity::tool_schemas()
+/// Entity::from_tool_call(...)
+///
+/// and call patch.parallel_chat_completion::<Entity>(...)
+pub trait ParallelBase: Sized + Debug + Send + 'static {
+    ///the validation context shared by all the models
+    type Args: BaseArg;
+
+    ///returns the tool schema of every model, these are all sent in the tools field
+    fn tool_schemas() -> Vec<FunctionObject>;
+
+    ///parses and validates the arguments of a tool call into the model with the matching name
+    /// # Arguments
+    ///
+    /// * `name` - the name of the function that was called
+    /// * `arguments` - the arguments of the function call as a json string
+    /// * `validation_context` - The validation context to use
+    fn from_tool_call(
+        name: &str,
+        arguments: &str,
+        validation_context: &Self::Args,
+    ) -> Result<Self, Error>;
+}
+
+///implemented for the validation context A itself, the ParallelBase derive checks
+/// that the Args of every variant is the Args of the first one
+#[diagnostic::on_unimplemented(
+    message = "the models of a ParallelBase enum must share one validation context, this one takes `{Self}` instead of `{A}`",
+    label = "the Args of this model differ from the Args of the first variant"
+)]
+pub trait SameArgs<A> {}
+
+impl<A> SameArgs<A> for A {}
+
+///parses the arguments of a single tool call into T and validates it, used by the ParallelBase derive
+pub fn parse_tool_arguments<T, A>(arguments: &str, validation_context: &A) -> Result<T, Error>
+where
+    T: ValidateArgs<'static, Args=A> + BaseSchema,
+    A: BaseArg,
+{
+    let data = deserialize_json::<T>(arguments, None)?;
+    match data.validate_args(validation_context.clone()) {
+        Ok(_) => Ok(data),
+        Err(e) => Err(Error::ValidationErrors(e)),
+    }
+}
+
+///dispatches every tool call of the response to P::from_tool_call,
+/// the errors of all tool calls that fail are collected into one error so they can be re-asked together,
+/// arguments that do not fit their model are an Error::DeserializeErrors with the index of their tool call
+/// # Arguments
+///
+/// * `response` - the response from the llm (openai or anthropic)
+/// * `validation_context` - The validation context to use
+/// # Returns
+/// * `Vec<P>` - one item per tool call in the order they were made
+pub fn parse_parallel_tools<P: ParallelBase>(
+    response: &ChatCompletionResponseWrapper,
+    validation_context: &P::Args,
+) -> Result<Vec<P>, Error> {
+    let tool_calls = match response.get_tool_calls() {
+        Some(tool_calls) if !tool_calls.is_empty() => tool_calls,
+        _ => return Err(Error::Generic("No tool calls found in response".to_string())),
+    };
+
+    let mut items = Vec::with_capacity(tool_calls.len());
+    let mut errors = Vec::new();
+    // the arguments that do not fit their model, reported with the position of their tool call
+    let mut deserialize_errors = Vec::new();
+    for (index, tool_call) in tool_calls.iter().enumerate() {
+        match P::from_tool_call(&tool_call.function.name, &tool_call.function.arguments, validation_context) {
+            Ok(item) => items.push(item),
+            Err(Error::DeserializeErrors(call_errors)) => {
+                deserialize_errors.extend(call_errors.into_iter().map(|error| DeserializeError {
+                    item: Some(index),
+                    path: format!("/{}{}", index, error.path),
+                    ..error
+                }));
+            },
+            Err(e) => errors.push(format!("tool call {} ({}): {}", tool_call.id, tool_call.function.name, e)),
+        }
+    }
+
+    match (errors.is_empty(), deserialize_errors.is_empty()) {
+        (true, true) => Ok(items),
+        (true, false) => Err(Error::DeserializeErrors(deserialize_errors)),
+        (false, _) => {
+            errors.extend(deserialize_errors.iter().map(|error| error.to_string()));
+            Err(Error::Generic(errors.join("\n")))
+        },
+    }
+}
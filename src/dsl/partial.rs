@@ -0,0 +1,253 @@
+use validator::ValidateArgs;
+use crate::enums::IterableOrSingle;
+use crate::openai_schema::BaseArg;
+use crate::openai_schema::BaseSchema;
+use crate::openai_schema::OpenAISchema;
+use crate::enums::InstructorResponse;
+use crate::types::JsonStream;
+use crate::utils::extract_json_from_codeblock;
+use futures::stream::StreamExt;
+use async_stream::stream;
+use pin_utils::pin_mut;
+use serde_json::{Map, Value};
+use std::collections::HashSet;
+use crate::json_stream::{JsonEvent, JsonEventParser};
+
+
+///This is the trait for parsing a single object from a streaming response as it is being generated.
+/// It is implemented for every struct that implements IterableBase,
+/// it is used when the response model is wrapped in IterableOrSingle::Partial
+///
+/// now you can access the following methods:
+///
+/// Mystruct::partials_from_chunks(...)
+/// Mystruct::parse_partial(...)
+pub trait PartialBase<Args, T>
+where
+    T: ValidateArgs<'static, Args=Args> + BaseSchema + 'static ,
+    Args: BaseArg,
+{
+    type Args : BaseArg;
+
+    ///recieves a stream of strings(JsonStream) and returns a stream of Result<T, Error>.
+    /// every chunk that changes the parsed object yields a snapshot of T with the fields parsed so far,
+    /// fields that have not been generated yet are taken from the instance wrapped in the model
+    /// (usually T::default()) and Option fields are None. The chunks are parsed once, with JsonEventParser.
+    /// The snapshots are not validated, the last item of the stream is the complete object after
+    /// T::model_validate_json(), the chunk that completes the object yields no snapshot of its own
+    ///
+    /// # Arguments
+    ///
+    /// * `model` - The model to use, the wrapped instance provides the defaults for missing fields
+    /// * `json_chunks` - The stream of json strings
+    /// * `validation_context` - The validation context to use for the final object
+    ///
+    /// # Returns
+    /// * `InstructorResponse::Stream(stream)` - A stream of Result<T, Error> where T is a snapshot of the struct
+    fn partials_from_chunks(
+        model: IterableOrSingle<Self>,
+        json_chunks: JsonStream,
+        validation_context: Args
+    ) -> InstructorResponse<T>
+    where
+        Self: Sized + ValidateArgs<'static> + BaseSchema;
+
+    ///parses an incomplete json string into a snapshot of T, the snapshot partials_from_chunks yields for the same text.
+    /// Returns None if the snapshot can not be deserialized into T
+    ///
+    /// # Arguments
+    ///
+    /// * `template` - the instance that provides values for the fields that are missing
+    /// * `data` - the incomplete json string
+    fn parse_partial(template: &Self, data: &str) -> Option<T>
+    where
+        Self: Sized + ValidateArgs<'static> + BaseSchema;
+}
+
+impl<A, T> PartialBase<A, T> for T
+where
+    T: ValidateArgs<'static, Args=A> + BaseSchema + 'static ,
+    A: BaseArg + 'static,
+{
+    type Args = A;
+
+    fn partials_from_chunks(
+        model: IterableOrSingle<Self>,
+        json_chunks: JsonStream,
+        validation_context: Self::Args,
+    ) -> InstructorResponse<T>
+    where
+        Self: Sized + ValidateArgs<'static> + BaseSchema,
+    {
+        let template = match model {
+            IterableOrSingle::Iterable(item) | IterableOrSingle::Single(item) | IterableOrSingle::Partial(item) => item,
+        };
+        let template_value = serde_json::to_value(&template).unwrap_or(Value::Null);
+
+        let stream = stream! {
+            pin_mut!(json_chunks);
+            let mut buffer = String::new();
+            let mut parser = JsonEventParser::new();
+            let mut snapshot = PartialSnapshot::new(template_value);
+            let mut last_snapshot: Option<Value> = None;
+            // the json of the first object, set once it is complete
+            let mut complete: Option<String> = None;
+            while let Some(chunk_result) = json_chunks.next().await {
+                match chunk_result {
+                    Ok(chunk) => {
+                        buffer.push_str(&chunk);
+                        if complete.is_some() {
+                            continue;
+                        }
+                        let mut changed = false;
+                        for event in parser.push(&chunk) {
+                            match event {
+                                // the validated object follows, so this chunk yields no snapshot
+                                JsonEvent::ObjectEnded { json, .. } => complete = Some(json),
+                                event => changed |= snapshot.apply(event),
+                            }
+                            if complete.is_some() {
+                                break;
+                            }
+                        }
+                        if complete.is_some() || !changed || last_snapshot.as_ref() == Some(&snapshot.value) {
+                            continue;
+                        }
+                        if let Ok(item) = serde_json::from_value::<T>(snapshot.value.clone()) {
+                            last_snapshot = Some(snapshot.value.clone());
+                            yield Ok(item);
+                        }
+                    },
+                    Err(e) => {
+                        yield Err(e);
+                    },
+                }
+            }
+
+            // only the complete object is validated, the text around it is ignored.
+            // An object that never completed fails to parse as far as it got
+            let single = IterableOrSingle::Single(template.clone());
+            let result = complete.map(Ok).unwrap_or_else(|| extract_json_from_codeblock(&buffer))
+                .and_then(|json| Self::model_validate_json(&single, &json, &validation_context))
+                .and_then(|response| response.unwrap());
+            yield result;
+        }.boxed();
+        InstructorResponse::Stream(stream)
+    }
+
+    fn parse_partial(template: &Self, data: &str) -> Option<T> {
+        let mut snapshot = PartialSnapshot::new(serde_json::to_value(template).ok()?);
+        for event in JsonEventParser::new().push(data) {
+            if let JsonEvent::ObjectEnded { .. } = event {
+                break;
+            }
+            snapshot.apply(event);
+        }
+        serde_json::from_value::<T>(snapshot.value).ok()
+    }
+}
+
+///the snapshot of the first object of a stream, built from the events of JsonEventParser on top of the template.
+/// A container (object or list) of the template is replaced by an empty one the first time a field inside it
+/// arrives, so the defaults of the template never mix with generated values
+struct PartialSnapshot {
+    value: Value,
+    // the paths of the values that were generated, see JsonEvent for the format
+    generated: HashSet<String>,
+}
+
+enum Segment {
+    Key(String),
+    Index(usize),
+}
+
+impl PartialSnapshot {
+    fn new(template: Value) -> Self {
+        PartialSnapshot { value: template, generated: HashSet::new() }
+    }
+
+    ///applies an event of the first object, returns whether the snapshot changed
+    fn apply(&mut self, event: JsonEvent) -> bool {
+        match event {
+            JsonEvent::FieldDelta { path, text } if !path.is_empty() => {
+                let fresh = self.generated.insert(path.clone());
+                let slot = self.slot(&path);
+                if fresh || !slot.is_string() {
+                    *slot = Value::String(String::new());
+                }
+                if let Value::String(value) = slot {
+                    value.push_str(&text);
+                }
+                true
+            }
+            JsonEvent::FieldCompleted { path, value } if !path.is_empty() => {
+                self.generated.insert(path.clone());
+                *self.slot(&path) = value;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    ///the value at `path`, the containers on the way are created (or replaced) when they were not generated yet
+    fn slot(&mut self, path: &str) -> &mut Value {
+        let segments = segments(path);
+        let mut prefix = String::new();
+        let mut current = &mut self.value;
+        for (i, segment) in segments.iter().enumerate() {
+            let child = match segment {
+                Segment::Key(key) => {
+                    if !prefix.is_empty() {
+                        prefix.push('.');
+                    }
+                    prefix.push_str(key);
+                    if !current.is_object() {
+                        *current = Value::Object(Map::new());
+                    }
+                    current.as_object_mut().expect("set above").entry(key.clone()).or_insert(Value::Null)
+                }
+                Segment::Index(index) => {
+                    prefix.push_str(&format!("[{}]", index));
+                    if !current.is_array() {
+                        *current = Value::Array(Vec::new());
+                    }
+                    let list = current.as_array_mut().expect("set above");
+                    while list.len() <= *index {
+                        list.push(Value::Null);
+                    }
+                    &mut list[*index]
+                }
+            };
+            if let Some(next) = segments.get(i + 1) {
+                if self.generated.insert(prefix.clone()) {
+                    *child = match next {
+                        Segment::Key(_) => Value::Object(Map::new()),
+                        Segment::Index(_) => Value::Array(Vec::new()),
+                    };
+                }
+            }
+            current = child;
+        }
+        current
+    }
+}
+
+///splits a path of JsonEventParser ("address.city", "tags[1]") into its keys and indices
+fn segments(path: &str) -> Vec<Segment> {
+    let mut segments = Vec::new();
+    for part in path.split('.') {
+        let (key, indices) = match part.find('[') {
+            Some(start) => part.split_at(start),
+            None => (part, ""),
+        };
+        if !key.is_empty() {
+            segments.push(Segment::Key(key.to_string()));
+        }
+        for index in indices.split(['[', ']']).filter(|index| !index.is_empty()) {
+            if let Ok(index) = index.parse() {
+                segments.push(Segment::Index(index));
+            }
+        }
+    }
+    segments
+}
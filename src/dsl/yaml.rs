@@ -0,0 +1,185 @@
+use validator::ValidateArgs;
+use crate::enums::IterableOrSingle;
+use crate::openai_schema::BaseArg;
+use crate::openai_schema::BaseSchema;
+use crate::openai_schema::OpenAISchema;
+use crate::enums::InstructorResponse;
+use crate::types::JsonStream;
+use futures::stream::StreamExt;
+use async_stream::stream;
+use pin_utils::pin_mut;
+
+
+///splits a streamed yaml list into its items, text is pushed as it arrives and an item
+/// is returned as soon as the next item (or the closing fence) starts, so only complete lines are inspected.
+/// Text before the list (the ```yaml fence or a sentence) is skipped, a nested list is part of its item
+/// because it is indented deeper than the first item
+///
+/// # Example
+///
+/// pushing "```yaml\n- name: a\n  age: 1\n- na" returns ["- name: a\n  age: 1\n"],
+/// the second item is returned by a later push or by finish()
+#[derive(Debug, Default)]
+pub struct YamlListSplitter {
+    buffer: String,
+    opened: bool,
+    done: bool,
+    indent: Option<usize>,
+    item: Option<String>,
+}
+
+impl YamlListSplitter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///adds a chunk of text and returns the items that were completed by it
+    pub fn push(&mut self, chunk: &str) -> Vec<String> {
+        self.buffer.push_str(chunk);
+        let mut items = Vec::new();
+        while let Some(newline) = self.buffer.find('\n') {
+            let line: String = self.buffer.drain(..=newline).collect();
+            items.extend(self.line(&line));
+        }
+        // the current item is complete once the partial line starts the next item or closes the fence,
+        // it is replaced by an empty item that the rest of the line is appended to when it completes
+        if let (false, Some(indent), Some(_)) = (self.done, self.indent, &self.item) {
+            let rest = self.buffer.as_str();
+            let next_item = rest.len() > indent + 1
+                && rest.is_char_boundary(indent)
+                && rest[..indent].chars().all(|c| c == ' ')
+                && rest[indent..].starts_with("- ");
+            if next_item || rest.trim_start().starts_with("```") {
+                items.extend(self.item.replace(String::new()));
+            }
+        }
+        items.retain(|item| !item.trim().is_empty());
+        items
+    }
+
+    ///returns the remaining items once the stream has ended
+    pub fn finish(&mut self) -> Vec<String> {
+        let rest = std::mem::take(&mut self.buffer);
+        let mut items: Vec<String> = self.line(&rest).into_iter().collect();
+        if !self.done {
+            self.done = true;
+            items.extend(self.item.take());
+        }
+        items.retain(|item| !item.trim().is_empty());
+        items
+    }
+
+    fn line(&mut self, line: &str) -> Option<String> {
+        if self.done || line.is_empty() {
+            return None;
+        }
+        let trimmed = line.trim();
+        if trimmed.starts_with("```") {
+            if self.opened || self.item.is_some() {
+                self.done = true;
+                return self.item.take();
+            }
+            self.opened = true;
+            return None;
+        }
+
+        let indent = line.len() - line.trim_start_matches(' ').len();
+        let is_item = trimmed == "-" || trimmed.starts_with("- ");
+        match self.indent {
+            None if is_item => {
+                self.indent = Some(indent);
+                self.item = Some(line.to_string());
+                None
+            }
+            Some(list_indent) if is_item && indent == list_indent => self.item.replace(line.to_string()),
+            // a line that is indented less than the list ends it
+            Some(list_indent) if !trimmed.is_empty() && indent < list_indent => {
+                self.done = true;
+                self.item.take()
+            }
+            _ => {
+                if let Some(item) = self.item.as_mut() {
+                    item.push_str(line);
+                }
+                None
+            }
+        }
+    }
+}
+
+
+///This is the trait for parsing a streamed yaml list (Mode::MD_YAML) into a stream of structs.
+/// It is implemented for every struct that implements OpenAISchema,
+/// it is used when the response model is wrapped in IterableOrSingle::Iterable
+///
+/// now you can access the following methods:
+///
+/// Mystruct::yaml_tasks_from_chunks(...)
+pub trait YamlBase<Args, T>
+where
+    T: ValidateArgs<'static, Args=Args> + BaseSchema + 'static ,
+    Args: BaseArg,
+{
+    type Args : BaseArg;
+
+    ///recieves a stream of strings(JsonStream) and returns a stream of Result<T, Error>,
+    /// every item of the yaml list is parsed with model_validate_yaml() as soon as it is complete (see YamlListSplitter)
+    ///
+    /// # Arguments
+    ///
+    /// * `model` - The model to use
+    /// * `yaml_chunks` - The stream of yaml strings
+    /// * `validation_context` - The validation context to use for each struct
+    ///
+    /// # Returns
+    /// * `InstructorResponse::Stream(stream)` - A stream of Result<T, Error> where T is the parsed struct
+    fn yaml_tasks_from_chunks(
+        model: IterableOrSingle<Self>,
+        yaml_chunks: JsonStream,
+        validation_context: Args
+    ) -> InstructorResponse<T>
+    where
+        Self: Sized + ValidateArgs<'static> + BaseSchema;
+}
+
+impl<A, T> YamlBase<A, T> for T
+where
+    T: ValidateArgs<'static, Args=A> + BaseSchema + 'static ,
+    A: BaseArg + 'static,
+{
+    type Args = A;
+
+    fn yaml_tasks_from_chunks(
+        model: IterableOrSingle<Self>,
+        yaml_chunks: JsonStream,
+        validation_context: A,
+    ) -> InstructorResponse<T>
+    where
+        Self: Sized + ValidateArgs<'static> + BaseSchema,
+    {
+        // every item is parsed as a list with a single element
+        let model = IterableOrSingle::Iterable(model.unwrap().expect("IterableOrSingle::unwrap can not fail"));
+        let stream = stream! {
+            pin_mut!(yaml_chunks);
+            let mut splitter = YamlListSplitter::new();
+            while let Some(chunk_result) = yaml_chunks.next().await {
+                match chunk_result {
+                    Ok(chunk) => {
+                        for item in splitter.push(&chunk) {
+                            yield Self::model_validate_yaml(&model, &item, &validation_context)
+                                .and_then(|res| res.unwrap());
+                        }
+                    },
+                    Err(e) => {
+                        yield Err(e);
+                    },
+                }
+            }
+            for item in splitter.finish() {
+                yield Self::model_validate_yaml(&model, &item, &validation_context)
+                    .and_then(|res| res.unwrap());
+            }
+        }.boxed();
+        InstructorResponse::Stream(stream)
+    }
+}
@@ -1,9 +1,15 @@
 use crate::openai_schema::BaseSchema;
 use validator::{ValidateArgs, ValidationErrors};
 use crate::error::Error;
-use async_openai::types::{CreateChatCompletionResponse, ChatCompletionResponseStream};
+use async_openai::types::{
+    CreateChatCompletionResponse, ChatCompletionResponseStream, ChatCompletionMessageToolCall, CreateCompletionResponse,
+    CompletionUsage
+};
+use crate::anthropic::MessagesResponse;
+use crate::gemini::GenerateContentResponse;
 use std::pin::Pin;
-use futures::stream::Stream;
+use futures::stream::{Stream, StreamExt};
+use async_stream::stream;
 use serde::{Deserialize, Serialize};
 use schemars::JsonSchema;
 use std::fmt::{Formatter, Debug};
@@ -12,6 +18,10 @@ use crate::mode::Mode;
 pub enum ChatCompletionResponseWrapper {
     AtOnce(CreateChatCompletionResponse),
     Stream(ChatCompletionResponseStream),
+    Anthropic(MessagesResponse),
+    Gemini(GenerateContentResponse),
+    ///the response of the legacy completions endpoint, see Mode::COMPLETION
+    Completion(CreateCompletionResponse),
 }
 
 impl ChatCompletionResponseWrapper {
@@ -21,32 +31,92 @@ impl ChatCompletionResponseWrapper {
                 println!("resp: {:?}", resp);
                 //TODO make this work for tool calls as well currently it is assumed
                 match mode {
-                    Mode::JSON | Mode::MD_JSON | Mode::JSON_SCHEMA => {
+                    Mode::JSON | Mode::MD_JSON | Mode::MD_YAML | Mode::XML | Mode::JSON_SCHEMA | Mode::GEMINI_JSON |
+                    Mode::OLLAMA_JSON_SCHEMA | Mode::LLAMA_CPP_JSON_SCHEMA | Mode::LLAMA_CPP_GRAMMAR | Mode::COMPLETION => {
                         let message = resp.choices.get(0).unwrap().message.content.clone().unwrap();
                         Some(message)
                     }
-                    Mode::TOOLS => {
-                        let message = resp.choices.get(0).unwrap()
-                        .message
-                        .tool_calls.as_ref().unwrap() // Use as_ref() to borrow
-                        .iter()
-                        .map(|x| x.function.arguments.clone()) // Clone to move
-                        .collect::<Vec<String>>().join(", ");
-                        Some(message)
+                    Mode::TOOLS | Mode::ANTHROPIC_TOOLS | Mode::GEMINI_TOOLS => {
+                        let message = resp.choices.get(0).unwrap().message.clone();
+                        match message.tool_calls {
+                            Some(tool_calls) => Some(tool_calls
+                                .iter()
+                                .map(|x| x.function.arguments.clone()) // Clone to move
+                                .collect::<Vec<String>>().join(", ")),
+                            // with tool_choice auto the llm can answer with text instead
+                            None => message.content,
+                        }
                     },
 
                 }
             },
+            ChatCompletionResponseWrapper::Anthropic(resp) => {
+                let tool_calls = resp.tool_calls();
+                if tool_calls.is_empty() {
+                    Some(resp.text())
+                } else {
+                    Some(tool_calls.iter()
+                        .map(|x| x.function.arguments.clone())
+                        .collect::<Vec<String>>().join(", "))
+                }
+            },
+            ChatCompletionResponseWrapper::Gemini(resp) => {
+                let tool_calls = resp.tool_calls();
+                if tool_calls.is_empty() {
+                    Some(resp.text())
+                } else {
+                    Some(tool_calls.iter()
+                        .map(|x| x.function.arguments.clone())
+                        .collect::<Vec<String>>().join(", "))
+                }
+            },
+            ChatCompletionResponseWrapper::Completion(resp) => {
+                resp.choices.first().map(|choice| choice.text.clone())
+            },
             ChatCompletionResponseWrapper::Stream(_) => {
-                Some("".to_string()) // we will not reask in case of streaming error so this will never be used
+                Some("".to_string()) // the items of a stream are re-asked with their own text by retry::reask_stream_items
             }
         }
     }
 
+    ///returns the tool calls of a non streaming response, anthropic tool_use blocks are returned as openai tool calls
+    pub fn get_tool_calls(&self) -> Option<Vec<ChatCompletionMessageToolCall>> {
+        match self {
+            ChatCompletionResponseWrapper::AtOnce(resp) => {
+                resp.choices.first().and_then(|choice| choice.message.tool_calls.clone())
+            },
+            ChatCompletionResponseWrapper::Anthropic(resp) => Some(resp.tool_calls()),
+            ChatCompletionResponseWrapper::Gemini(resp) => Some(resp.tool_calls()),
+            ChatCompletionResponseWrapper::Completion(_) | ChatCompletionResponseWrapper::Stream(_) => None,
+        }
+    }
+
+    ///returns the usage of a non streaming response, the usage of a stream is only known once it ends (see metadata::StreamMetadata)
+    pub fn get_usage(&self) -> Option<CompletionUsage> {
+        match self {
+            ChatCompletionResponseWrapper::AtOnce(resp) => resp.usage.clone(),
+            ChatCompletionResponseWrapper::Anthropic(resp) => Some(CompletionUsage {
+                prompt_tokens: resp.usage.input_tokens,
+                completion_tokens: resp.usage.output_tokens,
+                total_tokens: resp.usage.input_tokens + resp.usage.output_tokens,
+            }),
+            ChatCompletionResponseWrapper::Gemini(resp) => resp.usage_metadata.as_ref().map(|usage| CompletionUsage {
+                prompt_tokens: usage.prompt_token_count,
+                completion_tokens: usage.candidates_token_count,
+                total_tokens: usage.total_token_count,
+            }),
+            ChatCompletionResponseWrapper::Completion(resp) => resp.usage.clone(),
+            ChatCompletionResponseWrapper::Stream(_) => None,
+        }
+    }
+
     pub fn get_AtOnce(self) -> Result<CreateChatCompletionResponse, Error> {
         match self {
             ChatCompletionResponseWrapper::AtOnce(resp) => Ok(resp),
             ChatCompletionResponseWrapper::Stream(_) => Err(Error::Generic("Got a stream".to_string())),
+            ChatCompletionResponseWrapper::Anthropic(_) => Err(Error::Generic("Got an anthropic response".to_string())),
+            ChatCompletionResponseWrapper::Gemini(_) => Err(Error::Generic("Got a gemini response".to_string())),
+            ChatCompletionResponseWrapper::Completion(_) => Err(Error::Generic("Got a completion response".to_string())),
         }
     }
 }
@@ -73,10 +143,68 @@ where
     pub fn unwrap(self) -> Result<T, Error> {
         match self {
             InstructorResponse::One(item) => Ok(item),
-            InstructorResponse::Many(mut items) => Ok(items.pop().expect("InstructorResponse::Many should not be empty")),
+            InstructorResponse::Many(mut items) => items.pop().ok_or_else(|| Error::Generic("Cannot unwrap an empty InstructorResponse::Many".to_string())),
             InstructorResponse::Stream(_) => Err(Error::Generic("Cannot unwrap a stream".to_string())),
         }
     }
+
+    ///keeps the first `n` valid items. A stream ends as soon as the n-th valid item has arrived,
+    /// the request is dropped right away so no more tokens are generated.
+    /// Errors are passed on and are not counted, invalid items still waiting to be re-asked are discarded.
+    /// A single object (One) is kept as it is, like in until
+    ///
+    /// Example
+    ///
+    /// let response = patch.chat_completion(IterableOrSingle::Iterable(Record::default()), (), 3, request).await?.take_validated(5);
+    pub fn take_validated(self, n: usize) -> Self {
+        if n == 0 {
+            return match self {
+                InstructorResponse::Stream(_) => InstructorResponse::Stream(futures::stream::empty().boxed()),
+                InstructorResponse::One(item) => InstructorResponse::One(item),
+                InstructorResponse::Many(_) => InstructorResponse::Many(Vec::new()),
+            };
+        }
+        let mut count = 0;
+        self.until(move |_| {
+            count += 1;
+            count >= n
+        })
+    }
+
+    ///keeps the valid items up to and including the first one for which `predicate` returns true,
+    /// a stream is cancelled like in take_validated. The predicate sees every valid item in order, so it can keep
+    /// its own state (e.g. count the items that match a filter)
+    ///
+    /// Example
+    ///
+    /// let mut matches = 0;
+    /// let response = response.until(move |record: &Record| {
+    ///     matches += record.is_match() as usize;
+    ///     matches == 3
+    /// });
+    pub fn until<F>(self, mut predicate: F) -> Self
+    where
+        F: FnMut(&T) -> bool + Send + 'static,
+    {
+        match self {
+            InstructorResponse::One(item) => InstructorResponse::One(item),
+            InstructorResponse::Many(items) => {
+                let end = items.iter().position(&mut predicate).map_or(items.len(), |index| index + 1);
+                InstructorResponse::Many(items.into_iter().take(end).collect())
+            },
+            InstructorResponse::Stream(mut response) => InstructorResponse::Stream(stream! {
+                while let Some(item) = response.next().await {
+                    if item.as_ref().is_ok_and(&mut predicate) {
+                        // dropping the response closes the connection before the caller gets the last item
+                        drop(response);
+                        yield item;
+                        return;
+                    }
+                    yield item;
+                }
+            }.boxed()),
+        }
+    }
 }
 
 
@@ -98,6 +226,9 @@ where T: ValidateArgs<'static> + BaseSchema
 {
     Iterable(T), 
     Single(T),
+    ///like Single but when streaming every chunk yields a progressively filled snapshot of T,
+    /// only the last item of the stream is validated (similar to Partial[model] in instructor)
+    Partial(T),
 }
 
 impl<T> IterableOrSingle<T>
@@ -107,7 +238,7 @@ where
     // This method is now correctly placed outside the ValidateArgs trait impl block
     pub fn unwrap(self) -> Result<T, ()> {
         match self {
-            IterableOrSingle::Iterable(item) | IterableOrSingle::Single(item) => Ok(item),
+            IterableOrSingle::Iterable(item) | IterableOrSingle::Single(item) | IterableOrSingle::Partial(item) => Ok(item),
         }
     }
 }
@@ -121,7 +252,7 @@ where
 
     fn validate_args(&self, args: Self::Args) -> Result<(), ValidationErrors> {
         match self {
-            IterableOrSingle::Iterable(item) | IterableOrSingle::Single(item) => {
+            IterableOrSingle::Iterable(item) | IterableOrSingle::Single(item) | IterableOrSingle::Partial(item) => {
                 item.validate_args(args)
             },
         }
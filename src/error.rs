@@ -1,14 +1,65 @@
 use serde_json::Error as SerdeError;
+use async_openai::error::{ApiError, OpenAIError};
 use std::fmt;
+use std::time::Duration;
+use crate::types::RetryError;
+use serde_json::Value;
+
+///a request that was answered with a non success http status,
+/// `retry_after` is the delay the provider asked for with a Retry-After (or retry-after-ms) header
+#[derive(Debug)]
+pub struct HttpError {
+    pub status: u16,
+    pub retry_after: Option<Duration>,
+    pub error: ApiError,
+}
+
+///a value of a response that does not fit the response model, see Error::DeserializeErrors
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeserializeError {
+    ///the position of the item in the list of an IterableOrSingle::Iterable response
+    pub item: Option<usize>,
+    ///the json pointer of the value in the response, e.g. "/2/address/zip" (the index is that of the item)
+    pub path: String,
+    ///the value that was found there, None if the field is missing
+    pub value: Option<Value>,
+    ///the type serde expected, e.g. "u32" or "`Low` or `High`"
+    pub expected: Option<String>,
+    ///serde's message without its position
+    pub message: String,
+}
+
+impl fmt::Display for DeserializeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.item {
+            Some(item) => write!(f, "the field `{}` of item {}: {}", self.path, item + 1, self.message)?,
+            None => write!(f, "the field `{}`: {}", self.path, self.message)?,
+        }
+        match &self.value {
+            Some(value) => write!(f, ", the value was {}", value),
+            None => Ok(()),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum Error {
     ValidationErrors(validator::ValidationErrors),
     ValidationError(validator::ValidationError),
     SerdeError(SerdeError),
+    YamlError(serde_yaml::Error),
     NotImplementedError(String),
     APIError(String),
+    OpenAIError(OpenAIError),
     Generic(String),
     JsonExtractionError(String),
+    XmlError(String),
+    Refusal(String),
+    DeadlineExceeded(Duration),
+    IdleTimeout(Duration),
+    HttpError(Box<HttpError>),
+    RetryError(RetryError),
+    DeserializeErrors(Vec<DeserializeError>),
 }
 
 impl fmt::Display for Error {
@@ -17,10 +68,29 @@ impl fmt::Display for Error {
             Error::ValidationErrors(ref err) => write!(f, "Validation error: {}", err),
             Error::ValidationError(ref err) => write!(f, "Validation error: {}", err),
             Error::SerdeError(ref err) => write!(f, "Serde error: {}", err),
+            Error::YamlError(ref err) => write!(f, "Yaml error: {}", err),
             Error::NotImplementedError(ref err) => write!(f, "Not implemented: {}", err),
             Error::APIError(ref err) => write!(f, "API error: {}", err),
+            Error::OpenAIError(ref err) => write!(f, "API error: {}", err),
             Error::Generic(ref err) => write!(f, "Error: {}", err),
             Error::JsonExtractionError(ref err) => write!(f, "Error: {}", err),
+            Error::XmlError(ref err) => write!(f, "Xml error: {}", err),
+            Error::Refusal(ref err) => write!(f, "The model refused to respond: {}", err),
+            Error::DeadlineExceeded(ref deadline) => write!(f, "Deadline exceeded: no result within {:?}", deadline),
+            Error::IdleTimeout(ref timeout) => write!(f, "Idle timeout: no stream chunk received for {:?}", timeout),
+            Error::HttpError(ref err) => write!(f, "API error ({}): {}", err.status, err.error.message),
+            Error::RetryError(ref err) => write!(f, "{}", err),
+            Error::DeserializeErrors(ref errors) => write!(
+                f,
+                "Deserialization error: {}",
+                errors.iter().map(|error| error.to_string()).collect::<Vec<String>>().join("\n")
+            ),
         }
     }
 }
+
+impl From<OpenAIError> for Error {
+    fn from(err: OpenAIError) -> Error {
+        Error::OpenAIError(err)
+    }
+}
@@ -0,0 +1,127 @@
+use crate::enums::InstructorResponse;
+use crate::mode::Mode;
+use crate::openai_schema::BaseSchema;
+use crate::retry_policy::RetryPolicy;
+use async_openai::types::{
+    ChatCompletionRequestMessage, ChatCompletionRequestAssistantMessage, ChatCompletionRequestUserMessage,
+    ChatCompletionRequestUserMessageContent, Role
+};
+use validator::ValidateArgs;
+
+///a model of a fallback chain and the mode it is used in, None is the mode of the Patch
+#[derive(Debug, Clone, PartialEq)]
+pub struct FallbackModel {
+    pub model: String,
+    pub mode: Option<Mode>,
+}
+
+impl FallbackModel {
+    pub fn new(model: impl Into<String>) -> Self {
+        FallbackModel { model: model.into(), mode: None }
+    }
+
+    pub fn with_mode(model: impl Into<String>, mode: Mode) -> Self {
+        FallbackModel { model: model.into(), mode: Some(mode) }
+    }
+}
+
+impl From<&str> for FallbackModel {
+    fn from(model: &str) -> Self {
+        FallbackModel::new(model)
+    }
+}
+
+///what the next model of a fallback chain is sent
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum FallbackConversation {
+    ///the original messages, as if the previous models had not been asked
+    #[default]
+    Reset,
+    ///the original messages followed by the outputs of the previous models and their re-asks,
+    /// so the next model can learn from their mistakes. A next model that is not in a tool mode
+    /// gets the tool calls and tool messages of a tool mode as text, see text_conversation
+    CarryOver,
+}
+
+///the configuration of Patch::chat_completion_with_fallbacks
+///
+/// * `models` - the models to try in order, the next one is asked once a model used up its retries
+/// * `conversation` - whether the next model gets the conversation of the previous ones, see FallbackConversation
+/// * `policy` - the retries of every model, each of them gets the whole budget
+///
+/// Example
+///
+/// let fallbacks = Fallbacks::new(vec![
+///     FallbackModel::with_mode(GPT3_5_TURBO, Mode::JSON),
+///     FallbackModel::with_mode(GPT4_TURBO_PREVIEW, Mode::TOOLS),
+/// ], 2);
+#[derive(Debug, Clone)]
+pub struct Fallbacks {
+    pub models: Vec<FallbackModel>,
+    pub conversation: FallbackConversation,
+    pub policy: RetryPolicy,
+}
+
+impl Fallbacks {
+    ///a chain that resets the conversation, each model is asked up to max_retries times
+    pub fn new(models: Vec<FallbackModel>, max_retries: usize) -> Self {
+        Fallbacks { models, conversation: FallbackConversation::Reset, policy: RetryPolicy::new(max_retries) }
+    }
+}
+
+///the response of Patch::chat_completion_with_fallbacks together with the model that produced it
+pub struct FallbackResponse<T>
+where
+    T: ValidateArgs<'static> + BaseSchema,
+{
+    pub response: InstructorResponse<T>,
+    pub model: String,
+    pub mode: Mode,
+    ///the position of the model in Fallbacks::models
+    pub index: usize,
+}
+
+///the messages of a tool mode conversation as text, for a model that is asked without tools.
+/// The tool calls of an assistant message become its content and the tool messages answering them
+/// become a single user message, the api rejects tool messages in a request without tools
+pub(crate) fn text_conversation(messages: &[ChatCompletionRequestMessage]) -> Vec<ChatCompletionRequestMessage> {
+    let mut converted: Vec<ChatCompletionRequestMessage> = Vec::new();
+    // the distinct contents of the tool messages that are being merged
+    let mut results: Vec<String> = Vec::new();
+    for message in messages.iter() {
+        if let ChatCompletionRequestMessage::Tool(message) = message {
+            if !results.contains(&message.content) {
+                results.push(message.content.clone());
+            }
+            continue;
+        }
+        if !results.is_empty() {
+            converted.push(user_message(results.join("\n")));
+            results.clear();
+        }
+        match message {
+            ChatCompletionRequestMessage::Assistant(message) if message.tool_calls.is_some() => {
+                let arguments = message.tool_calls.iter().flatten().map(|tool_call| tool_call.function.arguments.clone());
+                let content = message.content.clone().into_iter().chain(arguments).collect::<Vec<String>>().join("\n");
+                converted.push(ChatCompletionRequestMessage::Assistant(ChatCompletionRequestAssistantMessage {
+                    role: Role::Assistant,
+                    content: Some(content),
+                    ..Default::default()
+                }));
+            }
+            message => converted.push(message.clone()),
+        }
+    }
+    if !results.is_empty() {
+        converted.push(user_message(results.join("\n")));
+    }
+    converted
+}
+
+fn user_message(text: String) -> ChatCompletionRequestMessage {
+    ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
+        role: Role::User,
+        content: ChatCompletionRequestUserMessageContent::Text(text),
+        name: None,
+    })
+}
@@ -0,0 +1,248 @@
+use crate::error::Error;
+use serde_json::{Map, Value};
+use std::collections::BTreeSet;
+
+// the rules for the json primitives, they are only added to the grammar when they are used
+const PRIMITIVE_RULES: [(&str, &str); 9] = [
+    ("ws", "([ \\t\\n] ws)?"),
+    ("string", "\"\\\"\" ( [^\"\\\\\\x7F\\x00-\\x1F] | \"\\\\\" ([\"\\\\/bfnrt] | \"u\" [0-9a-fA-F] [0-9a-fA-F] [0-9a-fA-F] [0-9a-fA-F]) )* \"\\\"\" ws"),
+    ("number", "(\"-\"? ([0-9] | [1-9] [0-9]*)) (\".\" [0-9]+)? ([eE] [-+]? [0-9]+)? ws"),
+    ("integer", "(\"-\"? ([0-9] | [1-9] [0-9]*)) ws"),
+    ("boolean", "(\"true\" | \"false\") ws"),
+    ("null", "\"null\" ws"),
+    ("value", "object | array | string | number | boolean | null"),
+    ("object", "\"{\" ws ( string \":\" ws value (\",\" ws string \":\" ws value)* )? \"}\" ws"),
+    ("array", "\"[\" ws ( value (\",\" ws value)* )? \"]\" ws"),
+];
+
+///converts a json schema (the schemars output used by OpenAISchema::openai_schema) into a GBNF grammar
+/// that llama.cpp can use to constrain generation, the root rule matches exactly one instance of the schema.
+///
+/// * properties are generated in the order of the schema, required ones first and optional ones after them
+/// * $ref to #/definitions and #/$defs become their own rules so recursive types are supported
+/// * keywords that can not be expressed cheaply in GBNF (format, minimum, pattern, ...) are ignored,
+///   they are still enforced by the validator after parsing
+///
+/// # Example
+///
+/// json_schema_to_gbnf(&json!({"type": "object", "properties": {"a": {"type": "integer"}}, "required": ["a"]}))
+/// returns a grammar with the rule: root ::= "{" ws "\"a\"" ws ":" ws integer "}" ws
+pub fn json_schema_to_gbnf(schema: &Value) -> Result<String, Error> {
+    let mut converter = GbnfConverter {
+        root: schema,
+        rules: Vec::new(),
+        primitives: BTreeSet::new(),
+        in_progress: BTreeSet::new(),
+    };
+    let root = converter.visit(schema, "root")?;
+    match converter.rules.iter().position(|(name, _)| name == "root") {
+        // the rules a rule depends on are generated before it, the root is moved to the top for readability
+        Some(index) => {
+            let rule = converter.rules.remove(index);
+            converter.rules.insert(0, rule);
+        }
+        None => converter.rules.insert(0, ("root".to_string(), root)),
+    }
+
+    let mut grammar = String::new();
+    for (name, body) in converter.rules.iter() {
+        grammar.push_str(&format!("{} ::= {}\n", name, body));
+    }
+    for (name, body) in PRIMITIVE_RULES.iter() {
+        if converter.primitives.contains(*name) {
+            grammar.push_str(&format!("{} ::= {}\n", name, body));
+        }
+    }
+    Ok(grammar)
+}
+
+struct GbnfConverter<'a> {
+    root: &'a Value,
+    rules: Vec<(String, String)>,
+    primitives: BTreeSet<&'static str>,
+    in_progress: BTreeSet<String>,
+}
+
+impl<'a> GbnfConverter<'a> {
+    ///returns the name of a rule (or an inline expression) matching the schema
+    fn visit(&mut self, schema: &Value, name: &str) -> Result<String, Error> {
+        let object = match schema {
+            Value::Object(object) => object,
+            Value::Bool(true) => return Ok(self.primitive("value")),
+            _ => return Err(Error::Generic(format!("can not convert schema {} to a grammar", schema))),
+        };
+
+        if let Some(reference) = object.get("$ref").and_then(|reference| reference.as_str()) {
+            return self.visit_ref(reference);
+        }
+
+        if let Some(constant) = object.get("const") {
+            return Ok(format!("{} {}", literal(&constant.to_string()), self.primitive("ws")));
+        }
+
+        if let Some(Value::Array(values)) = object.get("enum") {
+            let ws = self.primitive("ws");
+            let alternatives: Vec<String> = values.iter()
+                .map(|value| literal(&value.to_string()))
+                .collect();
+            return self.add_rule(name, format!("({}) {}", alternatives.join(" | "), ws));
+        }
+
+        for keyword in ["anyOf", "oneOf", "allOf"] {
+            if let Some(Value::Array(schemas)) = object.get(keyword) {
+                if keyword == "allOf" && schemas.len() != 1 {
+                    return Err(Error::NotImplementedError("allOf with more than one schema is not supported".to_string()));
+                }
+                let alternatives = schemas.iter().enumerate()
+                    .map(|(i, schema)| self.visit(schema, &format!("{}-{}", name, i)))
+                    .collect::<Result<Vec<String>, Error>>()?;
+                return self.add_rule(name, alternatives.join(" | "));
+            }
+        }
+
+        match object.get("type") {
+            Some(Value::String(ty)) => self.visit_type(ty, object, name),
+            Some(Value::Array(types)) => {
+                let alternatives = types.iter()
+                    .filter_map(|ty| ty.as_str())
+                    .map(|ty| self.visit_type(ty, object, &format!("{}-{}", name, ty)))
+                    .collect::<Result<Vec<String>, Error>>()?;
+                self.add_rule(name, alternatives.join(" | "))
+            }
+            _ => Ok(self.primitive("value")),
+        }
+    }
+
+    fn visit_type(&mut self, ty: &str, object: &Map<String, Value>, name: &str) -> Result<String, Error> {
+        match ty {
+            "string" | "number" | "integer" | "boolean" | "null" => Ok(self.primitive(match ty {
+                "string" => "string",
+                "number" => "number",
+                "integer" => "integer",
+                "boolean" => "boolean",
+                _ => "null",
+            })),
+            "array" => {
+                let item = match object.get("items") {
+                    Some(items) => self.visit(items, &format!("{}-item", name))?,
+                    None => self.primitive("value"),
+                };
+                let ws = self.primitive("ws");
+                self.add_rule(name, format!(
+                    "\"[\" {ws} ( {item} (\",\" {ws} {item})* )? \"]\" {ws}",
+                    ws = ws, item = item
+                ))
+            }
+            "object" => self.visit_object(object, name),
+            _ => Err(Error::Generic(format!("unknown json schema type: {}", ty))),
+        }
+    }
+
+    fn visit_object(&mut self, object: &Map<String, Value>, name: &str) -> Result<String, Error> {
+        let ws = self.primitive("ws");
+        let properties = match object.get("properties") {
+            Some(Value::Object(properties)) if !properties.is_empty() => properties,
+            _ => {
+                // a map, the values follow additionalProperties
+                let value = match object.get("additionalProperties") {
+                    Some(Value::Object(_)) => self.visit(&object["additionalProperties"], &format!("{}-value", name))?,
+                    _ => self.primitive("value"),
+                };
+                let string = self.primitive("string");
+                let member = format!("{} \":\" {} {}", string, ws, value);
+                return self.add_rule(name, format!(
+                    "\"{{\" {ws} ( {member} (\",\" {ws} {member})* )? \"}}\" {ws}",
+                    ws = ws, member = member
+                ));
+            }
+        };
+
+        let required: Vec<&str> = match object.get("required") {
+            Some(Value::Array(required)) => required.iter().filter_map(|name| name.as_str()).collect(),
+            _ => Vec::new(),
+        };
+
+        let mut required_members = Vec::new();
+        let mut optional_members = Vec::new();
+        for (property, schema) in properties.iter() {
+            let rule = self.visit(schema, &format!("{}-{}", name, rule_name(property)))?;
+            let member = format!("{} {} \":\" {} {}", literal(&Value::String(property.clone()).to_string()), ws, ws, rule);
+            if required.contains(&property.as_str()) {
+                required_members.push(member);
+            } else {
+                optional_members.push(member);
+            }
+        }
+
+        let separator = format!("\",\" {}", ws);
+        let body = if !required_members.is_empty() {
+            let mut body = required_members.join(&format!(" {} ", separator));
+            for member in optional_members.iter() {
+                body.push_str(&format!(" ({} {})?", separator, member));
+            }
+            body
+        } else {
+            // every member is optional, any of them can be the first one
+            let alternatives: Vec<String> = (0..optional_members.len()).map(|first| {
+                let mut alternative = optional_members[first].clone();
+                for member in optional_members[first + 1..].iter() {
+                    alternative.push_str(&format!(" ({} {})?", separator, member));
+                }
+                alternative
+            }).collect();
+            format!("( {} )?", alternatives.join(" | "))
+        };
+
+        self.add_rule(name, format!("\"{{\" {ws} {body} \"}}\" {ws}", ws = ws, body = body))
+    }
+
+    fn visit_ref(&mut self, reference: &str) -> Result<String, Error> {
+        let definition_name = reference.split('/').next_back().unwrap_or_default();
+        let name = rule_name(definition_name);
+        // a recursive reference points at the rule that is being generated
+        if self.in_progress.contains(&name) || self.rules.iter().any(|(rule, _)| *rule == name) {
+            return Ok(name);
+        }
+
+        let definition = ["definitions", "$defs"].iter()
+            .find_map(|key| self.root.get(key).and_then(|definitions| definitions.get(definition_name)))
+            .ok_or_else(|| Error::Generic(format!("could not resolve {}", reference)))?;
+
+        self.in_progress.insert(name.clone());
+        let rule = self.visit(definition, &name)?;
+        self.in_progress.remove(&name);
+        if rule != name {
+            self.add_rule(&name, rule)?;
+        }
+        Ok(name)
+    }
+
+    fn add_rule(&mut self, name: &str, body: String) -> Result<String, Error> {
+        self.rules.push((name.to_string(), body));
+        Ok(name.to_string())
+    }
+
+    fn primitive(&mut self, name: &'static str) -> String {
+        self.primitives.insert(name);
+        match name {
+            "string" | "number" | "integer" | "boolean" | "null" => { self.primitives.insert("ws"); }
+            "value" | "object" | "array" => {
+                for dependency in ["ws", "string", "number", "boolean", "null", "object", "array"] {
+                    self.primitives.insert(dependency);
+                }
+            }
+            _ => {}
+        }
+        name.to_string()
+    }
+}
+
+///rule names may only contain letters, digits and dashes
+fn rule_name(name: &str) -> String {
+    name.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '-' }).collect()
+}
+
+///a GBNF string literal matching the text exactly
+fn literal(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
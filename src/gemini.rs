@@ -0,0 +1,566 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use crate::error::Error;
+use crate::enums::ChatCompletionResponseWrapper;
+use crate::backend::{ChatBackend, BackendFuture, ChatRequest};
+use async_openai::error::{ApiError, OpenAIError};
+use async_openai::types::{
+    ChatCompletionMessageToolCall, ChatCompletionRequestMessage, ChatCompletionRequestMessageContentPart,
+    ChatCompletionRequestUserMessageContent, ChatCompletionToolChoiceOption, ChatCompletionToolType,
+    FunctionCall, FunctionObject, Stop
+};
+
+pub const GEMINI_API_BASE: &str = "https://generativelanguage.googleapis.com/v1beta";
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum GeminiRole {
+    User,
+    Model,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct GeminiFunctionCall {
+    pub name: String,
+    #[serde(default)]
+    pub args: Value,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct GeminiFunctionResponse {
+    pub name: String,
+    pub response: Value,
+}
+
+///a part of a message, exactly one of the fields is set
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Part {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub text: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub function_call: Option<GeminiFunctionCall>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub function_response: Option<GeminiFunctionResponse>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Content {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub role: Option<GeminiRole>,
+    #[serde(default)]
+    pub parts: Vec<Part>,
+}
+
+///the gemini equivalent of a FunctionObject, parameters must be in the OpenAPI subset (see gemini_schema)
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct FunctionDeclaration {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parameters: Option<Value>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct GeminiTool {
+    pub function_declarations: Vec<FunctionDeclaration>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct FunctionCallingConfig {
+    ///AUTO, ANY or NONE
+    pub mode: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allowed_function_names: Option<Vec<String>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ToolConfig {
+    pub function_calling_config: FunctionCallingConfig,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct GenerationConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_output_tokens: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stop_sequences: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_mime_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub response_schema: Option<Value>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct GenerateContentRequest {
+    ///the model is part of the url, it is not sent in the body
+    #[serde(skip)]
+    pub model: String,
+    pub contents: Vec<Content>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub system_instruction: Option<Content>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tools: Option<Vec<GeminiTool>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_config: Option<ToolConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub generation_config: Option<GenerationConfig>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Candidate {
+    pub content: Option<Content>,
+    pub finish_reason: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct UsageMetadata {
+    #[serde(default)]
+    pub prompt_token_count: u32,
+    #[serde(default)]
+    pub candidates_token_count: u32,
+    #[serde(default)]
+    pub total_token_count: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct GenerateContentResponse {
+    #[serde(default)]
+    pub candidates: Vec<Candidate>,
+    pub usage_metadata: Option<UsageMetadata>,
+}
+
+impl GenerateContentResponse {
+    fn parts(&self) -> impl Iterator<Item = &Part> {
+        self.candidates.first()
+            .and_then(|candidate| candidate.content.as_ref())
+            .map(|content| content.parts.iter())
+            .into_iter()
+            .flatten()
+    }
+
+    ///returns the functionCall parts of the first candidate in the same shape as openai tool calls.
+    /// gemini does not give function calls an id, the id is made up from the name and position of the call
+    pub fn tool_calls(&self) -> Vec<ChatCompletionMessageToolCall> {
+        self.parts()
+            .filter_map(|part| part.function_call.as_ref())
+            .enumerate()
+            .map(|(index, call)| ChatCompletionMessageToolCall {
+                id: format!("{}-{}", call.name, index),
+                r#type: ChatCompletionToolType::Function,
+                function: FunctionCall {
+                    name: call.name.clone(),
+                    arguments: call.args.to_string(),
+                },
+            })
+            .collect()
+    }
+
+    ///returns the concatenated text parts of the first candidate
+    pub fn text(&self) -> String {
+        self.parts()
+            .filter_map(|part| part.text.as_deref())
+            .collect::<Vec<&str>>()
+            .join("")
+    }
+}
+
+impl From<FunctionObject> for FunctionDeclaration {
+    fn from(function: FunctionObject) -> Self {
+        FunctionDeclaration {
+            name: function.name,
+            description: function.description,
+            parameters: function.parameters,
+        }
+    }
+}
+
+// the keywords of the OpenAPI subset that gemini accepts, everything else is dropped
+const GEMINI_KEYWORDS: [&str; 8] = [
+    "type", "format", "description", "nullable", "enum", "properties", "required", "items",
+];
+
+///translates a schemars json schema into the OpenAPI subset used by gemini for
+/// FunctionDeclaration.parameters and GenerationConfig.response_schema.
+/// * $ref is inlined from definitions (gemini rejects $ref, so recursive types are an error)
+/// * single element allOf is inlined, oneOf becomes anyOf and {"type": "null"} alternatives become nullable: true
+/// * keywords outside of the subset (title, default, minimum, additionalProperties, ...) are removed
+///
+/// # Arguments
+/// * `schema`: `&Value` - the schema, usually serde_json::to_value(schema_for!(T))
+pub fn gemini_schema(schema: &Value) -> Result<Value, Error> {
+    translate_schema(schema, schema, &mut Vec::new())
+}
+
+fn translate_schema(schema: &Value, root: &Value, refs: &mut Vec<String>) -> Result<Value, Error> {
+    let object = match schema {
+        Value::Object(object) => object,
+        Value::Bool(true) => return Ok(json!({})),
+        _ => return Err(Error::Generic(format!("can not translate schema {} for gemini", schema))),
+    };
+
+    if let Some(reference) = object.get("$ref").and_then(|reference| reference.as_str()) {
+        let name = reference.split('/').next_back().unwrap_or_default().to_string();
+        if refs.contains(&name) {
+            return Err(Error::NotImplementedError(format!(
+                "gemini does not support recursive schemas ({} references itself)", name
+            )));
+        }
+        let definition = ["definitions", "$defs"].iter()
+            .find_map(|key| root.get(key).and_then(|definitions| definitions.get(&name)))
+            .ok_or_else(|| Error::Generic(format!("could not resolve {}", reference)))?;
+        refs.push(name);
+        let mut translated = translate_schema(definition, root, refs)?;
+        refs.pop();
+        // a documented $ref keeps the description of the field
+        if let (Some(description), Value::Object(translated)) = (object.get("description"), &mut translated) {
+            translated.insert("description".to_string(), description.clone());
+        }
+        return Ok(translated);
+    }
+
+    if let Some(Value::Array(all_of)) = object.get("allOf") {
+        if all_of.len() != 1 {
+            return Err(Error::NotImplementedError("allOf with more than one schema is not supported by gemini".to_string()));
+        }
+        let mut merged = object.clone();
+        merged.remove("allOf");
+        if let Value::Object(inner) = &all_of[0] {
+            for (key, value) in inner {
+                merged.entry(key.clone()).or_insert(value.clone());
+            }
+        }
+        return translate_schema(&Value::Object(merged), root, refs);
+    }
+
+    if let Some(Value::Array(alternatives)) = object.get("anyOf").or_else(|| object.get("oneOf")) {
+        let null = json!({"type": "null"});
+        let nullable = alternatives.contains(&null);
+        let alternatives: Vec<&Value> = alternatives.iter().filter(|schema| **schema != null).collect();
+        let mut translated = if alternatives.len() == 1 {
+            translate_schema(alternatives[0], root, refs)?
+        } else {
+            let any_of = alternatives.into_iter()
+                .map(|schema| translate_schema(schema, root, refs))
+                .collect::<Result<Vec<Value>, Error>>()?;
+            json!({"anyOf": any_of})
+        };
+        if let Value::Object(translated) = &mut translated {
+            if nullable {
+                translated.insert("nullable".to_string(), Value::Bool(true));
+            }
+            if let Some(description) = object.get("description") {
+                translated.insert("description".to_string(), description.clone());
+            }
+        }
+        return Ok(translated);
+    }
+
+    let mut translated = Map::new();
+    for (key, value) in object.iter() {
+        if !GEMINI_KEYWORDS.contains(&key.as_str()) {
+            continue;
+        }
+        match key.as_str() {
+            "type" => {
+                let types: Vec<&str> = match value {
+                    Value::String(ty) => vec![ty.as_str()],
+                    Value::Array(types) => types.iter().filter_map(|ty| ty.as_str()).collect(),
+                    _ => Vec::new(),
+                };
+                let non_null: Vec<&str> = types.iter().copied().filter(|ty| *ty != "null").collect();
+                if non_null.len() > 1 {
+                    return Err(Error::NotImplementedError(format!(
+                        "gemini does not support values of several types: {:?}", non_null
+                    )));
+                }
+                if let Some(ty) = non_null.first() {
+                    translated.insert("type".to_string(), Value::String(ty.to_uppercase()));
+                }
+                if types.contains(&"null") {
+                    translated.insert("nullable".to_string(), Value::Bool(true));
+                }
+            }
+            "format" => {
+                // only these formats are accepted, schemars also emits uint8, int16, ...
+                if let Some(format @ ("int32" | "int64" | "float" | "double" | "date-time" | "enum")) = value.as_str() {
+                    translated.insert("format".to_string(), Value::String(format.to_string()));
+                }
+            }
+            "properties" => {
+                let properties = value.as_object().into_iter().flatten()
+                    .map(|(name, property)| Ok((name.clone(), translate_schema(property, root, refs)?)))
+                    .collect::<Result<Map<String, Value>, Error>>()?;
+                translated.insert("properties".to_string(), Value::Object(properties));
+            }
+            "items" => {
+                translated.insert("items".to_string(), translate_schema(value, root, refs)?);
+            }
+            _ => {
+                translated.insert(key.clone(), value.clone());
+            }
+        }
+    }
+    if let Some(constant) = object.get("const") {
+        translated.insert("enum".to_string(), json!([constant]));
+    }
+    if translated.contains_key("enum") && !translated.contains_key("type") {
+        translated.insert("type".to_string(), Value::String("STRING".to_string()));
+    }
+    Ok(Value::Object(translated))
+}
+
+#[derive(Debug, Deserialize)]
+struct GeminiErrorBody {
+    error: GeminiErrorDetail,
+}
+
+#[derive(Debug, Deserialize)]
+struct GeminiErrorDetail {
+    message: String,
+    status: Option<String>,
+}
+
+impl GenerateContentRequest {
+    ///translates an openai chat completion request into a request for the gemini generateContent api.
+    /// system messages are moved into system_instruction, assistant tool calls become functionCall parts
+    /// and tool messages become functionResponse parts. Tool messages are only ever produced by re-asks
+    /// after a failed validation, so their content is sent as the error of the function.
+    /// The response_mime_type and response_schema fields of extra_body go into the generation config.
+    ///
+    /// # Arguments
+    /// * `chat_request`: `&ChatRequest` - the request to translate
+    pub fn from_chat_request(chat_request: &ChatRequest) -> Result<Self, Error> {
+        let kwargs = &chat_request.request;
+        let mut system: Vec<String> = Vec::new();
+        let mut contents: Vec<Content> = Vec::new();
+        // functionResponse parts are matched to their call by name, tool messages only carry the id
+        let mut call_names: Vec<(String, String)> = Vec::new();
+
+        for message in &kwargs.messages {
+            match message {
+                ChatCompletionRequestMessage::System(message) => {
+                    system.push(message.content.clone());
+                }
+                ChatCompletionRequestMessage::User(message) => {
+                    let parts = match &message.content {
+                        ChatCompletionRequestUserMessageContent::Text(text) => vec![text_part(text)],
+                        ChatCompletionRequestUserMessageContent::Array(parts) => {
+                            parts.iter().map(|part| match part {
+                                ChatCompletionRequestMessageContentPart::Text(part) => Ok(text_part(&part.text)),
+                                ChatCompletionRequestMessageContentPart::Image(_) => Err(Error::NotImplementedError(
+                                    "image content is not supported in gemini modes".to_string()
+                                )),
+                            }).collect::<Result<Vec<Part>, Error>>()?
+                        }
+                    };
+                    push_parts(&mut contents, GeminiRole::User, parts);
+                }
+                ChatCompletionRequestMessage::Assistant(message) => {
+                    let mut parts = Vec::new();
+                    if let Some(text) = &message.content {
+                        parts.push(text_part(text));
+                    }
+                    for tool_call in message.tool_calls.iter().flatten() {
+                        call_names.push((tool_call.id.clone(), tool_call.function.name.clone()));
+                        let args = serde_json::from_str(&tool_call.function.arguments)
+                            .unwrap_or_else(|_| Value::String(tool_call.function.arguments.clone()));
+                        parts.push(Part {
+                            function_call: Some(GeminiFunctionCall {
+                                name: tool_call.function.name.clone(),
+                                args,
+                            }),
+                            ..Default::default()
+                        });
+                    }
+                    push_parts(&mut contents, GeminiRole::Model, parts);
+                }
+                ChatCompletionRequestMessage::Tool(message) => {
+                    let name = call_names.iter()
+                        .find(|(id, _)| *id == message.tool_call_id)
+                        .map(|(_, name)| name.clone())
+                        .unwrap_or_else(|| message.tool_call_id.clone());
+                    push_parts(&mut contents, GeminiRole::User, vec![Part {
+                        function_response: Some(GeminiFunctionResponse {
+                            name,
+                            response: json!({"error": message.content}),
+                        }),
+                        ..Default::default()
+                    }]);
+                }
+                ChatCompletionRequestMessage::Function(_) => {
+                    return Err(Error::NotImplementedError(
+                        "function messages are not supported in gemini modes".to_string()
+                    ));
+                }
+            }
+        }
+
+        let tool_config = match &kwargs.tool_choice {
+            Some(ChatCompletionToolChoiceOption::Named(choice)) => Some(FunctionCallingConfig {
+                mode: "ANY".to_string(),
+                allowed_function_names: Some(vec![choice.function.name.clone()]),
+            }),
+            Some(ChatCompletionToolChoiceOption::Auto) => Some(FunctionCallingConfig {
+                mode: "AUTO".to_string(),
+                allowed_function_names: None,
+            }),
+            Some(ChatCompletionToolChoiceOption::None) => Some(FunctionCallingConfig {
+                mode: "NONE".to_string(),
+                allowed_function_names: None,
+            }),
+            None => None,
+        }.map(|function_calling_config| ToolConfig { function_calling_config });
+
+        let generation_config = GenerationConfig {
+            temperature: kwargs.temperature,
+            top_p: kwargs.top_p,
+            max_output_tokens: kwargs.max_tokens.map(u32::from),
+            stop_sequences: match &kwargs.stop {
+                Some(Stop::String(stop)) => Some(vec![stop.clone()]),
+                Some(Stop::StringArray(stop)) => Some(stop.clone()),
+                None => None,
+            },
+            response_mime_type: chat_request.extra_body.get("response_mime_type")
+                .and_then(|mime_type| mime_type.as_str())
+                .map(|mime_type| mime_type.to_string()),
+            response_schema: chat_request.extra_body.get("response_schema").cloned(),
+        };
+
+        Ok(GenerateContentRequest {
+            model: kwargs.model.clone(),
+            contents,
+            system_instruction: if system.is_empty() {
+                None
+            } else {
+                Some(Content { role: None, parts: vec![text_part(&system.join("\n\n"))] })
+            },
+            tools: kwargs.tools.as_ref().map(|tools| vec![GeminiTool {
+                function_declarations: tools.iter()
+                    .map(|tool| FunctionDeclaration::from(tool.function.clone()))
+                    .collect(),
+            }]),
+            tool_config,
+            generation_config: if generation_config == GenerationConfig::default() {
+                None
+            } else {
+                Some(generation_config)
+            },
+        })
+    }
+}
+
+fn text_part(text: &str) -> Part {
+    Part { text: Some(text.to_string()), ..Default::default() }
+}
+
+///gemini requires user and model turns to alternate, so consecutive parts of the same role are merged
+/// into one content. Empty text parts are rejected by the api and dropped.
+fn push_parts(contents: &mut Vec<Content>, role: GeminiRole, parts: Vec<Part>) {
+    let parts: Vec<Part> = parts.into_iter()
+        .filter(|part| !matches!(&part.text, Some(text) if text.trim().is_empty()))
+        .collect();
+    if parts.is_empty() {
+        return;
+    }
+    match contents.last_mut() {
+        Some(last) if last.role == Some(role) => last.parts.extend(parts),
+        _ => contents.push(Content { role: Some(role), parts }),
+    }
+}
+
+///a minimal client for the gemini generateContent api, it implements ChatBackend and is meant to be used
+/// with Mode::GEMINI_TOOLS or Mode::GEMINI_JSON
+///
+/// Example
+///
+/// let client = GeminiClient::new() //defaults to env variable GEMINI_API_KEY
+///     .with_api_base("http://localhost:8080/v1beta");
+/// let patched_client = Patch { client, mode: Some(Mode::GEMINI_TOOLS) };
+#[derive(Debug, Clone)]
+pub struct GeminiClient {
+    http_client: reqwest::Client,
+    api_key: String,
+    api_base: String,
+}
+
+impl Default for GeminiClient {
+    fn default() -> Self {
+        GeminiClient {
+            http_client: reqwest::Client::new(),
+            api_key: std::env::var("GEMINI_API_KEY").unwrap_or_default(),
+            api_base: GEMINI_API_BASE.to_string(),
+        }
+    }
+}
+
+impl GeminiClient {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_api_key<S: Into<String>>(mut self, api_key: S) -> Self {
+        self.api_key = api_key.into();
+        self
+    }
+
+    pub fn with_api_base<S: Into<String>>(mut self, api_base: S) -> Self {
+        self.api_base = api_base.into();
+        self
+    }
+
+    ///sends a request to the /models/{model}:generateContent endpoint
+    pub async fn generate_content(&self, request: GenerateContentRequest) -> Result<GenerateContentResponse, OpenAIError> {
+        let response = self.http_client
+            .post(format!("{}/models/{}:generateContent", self.api_base, request.model))
+            .header("x-goog-api-key", &self.api_key)
+            .json(&request)
+            .send()
+            .await?;
+
+        let status = response.status();
+        let bytes = response.bytes().await?;
+        if !status.is_success() {
+            let error = match serde_json::from_slice::<GeminiErrorBody>(&bytes) {
+                Ok(body) => ApiError {
+                    message: body.error.message,
+                    r#type: body.error.status,
+                    param: None,
+                    code: Some(json!(status.as_u16())),
+                },
+                Err(_) => ApiError {
+                    message: String::from_utf8_lossy(&bytes).to_string(),
+                    r#type: None,
+                    param: None,
+                    code: Some(json!(status.as_u16())),
+                },
+            };
+            return Err(OpenAIError::ApiError(error));
+        }
+        serde_json::from_slice::<GenerateContentResponse>(&bytes).map_err(OpenAIError::JSONDeserialize)
+    }
+}
+
+impl ChatBackend for GeminiClient {
+    fn create(&self, request: ChatRequest) -> BackendFuture<ChatCompletionResponseWrapper> {
+        let client = self.clone();
+        Box::pin(async move {
+            let request = GenerateContentRequest::from_chat_request(&request)?;
+            let res = client.generate_content(request).await?;
+            Ok(ChatCompletionResponseWrapper::Gemini(res))
+        })
+    }
+}
@@ -0,0 +1,152 @@
+use serde_json::{json, Map, Value};
+
+// keywords that openai rejects in strict mode, the constraints they express are still enforced by the validator
+const UNSUPPORTED_KEYWORDS: [&str; 17] = [
+    "$schema", "default", "format", "minimum", "maximum", "exclusiveMinimum", "exclusiveMaximum",
+    "multipleOf", "minLength", "maxLength", "pattern", "minItems", "maxItems", "uniqueItems",
+    "minProperties", "maxProperties", "patternProperties",
+];
+
+///rewrites a schemars json schema into the subset accepted by openai structured outputs (strict: true)
+/// * every object gets additionalProperties: false
+/// * every property is required, the properties that were optional become nullable
+/// * oneOf is replaced by anyOf and single element allOf (schemars uses it for documented $refs) is inlined
+/// * keywords strict mode does not support are removed
+///
+/// # Example
+///
+/// strict_json_schema(schema_for!(MyStruct)) turns {"properties": {"a": {"type": "string"}}, "required": []}
+/// into {"properties": {"a": {"type": ["string", "null"]}}, "required": ["a"], "additionalProperties": false}
+pub fn strict_json_schema(schema: Value) -> Value {
+    match schema {
+        Value::Object(object) => Value::Object(strict_object(object)),
+        Value::Array(items) => Value::Array(items.into_iter().map(strict_json_schema).collect()),
+        other => other,
+    }
+}
+
+fn strict_object(mut object: Map<String, Value>) -> Map<String, Value> {
+    for keyword in UNSUPPORTED_KEYWORDS {
+        object.remove(keyword);
+    }
+
+    if let Some(one_of) = object.remove("oneOf") {
+        object.insert("anyOf".to_string(), one_of);
+    }
+
+    if let Some(Value::Array(mut all_of)) = object.remove("allOf") {
+        if all_of.len() == 1 {
+            if let Value::Object(inner) = all_of.remove(0) {
+                for (key, value) in inner {
+                    object.entry(key).or_insert(value);
+                }
+            }
+        } else {
+            object.insert("allOf".to_string(), Value::Array(all_of));
+        }
+    }
+
+    for keyword in ["anyOf", "allOf", "items"] {
+        if let Some(value) = object.remove(keyword) {
+            object.insert(keyword.to_string(), strict_json_schema(value));
+        }
+    }
+
+    for keyword in ["definitions", "$defs"] {
+        if let Some(Value::Object(definitions)) = object.remove(keyword) {
+            let definitions = definitions.into_iter()
+                .map(|(name, definition)| (name, strict_json_schema(definition)))
+                .collect();
+            object.insert(keyword.to_string(), Value::Object(definitions));
+        }
+    }
+
+    if let Some(Value::Object(properties)) = object.remove("properties") {
+        let required: Vec<String> = match object.get("required") {
+            Some(Value::Array(required)) => required.iter()
+                .filter_map(|name| name.as_str().map(|name| name.to_string()))
+                .collect(),
+            _ => Vec::new(),
+        };
+
+        let mut names = Vec::with_capacity(properties.len());
+        let properties: Map<String, Value> = properties.into_iter().map(|(name, property)| {
+            let property = strict_json_schema(property);
+            let property = if required.contains(&name) { property } else { nullable(property) };
+            names.push(Value::String(name.clone()));
+            (name, property)
+        }).collect();
+
+        object.insert("properties".to_string(), Value::Object(properties));
+        object.insert("required".to_string(), Value::Array(names));
+        object.insert("additionalProperties".to_string(), Value::Bool(false));
+    } else if object.get("type") == Some(&Value::String("object".to_string())) {
+        object.insert("additionalProperties".to_string(), Value::Bool(false));
+    }
+
+    object
+}
+
+///allows null as a value of the schema, this is how strict mode expresses an optional field
+fn nullable(schema: Value) -> Value {
+    let null = json!({"type": "null"});
+    let mut object = match schema {
+        Value::Object(object) => object,
+        other => return json!({"anyOf": [other, null]}),
+    };
+
+    match object.get_mut("type") {
+        Some(Value::String(ty)) if ty == "null" => {}
+        Some(Value::String(ty)) => {
+            let ty = Value::String(ty.clone());
+            object.insert("type".to_string(), json!([ty, "null"]));
+            if let Some(Value::Array(values)) = object.get_mut("enum") {
+                if !values.contains(&Value::Null) {
+                    values.push(Value::Null);
+                }
+            }
+        }
+        Some(Value::Array(types)) => {
+            if !types.contains(&json!("null")) {
+                types.push(json!("null"));
+            }
+        }
+        _ => {
+            if let Some(Value::Array(any_of)) = object.get_mut("anyOf") {
+                if !any_of.contains(&null) {
+                    any_of.push(null);
+                }
+            } else {
+                let description = object.remove("description");
+                let mut wrapper = Map::new();
+                wrapper.insert("anyOf".to_string(), json!([Value::Object(object), null]));
+                if let Some(description) = description {
+                    wrapper.insert("description".to_string(), description);
+                }
+                return Value::Object(wrapper);
+            }
+        }
+    }
+    Value::Object(object)
+}
+
+///wraps the schema of a single object into an object with an `items` array,
+/// strict mode requires the root of the schema to be an object so an Iterable is sent as {"items": [...]}
+pub fn iterable_json_schema(mut schema: Value) -> Value {
+    let definitions = schema.as_object_mut().and_then(|object| object.remove("definitions"));
+    let mut wrapper = json!({
+        "type": "object",
+        "properties": {
+            "items": {
+                "type": "array",
+                "items": schema,
+            }
+        },
+        "required": ["items"],
+        "additionalProperties": false,
+    });
+    if let Some(definitions) = definitions {
+        wrapper["definitions"] = definitions;
+    }
+    wrapper
+}
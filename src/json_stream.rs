@@ -0,0 +1,750 @@
+use crate::types::JsonStream;
+use async_openai::types::{
+    ChatCompletionMessageToolCall, ChatCompletionMessageToolCallChunk, ChatCompletionToolType, FunctionCall
+};
+use std::collections::BTreeMap;
+use serde_json::Value;
+use futures::stream::StreamExt;
+use async_stream::stream;
+
+///splits streamed text into the json objects it contains, text is pushed as it arrives and an object
+/// is returned as soon as its closing brace is seen. Every character is looked at once,
+/// so the cost is linear in the length of the stream however it is chunked.
+///
+/// * braces and brackets inside strings (including escaped quotes) do not count
+/// * objects inside a top level array are returned one by one, objects nested in an object are part of it
+/// * text outside of objects and arrays (prose, a ```json fence, commas) is skipped
+///
+/// # Example
+///
+/// pushing "[{\"code\": \"fn a() {\"}, {\"co" returns ["{\"code\": \"fn a() {\"}"],
+/// the second object is returned by the push that completes it
+#[derive(Debug, Default)]
+pub struct JsonObjectSplitter {
+    // the open arrays outside of the current object
+    arrays: usize,
+    // the open braces and brackets of the current object
+    stack: Vec<char>,
+    current: String,
+    in_string: bool,
+    escaped: bool,
+}
+
+impl JsonObjectSplitter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///adds a chunk of text and returns the objects that were completed by it
+    pub fn push(&mut self, chunk: &str) -> Vec<String> {
+        let mut objects = Vec::new();
+        for c in chunk.chars() {
+            if let Some(object) = self.next_char(c) {
+                objects.push(object);
+            }
+        }
+        objects
+    }
+
+    ///the first object of text and the byte offset right after it, None if no object is complete
+    pub fn first_object(text: &str) -> Option<(String, usize)> {
+        let mut splitter = Self::new();
+        text.char_indices()
+            .find_map(|(i, c)| splitter.next_char(c).map(|object| (object, i + c.len_utf8())))
+    }
+
+    fn next_char(&mut self, c: char) -> Option<String> {
+        let capturing = !self.stack.is_empty();
+        if capturing {
+            self.current.push(c);
+        }
+        // outside of objects and arrays quotes are prose
+        if self.in_string || (c == '"' && (capturing || self.arrays > 0)) {
+            self.string_char(c);
+            return None;
+        }
+
+        match c {
+            '{' => {
+                if !capturing {
+                    self.current.push(c);
+                }
+                self.stack.push(c);
+            }
+            '[' if capturing => self.stack.push(c),
+            '[' => self.arrays += 1,
+            '}' | ']' if capturing => {
+                self.stack.pop();
+                if self.stack.is_empty() {
+                    return Some(std::mem::take(&mut self.current));
+                }
+            }
+            ']' => self.arrays = self.arrays.saturating_sub(1),
+            _ => {}
+        }
+        None
+    }
+
+    fn string_char(&mut self, c: char) {
+        if self.escaped {
+            self.escaped = false;
+        } else if c == '\\' {
+            self.escaped = true;
+        } else if c == '"' {
+            self.in_string = !self.in_string;
+        }
+    }
+}
+
+///skips the text up to and including the first '[' that is not in a string,
+/// used for the {"items": [...]} wrapper that the schema constrained modes ask for when streaming an Iterable
+pub fn skip_to_first_array(mut chunks: JsonStream) -> JsonStream {
+    stream! {
+        let mut started = false;
+        let mut in_string = false;
+        let mut escaped = false;
+        while let Some(chunk) = chunks.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    yield Err(e);
+                    continue;
+                }
+            };
+            if started {
+                yield Ok(chunk);
+                continue;
+            }
+            for (index, c) in chunk.char_indices() {
+                if escaped {
+                    escaped = false;
+                } else if in_string && c == '\\' {
+                    escaped = true;
+                } else if c == '"' {
+                    in_string = !in_string;
+                } else if c == '[' && !in_string {
+                    started = true;
+                    yield Ok(chunk[index + 1..].to_string());
+                    break;
+                }
+            }
+        }
+    }.boxed()
+}
+
+#[derive(Debug, Default, PartialEq)]
+enum WrapperState {
+    #[default]
+    Start,
+    Key,
+    Value,
+    Array,
+    Unwrapped,
+    Passed,
+}
+
+///drops the {"items": [ wrapper of a streamed Iterable (Mode::JSON has to answer with an object so the llm wraps the list),
+/// text is pushed as it arrives. The wrapper is an object whose first value is an array of objects,
+/// anything else (a bare array, objects one after the other) is passed on unchanged.
+/// Unlike skip_to_first_array the text is held back only until the first value of the object is seen
+#[derive(Debug, Default)]
+pub struct ItemsWrapperFilter {
+    state: WrapperState,
+    // the text that is held back until it is known whether it is a wrapper
+    held: String,
+    in_string: bool,
+    escaped: bool,
+}
+
+impl ItemsWrapperFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///adds a chunk of text and returns the part of it that is not the wrapper
+    pub fn push(&mut self, chunk: &str) -> String {
+        let mut out = String::new();
+        for (index, c) in chunk.char_indices() {
+            match self.state {
+                WrapperState::Unwrapped | WrapperState::Passed => {
+                    out.push_str(&chunk[index..]);
+                    break;
+                }
+                _ => self.next_char(c, &mut out),
+            }
+        }
+        out
+    }
+
+    ///returns the text that is still held back once the stream has ended
+    pub fn finish(&mut self) -> String {
+        std::mem::take(&mut self.held)
+    }
+
+    fn next_char(&mut self, c: char, out: &mut String) {
+        self.held.push(c);
+        if self.in_string {
+            if self.escaped {
+                self.escaped = false;
+            } else if c == '\\' {
+                self.escaped = true;
+            } else if c == '"' {
+                self.in_string = false;
+            }
+            return;
+        }
+        if c.is_whitespace() {
+            return;
+        }
+        self.state = match (&self.state, c) {
+            (WrapperState::Start, '{') => WrapperState::Key,
+            (WrapperState::Key, '"') => {
+                self.in_string = true;
+                WrapperState::Key
+            }
+            (WrapperState::Key, ':') => WrapperState::Value,
+            (WrapperState::Value, '[') => WrapperState::Array,
+            // the items start right after the '['
+            (WrapperState::Array, '{' | ']') => {
+                self.held.clear();
+                out.push(c);
+                WrapperState::Unwrapped
+            }
+            _ => {
+                out.push_str(&std::mem::take(&mut self.held));
+                WrapperState::Passed
+            }
+        };
+    }
+}
+
+///yields the items of a streamed Iterable without their {"items": [ wrapper, see ItemsWrapperFilter
+pub fn skip_items_wrapper(mut chunks: JsonStream) -> JsonStream {
+    stream! {
+        let mut filter = ItemsWrapperFilter::new();
+        while let Some(chunk) = chunks.next().await {
+            match chunk {
+                Ok(chunk) => {
+                    let content = filter.push(&chunk);
+                    if !content.is_empty() {
+                        yield Ok(content);
+                    }
+                }
+                Err(e) => yield Err(e),
+            }
+        }
+        let rest = filter.finish();
+        if !rest.is_empty() {
+            yield Ok(rest);
+        }
+    }.boxed()
+}
+
+#[derive(Debug, Default)]
+enum FenceState {
+    #[default]
+    Before,
+    InfoString,
+    Inside,
+    After,
+}
+
+///extracts the content of a ```json codeblock from streamed text (Mode::MD_JSON), text is pushed as it arrives
+/// and the part of it that is inside the codeblock is returned right away.
+///
+/// * the opening fence is found across chunk boundaries, the prose before it and its info string are dropped
+/// * the closing fence has to start a line, a json string can not contain a raw newline so "```" in a value is kept
+/// * everything after the closing fence is dropped
+/// * if the reply starts with '{' or '[' it is used without a fence, if no fence is found at all
+///   the whole text is returned by finish()
+#[derive(Debug, Default)]
+pub struct CodeFenceFilter {
+    state: FenceState,
+    // the text before the opening fence, returned by finish() if there is no fence
+    prefix: String,
+    // whether prefix has anything but whitespace
+    prose: bool,
+    backticks: usize,
+    // the spaces and backticks at the start of a line inside the codeblock that might be the closing fence
+    line_start: Option<String>,
+}
+
+impl CodeFenceFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///adds a chunk of text and returns the part of it that is inside the codeblock
+    pub fn push(&mut self, chunk: &str) -> String {
+        let mut out = String::new();
+        for c in chunk.chars() {
+            match self.state {
+                FenceState::Before => {
+                    self.prefix.push(c);
+                    if c == '`' {
+                        self.backticks += 1;
+                        if self.backticks == 3 {
+                            self.state = FenceState::InfoString;
+                        }
+                    } else {
+                        self.backticks = 0;
+                        if (c == '{' || c == '[') && !self.prose {
+                            self.state = FenceState::Inside;
+                            out.push(c);
+                        }
+                    }
+                    self.prose |= !c.is_whitespace();
+                }
+                FenceState::InfoString => {
+                    if c == '\n' {
+                        self.state = FenceState::Inside;
+                        self.line_start = Some(String::new());
+                    }
+                }
+                FenceState::Inside => self.inside_char(c, &mut out),
+                FenceState::After => break,
+            }
+        }
+        out
+    }
+
+    ///returns the rest of the text once the stream has ended
+    pub fn finish(&mut self) -> String {
+        match self.state {
+            FenceState::Before => std::mem::take(&mut self.prefix),
+            _ => self.line_start.take().unwrap_or_default(),
+        }
+    }
+
+    fn inside_char(&mut self, c: char, out: &mut String) {
+        match self.line_start.as_mut() {
+            Some(line_start) if c == '`' || (c == ' ' && !line_start.contains('`')) => {
+                line_start.push(c);
+                if line_start.matches('`').count() == 3 {
+                    self.state = FenceState::After;
+                    self.line_start = None;
+                }
+            }
+            _ => {
+                if let Some(line_start) = self.line_start.take() {
+                    out.push_str(&line_start);
+                }
+                out.push(c);
+                if c == '\n' {
+                    self.line_start = Some(String::new());
+                }
+            }
+        }
+    }
+}
+
+///yields the content of the ```json codeblock of the stream, see CodeFenceFilter
+pub fn extract_from_code_fence(mut chunks: JsonStream) -> JsonStream {
+    stream! {
+        let mut filter = CodeFenceFilter::new();
+        while let Some(chunk) = chunks.next().await {
+            match chunk {
+                Ok(chunk) => {
+                    let content = filter.push(&chunk);
+                    if !content.is_empty() {
+                        yield Ok(content);
+                    }
+                }
+                Err(e) => yield Err(e),
+            }
+        }
+        let rest = filter.finish();
+        if !rest.is_empty() {
+            yield Ok(rest);
+        }
+    }.boxed()
+}
+
+#[derive(Debug, Default)]
+struct ToolCallState {
+    id: String,
+    name: String,
+    arguments: String,
+    splitter: JsonObjectSplitter,
+    done: bool,
+}
+
+///assembles streamed tool calls, the argument fragments of parallel tool calls are interleaved in the stream
+/// and are told apart by ChatCompletionMessageToolCallChunk::index. A call is returned as soon as its arguments
+/// are a complete json object, the id and the name are sent with the first fragment of a call
+#[derive(Debug, Default)]
+pub struct ToolCallSplitter {
+    calls: BTreeMap<i32, ToolCallState>,
+}
+
+impl ToolCallSplitter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///adds the tool call fragments of a chunk and returns the calls that were completed by them
+    pub fn push(&mut self, chunks: &[ChatCompletionMessageToolCallChunk]) -> Vec<ChatCompletionMessageToolCall> {
+        let mut completed = Vec::new();
+        for chunk in chunks {
+            let call = self.calls.entry(chunk.index).or_default();
+            if let Some(id) = chunk.id.as_ref() {
+                call.id.push_str(id);
+            }
+            let function = match chunk.function.as_ref() {
+                Some(function) => function,
+                None => continue,
+            };
+            if let Some(name) = function.name.as_ref() {
+                call.name.push_str(name);
+            }
+            let arguments = match function.arguments.as_ref() {
+                Some(arguments) if !call.done => arguments,
+                _ => continue,
+            };
+            call.arguments.push_str(arguments);
+            if let Some(arguments) = call.splitter.push(arguments).into_iter().next() {
+                call.done = true;
+                completed.push(tool_call(call, arguments));
+            }
+        }
+        completed
+    }
+
+    ///returns the calls whose arguments never closed once the stream has ended, their arguments are incomplete
+    pub fn finish(&mut self) -> Vec<ChatCompletionMessageToolCall> {
+        std::mem::take(&mut self.calls).into_values()
+            .filter(|call| !call.done)
+            .map(|call| {
+                let arguments = call.arguments.clone();
+                tool_call(&call, arguments)
+            })
+            .collect()
+    }
+}
+
+fn tool_call(call: &ToolCallState, arguments: String) -> ChatCompletionMessageToolCall {
+    ChatCompletionMessageToolCall {
+        id: call.id.clone(),
+        r#type: ChatCompletionToolType::Function,
+        function: FunctionCall {
+            name: call.name.clone(),
+            arguments,
+        },
+    }
+}
+
+///an event of JsonEventParser, see StreamEvent in dsl::events for the validated version
+#[derive(Debug, Clone, PartialEq)]
+pub enum JsonEvent {
+    ObjectStarted { index: usize },
+    FieldDelta { path: String, text: String },
+    FieldCompleted { path: String, value: Value },
+    ObjectEnded { index: usize, json: String },
+}
+
+#[derive(Debug)]
+enum Frame {
+    // the key of the field that is being parsed, in_value is set once its ':' is seen
+    Object { start: usize, key: Option<String>, in_value: bool },
+    Array { start: usize, index: usize },
+}
+
+impl Frame {
+    fn start(&self) -> usize {
+        match self {
+            Frame::Object { start, .. } | Frame::Array { start, .. } => *start,
+        }
+    }
+}
+
+///parses streamed json into field level events, text is pushed as it arrives.
+/// Objects are found like JsonObjectSplitter finds them (a top level array of objects or a single object),
+/// within an object the path of a field is its key, "address.city" for a nested field and "tags[1]" for a list element.
+///
+/// * ObjectStarted when the '{' of an object is seen, objects are numbered from 0
+/// * FieldDelta with the unescaped text of a string value that arrived in this push, so a long string can be shown as it is generated
+/// * FieldCompleted when a value (of any type, at any depth) is complete
+/// * ObjectEnded with the json of the object when its '}' is seen
+#[derive(Debug, Default)]
+pub struct JsonEventParser {
+    // the open arrays outside of the current object
+    arrays: usize,
+    objects: usize,
+    current: String,
+    frames: Vec<Frame>,
+    in_string: bool,
+    escaped: bool,
+    // the string is a key rather than a value
+    is_key: bool,
+    key: String,
+    // the hex digits of a \u escape and the first half of a surrogate pair
+    unicode: Option<String>,
+    surrogate: Option<u16>,
+    // the start of the string or scalar value being parsed
+    value_start: Option<usize>,
+    scalar: bool,
+    delta: String,
+}
+
+impl JsonEventParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    ///adds a chunk of text and returns the events it caused
+    pub fn push(&mut self, chunk: &str) -> Vec<JsonEvent> {
+        let mut events = Vec::new();
+        for c in chunk.chars() {
+            self.next_char(c, &mut events);
+        }
+        self.flush_delta(&mut events);
+        events
+    }
+
+    fn next_char(&mut self, c: char, events: &mut Vec<JsonEvent>) {
+        if self.frames.is_empty() {
+            self.outside_char(c, events);
+            return;
+        }
+        if self.in_string {
+            self.current.push(c);
+            self.string_char(c, events);
+            return;
+        }
+        if self.scalar && (c.is_whitespace() || matches!(c, ',' | '}' | ']')) {
+            self.complete_value(self.current.len(), events);
+        }
+        self.current.push(c);
+        match c {
+            '"' => {
+                self.in_string = true;
+                self.is_key = matches!(self.frames.last(), Some(Frame::Object { in_value: false, .. }));
+                if !self.is_key {
+                    self.value_start = Some(self.current.len() - 1);
+                }
+            }
+            '{' => self.frames.push(Frame::Object { start: self.current.len() - 1, key: None, in_value: false }),
+            '[' => self.frames.push(Frame::Array { start: self.current.len() - 1, index: 0 }),
+            '}' | ']' => {
+                let frame = self.frames.pop().expect("checked above");
+                if self.frames.is_empty() {
+                    events.push(JsonEvent::ObjectEnded { index: self.objects - 1, json: std::mem::take(&mut self.current) });
+                } else {
+                    self.value_start = Some(frame.start());
+                    self.complete_value(self.current.len(), events);
+                }
+            }
+            ':' => {
+                if let Some(Frame::Object { in_value, .. }) = self.frames.last_mut() {
+                    *in_value = true;
+                }
+            }
+            ',' => match self.frames.last_mut() {
+                Some(Frame::Object { key, in_value, .. }) => {
+                    *key = None;
+                    *in_value = false;
+                }
+                Some(Frame::Array { index, .. }) => *index += 1,
+                None => {}
+            },
+            c if c.is_whitespace() => {}
+            _ => {
+                if !self.scalar {
+                    self.scalar = true;
+                    self.value_start = Some(self.current.len() - 1);
+                }
+            }
+        }
+    }
+
+    // outside of objects quotes only start strings inside an array, like in JsonObjectSplitter
+    fn outside_char(&mut self, c: char, events: &mut Vec<JsonEvent>) {
+        if self.in_string || (c == '"' && self.arrays > 0) {
+            if self.escaped {
+                self.escaped = false;
+            } else if c == '\\' {
+                self.escaped = true;
+            } else if c == '"' {
+                self.in_string = !self.in_string;
+            }
+            return;
+        }
+        match c {
+            '{' => {
+                self.current.push(c);
+                self.frames.push(Frame::Object { start: 0, key: None, in_value: false });
+                events.push(JsonEvent::ObjectStarted { index: self.objects });
+                self.objects += 1;
+            }
+            '[' => self.arrays += 1,
+            ']' => self.arrays = self.arrays.saturating_sub(1),
+            _ => {}
+        }
+    }
+
+    fn string_char(&mut self, c: char, events: &mut Vec<JsonEvent>) {
+        if let Some(hex) = self.unicode.as_mut() {
+            hex.push(c);
+            if hex.len() == 4 {
+                let code = u16::from_str_radix(hex, 16).unwrap_or(0xFFFD);
+                self.unicode = None;
+                match self.surrogate.take() {
+                    Some(high) => self.push_text(char::decode_utf16([high, code]).map(|c| c.unwrap_or('\u{FFFD}')).collect::<String>().as_str()),
+                    None if (0xD800..0xDC00).contains(&code) => self.surrogate = Some(code),
+                    None => self.push_text(&char::from_u32(code as u32).unwrap_or('\u{FFFD}').to_string()),
+                }
+            }
+            return;
+        }
+        if self.escaped {
+            self.escaped = false;
+            let text = match c {
+                'n' => '\n',
+                't' => '\t',
+                'r' => '\r',
+                'b' => '\u{8}',
+                'f' => '\u{c}',
+                'u' => {
+                    self.unicode = Some(String::new());
+                    return;
+                }
+                c => c,
+            };
+            self.push_text(&text.to_string());
+            return;
+        }
+        match c {
+            '\\' => self.escaped = true,
+            '"' => {
+                self.in_string = false;
+                if self.is_key {
+                    let key = std::mem::take(&mut self.key);
+                    if let Some(Frame::Object { key: field, .. }) = self.frames.last_mut() {
+                        *field = Some(key);
+                    }
+                } else {
+                    self.flush_delta(events);
+                    self.complete_value(self.current.len(), events);
+                }
+            }
+            c => self.push_text(&c.to_string()),
+        }
+    }
+
+    fn push_text(&mut self, text: &str) {
+        if self.is_key {
+            self.key.push_str(text);
+        } else {
+            self.delta.push_str(text);
+        }
+    }
+
+    fn flush_delta(&mut self, events: &mut Vec<JsonEvent>) {
+        if !self.delta.is_empty() {
+            events.push(JsonEvent::FieldDelta { path: self.path(), text: std::mem::take(&mut self.delta) });
+        }
+    }
+
+    // the value from value_start up to end is complete
+    fn complete_value(&mut self, end: usize, events: &mut Vec<JsonEvent>) {
+        self.scalar = false;
+        let start = match self.value_start.take() {
+            Some(start) => start,
+            None => return,
+        };
+        if let Ok(value) = serde_json::from_str::<Value>(&self.current[start..end]) {
+            events.push(JsonEvent::FieldCompleted { path: self.path(), value });
+        }
+    }
+
+    ///the path of the value that is being parsed in the current object
+    fn path(&self) -> String {
+        let mut path = String::new();
+        for frame in self.frames.iter() {
+            match frame {
+                Frame::Object { key: Some(key), .. } => {
+                    if !path.is_empty() {
+                        path.push('.');
+                    }
+                    path.push_str(key);
+                }
+                Frame::Object { key: None, .. } => {}
+                Frame::Array { index, .. } => path.push_str(&format!("[{}]", index)),
+            }
+        }
+        path
+    }
+}
+
+///the json pointer (RFC 6901) of the value at the byte `offset` of `text`, e.g. "/items/2/name".
+/// serde reports an invalid value at the position right after it, or right after the '{' or '['
+/// that opens it when the value has the wrong type, both are resolved to the pointer of the value
+pub fn json_pointer_at(text: &str, offset: usize) -> String {
+    enum Pointer {
+        Object { key: Option<String>, expect_key: bool },
+        Array(usize),
+    }
+    let mut end = offset.min(text.len());
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    let text = &text[..end];
+    let mut frames: Vec<Pointer> = Vec::new();
+    // the key that is being read
+    let mut key: Option<String> = None;
+    let mut in_string = false;
+    let mut escaped = false;
+    for c in text.chars() {
+        if in_string {
+            if escaped {
+                escaped = false;
+                if let Some(key) = key.as_mut() {
+                    key.push(c);
+                }
+            } else if c == '\\' {
+                escaped = true;
+            } else if c == '"' {
+                in_string = false;
+                if let (Some(read), Some(Pointer::Object { key, .. })) = (key.take(), frames.last_mut()) {
+                    *key = Some(read);
+                }
+            } else if let Some(key) = key.as_mut() {
+                key.push(c);
+            }
+            continue;
+        }
+        match c {
+            '"' => {
+                in_string = true;
+                if let Some(Pointer::Object { expect_key: true, .. }) = frames.last() {
+                    key = Some(String::new());
+                }
+            }
+            '{' => frames.push(Pointer::Object { key: None, expect_key: true }),
+            '[' => frames.push(Pointer::Array(0)),
+            '}' | ']' => {
+                frames.pop();
+            }
+            ':' => {
+                if let Some(Pointer::Object { expect_key, .. }) = frames.last_mut() {
+                    *expect_key = false;
+                }
+            }
+            ',' => match frames.last_mut() {
+                Some(Pointer::Object { expect_key, .. }) => *expect_key = true,
+                Some(Pointer::Array(index)) => *index += 1,
+                None => {}
+            },
+            _ => {}
+        }
+    }
+    // the value is the object or array that was just opened
+    if !in_string && text.trim_end().ends_with(['{', '[']) {
+        frames.pop();
+    }
+    frames.iter().map(|frame| match frame {
+        Pointer::Object { key: Some(key), .. } => format!("/{}", key.replace('~', "~0").replace('/', "~1")),
+        Pointer::Object { key: None, .. } => String::new(),
+        Pointer::Array(index) => format!("/{}", index),
+    }).collect()
+}
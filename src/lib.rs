@@ -9,4 +9,16 @@ pub mod common;
 pub mod openai_schema;
 pub mod dsl;
 pub mod error;
+pub mod anthropic;
+pub mod gemini;
+pub mod backend;
+pub mod json_schema;
+pub mod gbnf;
 
+pub mod completion;
+pub mod xml;
+pub mod json_stream;
+pub mod timeout;
+pub mod metadata;
+pub mod retry_policy;
+pub mod fallback;
@@ -0,0 +1,271 @@
+use crate::error::Error;
+use crate::backend::{ChatBackend, BackendFuture, ChatRequest, UsageSink};
+use crate::enums::{ChatCompletionResponseWrapper, InstructorResponse};
+use crate::openai_schema::BaseSchema;
+use async_openai::types::{
+    ChatCompletionResponseStream, CompletionUsage, CreateChatCompletionResponse, CreateChatCompletionStreamResponse,
+    CreateCompletionResponse, FinishReason
+};
+use validator::ValidateArgs;
+use futures::stream::StreamExt;
+use async_stream::stream;
+use std::sync::{Arc, Mutex};
+use tokio::sync::oneshot;
+
+///the metadata of the responses of a call to Patch::chat_completion_with_metadata,
+/// which InstructorResponse::Stream does not carry. The fields are those of the last response (or chunk) received,
+/// except `usage` which has an entry for every request of the call.
+/// The usage of every provider is recorded, the other fields only for openai style responses
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct StreamMetadata {
+    pub id: Option<String>,
+    pub model: Option<String>,
+    pub created: Option<u32>,
+    pub system_fingerprint: Option<String>,
+    pub finish_reason: Option<FinishReason>,
+    ///the usage reported by every request of the call, in the order they finished.
+    /// Retries, re-asks of invalid items and reconnects are requests of their own.
+    /// A stream that is cut off before its end never reports its usage
+    pub usage: Vec<CompletionUsage>,
+    ///the number of streams that ended without reporting their usage, because they were cancelled
+    /// (e.g. by InstructorResponse::take_validated) or cut off, or because the provider does not send it.
+    /// The provider still bills the tokens generated until then
+    pub unreported_requests: usize,
+    ///the number of chunks received from those streams, every chunk is about one completion token
+    pub unreported_chunks: usize,
+    ///whether the response was read to its end rather than dropped, the last item may have been an error
+    pub complete: bool,
+}
+
+impl StreamMetadata {
+    ///the sum of `usage`, None if no request reported its usage
+    pub fn total_usage(&self) -> Option<CompletionUsage> {
+        self.usage.iter().cloned().reduce(|total, usage| CompletionUsage {
+            prompt_tokens: total.prompt_tokens + usage.prompt_tokens,
+            completion_tokens: total.completion_tokens + usage.completion_tokens,
+            total_tokens: total.total_tokens + usage.total_tokens,
+        })
+    }
+
+    fn record(&mut self, id: &str, model: &str, created: u32, system_fingerprint: &Option<String>) {
+        self.id = Some(id.to_string());
+        self.model = Some(model.to_string());
+        self.created = Some(created);
+        if system_fingerprint.is_some() {
+            self.system_fingerprint = system_fingerprint.clone();
+        }
+    }
+
+    fn record_chunk(&mut self, chunk: &CreateChatCompletionStreamResponse) {
+        self.record(&chunk.id, &chunk.model, chunk.created, &chunk.system_fingerprint);
+        if let Some(finish_reason) = chunk.choices.iter().find(|choice| choice.index == 0).and_then(|choice| choice.finish_reason) {
+            self.finish_reason = Some(finish_reason);
+        }
+    }
+
+    fn record_response(&mut self, response: &CreateChatCompletionResponse) {
+        self.record(&response.id, &response.model, response.created, &response.system_fingerprint);
+        self.finish_reason = response.choices.first().and_then(|choice| choice.finish_reason);
+    }
+
+    fn record_completion(&mut self, response: &CreateCompletionResponse) {
+        self.record(&response.id, &response.model, response.created, &response.system_fingerprint);
+    }
+
+    fn record_wrapper(&mut self, response: &ChatCompletionResponseWrapper) {
+        match response {
+            ChatCompletionResponseWrapper::AtOnce(response) => self.record_response(response),
+            ChatCompletionResponseWrapper::Completion(response) => self.record_completion(response),
+            _ => {}
+        }
+        self.usage.extend(response.get_usage());
+    }
+}
+
+///resolves to the StreamMetadata of a call once its response is complete,
+/// see Patch::chat_completion_with_metadata
+#[derive(Debug)]
+pub struct StreamMetadataHandle {
+    metadata: Arc<Mutex<StreamMetadata>>,
+    finished: oneshot::Receiver<()>,
+}
+
+impl StreamMetadataHandle {
+    ///waits until the InstructorResponse::Stream has ended or has been dropped,
+    /// a response that is not streamed (or an error) is complete right away
+    pub async fn finished(self) -> StreamMetadata {
+        // the sender is only dropped after it has sent
+        let _ = self.finished.await;
+        self.metadata.lock().unwrap().clone()
+    }
+
+    ///the metadata received so far
+    pub fn current(&self) -> StreamMetadata {
+        self.metadata.lock().unwrap().clone()
+    }
+}
+
+///resolves the handle when it is dropped, i.e. when the stream holding it ends or is dropped.
+/// It owns the response, so the requests of the response are dropped (and recorded) before the handle resolves
+struct Finished<S> {
+    response: Option<S>,
+    sender: Option<oneshot::Sender<()>>,
+}
+
+impl<S> Drop for Finished<S> {
+    fn drop(&mut self) {
+        self.response.take();
+        if let Some(sender) = self.sender.take() {
+            let _ = sender.send(());
+        }
+    }
+}
+
+///a request of a stream, see StreamRequests
+struct StreamRequest {
+    id: String,
+    chunks: usize,
+    reported: bool,
+}
+
+///the requests behind the stream of a create_stream call, a wrapper like ReconnectingBackend may send several of them.
+/// They are told apart by the id of their chunks, a usage is reported right before the chunk carrying it
+/// so it belongs to the request of the next chunk
+#[derive(Default)]
+struct StreamRequests {
+    requests: Vec<StreamRequest>,
+    // the usages whose chunk has not been seen yet
+    pending: usize,
+}
+
+impl StreamRequests {
+    fn chunk(&mut self, id: &str) {
+        if self.requests.last().is_none_or(|request| request.id != id) {
+            self.requests.push(StreamRequest { id: id.to_string(), chunks: 0, reported: false });
+        }
+        let request = self.requests.last_mut().expect("a request was pushed");
+        request.chunks += 1;
+        if self.pending > 0 && !request.reported {
+            request.reported = true;
+            self.pending -= 1;
+        }
+    }
+}
+
+///counts the requests of a stream that did not report their usage in StreamMetadata::unreported_requests
+/// once the stream is dropped
+struct Unreported {
+    metadata: Arc<Mutex<StreamMetadata>>,
+    requests: Arc<Mutex<StreamRequests>>,
+}
+
+impl Drop for Unreported {
+    fn drop(&mut self) {
+        let requests = self.requests.lock().unwrap();
+        let mut metadata = self.metadata.lock().unwrap();
+        // a stream dropped before its first chunk is a request that did not report
+        if requests.requests.is_empty() && requests.pending == 0 {
+            metadata.unreported_requests += 1;
+            return;
+        }
+        // a usage whose chunk never arrived belongs to the last requests
+        let unreported = requests.requests.iter().filter(|request| !request.reported).collect::<Vec<&StreamRequest>>();
+        let count = unreported.len().saturating_sub(requests.pending);
+        metadata.unreported_requests += count;
+        metadata.unreported_chunks += unreported[..count].iter().map(|request| request.chunks).sum::<usize>();
+    }
+}
+
+///wraps a ChatBackend and records the metadata of every response it returns,
+/// the usage of a stream is requested from the inner backend with create_stream_with_usage
+#[derive(Debug, Clone)]
+pub(crate) struct MetadataBackend<B: ChatBackend> {
+    backend: B,
+    metadata: Arc<Mutex<StreamMetadata>>,
+}
+
+impl<B: ChatBackend> MetadataBackend<B> {
+    pub(crate) fn new(backend: B) -> Self {
+        MetadataBackend { backend, metadata: Arc::new(Mutex::new(StreamMetadata::default())) }
+    }
+
+    ///returns the handle of the call together with its result,
+    /// a streamed response resolves the handle when it ends or is dropped
+    pub(crate) fn finish<T>(&self, result: Result<InstructorResponse<T>, Error>) -> (Result<InstructorResponse<T>, Error>, StreamMetadataHandle)
+    where
+        T: ValidateArgs<'static> + BaseSchema + Send + 'static,
+    {
+        let (sender, receiver) = oneshot::channel();
+        let handle = StreamMetadataHandle { metadata: self.metadata.clone(), finished: receiver };
+        let result = match result {
+            Ok(InstructorResponse::Stream(response)) => {
+                let metadata = self.metadata.clone();
+                let mut finished = Finished { response: Some(response), sender: Some(sender) };
+                Ok(InstructorResponse::Stream(stream! {
+                    while let Some(item) = finished.response.as_mut().unwrap().next().await {
+                        yield item;
+                    }
+                    metadata.lock().unwrap().complete = true;
+                }.boxed()))
+            }
+            result => {
+                self.metadata.lock().unwrap().complete = true;
+                let _ = sender.send(());
+                result
+            }
+        };
+        (result, handle)
+    }
+}
+
+impl<B: ChatBackend> ChatBackend for MetadataBackend<B> {
+    fn create(&self, request: ChatRequest) -> BackendFuture<ChatCompletionResponseWrapper> {
+        let metadata = self.metadata.clone();
+        let response = self.backend.create(request);
+        Box::pin(async move {
+            let response = response.await?;
+            metadata.lock().unwrap().record_wrapper(&response);
+            Ok(response)
+        })
+    }
+
+    fn complete(&self, request: ChatRequest) -> BackendFuture<ChatCompletionResponseWrapper> {
+        let metadata = self.metadata.clone();
+        let response = self.backend.complete(request);
+        Box::pin(async move {
+            let response = response.await?;
+            metadata.lock().unwrap().record_wrapper(&response);
+            Ok(response)
+        })
+    }
+
+    fn create_stream(&self, request: ChatRequest) -> BackendFuture<ChatCompletionResponseStream> {
+        let requests = Arc::new(Mutex::new(StreamRequests::default()));
+        let usage: UsageSink = {
+            let metadata = self.metadata.clone();
+            let requests = requests.clone();
+            Arc::new(move |usage| {
+                requests.lock().unwrap().pending += 1;
+                metadata.lock().unwrap().usage.push(usage);
+            })
+        };
+        let response = self.backend.create_stream_with_usage(request, usage);
+        let metadata = self.metadata.clone();
+        Box::pin(async move {
+            let mut response = response.await?;
+            // created outside of the stream so it is counted even if the stream is dropped before it is polled
+            let unreported = Unreported { metadata: metadata.clone(), requests: requests.clone() };
+            let stream = stream! {
+                let _unreported = unreported;
+                while let Some(chunk) = response.next().await {
+                    if let Ok(chunk) = &chunk {
+                        requests.lock().unwrap().chunk(&chunk.id);
+                        metadata.lock().unwrap().record_chunk(chunk);
+                    }
+                    yield chunk;
+                }
+            };
+            Ok(Box::pin(stream) as ChatCompletionResponseStream)
+        })
+    }
+}
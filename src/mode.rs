@@ -4,8 +4,16 @@ use std::fmt;
 pub enum Mode {
     JSON,
     MD_JSON,
+    MD_YAML,
+    XML,
     JSON_SCHEMA,
-    //ANTHROPIC_TOOLS, TODO
+    ANTHROPIC_TOOLS,
+    GEMINI_TOOLS,
+    GEMINI_JSON,
+    OLLAMA_JSON_SCHEMA,
+    LLAMA_CPP_JSON_SCHEMA,
+    LLAMA_CPP_GRAMMAR,
+    COMPLETION,
     TOOLS,
 }
 
@@ -14,8 +22,16 @@ impl fmt::Display for Mode {
         let mode_str = match self {
             Mode::JSON => "json_mode",
             Mode::MD_JSON => "markdown_json_mode",
+            Mode::MD_YAML => "markdown_yaml_mode",
+            Mode::XML => "xml_mode",
             Mode::JSON_SCHEMA => "json_schema_mode",
-            //Mode::ANTHROPIC_TOOLS => "anthropic_tools", TODO
+            Mode::ANTHROPIC_TOOLS => "anthropic_tools",
+            Mode::GEMINI_TOOLS => "gemini_tools",
+            Mode::GEMINI_JSON => "gemini_json",
+            Mode::OLLAMA_JSON_SCHEMA => "ollama_json_schema",
+            Mode::LLAMA_CPP_JSON_SCHEMA => "llama_cpp_json_schema",
+            Mode::LLAMA_CPP_GRAMMAR => "llama_cpp_grammar",
+            Mode::COMPLETION => "completion",
             Mode::TOOLS => "tools",
         };
         write!(f, "{}", mode_str)
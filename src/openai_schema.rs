@@ -1,15 +1,21 @@
 use schemars::JsonSchema;
-use validator::ValidateArgs;
+use validator::{ValidateArgs, ValidationErrors, ValidationErrorsKind};
 use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use std::collections::BTreeMap;
 use std::fmt::Debug;
 use std::any::type_name;
-use crate::error::Error;
+use crate::error::{Error, DeserializeError};
+use crate::json_stream::json_pointer_at;
 use crate::enums::InstructorResponse;
 use crate::enums::IterableOrSingle;
 use crate::mode::Mode;
-use crate::utils::extract_json_from_codeblock;
-use async_openai::types::CreateChatCompletionResponse;
+use crate::utils::{extract_json_from_codeblock, extract_yaml_from_codeblock};
+use async_openai::types::{CreateChatCompletionResponse, CreateCompletionResponse};
 use async_openai::types::{ChatCompletionMessageToolCall, FunctionObject };
+use crate::anthropic::{MessagesResponse, ContentBlock};
+use crate::gemini::GenerateContentResponse;
+use crate::xml::xml_to_values;
 
 pub trait BaseSchema: 
      Debug + Serialize + for<'de> Deserialize<'de> + 
@@ -60,6 +66,10 @@ where
     ///returns the openai schema for the struct as a FunctionObject 
     /// that can be used in tools field (functions are deperecated)
     fn tool_schema() -> FunctionObject;
+
+    ///returns the json schema of the struct rewritten for openai structured outputs (strict: true),
+    /// this is what Mode::JSON_SCHEMA sends in response_format
+    fn strict_json_schema() -> serde_json::Value;
  
     ///parses the model from string to struct and does struct validation
     /// # Arguments
@@ -80,6 +90,27 @@ where
     ) -> Result<InstructorResponse<T>, Error>
     where
         Self: Sized + ValidateArgs<'static> + BaseSchema;
+
+    ///like model_validate_json but parses yaml (Mode::MD_YAML), 
+    /// if Iterable() is used the data is a yaml list with one item per struct
+    fn model_validate_yaml(
+        model: &IterableOrSingle<Self>, 
+        data: &str, 
+        validation_context: &Args
+    ) -> Result<InstructorResponse<T>, Error>
+    where
+        Self: Sized + ValidateArgs<'static> + BaseSchema;
+
+    ///parses a reply in the xml tag format of Mode::XML (see xml::xml_template), 
+    /// the tags are converted to json following the schema of the struct and passed to model_validate_json(),
+    /// if Iterable() is used the reply contains one root tag per struct
+    fn model_validate_xml(
+        model: &IterableOrSingle<Self>, 
+        data: &str, 
+        validation_context: &Args
+    ) -> Result<InstructorResponse<T>, Error>
+    where
+        Self: Sized + ValidateArgs<'static> + BaseSchema;
     
     ///takes a response and parses it into the struct using functions like model_validate_json()
     /// #Arguments
@@ -119,6 +150,31 @@ where
     where
         Self: Sized + ValidateArgs<'static> + BaseSchema;
 
+    fn parse_anthropic_tools(
+        model: &IterableOrSingle<Self>,
+        completion: &MessagesResponse,
+        validation_context: &Args,
+    ) -> Result<InstructorResponse<T>, Error>
+    where
+        Self: Sized + ValidateArgs<'static> + BaseSchema;
+
+    fn parse_gemini(
+        model: &IterableOrSingle<Self>,
+        completion: &GenerateContentResponse,
+        validation_context: &Args,
+        mode: Mode,
+    ) -> Result<InstructorResponse<T>, Error>
+    where
+        Self: Sized + ValidateArgs<'static> + BaseSchema;
+
+    fn parse_completion(
+        model: &IterableOrSingle<Self>,
+        completion: &CreateCompletionResponse,
+        validation_context: &Args,
+    ) -> Result<InstructorResponse<T>, Error>
+    where
+        Self: Sized + ValidateArgs<'static> + BaseSchema;
+
 }
 
 impl<A, T> OpenAISchema<A, T> for T
@@ -159,7 +215,7 @@ where
                     if let Some(ref_value) = all_of[0]["$ref"].as_str() {
                         if let Some(def_name) = ref_value.split('/').last() {
                             if let Some(def_value) = schema_json["definitions"][def_name].as_object() {
-                                if let Some(enum_values) = def_value["enum"].as_array() {
+                                if let Some(enum_values) = def_value.get("enum").and_then(|values| values.as_array()) {
                                     prop_schema["enum"] = serde_json::Value::Array(enum_values.clone());
                                 }
                             }
@@ -186,6 +242,11 @@ where
         }
     }
 
+    fn strict_json_schema() -> serde_json::Value {
+        let schema = schemars::schema_for!(T);
+        crate::json_schema::strict_json_schema(serde_json::to_value(&schema).unwrap())
+    }
+
     fn model_validate_json(
         model: &IterableOrSingle<Self>, 
         data: &str, 
@@ -198,27 +259,67 @@ where
         match model {
             IterableOrSingle::Iterable(_) => {
                 let bracketed_data = &format!("[{}]", data);
-                let data = serde_json::from_str::<Vec<T>>(bracketed_data)
-                    .map_err(|e| Error::SerdeError(e)); // Convert serde_json::Error to custom Error::SerdeError
+                let data = serde_json::from_str::<Vec<serde_json::Value>>(bracketed_data)
+                    .map_err(|e| Error::SerdeError(e)) // Convert serde_json::Error to custom Error::SerdeError
+                    .and_then(deserialize_items::<T>);
                 data.and_then(|data| {
-                    data.into_iter().map(|item| validate_single(item, validation_context.clone()))
-                        .collect::<Result<Vec<T>, Error>>() 
+                    validate_items(data, validation_context)
                         .map(|data| InstructorResponse::Many(data)) 
                 })
             },
-            IterableOrSingle::Single(_) => {
-                let data = serde_json::from_str::<T>(data);
-                match data {
-                    Ok(data) => {
-                        let validated_data = validate_single(data, validation_context.clone()); 
-                        validated_data.map(|data| InstructorResponse::One(data)) 
-                    },
-                    Err(e) => Err(Error::SerdeError(e)),
-                }
+            IterableOrSingle::Single(_) | IterableOrSingle::Partial(_) => {
+                let data = deserialize_json::<T>(data, None);
+                data.and_then(|data| {
+                    let validated_data = validate_single(data, validation_context.clone()); 
+                    validated_data.map(|data| InstructorResponse::One(data)) 
+                })
             }
         }
     }
 
+    fn model_validate_yaml(
+        model: &IterableOrSingle<Self>, 
+        data: &str, 
+        validation_context: &Self::Args
+    ) -> Result<InstructorResponse<T>, Error>
+    where
+        Self: Sized + ValidateArgs<'static> + BaseSchema,
+    {
+        match model {
+            IterableOrSingle::Iterable(_) => {
+                let data = serde_yaml::from_str::<Vec<T>>(data).map_err(Error::YamlError)?;
+                validate_items(data, validation_context).map(InstructorResponse::Many)
+            },
+            IterableOrSingle::Single(_) | IterableOrSingle::Partial(_) => {
+                let data = serde_yaml::from_str::<T>(data).map_err(Error::YamlError)?;
+                validate_single(data, validation_context.clone()).map(InstructorResponse::One)
+            }
+        }
+    }
+
+    fn model_validate_xml(
+        model: &IterableOrSingle<Self>, 
+        data: &str, 
+        validation_context: &Self::Args
+    ) -> Result<InstructorResponse<T>, Error>
+    where
+        Self: Sized + ValidateArgs<'static> + BaseSchema,
+    {
+        let schema = serde_json::to_value(schemars::schema_for!(T)).map_err(Error::SerdeError)?;
+        let values = xml_to_values(data, &schema)?;
+        let data = match model {
+            // model_validate_json wraps the objects in brackets
+            IterableOrSingle::Iterable(_) => values.iter().map(|value| value.to_string()).collect::<Vec<String>>().join(","),
+            IterableOrSingle::Single(_) | IterableOrSingle::Partial(_) => match values.as_slice() {
+                [value] => value.to_string(),
+                _ => return Err(Error::XmlError(format!(
+                    "expected exactly one <{}> tag, found {}", T::tool_schema().name, values.len()
+                ))),
+            },
+        };
+        Self::model_validate_json(model, &data, validation_context)
+    }
+
     fn from_response(
         model: &IterableOrSingle<Self>,
         response: &CreateChatCompletionResponse,
@@ -229,9 +330,21 @@ where
         Self: Sized + ValidateArgs<'static> + BaseSchema,
     {
         match mode {
-            Mode::JSON | Mode::JSON_SCHEMA | Mode::MD_JSON => {
+            Mode::JSON | Mode::MD_JSON => {
                 Self::parse_json(model, response, validation_context)
             }
+            Mode::XML => {
+                let text = response.choices[0].message.content.clone().unwrap_or_default();
+                Self::model_validate_xml(model, &text, validation_context)
+            }
+            Mode::MD_YAML => {
+                let text = response.choices[0].message.content.clone().unwrap_or_default();
+                let yaml_extract = extract_yaml_from_codeblock(&text)?;
+                Self::model_validate_yaml(model, &yaml_extract, validation_context)
+            }
+            Mode::JSON_SCHEMA | Mode::OLLAMA_JSON_SCHEMA | Mode::LLAMA_CPP_JSON_SCHEMA | Mode::LLAMA_CPP_GRAMMAR => {
+                parse_json_schema(model, response, validation_context)
+            }
             Mode::TOOLS => {
                 println!("\n\nMode::TOOLS response: {:?}", response);
                 return Self::parse_tools(model, response, validation_context);
@@ -289,7 +402,7 @@ where
     {
         let message = &completion.choices[0].message;
         match model {
-            IterableOrSingle::Single(_) => {
+            IterableOrSingle::Single(_) | IterableOrSingle::Partial(_) => {
                 match &message.tool_calls {
                     Some(tool_calls) => {
                         if tool_calls.len() != 1 {
@@ -319,6 +432,82 @@ where
             }
         }
     }
+
+    ///this function is used to parse the tool_use content blocks of an anthropic messages api response
+    /// to one or more structs of type Self
+    /// # Arguments:
+    /// * `model` - The model to use, with Iterable() every tool_use block is parsed into a struct, with Single() exactly one is expected
+    /// * `completion` - The response to parse
+    /// * `validation_context` - The validation context to use
+    fn parse_anthropic_tools(
+        model: &IterableOrSingle<Self>,
+        completion: &MessagesResponse,
+        validation_context: &Self::Args,
+    ) -> Result<InstructorResponse<T>, Error>
+    where
+        Self: Sized + ValidateArgs<'static> + BaseSchema,
+    {
+        let tool_strings = completion.content
+            .iter()
+            .filter_map(|block| match block {
+                ContentBlock::ToolUse { name, input, .. } => Some(
+                    check_tool_name::<T>(name).map(|_| input.to_string())
+                ),
+                _ => None,
+            })
+            .collect::<Result<Vec<String>, Error>>()?;
+
+        validate_tool_strings(model, tool_strings, validation_context, "tool_use block")
+    }
+
+    ///this function is used to parse a gemini response into one or more structs of type Self,
+    /// in Mode::GEMINI_TOOLS the functionCall parts are parsed, in Mode::GEMINI_JSON the text is parsed
+    /// # Arguments:
+    /// * `model` - The model to use (IterableOrSingle::Iterable(model) or IterableOrSingle::Single(model)) 
+    /// * `completion` - The response to parse
+    /// * `validation_context` - The validation context to use 
+    /// * `mode` - Mode::GEMINI_TOOLS or Mode::GEMINI_JSON
+    fn parse_gemini(
+        model: &IterableOrSingle<Self>,
+        completion: &GenerateContentResponse,
+        validation_context: &Self::Args,
+        mode: Mode,
+    ) -> Result<InstructorResponse<T>, Error>
+    where
+        Self: Sized + ValidateArgs<'static> + BaseSchema,
+    {
+        match mode {
+            Mode::GEMINI_JSON => parse_json_schema_text(model, &completion.text(), validation_context),
+            _ => {
+                let tool_strings = completion.tool_calls()
+                    .iter()
+                    .map(check_tool_call::<T>)
+                    .collect::<Result<Vec<String>, Error>>()?;
+                validate_tool_strings(model, tool_strings, validation_context, "functionCall part")
+            }
+        }
+    }
+
+    ///this function is used to parse the text of a completions endpoint response (Mode::COMPLETION),
+    /// like parse_json the json is extracted from the text before it is validated
+    /// # Arguments:
+    /// * `model` - The model to use (IterableOrSingle::Iterable(model) or IterableOrSingle::Single(model)) 
+    /// * `completion` - The response to parse
+    /// * `validation_context` - The validation context to use 
+    fn parse_completion(
+        model: &IterableOrSingle<Self>,
+        completion: &CreateCompletionResponse,
+        validation_context: &Self::Args,
+    ) -> Result<InstructorResponse<T>, Error>
+    where
+        Self: Sized + ValidateArgs<'static> + BaseSchema,
+    {
+        let text = completion.choices.first()
+            .map(|choice| choice.text.clone())
+            .ok_or(Error::Generic("No choices found in completion response".to_string()))?;
+        let json_extract = extract_json_from_codeblock(&text)?;
+        Self::model_validate_json(model, &json_extract, validation_context)
+    }
 }
 
 
@@ -333,9 +522,102 @@ where
     }
 }
 
-fn check_tool_call<T>(tool_call: &ChatCompletionMessageToolCall) -> Result<String, Error> 
+///validates every item of a list, the errors of all the invalid items are returned together
+/// as a list under the field `items`, so they are shown as `items[index].field`
+fn validate_items<A, T>(data: Vec<T>, validation_context: &A) -> Result<Vec<T>, Error>
+where
+    T: ValidateArgs<'static, Args=A> + BaseSchema,
+    A: BaseArg,
+{
+    let mut errors = BTreeMap::new();
+    for (index, item) in data.iter().enumerate() {
+        if let Err(e) = item.validate_args(validation_context.clone()) {
+            errors.insert(index, Box::new(e));
+        }
+    }
+    if errors.is_empty() {
+        return Ok(data);
+    }
+    let mut validation_errors = ValidationErrors::new();
+    validation_errors.errors_mut().insert("items", ValidationErrorsKind::List(errors));
+    Err(Error::ValidationErrors(validation_errors))
+}
+
+///parses a response to a request constrained by a json schema (Mode::JSON_SCHEMA and the local server modes),
+/// an Iterable was requested as {"items": [...]}
+fn parse_json_schema<A, T>(
+    model: &IterableOrSingle<T>,
+    completion: &CreateChatCompletionResponse,
+    validation_context: &A,
+) -> Result<InstructorResponse<T>, Error>
+where
+    T: ValidateArgs<'static, Args=A> + BaseSchema,
+    A: BaseArg,
+{
+    let text = completion.choices[0].message.content.clone().unwrap_or_default();
+    parse_json_schema_text(model, &text, validation_context)
+}
+
+fn parse_json_schema_text<A, T>(
+    model: &IterableOrSingle<T>,
+    text: &str,
+    validation_context: &A,
+) -> Result<InstructorResponse<T>, Error>
+where
+    T: ValidateArgs<'static, Args=A> + BaseSchema,
+    A: BaseArg,
+{
+    match model {
+        IterableOrSingle::Iterable(_) => {
+            #[derive(Deserialize)]
+            struct Items {
+                items: Vec<serde_json::Value>,
+            }
+            let data = deserialize_json::<Items>(text, None)?;
+            validate_items(deserialize_items::<T>(data.items)?, validation_context).map(InstructorResponse::Many)
+        },
+        IterableOrSingle::Single(_) | IterableOrSingle::Partial(_) => {
+            T::model_validate_json(model, text, validation_context)
+        }
+    }
+}
+
+///validates the arguments of the tool calls (tool_use blocks, functionCall parts) of a provider response,
+/// a Single model expects exactly one call and an Iterable joins all of them
+fn validate_tool_strings<A, T>(
+    model: &IterableOrSingle<T>,
+    tool_strings: Vec<String>,
+    validation_context: &A,
+    call_name: &str,
+) -> Result<InstructorResponse<T>, Error>
+where
+    T: ValidateArgs<'static, Args=A> + BaseSchema,
+    A: BaseArg,
+{
+    if tool_strings.is_empty() {
+        return Err(Error::Generic(format!("No {}s found", call_name)));
+    }
+    match model {
+        IterableOrSingle::Single(_) | IterableOrSingle::Partial(_) => {
+            if tool_strings.len() != 1 {
+                return Err(Error::Generic(format!("Expected exactly one {}", call_name)));
+            }
+            T::model_validate_json(model, &tool_strings[0], validation_context)
+        }
+        IterableOrSingle::Iterable(_) => {
+            T::model_validate_json(model, &tool_strings.join(","), validation_context)
+        }
+    }
+}
+
+pub(crate) fn check_tool_call<T>(tool_call: &ChatCompletionMessageToolCall) -> Result<String, Error> 
+{
+    check_tool_name::<T>(&tool_call.function.name)?;
+    Ok(tool_call.function.arguments.clone())
+}
+
+fn check_tool_name<T>(tool_name: &str) -> Result<(), Error> 
 {
-    let tool_name = &tool_call.function.name;
     let model_name = type_name::<T>().split("::").last().unwrap();
     if tool_name != model_name {
         return Err(Error::Generic(format!(
@@ -343,6 +625,60 @@ fn check_tool_call<T>(tool_call: &ChatCompletionMessageToolCall) -> Result<Strin
             tool_name, model_name
         )));
     }
-    Ok(tool_call.function.arguments.clone())
+    Ok(())
+}
+
+
+///deserializes json into T, a value that does not fit T is reported as an Error::DeserializeErrors
+/// with its json pointer, `item` is the position of the value in the list it comes from.
+/// Invalid json stays an Error::SerdeError
+pub(crate) fn deserialize_json<T>(data: &str, item: Option<usize>) -> Result<T, Error>
+where
+    T: DeserializeOwned,
+{
+    serde_json::from_str::<T>(data).map_err(|e| {
+        if e.classify() != serde_json::error::Category::Data {
+            return Error::SerdeError(e);
+        }
+        // serde counts lines from 1 and columns from 1 after the start of the line
+        let line_start: usize = data.split('\n').take(e.line().saturating_sub(1)).map(|line| line.len() + 1).sum();
+        let mut path = json_pointer_at(data, line_start + e.column());
+        let error = e.to_string();
+        let message = match error.rfind(" at line ") {
+            Some(index) => error[..index].to_string(),
+            None => error,
+        };
+        let missing = message.strip_prefix("missing field `").and_then(|field| field.strip_suffix('`'));
+        if let Some(field) = missing {
+            path.push_str(&format!("/{}", field.replace('~', "~0").replace('/', "~1")));
+        }
+        let value = serde_json::from_str::<serde_json::Value>(data).ok()
+            .and_then(|value| value.pointer(&path).cloned());
+        let expected = message.split_once(", expected ").map(|(_, expected)| expected.to_string());
+        if let Some(item) = item {
+            path = format!("/{}{}", item, path);
+        }
+        Error::DeserializeErrors(vec![DeserializeError { item, path, value, expected, message }])
+    })
 }
 
+///deserializes every item of a list, the errors of all the invalid items are returned together
+fn deserialize_items<T>(items: Vec<serde_json::Value>) -> Result<Vec<T>, Error>
+where
+    T: BaseSchema,
+{
+    let mut data = Vec::with_capacity(items.len());
+    let mut errors = Vec::new();
+    for (index, item) in items.iter().enumerate() {
+        match deserialize_json::<T>(&item.to_string(), Some(index)) {
+            Ok(item) => data.push(item),
+            Err(Error::DeserializeErrors(item_errors)) => errors.extend(item_errors),
+            Err(e) => return Err(e),
+        }
+    }
+    if errors.is_empty() {
+        Ok(data)
+    } else {
+        Err(Error::DeserializeErrors(errors))
+    }
+}
@@ -1,33 +1,38 @@
-
 use crate::process_response::handle_response_model;
 use crate::enums::IterableOrSingle;
-use crate::retry::retry_async;
-use async_openai::types::CreateChatCompletionRequest;
-use std::marker::{Send, Sync};
-use async_openai::Client;
-use async_openai::error::OpenAIError;
-use async_openai::config::Config;
+use crate::retry::{retry_async, retry_with};
+use crate::dsl::parallel::{ParallelBase, parse_parallel_tools};
+use crate::backend::{ChatBackend, ChatRequest};
+use async_openai::types::{CreateChatCompletionRequest, ChatCompletionTool, ChatCompletionToolType, ChatCompletionToolChoiceOption};
 use crate::openai_schema::{BaseSchema, BaseArg};
 use validator::ValidateArgs;
 use crate::mode::Mode;
 use crate::error::Error;
 use crate::enums::{InstructorResponse, ChatCompletionResponseWrapper};
-use std::pin::Pin;
-use std::future::Future;
+use crate::dsl::events::{EventsBase, EventStream};
+use crate::timeout::{Timeouts, ActivityBackend, with_deadline, guard_response, guard_events};
+use crate::metadata::{MetadataBackend, StreamMetadataHandle};
+use crate::retry_policy::{RetryPolicy, RetryingBackend};
+use crate::fallback::{Fallbacks, FallbackConversation, FallbackResponse, text_conversation};
+use crate::types::RetryError;
+use serde_json::json;
+use std::time::Instant;
 // Define a wrapper type for the Client.
 
+///wraps a ChatBackend, async_openai::Client<C> is the default backend 
+/// but any type implementing ChatBackend can be used (see backend.rs)
 #[derive(Debug, Clone)]
-pub struct Patch<C: Config> {
-    pub client: Client<C>,
+pub struct Patch<B: ChatBackend> {
+    pub client: B,
     pub mode: Option<Mode>,
 }
 
 
-impl<C> Patch<C> 
+impl<B> Patch<B> 
 where
-    C: Config + Clone + Send + Sync + 'static,
+    B: ChatBackend,
 {
-    /// Initiates a chat completion request with the OpenAI API.
+    /// Initiates a chat completion request with the backend (the OpenAI API by default).
     /// 
     /// # Arguments
     /// 
@@ -44,7 +49,7 @@ where
     /// 
     /// A `Result` type that, on success, contains an `InstructorResponse<T>`, 
     /// which wraps the response model(s) in the specified format (either single or iterable). 
-    /// On failure, it returns an `Error`.
+    /// On failure, it returns an `Error`, Error::RetryError with every attempt if no response was valid within max_retries.
     /// 
     /// # Examples
     /// 
@@ -64,8 +69,196 @@ where
         T: ValidateArgs<'static, Args=A> + BaseSchema + 'static,
         A: BaseArg,
     {
+        self.chat_completion_with_timeouts(response_model, validation_context, max_retries, kwargs, Timeouts::default()).await
+    }
 
-        let mut kwargs = kwargs.clone();
+    /// Like chat_completion but with time limits (see Timeouts), so a hung provider can not block the caller forever.
+    /// 
+    /// * the deadline covers all retries and, for a streamed response, consuming the stream,
+    ///   it fails with Error::DeadlineExceeded (as the result or as the last item of the stream)
+    /// * the idle timeout ends a stream that has not sent a chunk for that long with Error::IdleTimeout
+    /// 
+    /// When a limit is hit the pending request is dropped, which closes the connection.
+    /// Dropping the InstructorResponse::Stream does the same, nothing is left running in the background.
+    /// 
+    /// # Examples
+    /// 
+    /// ```ignore
+    /// let timeouts = Timeouts { deadline: Some(Duration::from_secs(60)), idle_timeout: Some(Duration::from_secs(10)) };
+    /// let response = patch.chat_completion_with_timeouts(IterableOrSingle::Iterable(MyModel::default()), (), 3, request, timeouts).await?;
+    /// ```
+    pub async fn chat_completion_with_timeouts<T, A>(
+        &self, 
+        response_model:IterableOrSingle<T>,
+        validation_context: A,
+        max_retries: usize,
+        kwargs: CreateChatCompletionRequest,
+        timeouts: Timeouts,
+    ) -> Result<InstructorResponse<T>, Error>
+    where
+        T: ValidateArgs<'static, Args=A> + BaseSchema + 'static,
+        A: BaseArg,
+    {
+        self.chat_completion_with(self.client.clone(), response_model, validation_context, RetryPolicy::new(max_retries), &mut ChatRequest::new(kwargs), timeouts, Instant::now()).await
+    }
+
+    /// Like chat_completion_with_timeouts but retried according to a RetryPolicy: besides re-asking invalid responses
+    /// (up to policy.max_retries attempts) a request that fails with a transient error, like a rate limit or a server error,
+    /// is sent again after a backoff (up to policy.max_transport_retries times, honoring Retry-After).
+    /// The deadline of the timeouts includes the time spent waiting between retries.
+    /// 
+    /// # Examples
+    /// 
+    /// ```ignore
+    /// let policy = RetryPolicy { max_retries: 3, max_transport_retries: 5, ..Default::default() };
+    /// let response = patch.chat_completion_with_policy(IterableOrSingle::Single(MyModel::default()), (), policy, request, Timeouts::default()).await?;
+    /// ```
+    pub async fn chat_completion_with_policy<T, A>(
+        &self, 
+        response_model:IterableOrSingle<T>,
+        validation_context: A,
+        policy: RetryPolicy,
+        kwargs: CreateChatCompletionRequest,
+        timeouts: Timeouts,
+    ) -> Result<InstructorResponse<T>, Error>
+    where
+        T: ValidateArgs<'static, Args=A> + BaseSchema + 'static,
+        A: BaseArg,
+    {
+        self.chat_completion_with(self.client.clone(), response_model, validation_context, policy, &mut ChatRequest::new(kwargs), timeouts, Instant::now()).await
+    }
+
+    /// Like chat_completion but with a chain of models: when a model uses up its retries (Error::RetryError)
+    /// the request is sent to the next model of the chain, in its own mode. Any other error ends the call right away.
+    /// The model of kwargs is replaced by the models of the chain.
+    /// 
+    /// On success the response tells which model produced it, when every model failed
+    /// Error::RetryError has the attempts of all of them (see Attempt::model).
+    /// The deadline of the timeouts is that of the whole chain, the idle timeout applies to every streamed response.
+    /// 
+    /// # Examples
+    /// 
+    /// ```ignore
+    /// let fallbacks = Fallbacks::new(vec![
+    ///     FallbackModel::with_mode(GPT3_5_TURBO, Mode::JSON),
+    ///     FallbackModel::with_mode(GPT4_TURBO_PREVIEW, Mode::TOOLS),
+    /// ], 2);
+    /// let response = patch.chat_completion_with_fallbacks(IterableOrSingle::Single(MyModel::default()), (), fallbacks, request, Timeouts::default()).await?;
+    /// println!("answered by {}", response.model);
+    /// ```
+    pub async fn chat_completion_with_fallbacks<T, A>(
+        &self, 
+        response_model:IterableOrSingle<T>,
+        validation_context: A,
+        fallbacks: Fallbacks,
+        kwargs: CreateChatCompletionRequest,
+        timeouts: Timeouts,
+    ) -> Result<FallbackResponse<T>, Error>
+    where
+        T: ValidateArgs<'static, Args=A> + BaseSchema + 'static,
+        A: BaseArg,
+    {
+        if fallbacks.models.is_empty() {
+            return Err(Error::Generic("the fallback chain has no models".to_string()));
+        }
+        let start = Instant::now();
+        let mut attempts = Vec::new();
+        // the outputs of the previous models and their re-asks, see FallbackConversation::CarryOver
+        let mut conversation = Vec::new();
+        for (index, fallback) in fallbacks.models.iter().enumerate() {
+            let mode = fallback.mode.or(self.mode).unwrap_or(Mode::JSON);
+            let patch = Patch { client: self.client.clone(), mode: Some(mode) };
+            let mut request = ChatRequest::new(kwargs.clone());
+            request.request.model = fallback.model.clone();
+            if fallbacks.conversation == FallbackConversation::CarryOver {
+                match mode {
+                    Mode::TOOLS | Mode::ANTHROPIC_TOOLS | Mode::GEMINI_TOOLS => request.request.messages.extend(conversation.iter().cloned()),
+                    _ => request.request.messages.extend(text_conversation(&conversation)),
+                }
+            }
+
+            let result = patch.chat_completion_with(
+                self.client.clone(),
+                response_model.clone(),
+                validation_context.clone(),
+                fallbacks.policy.clone(),
+                &mut request,
+                timeouts,
+                start,
+            ).await;
+            match result {
+                Ok(response) => return Ok(FallbackResponse { response, model: fallback.model.clone(), mode, index }),
+                Err(Error::RetryError(error)) => {
+                    // the request holds the messages of the first attempt followed by every output and its re-ask
+                    if let Some(first) = error.attempts.first() {
+                        conversation.extend(request.request.messages.drain(first.messages.len()..));
+                    }
+                    attempts.extend(error.attempts);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Err(Error::RetryError(RetryError { attempts }))
+    }
+
+    /// Like chat_completion_with_timeouts but also returns a StreamMetadataHandle, which resolves to the
+    /// id, model, finish_reason, system_fingerprint and usage of the responses once the call is complete,
+    /// i.e. when the InstructorResponse::Stream has ended or has been dropped.
+    /// A streamed request asks for its usage with stream_options.include_usage.
+    /// 
+    /// The handle is returned even if the call fails, so the usage of the failed attempts is not lost.
+    /// StreamMetadata::usage has an entry per request (every retry, re-ask and reconnect is billed),
+    /// a stream that is cut off before its end does not report its usage.
+    /// 
+    /// # Examples
+    /// 
+    /// ```ignore
+    /// let (response, metadata) = patch.chat_completion_with_metadata(IterableOrSingle::Iterable(MyModel::default()), (), 3, request, Timeouts::default()).await;
+    /// if let InstructorResponse::Stream(mut stream) = response? {
+    ///     while let Some(item) = stream.next().await { ... }
+    /// }
+    /// let usage = metadata.finished().await.total_usage();
+    /// ```
+    pub async fn chat_completion_with_metadata<T, A>(
+        &self, 
+        response_model:IterableOrSingle<T>,
+        validation_context: A,
+        max_retries: usize,
+        kwargs: CreateChatCompletionRequest,
+        timeouts: Timeouts,
+    ) -> (Result<InstructorResponse<T>, Error>, StreamMetadataHandle)
+    where
+        T: ValidateArgs<'static, Args=A> + BaseSchema + 'static,
+        A: BaseArg,
+    {
+        let mut kwargs = ChatRequest::new(kwargs);
+        if kwargs.request.stream.unwrap_or(false) {
+            kwargs.extra_body.insert("stream_options".to_string(), json!({"include_usage": true}));
+        }
+        let backend = MetadataBackend::new(self.client.clone());
+        let result = self.chat_completion_with(backend.clone(), response_model, validation_context, RetryPolicy::new(max_retries), &mut kwargs, timeouts, Instant::now()).await;
+        backend.finish(result)
+    }
+
+    ///chat_completion_with_policy with the given backend in place of self.client,
+    /// kwargs is left with the re-asks of the call. The deadline is measured from `start`
+    #[allow(clippy::too_many_arguments)]
+    async fn chat_completion_with<C, T, A>(
+        &self,
+        client: C,
+        response_model:IterableOrSingle<T>,
+        validation_context: A,
+        policy: RetryPolicy,
+        kwargs: &mut ChatRequest,
+        timeouts: Timeouts,
+        start: Instant,
+    ) -> Result<InstructorResponse<T>, Error>
+    where
+        C: ChatBackend,
+        T: ValidateArgs<'static, Args=A> + BaseSchema + 'static,
+        A: BaseArg,
+    {
+        let deadline = timeouts.deadline_at(start);
         // if no mode is provided, default to Mode::JSON
         let mode = match self.mode {
             Some(mode) => mode,
@@ -75,48 +268,196 @@ where
         handle_response_model(
             &response_model, 
             mode, 
-            &mut kwargs
+            kwargs
         ).map_err(|e| e)?;
-        
-        let client = self.client.clone();
-        let func: Box<
-            dyn Fn(CreateChatCompletionRequest) -> Pin<Box<dyn Future<Output = Result<ChatCompletionResponseWrapper, OpenAIError>> + Send>>
-                + Send
-                + Sync,
-        > = Box::new(move |kwargs| {
-            let client = client.clone();
-            Box::pin(async move {
-                match kwargs.stream {
-                    Some(false) | None => {
-                        let res = client.chat()
-                        .create(kwargs).await?;
-                        Ok(ChatCompletionResponseWrapper::AtOnce(res))
-                    }
-                    Some(true) => {
-                        let res = client.chat()
-                        .create_stream(kwargs).await?;
-                        Ok(ChatCompletionResponseWrapper::Stream(res))
-                    }
-                }
-            })
-        });
 
-        retry_async(
-            func,
+        let (max_retries, reask) = (policy.max_retries, policy.reask.clone());
+        let backend = ActivityBackend::new(RetryingBackend::new(client, policy));
+        let response = with_deadline(retry_async(
+            &backend,
             response_model,
             validation_context,
-            &mut kwargs,
+            kwargs,
             max_retries,
-            self.mode.unwrap(),
-        ).await
+            mode,
+            reask,
+        ), deadline).await?;
+        Ok(guard_response(response, deadline, timeouts, &backend))
     }
-    
-}
 
+    /// Initiates a streaming chat completion request and returns the field level events of the response (see EventsBase),
+    /// e.g. to show a long text field token by token while the other fields are still loading.
+    /// The request is always streamed. Invalid objects arrive as StreamEvent::ValidationFailed and are not re-asked
+    /// 
+    /// # Arguments
+    /// 
+    /// * `response_model`: `IterableOrSingle<T>` - IterableOrSingle::Iterable yields the events of every element
+    /// * `validation_context`: `A` - The context used for validating every object.
+    /// * `kwargs`: `CreateChatCompletionRequest` - the request, stream is set to true
+    /// 
+    /// # Examples
+    /// 
+    /// ```ignore
+    /// let mut events = patch.chat_completion_events(IterableOrSingle::Single(Report::default()), (), request).await?;
+    /// while let Some(event) = events.next().await {
+    ///     if let StreamEvent::FieldDelta { path, text } = event? {
+    ///         if path == "summary" { print!("{}", text); }
+    ///     }
+    /// }
+    /// ```
+    pub async fn chat_completion_events<T, A>(
+        &self,
+        response_model: IterableOrSingle<T>,
+        validation_context: A,
+        kwargs: CreateChatCompletionRequest
+    ) -> Result<EventStream<T>, Error>
+    where
+        T: ValidateArgs<'static, Args=A> + BaseSchema + 'static,
+        A: BaseArg,
+    {
+        self.chat_completion_events_with_policy(response_model, validation_context, RetryPolicy::new(0), kwargs, Timeouts::default()).await
+    }
 
+    /// Like chat_completion_events but the request is retried according to a RetryPolicy (only its transport retries,
+    /// invalid objects are not re-asked) and the events are limited by the timeouts, see chat_completion_with_policy.
+    /// A limit that is hit ends the events with Error::DeadlineExceeded or Error::IdleTimeout
+    /// 
+    /// # Examples
+    /// 
+    /// ```ignore
+    /// let policy = RetryPolicy { max_transport_retries: 5, ..Default::default() };
+    /// let timeouts = Timeouts { deadline: Some(Duration::from_secs(60)), idle_timeout: Some(Duration::from_secs(10)) };
+    /// let mut events = patch.chat_completion_events_with_policy(IterableOrSingle::Single(Report::default()), (), policy, request, timeouts).await?;
+    /// ```
+    pub async fn chat_completion_events_with_policy<T, A>(
+        &self,
+        response_model: IterableOrSingle<T>,
+        validation_context: A,
+        policy: RetryPolicy,
+        kwargs: CreateChatCompletionRequest,
+        timeouts: Timeouts,
+    ) -> Result<EventStream<T>, Error>
+    where
+        T: ValidateArgs<'static, Args=A> + BaseSchema + 'static,
+        A: BaseArg,
+    {
+        let deadline = timeouts.deadline_at(Instant::now());
+        let mut kwargs = ChatRequest::new(kwargs);
+        kwargs.request.stream = Some(true);
+        let mode = match self.mode {
+            Some(mode) => mode,
+            None => Mode::JSON,
+        };
 
+        handle_response_model(&response_model, mode, &mut kwargs)?;
 
+        let backend = ActivityBackend::new(RetryingBackend::new(self.client.clone(), policy));
+        match with_deadline(backend.chat(kwargs), deadline).await? {
+            ChatCompletionResponseWrapper::Stream(response) => {
+                let events = T::events_from_streaming_response(response_model, response, &validation_context, mode).await;
+                Ok(guard_events(events, deadline, timeouts, &backend))
+            }
+            _ => Err(Error::Generic("the backend did not stream the response".to_string())),
+        }
+    }
 
+    /// Initiates a chat completion request where the llm can answer with several tool calls,
+    /// each of them is parsed into the response model with the same name (see ParallelBase).
+    /// Only Mode::TOOLS and Mode::ANTHROPIC_TOOLS are supported
+    /// 
+    /// # Arguments
+    /// 
+    /// * `validation_context`: `P::Args` - The context used for validating every response model.
+    /// * `max_retries`: `usize` - The maximum number of retries for the request in case of failures.
+    /// * `kwargs`: `CreateChatCompletionRequest` - the request, the tools and tool_choice fields are overwritten
+    /// 
+    /// # Returns
+    /// 
+    /// A `Result` that, on success, contains one `P` per tool call in the order they were made.
+    /// If any tool call fails to parse or validate, all of the errors are re-asked at once.
+    /// 
+    /// # Examples
+    /// 
+    /// ```ignore
+    /// #[derive(ParallelBase, Debug)]
+    /// enum Entity {
+    ///     Person(Person),
+    ///     Company(Company),
+    /// }
+    /// let entities = patch.parallel_chat_completion::<Entity>((), 3, request).await?;
+    /// ```
+    pub async fn parallel_chat_completion<P>(
+        &self,
+        validation_context: P::Args,
+        max_retries: usize,
+        kwargs: CreateChatCompletionRequest
+    ) -> Result<Vec<P>, Error>
+    where
+        P: ParallelBase,
+    {
+        self.parallel_chat_completion_with_policy::<P>(validation_context, RetryPolicy::new(max_retries), kwargs, Timeouts::default()).await
+    }
 
+    /// Like parallel_chat_completion but retried according to a RetryPolicy and with the deadline of the timeouts,
+    /// see chat_completion_with_policy. The response is not streamed, so the idle timeout is not used
+    /// 
+    /// # Examples
+    /// 
+    /// ```ignore
+    /// let policy = RetryPolicy { max_retries: 3, max_transport_retries: 5, ..Default::default() };
+    /// let entities = patch.parallel_chat_completion_with_policy::<Entity>((), policy, request, Timeouts::default()).await?;
+    /// ```
+    pub async fn parallel_chat_completion_with_policy<P>(
+        &self,
+        validation_context: P::Args,
+        policy: RetryPolicy,
+        kwargs: CreateChatCompletionRequest,
+        timeouts: Timeouts,
+    ) -> Result<Vec<P>, Error>
+    where
+        P: ParallelBase,
+    {
+        let deadline = timeouts.deadline_at(Instant::now());
+        let mut kwargs = ChatRequest::new(kwargs);
+        let mode = match self.mode {
+            Some(mode) => mode,
+            None => Mode::TOOLS,
+        };
 
+        match mode {
+            Mode::TOOLS | Mode::ANTHROPIC_TOOLS => {
+                if kwargs.request.stream.unwrap_or(false) {
+                    return Err(Error::NotImplementedError(
+                        "streaming is not supported for parallel tool calls".to_string()
+                    ));
+                }
+                kwargs.request.tools = Some(P::tool_schemas().into_iter().map(|function| {
+                    ChatCompletionTool {
+                        r#type: ChatCompletionToolType::Function,
+                        function,
+                    }
+                }).collect());
+                kwargs.request.tool_choice = Some(ChatCompletionToolChoiceOption::Auto);
+            }
+            _ => {
+                return Err(Error::NotImplementedError(format!(
+                    "parallel tool calls are not supported in mode {}", mode
+                )));
+            }
+        }
 
+        let (max_retries, reask) = (policy.max_retries, policy.reask.clone());
+        let backend = RetryingBackend::new(self.client.clone(), policy);
+        with_deadline(retry_with(
+            &backend,
+            &mut kwargs,
+            max_retries,
+            mode,
+            reask.as_ref(),
+            |response| {
+                let result = parse_parallel_tools::<P>(&response, &validation_context);
+                async move { result }
+            },
+        ), deadline).await
+    }
+}
@@ -9,9 +9,16 @@ use crate::enums::InstructorResponse;
 use async_openai::types::{
     ChatCompletionRequestMessage, ChatCompletionRequestSystemMessage, ChatCompletionRequestUserMessage, 
     ChatCompletionRequestUserMessageContent, ChatCompletionResponseFormat, ChatCompletionResponseFormatType, 
-    ChatCompletionTool, ChatCompletionToolType, CreateChatCompletionRequest, Role 
+    ChatCompletionTool, ChatCompletionToolType, CreateChatCompletionRequest, Role,
+    ChatCompletionToolChoiceOption, ChatCompletionNamedToolChoice, FunctionName
 };
 use crate::enums::ChatCompletionResponseWrapper;
+use crate::backend::ChatRequest;
+use crate::json_schema::iterable_json_schema;
+use crate::gbnf::json_schema_to_gbnf;
+use crate::gemini::gemini_schema;
+use crate::xml::xml_template;
+use serde_json::{json, Value};
 
 /// this function ads a prompt to the request messages or to the tools field(preferred) 
 /// 
@@ -19,16 +26,18 @@ use crate::enums::ChatCompletionResponseWrapper;
 /// * `response_model`: `&IterableOrSingle<T>` - a reference to an enum wrapper that is very similar to Iterable[model] in instructor
 ///   Can be either a single instance or an iterable collection of instances, depending on the use case.
 /// * `mode`: `Mode` - the mode to use for processing the response
-/// * `kwargs`: `&mut CreateChatCompletionRequest` - a mutable reference to a request object to modify
+/// * `chat_request`: `&mut ChatRequest` - a mutable reference to a request object to modify, 
+///   fields that async_openai does not model (like the json_schema response_format) are written to chat_request.extra_body
 pub fn handle_response_model<A, T>(
     response_model: &IterableOrSingle<T>, 
     mode: Mode, 
-    kwargs : &mut CreateChatCompletionRequest
+    chat_request : &mut ChatRequest
 ) -> Result<(), Error>
 where
     T: ValidateArgs<'static, Args=A> + BaseSchema,
     A: BaseArg,
 {
+    let kwargs = &mut chat_request.request;
 
     match mode {
         Mode::TOOLS => {
@@ -40,17 +49,97 @@ where
                 }
             ]);
         },
-        Mode::JSON | Mode::MD_JSON | Mode::JSON_SCHEMA => {
+        Mode::ANTHROPIC_TOOLS => {
+            if kwargs.stream == Some(true) {
+                return Err(
+                    Error::NotImplementedError(
+                        "stream=True is not yet supported in anthropic_tools mode".to_string()
+                    )
+                );
+            }
+            // the tool is rendered with an input_schema when the request is translated for the messages api
+            let function = T::tool_schema();
+            kwargs.tool_choice = Some(ChatCompletionToolChoiceOption::Named(
+                ChatCompletionNamedToolChoice {
+                    r#type: ChatCompletionToolType::Function,
+                    function: FunctionName { name: function.name.clone() },
+                }
+            ));
+            kwargs.tools = Some(
+                vec![
+                ChatCompletionTool {
+                    r#type: ChatCompletionToolType::Function,
+                    function,
+                }
+            ]);
+        },
+        Mode::GEMINI_TOOLS | Mode::GEMINI_JSON => {
+            if kwargs.stream == Some(true) {
+                return Err(
+                    Error::NotImplementedError(
+                        format!("stream=True is not yet supported in {} mode", mode)
+                    )
+                );
+            }
+            let schema = serde_json::to_value(schemars::schema_for!(T)).map_err(Error::SerdeError)?;
+
+            if mode == Mode::GEMINI_TOOLS {
+                // gemini rejects $ref and definitions so the parameters are the translated schema
+                let mut function = T::tool_schema();
+                function.parameters = Some(gemini_schema(&schema)?);
+                kwargs.tool_choice = Some(ChatCompletionToolChoiceOption::Named(
+                    ChatCompletionNamedToolChoice {
+                        r#type: ChatCompletionToolType::Function,
+                        function: FunctionName { name: function.name.clone() },
+                    }
+                ));
+                kwargs.tools = Some(
+                    vec![
+                    ChatCompletionTool {
+                        r#type: ChatCompletionToolType::Function,
+                        function,
+                    }
+                ]);
+            } else {
+                let schema = match response_model {
+                    IterableOrSingle::Iterable(_) => iterable_json_schema(schema),
+                    IterableOrSingle::Single(_) | IterableOrSingle::Partial(_) => schema,
+                };
+                // both are moved into the generation config when the request is translated
+                chat_request.extra_body.insert("response_mime_type".to_string(), json!("application/json"));
+                chat_request.extra_body.insert("response_schema".to_string(), gemini_schema(&schema)?);
+            }
+        },
+        Mode::JSON_SCHEMA => {
+            // the schema is enforced by the api so unlike Mode::JSON no prompt is added
             let schema = match response_model {
-                IterableOrSingle::Single(_) => {
-                    if kwargs.stream == Some(true) {
-                        return Err(
-                            Error::Generic(
-                                "stream=True is not supported when using response_model parameter for non-iterables".to_string()
-                            )
-                        );
+                // a streamed Single is validated field by field, see retry::reask_stream_items
+                IterableOrSingle::Single(_) | IterableOrSingle::Partial(_) => T::strict_json_schema(),
+                IterableOrSingle::Iterable(_) => iterable_json_schema(T::strict_json_schema()),
+            };
+            kwargs.response_format = None;
+            chat_request.extra_body.insert(
+                "response_format".to_string(),
+                json!({
+                    "type": "json_schema",
+                    "json_schema": {
+                        "name": T::tool_schema().name,
+                        "schema": schema,
+                        "strict": true,
                     }
-                    
+                })
+            );
+        },
+        Mode::JSON | Mode::MD_JSON | Mode::COMPLETION => {
+            if mode == Mode::COMPLETION && kwargs.stream == Some(true) {
+                return Err(
+                    Error::NotImplementedError(
+                        "stream=True is not yet supported in completion mode".to_string()
+                    )
+                );
+            }
+            let schema = match response_model {
+                IterableOrSingle::Single(_) | IterableOrSingle::Partial(_) => {
                     format!("Make sure for each schema to return an instance of the JSON, not the schema itself, use commas to seperate the schema/schemas: {:?}", T::openai_schema())
                 },
                 IterableOrSingle::Iterable(_) => T::openai_schema(),
@@ -71,13 +160,6 @@ where
                         }
                     );
                 },
-                Mode::JSON_SCHEMA => {
-                    kwargs.response_format = Some(
-                        ChatCompletionResponseFormat {
-                            r#type: ChatCompletionResponseFormatType::JsonObject,
-                        }
-                    );
-                },
                 Mode::MD_JSON => {
                     let user_message = ChatCompletionRequestMessage::User(
                         ChatCompletionRequestUserMessage {
@@ -90,28 +172,127 @@ where
                     );
                     kwargs.messages.push(user_message);
                 },
+                // the completions endpoint has no response_format, the messages are flattened into the prompt
                 _ => {}
             }
 
-            match &mut kwargs.messages[0] {
-                ChatCompletionRequestMessage::System(kwargs_message) => {
-                    kwargs_message.content += &message;
-                }
-                _=> {
-                    kwargs.messages.insert(0, 
-                    ChatCompletionRequestMessage::System(ChatCompletionRequestSystemMessage {
-                            role: Role::System,
-                            content: message,
-                            name: None, // Assuming name is optional and not required here
-                        }
-                    ));
+            add_system_message(kwargs, message);
+        },
+        Mode::XML => {
+            if kwargs.stream == Some(true) {
+                return Err(
+                    Error::NotImplementedError(
+                        "stream=True is not yet supported in xml_mode".to_string()
+                    )
+                );
+            }
+            let schema = serde_json::to_value(schemars::schema_for!(T)).map_err(Error::SerdeError)?;
+            let instruction = match response_model {
+                IterableOrSingle::Iterable(_) => format!("Repeat the <{}> tag for each instance", T::tool_schema().name),
+                IterableOrSingle::Single(_) | IterableOrSingle::Partial(_) => format!("Return a single <{}> tag", T::tool_schema().name),
+            };
+            add_system_message(kwargs, format!(
+                "As a genius expert, your task is to understand the content and provide
+                the parsed objects as XML that follows this template:\n{}\n
+                Replace the type in each tag with its value and leave out the optional tags you have no value for,
+                do not return JSON. {}",
+                xml_template(&schema)?,
+                instruction
+            ));
+        },
+        Mode::MD_YAML => {
+            let instruction = match response_model {
+                IterableOrSingle::Single(_) => {
+                    if kwargs.stream == Some(true) {
+                        return Err(
+                            Error::Generic(
+                                "stream=True is not supported when using response_model parameter for non-iterables".to_string()
+                            )
+                        );
+                    }
+                    "Return a single instance as a YAML mapping"
+                },
+                // partial snapshots are built from streamed json
+                IterableOrSingle::Partial(_) => {
+                    if kwargs.stream == Some(true) {
+                        return Err(
+                            Error::NotImplementedError(
+                                "stream=True is not yet supported for IterableOrSingle::Partial in markdown_yaml_mode".to_string()
+                            )
+                        );
+                    }
+                    "Return a single instance as a YAML mapping"
+                },
+                IterableOrSingle::Iterable(_) => "Return a YAML list with one item for each instance",
+            };
+
+            add_system_message(kwargs, format!(
+                "As a genius expert, your task is to understand the content and provide
+                the parsed objects in YAML that match the following json_schema:\n{}\n
+                Make sure to return instances of the YAML, not the schema itself. {}",
+                T::openai_schema(),
+                instruction
+            ));
+            kwargs.messages.push(ChatCompletionRequestMessage::User(
+                ChatCompletionRequestUserMessage {
+                    content: ChatCompletionRequestUserMessageContent::Text(
+                        "Return the correct YAML response within a ```yaml codeblock. not the JSON_SCHEMA".to_string()
+                    ),
+                    role: Role::User,
+                    name: None,
                 }
+            ));
+        },
+        Mode::OLLAMA_JSON_SCHEMA | Mode::LLAMA_CPP_JSON_SCHEMA | Mode::LLAMA_CPP_GRAMMAR => {
+            let schema = serde_json::to_value(schemars::schema_for!(T)).map_err(Error::SerdeError)?;
+            let schema = match response_model {
+                IterableOrSingle::Single(_) | IterableOrSingle::Partial(_) => schema,
+                IterableOrSingle::Iterable(_) => iterable_json_schema(schema),
+            };
+
+            // the server constrains the output, the prompt tells the model what the fields mean
+            add_system_message(kwargs, format!(
+                "As a genius expert, your task is to understand the content and provide
+                the parsed objects in JSON that match the following json_schema:\n{}\n
+                Make sure to return instances of the JSON, not the schema itself",
+                serde_json::to_string_pretty(&schema).map_err(Error::SerdeError)?
+            ));
+
+            match mode {
+                Mode::OLLAMA_JSON_SCHEMA => {
+                    chat_request.extra_body.insert("format".to_string(), schema);
+                },
+                Mode::LLAMA_CPP_JSON_SCHEMA => {
+                    chat_request.extra_body.insert("json_schema".to_string(), schema);
+                },
+                _ => {
+                    let grammar = json_schema_to_gbnf(&schema)?;
+                    chat_request.extra_body.insert("grammar".to_string(), Value::String(grammar));
+                },
             }
-        }  
+        }
     }
     Ok(())
 }
 
+///appends the message to the system message or inserts a system message if there is none
+fn add_system_message(kwargs: &mut CreateChatCompletionRequest, message: String) {
+    match kwargs.messages.first_mut() {
+        Some(ChatCompletionRequestMessage::System(kwargs_message)) => {
+            kwargs_message.content += &message;
+        }
+        _=> {
+            kwargs.messages.insert(0, 
+            ChatCompletionRequestMessage::System(ChatCompletionRequestSystemMessage {
+                    role: Role::System,
+                    content: message,
+                    name: None, // Assuming name is optional and not required here
+                }
+            ));
+        }
+    }
+}
+
 /// this function processes the response based on the mode and the response_model and parses the response accordingly. 
 /// It supports both streaming outputs and non-streaming outputs.
 /// 
@@ -144,5 +325,14 @@ where
         ChatCompletionResponseWrapper::AtOnce(res) => {
             return T::from_response(&response_model, &res, validation_context, mode);
         }
+        ChatCompletionResponseWrapper::Anthropic(res) => {
+            T::parse_anthropic_tools(&response_model, &res, validation_context)
+        }
+        ChatCompletionResponseWrapper::Gemini(res) => {
+            T::parse_gemini(&response_model, &res, validation_context, mode)
+        }
+        ChatCompletionResponseWrapper::Completion(res) => {
+            T::parse_completion(&response_model, &res, validation_context)
+        }
     }
 }
@@ -2,128 +2,276 @@ use crate::mode::Mode;
 use crate::error::Error;
 use crate::process_response::process_response_async;
 use crate::openai_schema::{BaseSchema, BaseArg};
-use validator::ValidateArgs;
+use validator::{ValidateArgs, ValidationErrorsKind};
 use std::fmt;
 use async_openai::types::{
     ChatCompletionRequestMessage, ChatCompletionRequestUserMessage, ChatCompletionRequestUserMessageContent, 
-    ChatCompletionRequestAssistantMessage, Role, CreateChatCompletionRequest
+    ChatCompletionRequestAssistantMessage, Role,
+    ChatCompletionMessageToolCall, ChatCompletionRequestToolMessage, ChatCompletionResponseStream
 };
-use std::pin::Pin;
-use std::future::Future;
-use async_openai::error::OpenAIError;
+use crate::backend::{ChatBackend, ChatRequest};
 use crate::enums::{InstructorResponse, ChatCompletionResponseWrapper};
+use std::future::Future;
 use crate::enums::IterableOrSingle;
+use crate::dsl::iterable::{IterableBase, item_chunks};
+use crate::json_stream::{JsonEvent, JsonEventParser};
+use crate::types::{JsonStream, Attempt, RetryError};
+use futures::stream::StreamExt;
+use async_stream::stream;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
 
-/// this function generates the retry messages for the given mode and exception, 
+///the wording of the message that tells the llm what was wrong with its response, see reask_messages_with.
+/// Implement it to override the wording for some modes, the shape of the conversation
+/// (tool results answering the tool calls, or a user message) is still chosen by the mode
+///
+/// Example
+///
+/// struct Terse;
+///
+/// impl ReaskStrategy for Terse {
+///     fn message(&self, mode: Mode, exception: &str) -> String {
+///         match mode {
+///             Mode::TOOLS => format!("Invalid arguments: {}", exception),
+///             _ => DefaultReask.message(mode, exception),
+///         }
+///     }
+/// }
+///
+/// let policy = RetryPolicy { max_retries: 3, reask: Arc::new(Terse), ..Default::default() };
+pub trait ReaskStrategy: Send + Sync {
+    ///the text of the user message, or of every tool result, re-asking a response that failed with `exception`
+    fn message(&self, mode: Mode, exception: &str) -> String;
+}
+
+///the wording used unless a RetryPolicy sets its own ReaskStrategy
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DefaultReask;
+
+impl ReaskStrategy for DefaultReask {
+    fn message(&self, mode: Mode, exception: &str) -> String {
+        match mode {
+            Mode::TOOLS | Mode::ANTHROPIC_TOOLS | Mode::GEMINI_TOOLS => format!(
+                "Validation Error found:\n{}\nRecall the function correctly, fix the errors",
+                exception
+            ),
+            Mode::MD_JSON => format!(
+                "Correct your JSON ONLY RESPONSE, based on the following errors:\n{}\n",
+                exception
+            ),
+            Mode::XML => format!(
+                "Your XML response could not be parsed:\n{}\nCorrect the malformed tag and return the whole response with the same tags",
+                exception
+            ),
+            Mode::MD_YAML => format!(
+                "Correct your YAML ONLY RESPONSE, based on the following errors:\n{}\n",
+                exception
+            ),
+            _ => format!(
+                "Recall the function correctly, fix the errors, exceptions found\n{}",
+                exception
+            ),
+        }
+    }
+}
+
+/// this function generates the retry messages for the given mode and exception with the wording of DefaultReask,
 /// to better inform the llm as to how to fix the error
 /// # Arguments
 /// * `model_message`: `String` - the model message to use for the retry
+/// * `tool_calls`: `Option<Vec<ChatCompletionMessageToolCall>>` - the tool calls of the response,
+///   in Mode::TOOLS, Mode::ANTHROPIC_TOOLS and Mode::GEMINI_TOOLS each of them is answered with a tool result carrying the exception
 /// * `mode`: `Mode` - the mode to use for processing the response
 /// * `exception`: `impl fmt::Display` - the exception to use for the retry
 /// # Returns
 /// * `Vec<ChatCompletionRequestMessage>` - the retry messages
 pub fn reask_messages(
     model_message: String,
+    tool_calls: Option<Vec<ChatCompletionMessageToolCall>>,
     mode: Mode,
     exception: impl fmt::Display,
 ) -> Vec<ChatCompletionRequestMessage> {
-    
+    reask_messages_with(&DefaultReask, model_message, tool_calls, mode, exception)
+}
 
-    //we extract the message from the stream or simply via message.choices[0].message.content
-    let message = ChatCompletionRequestMessage::Assistant(
-        ChatCompletionRequestAssistantMessage{
-            role: Role::Assistant,
-            content: Some(model_message),
-            name: None,
-            tool_calls : None,
-            function_call: None,
-        }
-    );
-       
-    let mut messages: Vec<ChatCompletionRequestMessage> = vec![message];
-
-    match mode {
-        Mode::MD_JSON => {
-            messages.push(ChatCompletionRequestMessage::User(
-                ChatCompletionRequestUserMessage{
-                    role: Role::User,
-                    content: ChatCompletionRequestUserMessageContent::Text(format!(
-                        "Correct your JSON ONLY RESPONSE, based on the following errors:\n{}\n",
-                        exception
-                    )),
-                    name: None,
-                }   
-            ));
-        }
-        _ => {
-            messages.push(ChatCompletionRequestMessage::User(
-                ChatCompletionRequestUserMessage{
-                role: Role::User,
-                content: ChatCompletionRequestUserMessageContent::Text(format!(
-                    "Recall the function correctly, fix the errors, exceptions found\n{}",
-                    exception
-                )),
-                    name: None,
-                }    
-            ));
+///reask_messages with the wording of `strategy`. The messages form a conversation the provider of the mode accepts:
+///
+/// * in the tool modes the assistant message carries the original tool calls and every tool_call_id
+///   is answered by a tool message (a tool_result block for anthropic, a functionResponse part for gemini)
+/// * otherwise (or when the llm answered with text instead of a tool call) the response is sent back
+///   as an assistant message followed by a user message
+pub fn reask_messages_with(
+    strategy: &dyn ReaskStrategy,
+    model_message: String,
+    tool_calls: Option<Vec<ChatCompletionMessageToolCall>>,
+    mode: Mode,
+    exception: impl fmt::Display,
+) -> Vec<ChatCompletionRequestMessage> {
+    let message = strategy.message(mode, &exception.to_string());
+
+    if matches!(mode, Mode::TOOLS | Mode::ANTHROPIC_TOOLS | Mode::GEMINI_TOOLS) {
+        if let Some(tool_calls) = tool_calls.filter(|tool_calls| !tool_calls.is_empty()) {
+            let mut messages = vec![ChatCompletionRequestMessage::Assistant(
+                ChatCompletionRequestAssistantMessage{
+                    role: Role::Assistant,
+                    tool_calls: Some(tool_calls.clone()),
+                    ..Default::default()
+                }
+            )];
+            messages.extend(tool_calls.iter().map(|tool_call| {
+                ChatCompletionRequestMessage::Tool(ChatCompletionRequestToolMessage{
+                    role: Role::Tool,
+                    content: message.clone(),
+                    tool_call_id: tool_call.id.clone(),
+                })
+            }));
+            return messages;
         }
     }
 
-    messages
+    //we extract the message from the stream or simply via message.choices[0].message.content
+    vec![
+        ChatCompletionRequestMessage::Assistant(
+            ChatCompletionRequestAssistantMessage{
+                role: Role::Assistant,
+                content: Some(model_message),
+                name: None,
+                tool_calls : None,
+                function_call: None,
+            }
+        ),
+        ChatCompletionRequestMessage::User(
+            ChatCompletionRequestUserMessage{
+                role: Role::User,
+                content: ChatCompletionRequestUserMessageContent::Text(message),
+                name: None,
+            }
+        ),
+    ]
 }
 
-///This function takes a reference to a function as input.
-///arguable whether this is a good idea, but trying to replicate the instructpr api as closely as possible
+///This function takes a reference to a ChatBackend as input.
 /// the it tries to process the response, if suceeding it returns the response else, it tri until it reaches max_retries
 /// #Arguments 
-/// * `func` a function that takes CreateChatCompletionRequest and returns a future of type Result<ChatCompletionResponseWrapper, OpenAIError>
+/// * `backend` the backend used to send the request, see ChatBackend
 /// * `response_model` the response model to use for processing the response
 /// * `validation_context` the validation context to use for validating each struct
 /// * `kwargs` the request object to modify
 /// * `max_retries` the maximum number of retries to attempt
 /// * `mode` the mode to use for processing the response 
-pub async fn retry_async<T, A>(
-    func: Box<dyn Fn(CreateChatCompletionRequest) -> Pin<Box<dyn Future<Output = Result<ChatCompletionResponseWrapper, OpenAIError>> + Send>> + Send + 'static>,
+/// * `reask` the wording of the re-ask messages, see ReaskStrategy
+pub async fn retry_async<B, T, A>(
+    backend: &B,
     response_model: IterableOrSingle<T>,
     validation_context: A,
-    kwargs: &mut CreateChatCompletionRequest,
+    kwargs: &mut ChatRequest,
     max_retries: usize,
     mode: Mode,
+    reask: Arc<dyn ReaskStrategy>,
 ) -> Result<InstructorResponse<T>, Error>
 where
+    B: ChatBackend,
     T: ValidateArgs<'static, Args = A> + BaseSchema + 'static,
     A: BaseArg,
 {
-    let mut attempt = 0;
+    // the items of a streamed Iterable are validated one by one, the invalid ones are re-asked once the stream ends.
+    // In the json modes the fields of a streamed Single or Iterable are validated as soon as they are complete
+    let streamed_items = match response_model {
+        IterableOrSingle::Iterable(_) => streams_items(mode),
+        IterableOrSingle::Single(_) => speculates(mode),
+        IterableOrSingle::Partial(_) => false,
+    };
+    if kwargs.request.stream == Some(true) && streamed_items {
+        let response = backend.chat(kwargs.clone()).await?;
+        if let ChatCompletionResponseWrapper::Stream(response) = response {
+            return Ok(reask_stream_items(
+                backend.clone(),
+                response,
+                response_model,
+                validation_context,
+                kwargs.clone(),
+                max_retries.saturating_sub(1),
+                mode,
+                reask,
+            ).await);
+        }
+        return process_response_async(response, response_model, &validation_context, mode).await;
+    }
+
+    retry_with(
+        backend,
+        kwargs,
+        max_retries,
+        mode,
+        reask.as_ref(),
+        |response| {
+            let response_model = response_model.clone();
+            let validation_context = validation_context.clone();
+            async move {
+                process_response_async(response, response_model, &validation_context, mode).await
+            }
+        },
+    ).await
+}
+
+///the retry loop used by retry_async, it is generic over how a response is processed 
+/// so that response models that are not a single struct (see ParallelBase) can be re-asked as well
+/// #Arguments 
+/// * `backend` the backend used to send the request, see ChatBackend
+/// * `kwargs` the request object to modify
+/// * `max_retries` the maximum number of retries to attempt
+/// * `mode` the mode to use for the re-ask messages 
+/// * `reask` the wording of the re-ask messages, see ReaskStrategy
+/// * `process` turns a response into the result, an Err triggers a re-ask
+/// 
+/// Once max_retries responses have failed it returns Error::RetryError with every attempt
+pub async fn retry_with<B, R, F, Fut>(
+    backend: &B,
+    kwargs: &mut ChatRequest,
+    max_retries: usize,
+    mode: Mode,
+    reask: &dyn ReaskStrategy,
+    process: F,
+) -> Result<R, Error>
+where
+    B: ChatBackend,
+    F: Fn(ChatCompletionResponseWrapper) -> Fut,
+    Fut: Future<Output = Result<R, Error>>,
+{
+    let mut attempts: Vec<Attempt> = Vec::new();
 
-    while attempt < max_retries {
-        let response = func(kwargs.clone());
+    while attempts.len() < max_retries {
+        let messages = kwargs.request.messages.clone();
+        let start = Instant::now();
+        // the completions endpoint takes a prompt, the messages (and the re-asks) are flattened by the backend
+        let response = match mode {
+            Mode::COMPLETION => backend.complete(kwargs.clone()),
+            _ => backend.chat(kwargs.clone()),
+        };
         match response.await {
             Ok(_response) => {
                 //we fetch the model message from the response before we process the response
                 let model_message = _response.get_llm_test_response(mode);
-                let result = process_response_async(
-                    _response,
-                    response_model.clone(),
-                    &validation_context,
-                    mode,
-                ).await;
+                let tool_calls = _response.get_tool_calls();
+                let usage = _response.get_usage();
+                let result = process(_response).await;
 
                 match result {
                     Ok(result) => return Ok(result),
                     Err(e) => {
                         //TODO think about how would 
                         //can use response here and whether you can use it as is or not
-                        if kwargs.stream.unwrap_or(false) {
+                        if kwargs.request.stream.unwrap_or(false) {
                             return Err(e);
                         }
                         
                         match model_message {
                             Some(message) => {
-                                let messages = reask_messages(message, mode, e);
-                                kwargs.messages.extend(messages);
-                                attempt += 1;
+                                kwargs.request.messages.extend(reask_messages_with(reask, message.clone(), tool_calls, mode, &e));
+                                attempts.push(Attempt { model: kwargs.request.model.clone(), messages, output: Some(message), error: e, usage, latency: start.elapsed() });
                                 continue;
                             }
                             None => {
@@ -135,10 +283,294 @@ where
             }
             Err(e) => {
                 println!("retry_sync Error: {}", e);
-                return Err(Error::Generic(format!("Error: {}", e).to_string()));
+                return Err(e);
             }
         }
     }
 
-    Err(Error::Generic("Max retries exceeded".to_string()))
-}
\ No newline at end of file
+    Err(Error::RetryError(RetryError { attempts }))
+}
+
+///whether the items of a streamed Iterable can be re-asked in this mode, see reask_stream_items
+fn streams_items(mode: Mode) -> bool {
+    matches!(mode,
+        Mode::JSON | Mode::MD_JSON | Mode::MD_YAML | Mode::JSON_SCHEMA | Mode::TOOLS |
+        Mode::OLLAMA_JSON_SCHEMA | Mode::LLAMA_CPP_JSON_SCHEMA | Mode::LLAMA_CPP_GRAMMAR
+    )
+}
+
+///whether the fields of a streamed response are validated before their object is complete in this mode,
+/// see reask_stream_items. The response has to be json text, tool calls and yaml are not checked
+fn speculates(mode: Mode) -> bool {
+    matches!(mode,
+        Mode::JSON | Mode::MD_JSON | Mode::JSON_SCHEMA |
+        Mode::OLLAMA_JSON_SCHEMA | Mode::LLAMA_CPP_JSON_SCHEMA | Mode::LLAMA_CPP_GRAMMAR
+    )
+}
+
+///a field that failed to parse or validate before its object was complete, the generation is stopped there
+#[derive(Debug)]
+struct FieldFailure {
+    // the number of the object in the response and the key of the field
+    index: usize,
+    path: String,
+    // the text of the response up to the invalid field
+    text: String,
+    error: Error,
+}
+
+impl FieldFailure {
+    ///the exception of the re-ask, the items that were complete but invalid are re-asked with it
+    fn exception(&self, failed: &[(String, Error)], iterable: bool) -> String {
+        if !iterable {
+            return format!("The field `{}` is invalid, your response was stopped there:\n{}", self.path, self.error);
+        }
+        let mut exception = format!(
+            "The field `{}` of item {} is invalid, your response was stopped there:\n{}",
+            self.path, self.index + 1, self.error
+        );
+        for (item, error) in failed {
+            exception.push_str(&format!("\n\nThis item is invalid as well:\n{}\nErrors: {}", item.trim(), error));
+        }
+        exception.push_str("\n\nThe other items before it are valid, return only the corrected items and the items that follow them");
+        exception
+    }
+}
+
+///the errors of the fields that are complete, None if they are valid.
+/// The fields are put into `template` (the serialized instance wrapped in the model), so fields that have not been
+/// generated yet take their values from it and only the errors of the complete fields count.
+/// validator names a field by its `#[serde(rename)]` or else by its rust name, which is not the json key under a
+/// `#[serde(rename_all)]`. An error of a name that is not a key of the template counts when the template itself
+/// does not have it (`template_errors`), rather than guessing its key. The errors of the struct level validators
+/// (`__all__`) wait for the complete object
+fn invalid_fields<T, A>(
+    template: &Map<String, Value>,
+    template_errors: &HashMap<&'static str, ValidationErrorsKind>,
+    fields: &Map<String, Value>,
+    validation_context: &A,
+) -> Option<Error>
+where
+    T: ValidateArgs<'static, Args = A> + BaseSchema,
+    A: BaseArg,
+{
+    let mut snapshot = template.clone();
+    snapshot.extend(fields.clone());
+    let data = match serde_json::from_value::<T>(Value::Object(snapshot)) {
+        Ok(data) => data,
+        // the template deserializes, so one of the complete fields has the wrong type or an unknown variant
+        Err(e) => return Some(Error::SerdeError(e)),
+    };
+    let mut errors = data.validate_args(validation_context.clone()).err()?;
+    errors.errors_mut().retain(|field, kind| match template.contains_key(*field) {
+        true => fields.contains_key(*field),
+        false => *field != "__all__" && template_errors.get(field) != Some(kind),
+    });
+    if errors.is_empty() {
+        return None;
+    }
+    Some(Error::ValidationErrors(errors))
+}
+
+///like IterableBase::raw_items_from_streaming_response for the json modes, but every top level field of an object
+/// is checked with invalid_fields as soon as it is complete. On the first invalid field the failure is stored
+/// in `failure` and the stream ends, which drops the request so the rest of the object is not generated
+async fn speculative_items<T, A>(
+    response_model: &IterableOrSingle<T>,
+    response: ChatCompletionResponseStream,
+    validation_context: &A,
+    mode: Mode,
+    failure: Arc<Mutex<Option<FieldFailure>>>,
+) -> JsonStream
+where
+    T: ValidateArgs<'static, Args = A> + BaseSchema + 'static,
+    A: BaseArg,
+{
+    let iterable = matches!(response_model, IterableOrSingle::Iterable(_));
+    // a template that does not survive a round trip through json can not tell which field is at fault
+    let template = response_model.clone().unwrap().ok()
+        .and_then(|template| serde_json::to_value(template).ok())
+        .filter(|template| serde_json::from_value::<T>(template.clone()).is_ok())
+        .and_then(|template| match template {
+            Value::Object(template) => Some(template),
+            _ => None,
+        });
+    let template_errors = template.as_ref()
+        .and_then(|template| serde_json::from_value::<T>(Value::Object(template.clone())).ok())
+        .and_then(|template| template.validate_args(validation_context.clone()).err())
+        .map(|errors| errors.into_errors())
+        .unwrap_or_default();
+    let json_chunks = T::extract_json_async(response, mode).await;
+    let mut json_chunks = item_chunks(iterable, json_chunks, mode);
+    let validation_context = validation_context.clone();
+    stream! {
+        let mut parser = JsonEventParser::new();
+        let mut text = String::new();
+        let mut fields = Map::new();
+        let mut index = 0;
+        'chunks: while let Some(chunk_result) = json_chunks.next().await {
+            let chunk = match chunk_result {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    yield Err(e);
+                    continue;
+                }
+            };
+            text.push_str(&chunk);
+            for event in parser.push(&chunk) {
+                match event {
+                    JsonEvent::ObjectStarted { index: started } => {
+                        index = started;
+                        fields.clear();
+                    },
+                    JsonEvent::FieldCompleted { path, value } if !path.contains(['.', '[']) => {
+                        let template = match template.as_ref() {
+                            Some(template) => template,
+                            None => continue,
+                        };
+                        fields.insert(path.clone(), value);
+                        if let Some(error) = invalid_fields::<T, A>(template, &template_errors, &fields, &validation_context) {
+                            *failure.lock().unwrap() = Some(FieldFailure { index, path, text, error });
+                            break 'chunks;
+                        }
+                    },
+                    JsonEvent::ObjectEnded { json, .. } => yield Ok(json),
+                    _ => {},
+                }
+            }
+        }
+    }.boxed()
+}
+
+///the message asking for corrected versions of the items that failed to parse or validate,
+/// every item is quoted as it was streamed together with its error
+pub fn reask_items_message(failed: &[(String, Error)], mode: Mode) -> ChatCompletionRequestMessage {
+    let items = failed.iter()
+        .map(|(item, error)| format!("{}\nErrors: {}", item.trim(), error))
+        .collect::<Vec<String>>()
+        .join("\n\n");
+    let instruction = match mode {
+        Mode::TOOLS => "Call the function once for each corrected item",
+        _ => "Return only the corrected items, in the same format as before",
+    };
+    ChatCompletionRequestMessage::User(
+        ChatCompletionRequestUserMessage{
+            role: Role::User,
+            content: ChatCompletionRequestUserMessageContent::Text(format!(
+                "The following items of your response are invalid:\n\n{}\n\nFix the errors. {}",
+                items, instruction
+            )),
+            name: None,
+        }
+    )
+}
+
+///yields the valid items of a streamed Iterable as they arrive and collects the items that fail to parse or validate.
+/// Once the stream ends the failed items are re-asked in a single follow-up request (see reask_items_message)
+/// that is streamed the same way, the fixed items are appended to the stream. 
+/// Items that are still invalid after max_retries follow-ups are yielded as errors.
+/// A streamed Single is handled the same way, as a stream of one item.
+///
+/// In the json modes every field is validated as soon as it is complete: when a field fails to parse or validate
+/// (e.g. a value out of range or an unknown enum variant) the request is cancelled and the re-ask (see reask_messages)
+/// is sent right away with the text received so far, instead of waiting for the rest of an invalid object.
+/// Only the errors of the complete fields count, validation that depends on several fields runs once the object is complete.
+/// Errors of the stream itself (e.g. a dropped connection) are yielded right away and are not re-asked
+/// #Arguments 
+/// * `backend` the backend used to send the follow-up requests
+/// * `response` the stream of the first request
+/// * `response_model` the response model, IterableOrSingle::Iterable or IterableOrSingle::Single
+/// * `validation_context` the validation context to use for validating each struct
+/// * `kwargs` the request of the first stream, every follow-up request adds the answer to the previous one and its re-ask
+/// * `max_retries` the maximum number of follow-up requests
+/// * `mode` the mode the request was made in
+/// * `reask` the wording of the re-ask of an invalid field, see ReaskStrategy
+#[allow(clippy::too_many_arguments)]
+pub async fn reask_stream_items<B, T, A>(
+    backend: B,
+    response: ChatCompletionResponseStream,
+    response_model: IterableOrSingle<T>,
+    validation_context: A,
+    kwargs: ChatRequest,
+    max_retries: usize,
+    mode: Mode,
+    reask: Arc<dyn ReaskStrategy>,
+) -> InstructorResponse<T>
+where
+    B: ChatBackend,
+    T: ValidateArgs<'static, Args = A> + BaseSchema + 'static,
+    A: BaseArg,
+{
+    let iterable = matches!(response_model, IterableOrSingle::Iterable(_));
+    let stream = stream! {
+        let mut response = response;
+        let mut request = kwargs;
+        let mut attempt = 0;
+        loop {
+            // the raw items of the response, sent back with the re-ask of its invalid items
+            let mut received: Vec<String> = Vec::new();
+            let mut failed: Vec<(String, Error)> = Vec::new();
+            let failure = Arc::new(Mutex::new(None));
+            let mut items = match speculates(mode) {
+                true => speculative_items(&response_model, response, &validation_context, mode, failure.clone()).await,
+                false => T::raw_items_from_streaming_response(response, mode).await,
+            };
+            while let Some(item) = items.next().await {
+                match item {
+                    Ok(item) => {
+                        received.push(item.trim().to_string());
+                        match T::validate_stream_item(&response_model, &item, &validation_context, mode) {
+                            Ok(item) => yield Ok(item),
+                            Err(e) => failed.push((item, e)),
+                        }
+                    },
+                    Err(e) => yield Err(e),
+                }
+            }
+            let failure = failure.lock().unwrap().take();
+            if failed.is_empty() && failure.is_none() {
+                break;
+            }
+            if attempt == max_retries {
+                if let Some(failure) = failure {
+                    yield Err(failure.error);
+                }
+                for (_, e) in failed {
+                    yield Err(e);
+                }
+                break;
+            }
+            attempt += 1;
+
+            match failure {
+                // the generation was stopped at an invalid field, the re-ask continues from the text received so far
+                Some(failure) => {
+                    let exception = failure.exception(&failed, iterable);
+                    request.request.messages.extend(reask_messages_with(reask.as_ref(), failure.text, None, mode, exception));
+                },
+                None => {
+                    request.request.messages.push(ChatCompletionRequestMessage::Assistant(
+                        ChatCompletionRequestAssistantMessage {
+                            role: Role::Assistant,
+                            content: Some(received.join("\n")),
+                            ..Default::default()
+                        }
+                    ));
+                    request.request.messages.push(reask_items_message(&failed, mode));
+                },
+            }
+            response = match backend.chat(request.clone()).await {
+                Ok(ChatCompletionResponseWrapper::Stream(response)) => response,
+                Ok(_) => {
+                    yield Err(Error::Generic("the backend did not stream the re-ask of the invalid items".to_string()));
+                    break;
+                }
+                Err(e) => {
+                    yield Err(e);
+                    break;
+                }
+            };
+        }
+    }.boxed();
+    InstructorResponse::Stream(stream)
+}
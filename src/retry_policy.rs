@@ -0,0 +1,180 @@
+use crate::error::Error;
+use crate::backend::{ChatBackend, BackendFuture, ChatRequest, UsageSink};
+use crate::enums::ChatCompletionResponseWrapper;
+use crate::retry::{ReaskStrategy, DefaultReask};
+use async_openai::error::OpenAIError;
+use async_openai::types::ChatCompletionResponseStream;
+use rand::Rng;
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+///decides whether a request that failed with the error is sent again, see RetryPolicy
+pub type RetryClassifier = Arc<dyn Fn(&Error) -> bool + Send + Sync>;
+
+///how Patch::chat_completion_with_policy retries a call. Responses that fail to parse or validate
+/// and requests that fail before a response arrives have separate budgets
+///
+/// * `max_retries` - the number of attempts at a valid response, an invalid response is re-asked until then.
+///   This is the max_retries of Patch::chat_completion
+/// * `max_transport_retries` - the number of times a failed request (a rate limit, a server error, a reset connection, ...)
+///   is sent again, across the whole call. Only the errors accepted by `classifier` are retried
+/// * `initial_backoff`, `multiplier`, `max_backoff` - the delay before the n-th transport retry is
+///   initial_backoff * multiplier^n, at most max_backoff
+/// * `jitter` - a random delay between zero and the backoff is used instead, so clients that hit the same rate limit spread out
+/// * `respect_retry_after` - the delay asked for by the provider (HttpError::retry_after) is used in place of the backoff,
+///   at most max_backoff
+/// * `classifier` - whether an error is transient, default_classifier unless you plug in your own
+/// * `reask` - the wording of the messages re-asking an invalid response, DefaultReask unless you plug in your own
+///
+/// A stream that fails after it started is not a failed request, see ReconnectingBackend for resuming it.
+/// A bare async_openai::Client retries rate limits with its own backoff and drops the status and headers of an error
+/// (OpenAIError::ApiError), wrap it in an OpenAIBackend to get an HttpError with its status and retry_after.
+///
+/// Example
+///
+/// let policy = RetryPolicy {
+///     max_retries: 2,
+///     max_transport_retries: 5,
+///     classifier: Arc::new(|error| matches!(error, Error::HttpError(e) if e.status == 429)),
+///     ..Default::default()
+/// };
+#[derive(Clone)]
+pub struct RetryPolicy {
+    pub max_retries: usize,
+    pub max_transport_retries: usize,
+    pub initial_backoff: Duration,
+    pub multiplier: f64,
+    pub max_backoff: Duration,
+    pub jitter: bool,
+    pub respect_retry_after: bool,
+    pub classifier: RetryClassifier,
+    pub reask: Arc<dyn ReaskStrategy>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 1,
+            max_transport_retries: 3,
+            initial_backoff: Duration::from_millis(500),
+            multiplier: 2.0,
+            max_backoff: Duration::from_secs(30),
+            jitter: true,
+            respect_retry_after: true,
+            classifier: Arc::new(default_classifier),
+            reask: Arc::new(DefaultReask),
+        }
+    }
+}
+
+impl fmt::Debug for RetryPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RetryPolicy")
+            .field("max_retries", &self.max_retries)
+            .field("max_transport_retries", &self.max_transport_retries)
+            .field("initial_backoff", &self.initial_backoff)
+            .field("multiplier", &self.multiplier)
+            .field("max_backoff", &self.max_backoff)
+            .field("jitter", &self.jitter)
+            .field("respect_retry_after", &self.respect_retry_after)
+            .finish_non_exhaustive()
+    }
+}
+
+impl RetryPolicy {
+    ///the policy of Patch::chat_completion, invalid responses are re-asked and failed requests are not retried
+    pub fn new(max_retries: usize) -> Self {
+        RetryPolicy { max_retries, max_transport_retries: 0, ..Default::default() }
+    }
+
+    ///the backoff before the transport retry `retry` (starting at 0), without jitter
+    pub fn backoff(&self, retry: usize) -> Duration {
+        let backoff = self.initial_backoff.as_secs_f64() * self.multiplier.powi(retry.min(i32::MAX as usize) as i32);
+        match Duration::try_from_secs_f64(backoff) {
+            Ok(backoff) => backoff.min(self.max_backoff),
+            Err(_) => self.max_backoff,
+        }
+    }
+
+    ///the delay before the transport retry `retry` of a request that failed with `error`
+    pub fn delay(&self, retry: usize, error: &Error) -> Duration {
+        if let Error::HttpError(e) = error {
+            if let Some(retry_after) = e.retry_after.filter(|_| self.respect_retry_after) {
+                return retry_after.min(self.max_backoff);
+            }
+        }
+        let backoff = self.backoff(retry);
+        match self.jitter {
+            true => backoff.mul_f64(rand::thread_rng().gen::<f64>()),
+            false => backoff,
+        }
+    }
+}
+
+///the errors that are worth sending the request again for: rate limits, timeouts, server errors and lost connections.
+/// Anthropic and Gemini put the http status in ApiError::code
+pub fn default_classifier(error: &Error) -> bool {
+    let transient = |status: u64| matches!(status, 408 | 409 | 429) || status >= 500;
+    match error {
+        Error::HttpError(e) => transient(e.status as u64),
+        Error::OpenAIError(OpenAIError::Reqwest(_)) | Error::OpenAIError(OpenAIError::StreamError(_)) => true,
+        Error::OpenAIError(OpenAIError::ApiError(e)) => e.code.as_ref().and_then(|code| code.as_u64()).is_some_and(transient),
+        _ => false,
+    }
+}
+
+///wraps a ChatBackend and sends a request again when it fails with an error the classifier of the policy accepts,
+/// until max_transport_retries is used up. The budget is shared by all requests of the call
+#[derive(Debug, Clone)]
+pub(crate) struct RetryingBackend<B: ChatBackend> {
+    backend: B,
+    policy: RetryPolicy,
+    retries: Arc<AtomicUsize>,
+}
+
+impl<B: ChatBackend> RetryingBackend<B> {
+    pub(crate) fn new(backend: B, policy: RetryPolicy) -> Self {
+        RetryingBackend { backend, policy, retries: Arc::new(AtomicUsize::new(0)) }
+    }
+
+    fn send<R, F>(&self, send: F) -> BackendFuture<R>
+    where
+        R: Send + 'static,
+        F: Fn(&B) -> BackendFuture<R> + Send + 'static,
+    {
+        let this = self.clone();
+        Box::pin(async move {
+            loop {
+                let error = match send(&this.backend).await {
+                    Err(e) if (this.policy.classifier)(&e) => e,
+                    result => return result,
+                };
+                let max_transport_retries = this.policy.max_transport_retries;
+                match this.retries.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |retries| (retries < max_transport_retries).then_some(retries + 1)) {
+                    Ok(retry) => tokio::time::sleep(this.policy.delay(retry, &error)).await,
+                    Err(_) => return Err(error),
+                }
+            }
+        })
+    }
+}
+
+impl<B: ChatBackend> ChatBackend for RetryingBackend<B> {
+    fn create(&self, request: ChatRequest) -> BackendFuture<ChatCompletionResponseWrapper> {
+        self.send(move |backend| backend.create(request.clone()))
+    }
+
+    fn complete(&self, request: ChatRequest) -> BackendFuture<ChatCompletionResponseWrapper> {
+        self.send(move |backend| backend.complete(request.clone()))
+    }
+
+    fn create_stream(&self, request: ChatRequest) -> BackendFuture<ChatCompletionResponseStream> {
+        self.send(move |backend| backend.create_stream(request.clone()))
+    }
+
+    fn create_stream_with_usage(&self, request: ChatRequest, usage: UsageSink) -> BackendFuture<ChatCompletionResponseStream> {
+        self.send(move |backend| backend.create_stream_with_usage(request.clone(), usage.clone()))
+    }
+}
@@ -0,0 +1,182 @@
+use crate::error::Error;
+use crate::backend::{ChatBackend, BackendFuture, ChatRequest, UsageSink};
+use crate::enums::{ChatCompletionResponseWrapper, InstructorResponse};
+use crate::dsl::events::EventStream;
+use crate::openai_schema::BaseSchema;
+use async_openai::types::ChatCompletionResponseStream;
+use validator::ValidateArgs;
+use futures::stream::{Stream, StreamExt};
+use async_stream::stream;
+use pin_utils::pin_mut;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+///the time limits of Patch::chat_completion_with_timeouts, None means no limit
+///
+/// * `deadline` - the time the whole call may take, across all retries and including the time it takes to consume
+///   a streamed response. It fails with Error::DeadlineExceeded
+/// * `idle_timeout` - the time a streamed response may go without sending a chunk. It fails with Error::IdleTimeout
+///
+/// Example
+///
+/// let timeouts = Timeouts { deadline: Some(Duration::from_secs(60)), idle_timeout: Some(Duration::from_secs(10)) };
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Timeouts {
+    pub deadline: Option<Duration>,
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Timeouts {
+    ///the instant the deadline passes for a call started at `start`, together with the deadline
+    pub fn deadline_at(&self, start: Instant) -> Option<(Instant, Duration)> {
+        self.deadline.map(|deadline| (start + deadline, deadline))
+    }
+}
+
+///wraps a ChatBackend and records when the last chunk of any of its streams arrived,
+/// the idle timeout is measured from that instant
+#[derive(Debug, Clone)]
+pub(crate) struct ActivityBackend<B: ChatBackend> {
+    backend: B,
+    last_chunk: Arc<Mutex<Instant>>,
+}
+
+impl<B: ChatBackend> ActivityBackend<B> {
+    pub(crate) fn new(backend: B) -> Self {
+        ActivityBackend { backend, last_chunk: Arc::new(Mutex::new(Instant::now())) }
+    }
+
+    fn touch(last_chunk: &Mutex<Instant>) {
+        *last_chunk.lock().unwrap() = Instant::now();
+    }
+
+    ///touches last_chunk for every chunk of the stream
+    fn watch(&self, response: BackendFuture<ChatCompletionResponseStream>) -> BackendFuture<ChatCompletionResponseStream> {
+        let last_chunk = self.last_chunk.clone();
+        // waiting for a new stream (e.g. the re-ask of invalid items) is not idle time of the previous one
+        Self::touch(&last_chunk);
+        Box::pin(async move {
+            let response = response.await?;
+            let stream = response.inspect(move |_| Self::touch(&last_chunk));
+            Ok(Box::pin(stream) as ChatCompletionResponseStream)
+        })
+    }
+}
+
+impl<B: ChatBackend> ChatBackend for ActivityBackend<B> {
+    fn create(&self, request: ChatRequest) -> BackendFuture<ChatCompletionResponseWrapper> {
+        self.backend.create(request)
+    }
+
+    fn complete(&self, request: ChatRequest) -> BackendFuture<ChatCompletionResponseWrapper> {
+        self.backend.complete(request)
+    }
+
+    fn create_stream(&self, request: ChatRequest) -> BackendFuture<ChatCompletionResponseStream> {
+        self.watch(self.backend.create_stream(request))
+    }
+
+    fn create_stream_with_usage(&self, request: ChatRequest, usage: UsageSink) -> BackendFuture<ChatCompletionResponseStream> {
+        self.watch(self.backend.create_stream_with_usage(request, usage))
+    }
+}
+
+///awaits the future, failing with Error::DeadlineExceeded if it does not finish before `deadline`.
+/// The future is dropped when the deadline passes, which aborts the request it is waiting for
+pub(crate) async fn with_deadline<F, R>(future: F, deadline: Option<(Instant, Duration)>) -> Result<R, Error>
+where
+    F: Future<Output = Result<R, Error>>,
+{
+    match deadline {
+        Some((at, duration)) => tokio::time::timeout_at(at.into(), future)
+            .await
+            .map_err(|_| Error::DeadlineExceeded(duration))?,
+        None => future.await,
+    }
+}
+
+///ends the stream with Error::DeadlineExceeded once `deadline` passes, or with Error::IdleTimeout
+/// once no chunk has arrived at the backend for `idle_timeout`.
+/// The stream of the backend is dropped right after the error, which closes the connection
+fn guard_stream<T>(
+    stream: Pin<Box<dyn Stream<Item = Result<T, Error>> + Send>>,
+    deadline: Option<(Instant, Duration)>,
+    idle_timeout: Option<Duration>,
+    last_chunk: Arc<Mutex<Instant>>,
+) -> Pin<Box<dyn Stream<Item = Result<T, Error>> + Send>>
+where
+    T: Send + 'static,
+{
+    stream! {
+        pin_mut!(stream);
+        loop {
+            let idle_at = idle_timeout.map(|idle_timeout| *last_chunk.lock().unwrap() + idle_timeout);
+            let wake_at = match (deadline.map(|(at, _)| at), idle_at) {
+                (Some(deadline_at), Some(idle_at)) => Some(deadline_at.min(idle_at)),
+                (deadline_at, idle_at) => deadline_at.or(idle_at),
+            };
+            let item = match wake_at {
+                Some(wake_at) => match tokio::time::timeout_at(wake_at.into(), stream.next()).await {
+                    Ok(item) => item,
+                    Err(_) => {
+                        if let Some((_, duration)) = deadline.filter(|(at, _)| Instant::now() >= *at) {
+                            yield Err(Error::DeadlineExceeded(duration));
+                            break;
+                        }
+                        // a chunk may have arrived without completing an item, the idle timer starts over from it
+                        match idle_timeout {
+                            Some(idle_timeout) if last_chunk.lock().unwrap().elapsed() >= idle_timeout => {
+                                yield Err(Error::IdleTimeout(idle_timeout));
+                                break;
+                            }
+                            _ => continue,
+                        }
+                    }
+                },
+                None => stream.next().await,
+            };
+            match item {
+                Some(item) => yield item,
+                None => break,
+            }
+        }
+    }.boxed()
+}
+
+///applies the timeouts to a response, a streamed response is guarded by guard_stream
+pub(crate) fn guard_response<T, B>(
+    response: InstructorResponse<T>,
+    deadline: Option<(Instant, Duration)>,
+    timeouts: Timeouts,
+    backend: &ActivityBackend<B>,
+) -> InstructorResponse<T>
+where
+    T: ValidateArgs<'static> + BaseSchema,
+    B: ChatBackend,
+{
+    match response {
+        InstructorResponse::Stream(stream) if deadline.is_some() || timeouts.idle_timeout.is_some() => {
+            InstructorResponse::Stream(guard_stream(stream, deadline, timeouts.idle_timeout, backend.last_chunk.clone()))
+        },
+        response => response,
+    }
+}
+
+///applies the timeouts to the field level events of a streamed response, like guard_response
+pub(crate) fn guard_events<T, B>(
+    events: EventStream<T>,
+    deadline: Option<(Instant, Duration)>,
+    timeouts: Timeouts,
+    backend: &ActivityBackend<B>,
+) -> EventStream<T>
+where
+    T: Send + 'static,
+    B: ChatBackend,
+{
+    if deadline.is_none() && timeouts.idle_timeout.is_none() {
+        return events;
+    }
+    guard_stream(events, deadline, timeouts.idle_timeout, backend.last_chunk.clone())
+}
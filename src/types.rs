@@ -1,9 +1,10 @@
 use validator::ValidationErrors;
 use std::fmt;
-use std::error::Error as StdError;
 use futures::stream::Stream;
 use std::pin::Pin;
 use crate::error::Error;
+use async_openai::types::{ChatCompletionRequestMessage, CompletionUsage};
+use std::time::Duration;
 pub type JsonStream = Pin<Box<dyn Stream<Item = Result<String, Error>> + Send>>;
 
 
@@ -29,8 +30,42 @@ impl From<ValidationErrors> for JsonError {
 }
 
 
+///a request of a call whose response failed to parse or validate, see RetryError
+#[derive(Debug)]
+pub struct Attempt {
+    ///the model the request was sent to
+    pub model: String,
+    ///the messages of the request, the re-asks of the previous attempts included
+    pub messages: Vec<ChatCompletionRequestMessage>,
+    ///the raw output of the model, the text or the arguments of the tool calls
+    pub output: Option<String>,
+    ///why the output was rejected
+    pub error: Error,
+    ///the usage reported by the provider
+    pub usage: Option<CompletionUsage>,
+    ///the time from sending the request until the output was rejected
+    pub latency: Duration,
+}
+
+///the error of a call that used up its retries, it has every attempt in the order they were made (the last one is
+/// the one that exhausted the retries), e.g. to debug the failure or to add it to a regression dataset
 #[derive(Debug)]
 pub struct RetryError {
-    pub last_attempt: Box<dyn StdError>, // Simplified for example purposes
+    pub attempts: Vec<Attempt>,
+}
+
+impl RetryError {
+    ///the error of the last attempt
+    pub fn last_error(&self) -> Option<&Error> {
+        self.attempts.last().map(|attempt| &attempt.error)
+    }
 }
 
+impl fmt::Display for RetryError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.last_error() {
+            Some(error) => write!(f, "Max retries exceeded after {} attempts, the last one failed with: {}", self.attempts.len(), error),
+            None => write!(f, "Max retries exceeded, no attempt was made"),
+        }
+    }
+}
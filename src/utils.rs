@@ -2,6 +2,7 @@ use crate::error::Error;
 use std::borrow::BorrowMut;
 use futures::stream::StreamExt;
 use crate::types::JsonStream;
+use crate::json_stream::JsonObjectSplitter;
 use async_stream::stream;
 use async_openai::types::{
     ChatChoice, ChatChoiceStream,ChatCompletionToolType,  ChatCompletionMessageToolCall, ChatCompletionMessageToolCallChunk, ChatCompletionResponseMessage, ChatCompletionResponseStream, ChatCompletionStreamResponseDelta, CreateChatCompletionResponse, CreateChatCompletionStreamResponse, FunctionCall, FunctionCallStream, Role
@@ -21,32 +22,40 @@ pub fn extract_json_from_codeblock(content: &str) -> Result<String, Error> {
     }
 }
 
+///returns the content of the first ```yaml (or plain ```) codeblock,
+/// if the content has no codeblock it is returned as is
+pub fn extract_yaml_from_codeblock(content: &str) -> Result<String, Error> {
+    let start = match content.find("```") {
+        Some(start) => start,
+        None => return Ok(content.to_string()),
+    };
+    // skip the info string (```yaml) up to the end of the line
+    let body = &content[start + 3..];
+    let body = match body.find('\n') {
+        Some(newline) => &body[newline + 1..],
+        None => return Err(Error::JsonExtractionError("No YAML found".to_string())),
+    };
+    match body.find("```") {
+        Some(end) => Ok(body[..end].to_string()),
+        // the closing fence is missing when the model ran out of tokens
+        None => Ok(body.to_string()),
+    }
+}
+
+///yields every json object in the stream as soon as it is complete, see JsonObjectSplitter
 pub async fn extract_json_from_stream_async(
     mut chunks: JsonStream,
 ) -> JsonStream {
     stream! {
-        let mut capturing = false;
-        let mut brace_count = 0;
-        let mut current_json = String::new();
-
+        let mut splitter = JsonObjectSplitter::new();
         while let Some(chunk) = chunks.next().await {
-            //TODO could this error be handled better
-            for char in chunk.expect("Error extracting json").chars() {
-                if char == '{' {
-                    capturing = true;
-                    brace_count += 1;
-                    current_json.push(char);
-                } else if char == '}' && capturing {
-                    brace_count -= 1;
-                    current_json.push(char);
-                    if brace_count == 0 {
-                        capturing = false;
-                        yield Ok(current_json.clone());
-                        current_json.clear();
+            match chunk {
+                Ok(chunk) => {
+                    for object in splitter.push(&chunk) {
+                        yield Ok(object);
                     }
-                } else if capturing {
-                    current_json.push(char);
                 }
+                Err(e) => yield Err(e),
             }
         }
     }.boxed()
@@ -0,0 +1,456 @@
+use crate::error::Error;
+use serde_json::{Map, Number, Value};
+use std::collections::BTreeSet;
+
+///renders a json schema (the schemars output used by OpenAISchema::openai_schema) as an xml tag template
+/// that is shown to the model in Mode::XML, the root tag is the title of the schema.
+///
+/// * every property is a tag containing its type, optional ones are marked with a comment
+/// * lists contain an <item> tag per element and maps a tag per key
+/// * enums list their variants, enums with data show one tag per variant
+///
+/// # Example
+///
+/// the schema of struct User { name: String, age: u8 } renders as
+/// <User>
+///   <name>string</name>
+///   <age>integer</age>
+/// </User>
+pub fn xml_template(schema: &Value) -> Result<String, Error> {
+    let mut renderer = XmlTemplate {
+        root: schema,
+        in_progress: BTreeSet::new(),
+        out: String::new(),
+    };
+    renderer.render(root_name(schema), schema, 0, Vec::new())?;
+    Ok(renderer.out)
+}
+
+///parses every <Title> element at the top level of text into a json value following the schema,
+/// so that the value can be deserialized into the struct the schema was generated for.
+/// Tags that are not in the schema are ignored and string fields take the raw text between their tags,
+/// so a reply does not have to be well formed xml (e.g. "a < b" is fine). If there is no <Title> element
+/// the whole text is parsed as the content of a single one
+///
+/// The errors quote the malformed tag, e.g. "<age>twenty</age> is not an integer"
+pub fn xml_to_values(text: &str, schema: &Value) -> Result<Vec<Value>, Error> {
+    let parser = XmlParser { root: schema };
+    let name = root_name(schema);
+    let elements = children(text)?;
+    let roots: Vec<&Element> = elements.iter().filter(|element| element.name == name).collect();
+    if roots.is_empty() {
+        return Ok(vec![parser.parse(text, schema, name)?]);
+    }
+    roots.into_iter().map(|element| parser.parse(element.inner, schema, name)).collect()
+}
+
+fn root_name(schema: &Value) -> &str {
+    schema.get("title").and_then(|title| title.as_str()).unwrap_or("response")
+}
+
+///resolves a $ref to #/definitions or #/$defs, returns the name of the definition as well
+fn resolve<'a>(root: &'a Value, schema: &'a Value) -> Result<(&'a Value, Option<&'a str>), Error> {
+    match schema.get("$ref").and_then(|reference| reference.as_str()) {
+        Some(reference) => {
+            let name = reference.rsplit('/').next().unwrap_or(reference);
+            let definition = root.get("definitions").and_then(|definitions| definitions.get(name))
+                .or_else(|| root.get("$defs").and_then(|definitions| definitions.get(name)))
+                .ok_or(Error::Generic(format!("could not resolve {} in the schema", reference)))?;
+            Ok((definition, Some(name)))
+        }
+        // schemars wraps a $ref with a description in a single allOf
+        None => match schema.get("allOf").and_then(|all_of| all_of.as_array()) {
+            Some(all_of) if all_of.len() == 1 => resolve(root, &all_of[0]),
+            _ => Ok((schema, None)),
+        },
+    }
+}
+
+///returns the alternatives of anyOf/oneOf and a type array without null, and whether null was one of them
+fn non_null_alternatives(schema: &Value) -> (Vec<Value>, bool) {
+    if let Some(types) = schema.get("type").and_then(|types| types.as_array()) {
+        let mut nullable = false;
+        let mut alternatives = Vec::new();
+        for schema_type in types {
+            if schema_type == "null" {
+                nullable = true;
+            } else {
+                let mut alternative = schema.clone();
+                alternative["type"] = schema_type.clone();
+                // the description belongs to the field, not to each of its types
+                if let Some(alternative) = alternative.as_object_mut() {
+                    alternative.remove("description");
+                }
+                alternatives.push(alternative);
+            }
+        }
+        return (alternatives, nullable);
+    }
+    let alternatives = schema.get("anyOf").or_else(|| schema.get("oneOf")).and_then(|alternatives| alternatives.as_array());
+    match alternatives {
+        Some(alternatives) => {
+            let nullable = alternatives.iter().any(|alternative| alternative.get("type") == Some(&Value::from("null")));
+            let alternatives = alternatives.iter()
+                .filter(|alternative| alternative.get("type") != Some(&Value::from("null")))
+                .cloned()
+                .collect();
+            (alternatives, nullable)
+        }
+        None => (vec![schema.clone()], false),
+    }
+}
+
+fn enum_values(schema: &Value) -> Option<Vec<String>> {
+    let values = schema.get("enum").and_then(|values| values.as_array()).cloned()
+        .or_else(|| schema.get("const").map(|constant| vec![constant.clone()]))?;
+    Some(values.iter().map(|value| match value {
+        Value::String(value) => value.clone(),
+        value => value.to_string(),
+    }).collect())
+}
+
+fn schema_type(schema: &Value) -> Option<&str> {
+    match schema.get("type").and_then(|schema_type| schema_type.as_str()) {
+        Some(schema_type) => Some(schema_type),
+        None if schema.get("properties").is_some() => Some("object"),
+        None if schema.get("items").is_some() => Some("array"),
+        None => None,
+    }
+}
+
+///the schema of the values of a map (HashMap<String, T>), None for structs
+fn map_values(schema: &Value) -> Option<&Value> {
+    match schema.get("additionalProperties") {
+        Some(Value::Bool(false)) | None => None,
+        Some(values) if schema.get("properties").is_none() => Some(values),
+        Some(_) => None,
+    }
+}
+
+struct XmlTemplate<'a> {
+    root: &'a Value,
+    in_progress: BTreeSet<String>,
+    out: String,
+}
+
+impl<'a> XmlTemplate<'a> {
+    fn line(&mut self, indent: usize, text: &str, comments: &[String]) {
+        self.out.push_str(&"  ".repeat(indent));
+        self.out.push_str(text);
+        if !comments.is_empty() {
+            self.out.push_str(&format!(" <!-- {} -->", comments.join(", ")));
+        }
+        self.out.push('\n');
+    }
+
+    fn render(&mut self, name: &str, schema: &Value, indent: usize, mut comments: Vec<String>) -> Result<(), Error> {
+        let (schema, definition) = resolve(self.root, schema)?;
+        if let Some(definition) = definition {
+            // a recursive type is only rendered once
+            if !self.in_progress.insert(definition.to_string()) {
+                comments.push(format!("same structure as <{}>", definition));
+                self.line(indent, &format!("<{}>...</{}>", name, name), &comments);
+                return Ok(());
+            }
+        }
+        if let Some(description) = schema.get("description").and_then(|description| description.as_str()) {
+            comments.push(description.to_string());
+        }
+        self.render_resolved(name, schema, indent, comments)?;
+        if let Some(definition) = definition {
+            self.in_progress.remove(definition);
+        }
+        Ok(())
+    }
+
+    fn render_resolved(&mut self, name: &str, schema: &Value, indent: usize, mut comments: Vec<String>) -> Result<(), Error> {
+        let (alternatives, nullable) = non_null_alternatives(schema);
+        if nullable {
+            comments.push("can be empty".to_string());
+        }
+        if alternatives.len() > 1 {
+            if let Some(values) = alternatives.iter().map(enum_values).collect::<Option<Vec<Vec<String>>>>() {
+                self.line(indent, &format!("<{}>one of: {}</{}>", name, values.concat().join(", "), name), &comments);
+                return Ok(());
+            }
+            comments.push("one of the following".to_string());
+            self.line(indent, &format!("<{}>", name), &comments);
+            for (index, alternative) in alternatives.iter().enumerate() {
+                if index > 0 {
+                    self.line(indent + 1, "<!-- or -->", &[]);
+                }
+                self.render_alternative(alternative, indent + 1)?;
+            }
+            self.line(indent, &format!("</{}>", name), &[]);
+            return Ok(());
+        }
+        // null was stripped from an Option, the remaining alternative is rendered instead
+        if let Some(alternative) = alternatives.first().filter(|alternative| *alternative != schema) {
+            return self.render(name, alternative, indent, comments);
+        }
+
+        if let Some(values) = enum_values(schema) {
+            self.line(indent, &format!("<{}>one of: {}</{}>", name, values.join(", "), name), &comments);
+            return Ok(());
+        }
+        match schema_type(schema) {
+            Some("object") => {
+                if let Some(values) = map_values(schema) {
+                    comments.push("one tag per key, the tag name is the key".to_string());
+                    self.line(indent, &format!("<{}>", name), &comments);
+                    self.render("key", values, indent + 1, Vec::new())?;
+                    self.line(indent + 1, "...", &[]);
+                    self.line(indent, &format!("</{}>", name), &[]);
+                    return Ok(());
+                }
+                self.line(indent, &format!("<{}>", name), &comments);
+                let required = required(schema);
+                if let Some(properties) = schema.get("properties").and_then(|properties| properties.as_object()) {
+                    for (property, property_schema) in properties {
+                        let comments = match required.contains(property.as_str()) {
+                            true => Vec::new(),
+                            false => vec!["optional".to_string()],
+                        };
+                        self.render(property, property_schema, indent + 1, comments)?;
+                    }
+                }
+                self.line(indent, &format!("</{}>", name), &[]);
+            }
+            Some("array") => {
+                self.line(indent, &format!("<{}>", name), &comments);
+                match schema.get("items") {
+                    Some(items) => self.render("item", items, indent + 1, Vec::new())?,
+                    None => self.line(indent + 1, "<item>any value</item>", &[]),
+                }
+                self.line(indent + 1, "...", &[]);
+                self.line(indent, &format!("</{}>", name), &[]);
+            }
+            Some("boolean") => self.line(indent, &format!("<{}>true or false</{}>", name, name), &comments),
+            Some(schema_type) => self.line(indent, &format!("<{}>{}</{}>", name, schema_type, name), &comments),
+            None => self.line(indent, &format!("<{}>any value</{}>", name, name), &comments),
+        }
+        Ok(())
+    }
+
+    ///an alternative of an enum with data, a variant is an object with a single property named after it
+    fn render_alternative(&mut self, alternative: &Value, indent: usize) -> Result<(), Error> {
+        let (resolved, _) = resolve(self.root, alternative)?;
+        if let Some(values) = enum_values(resolved) {
+            self.line(indent, &values.join(" or "), &[]);
+            return Ok(());
+        }
+        match resolved.get("properties").and_then(|properties| properties.as_object()) {
+            Some(properties) if properties.len() == 1 && map_values(resolved).is_none() => {
+                let (variant, variant_schema) = properties.iter().next().expect("checked above");
+                self.render(variant, variant_schema, indent, Vec::new())
+            }
+            _ => self.render("value", resolved, indent, Vec::new()),
+        }
+    }
+}
+
+fn required(schema: &Value) -> BTreeSet<&str> {
+    schema.get("required").and_then(|required| required.as_array())
+        .map(|required| required.iter().filter_map(|name| name.as_str()).collect())
+        .unwrap_or_default()
+}
+
+///an element in the reply, inner is the text between the tags and raw the whole element
+struct Element<'t> {
+    name: &'t str,
+    inner: &'t str,
+    raw: &'t str,
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '-' || c == '.' || c == ':'
+}
+
+///returns the elements at the top level of content, text between them is skipped
+fn children(content: &str) -> Result<Vec<Element<'_>>, Error> {
+    let mut elements = Vec::new();
+    let mut pos = 0;
+    while let Some(offset) = content[pos..].find('<') {
+        let start = pos + offset;
+        let rest = &content[start + 1..];
+        if rest.starts_with("!--") {
+            pos = match rest.find("-->") {
+                Some(end) => start + 1 + end + 3,
+                None => content.len(),
+            };
+            continue;
+        }
+        let name_len = rest.find(|c: char| !is_name_char(c)).unwrap_or(rest.len());
+        let name = &rest[..name_len];
+        let tag_end = rest.find('>');
+        // a "<" that does not start a tag is text, e.g. "a < b" or a stray closing tag
+        let (name, tag_end) = match (name.chars().next(), tag_end) {
+            (Some(first), Some(tag_end)) if (first.is_alphabetic() || first == '_') && !rest[..tag_end].contains('<') => (name, tag_end),
+            _ => {
+                pos = start + 1;
+                continue;
+            }
+        };
+        let open_end = start + 1 + tag_end + 1;
+        if rest[..tag_end].ends_with('/') {
+            elements.push(Element { name, inner: "", raw: &content[start..open_end] });
+            pos = open_end;
+            continue;
+        }
+        match find_close(content, open_end, name) {
+            Some((inner_end, close_end)) => {
+                elements.push(Element { name, inner: &content[open_end..inner_end], raw: &content[start..close_end] });
+                pos = close_end;
+            }
+            None => return Err(Error::XmlError(format!(
+                "the tag <{}> is not closed, expected </{}> in: {}", name, name, quote(&content[start..])
+            ))),
+        }
+    }
+    Ok(elements)
+}
+
+///returns the start and the end of the closing tag matching an element opened before from
+fn find_close(content: &str, from: usize, name: &str) -> Option<(usize, usize)> {
+    let mut depth = 1;
+    let mut pos = from;
+    while let Some(offset) = content[pos..].find('<') {
+        let start = pos + offset;
+        let rest = &content[start + 1..];
+        if let Some(closing) = rest.strip_prefix('/').and_then(|rest| rest.strip_prefix(name)) {
+            let trimmed = closing.trim_start();
+            if trimmed.starts_with('>') {
+                depth -= 1;
+                if depth == 0 {
+                    return Some((start, content.len() - trimmed.len() + 1));
+                }
+            }
+        } else if let Some(opening) = rest.strip_prefix(name) {
+            let tag = opening.find('>').map(|end| &opening[..end]);
+            let starts_tag = opening.starts_with(|c: char| c == '>' || c == '/' || c.is_whitespace());
+            if starts_tag && tag.is_some_and(|tag| !tag.ends_with('/')) {
+                depth += 1;
+            }
+        }
+        pos = start + 1;
+    }
+    None
+}
+
+///long elements are shortened when they are quoted in an error
+fn quote(raw: &str) -> String {
+    const MAX_LEN: usize = 200;
+    match raw.char_indices().nth(MAX_LEN) {
+        Some((end, _)) => format!("{}...", &raw[..end]),
+        None => raw.to_string(),
+    }
+}
+
+fn unescape(text: &str) -> String {
+    let text = text.trim();
+    let text = text.strip_prefix("<![CDATA[").and_then(|text| text.strip_suffix("]]>")).unwrap_or(text);
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+struct XmlParser<'a> {
+    root: &'a Value,
+}
+
+impl<'a> XmlParser<'a> {
+    ///parses the content of the element <name> with the schema
+    fn parse(&self, content: &str, schema: &Value, name: &str) -> Result<Value, Error> {
+        let (schema, _) = resolve(self.root, schema)?;
+        let (alternatives, nullable) = non_null_alternatives(schema);
+        let text = content.trim();
+        if nullable && (text.is_empty() || text == "null") {
+            return Ok(Value::Null);
+        }
+        if alternatives.len() > 1 {
+            let mut last_error = None;
+            for alternative in alternatives.iter() {
+                match self.parse(content, alternative, name) {
+                    Ok(value) => return Ok(value),
+                    Err(e) => last_error = Some(e),
+                }
+            }
+            return Err(Error::XmlError(format!(
+                "<{}>{}</{}> does not match any of the options: {}",
+                name, quote(text), name, last_error.map(|e| e.to_string()).unwrap_or_default()
+            )));
+        }
+        let schema = alternatives.first().unwrap_or(schema);
+
+        if let Some(values) = enum_values(schema) {
+            let text = unescape(content);
+            return match schema.get("enum").or_else(|| schema.get("const")) {
+                Some(_) if values.contains(&text) => Ok(Value::String(text)),
+                _ => Err(Error::XmlError(format!(
+                    "<{}>{}</{}> is not one of: {}", name, quote(&text), name, values.join(", ")
+                ))),
+            };
+        }
+
+        let not_a = |expected: &str| Error::XmlError(format!("<{}>{}</{}> is not {}", name, quote(text), name, expected));
+        match schema_type(schema) {
+            Some("object") => self.parse_object(content, schema, name),
+            Some("array") => {
+                let items = schema.get("items").cloned().unwrap_or(Value::Bool(true));
+                children(content)?.iter()
+                    .map(|element| self.parse(element.inner, &items, element.name))
+                    .collect::<Result<Vec<Value>, Error>>()
+                    .map(Value::Array)
+            }
+            Some("string") => Ok(Value::String(unescape(content))),
+            Some("integer") => text.parse::<i64>().map(Value::from)
+                .or_else(|_| text.parse::<u64>().map(Value::from))
+                .map_err(|_| not_a("an integer")),
+            Some("number") => text.parse::<f64>().ok()
+                .and_then(Number::from_f64)
+                .map(Value::Number)
+                .ok_or_else(|| not_a("a number")),
+            Some("boolean") => match text.to_lowercase().as_str() {
+                "true" => Ok(Value::Bool(true)),
+                "false" => Ok(Value::Bool(false)),
+                _ => Err(not_a("true or false")),
+            },
+            Some("null") => Ok(Value::Null),
+            _ => Ok(serde_json::from_str(text).unwrap_or_else(|_| Value::String(unescape(content)))),
+        }
+    }
+
+    fn parse_object(&self, content: &str, schema: &Value, name: &str) -> Result<Value, Error> {
+        let elements = children(content)?;
+        let mut object = Map::new();
+        if let Some(values) = map_values(schema) {
+            for element in elements.iter() {
+                object.insert(element.name.to_string(), self.parse(element.inner, values, element.name)?);
+            }
+            return Ok(Value::Object(object));
+        }
+
+        let required = required(schema);
+        if let Some(properties) = schema.get("properties").and_then(|properties| properties.as_object()) {
+            for (property, property_schema) in properties {
+                match elements.iter().find(|element| element.name == property) {
+                    Some(element) => {
+                        let value = self.parse(element.inner, property_schema, property)
+                            .map_err(|e| match e {
+                                Error::XmlError(_) => e,
+                                e => Error::XmlError(format!("{} in {}", e, quote(element.raw))),
+                            })?;
+                        object.insert(property.clone(), value);
+                    }
+                    None if required.contains(property.as_str()) => {
+                        return Err(Error::XmlError(format!("missing the tag <{}> in <{}>", property, name)));
+                    }
+                    None => {}
+                }
+            }
+        }
+        Ok(Value::Object(object))
+    }
+}
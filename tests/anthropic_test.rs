@@ -0,0 +1,143 @@
+use schemars::JsonSchema;
+use validator::Validate;
+use serde::{Deserialize, Serialize};
+use model_traits_macro::derive_all;
+use instructor_rs::anthropic::{AnthropicClient, MessagesRequest, MessagesResponse, AnthropicToolChoice};
+use instructor_rs::enums::{IterableOrSingle, InstructorResponse};
+use instructor_rs::mode::Mode;
+use instructor_rs::patch::Patch;
+use instructor_rs::openai_schema::OpenAISchema;
+use instructor_rs::process_response::handle_response_model;
+use instructor_rs::backend::ChatRequest;
+use crate::common::mock_server::{MockServer, MockResponse, chat_request};
+
+#[derive_all]
+struct Weather {
+    #[validate(range(min = 1, max = 12))]
+    time: i64,
+    city: String,
+}
+
+fn tool_use_response(inputs: Vec<serde_json::Value>) -> serde_json::Value {
+    let content: Vec<serde_json::Value> = inputs.into_iter().enumerate().map(|(i, input)| {
+        serde_json::json!({"type": "tool_use", "id": format!("toolu_{}", i), "name": "Weather", "input": input})
+    }).collect();
+    serde_json::json!({
+        "id": "msg_1",
+        "type": "message",
+        "role": "assistant",
+        "content": content,
+        "model": "claude-3-opus-20240229",
+        "stop_reason": "tool_use",
+        "stop_sequence": null,
+        "usage": {"input_tokens": 10, "output_tokens": 20}
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_tool_with_input_schema() {
+        let mut kwargs = ChatRequest::new(chat_request("claude-3-opus-20240229", "what is the weather at 10 in new york?", false));
+        handle_response_model(&IterableOrSingle::Single(Weather::default()), Mode::ANTHROPIC_TOOLS, &mut kwargs).unwrap();
+        let request = MessagesRequest::from_chat_request(&kwargs.request).unwrap();
+
+        let tools = request.tools.unwrap();
+        assert_eq!(tools.len(), 1);
+        assert_eq!(tools[0].name, "Weather");
+        assert_eq!(tools[0].input_schema["required"], serde_json::json!(["city", "time"]));
+        assert_eq!(request.tool_choice, Some(AnthropicToolChoice::Tool { name: "Weather".to_string() }));
+
+        let body = serde_json::to_value(MessagesRequest::from_chat_request(&kwargs.request).unwrap()).unwrap();
+        assert_eq!(body["tool_choice"], serde_json::json!({"type": "tool", "name": "Weather"}));
+        assert!(body["tools"][0].get("input_schema").is_some());
+    }
+
+    #[test]
+    fn rejects_streaming() {
+        let mut kwargs = ChatRequest::new(chat_request("claude-3-opus-20240229", "what is the weather at 10 in new york?", false));
+        kwargs.request.stream = Some(true);
+        let res = handle_response_model(&IterableOrSingle::Single(Weather::default()), Mode::ANTHROPIC_TOOLS, &mut kwargs);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn parse_anthropic_tools_single_and_iterable() {
+        let response: MessagesResponse = serde_json::from_value(tool_use_response(vec![
+            serde_json::json!({"time": 10, "city": "New York"}),
+        ])).unwrap();
+        let res = Weather::parse_anthropic_tools(&IterableOrSingle::Single(Weather::default()), &response, &()).unwrap();
+        assert_eq!(res.unwrap().unwrap().city, "New York");
+
+        let response: MessagesResponse = serde_json::from_value(tool_use_response(vec![
+            serde_json::json!({"time": 10, "city": "New York"}),
+            serde_json::json!({"time": 8, "city": "Copenhagen"}),
+        ])).unwrap();
+        match Weather::parse_anthropic_tools(&IterableOrSingle::Iterable(Weather::default()), &response, &()).unwrap() {
+            InstructorResponse::Many(items) => assert_eq!(items.len(), 2),
+            _ => panic!("expected many"),
+        }
+
+        let single = Weather::parse_anthropic_tools(&IterableOrSingle::Single(Weather::default()), &response, &());
+        assert!(single.is_err());
+    }
+
+    #[tokio::test]
+    async fn reasks_with_tool_result_error() {
+        let server = MockServer::start(vec![
+            MockResponse::json(200, tool_use_response(vec![serde_json::json!({"time": 22, "city": "New York"})])),
+            MockResponse::json(200, tool_use_response(vec![serde_json::json!({"time": 10, "city": "New York"})])),
+        ]).await;
+
+        let client = AnthropicClient::new()
+            .with_api_key("test-key")
+            .with_api_base(format!("{}/v1", server.url));
+        let patched_client = Patch { client, mode: Some(Mode::ANTHROPIC_TOOLS) };
+        let res = patched_client.chat_completion(
+            IterableOrSingle::Single(Weather::default()),
+            (),
+            2,
+            chat_request("claude-3-opus-20240229", "what is the weather at 10 in new york?", false),
+        ).await.unwrap();
+        assert_eq!(res.unwrap().unwrap().time, 10);
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].path, "/v1/messages");
+        assert_eq!(requests[0].header("x-api-key"), Some("test-key"));
+        assert!(requests[0].header("anthropic-version").is_some());
+
+        let messages = requests[1].body["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[1]["role"], "assistant");
+        assert_eq!(messages[1]["content"][0]["type"], "tool_use");
+        assert_eq!(messages[2]["role"], "user");
+        let tool_result = &messages[2]["content"][0];
+        assert_eq!(tool_result["type"], "tool_result");
+        assert_eq!(tool_result["tool_use_id"], "toolu_0");
+        assert_eq!(tool_result["is_error"], true);
+    }
+
+    #[tokio::test]
+    async fn surfaces_api_errors() {
+        let server = MockServer::start(vec![
+            MockResponse::json(400, serde_json::json!({
+                "type": "error",
+                "error": {"type": "invalid_request_error", "message": "max_tokens: field required"}
+            })),
+        ]).await;
+
+        let client = AnthropicClient::new().with_api_base(format!("{}/v1", server.url));
+        let patched_client = Patch { client, mode: Some(Mode::ANTHROPIC_TOOLS) };
+        let res = patched_client.chat_completion(
+            IterableOrSingle::Single(Weather::default()),
+            (),
+            1,
+            chat_request("claude-3-opus-20240229", "what is the weather at 10 in new york?", false),
+        ).await;
+        let err = res.unwrap_err().to_string();
+        assert!(err.contains("max_tokens: field required"), "{}", err);
+    }
+}
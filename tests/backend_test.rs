@@ -0,0 +1,140 @@
+use schemars::JsonSchema;
+use validator::Validate;
+use serde::{Deserialize, Serialize};
+use model_traits_macro::derive_all;
+use std::sync::{Arc, Mutex};
+use instructor_rs::backend::{ChatBackend, BackendFuture, ChatRequest, OpenAIBackend};
+use instructor_rs::enums::{IterableOrSingle, InstructorResponse, ChatCompletionResponseWrapper};
+use instructor_rs::error::Error;
+use instructor_rs::mode::Mode;
+use instructor_rs::patch::Patch;
+use instructor_rs::utils::{create_chat_completion_response, create_tool_call};
+use async_openai::Client;
+use async_openai::config::OpenAIConfig;
+use async_openai::types::CreateChatCompletionRequest;
+use futures::stream::StreamExt;
+use serde_json::json;
+use crate::common::mock_server::{MockServer, MockResponse, chat_request, chat_completion};
+
+#[derive_all]
+struct Number {
+    #[validate(range(min = 0))]
+    value: i64,
+}
+
+///an in-process backend that answers with canned tool calls and records every request
+#[derive(Clone, Default)]
+struct FakeBackend {
+    replies: Arc<Mutex<Vec<String>>>,
+    requests: Arc<Mutex<Vec<CreateChatCompletionRequest>>>,
+}
+
+impl ChatBackend for FakeBackend {
+    fn create(&self, request: ChatRequest) -> BackendFuture<ChatCompletionResponseWrapper> {
+        self.requests.lock().unwrap().push(request.request);
+        let reply = self.replies.lock().unwrap().remove(0);
+        Box::pin(async move {
+            let tool_call = create_tool_call("Number".to_string(), reply);
+            Ok(ChatCompletionResponseWrapper::AtOnce(
+                create_chat_completion_response(Some(vec![tool_call]), None)
+            ))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn patch_with_fake_backend() {
+        let backend = FakeBackend::default();
+        backend.replies.lock().unwrap().extend(vec![
+            "{\"value\": -1}".to_string(),
+            "{\"value\": 3}".to_string(),
+        ]);
+        let patched_client = Patch { client: backend.clone(), mode: Some(Mode::TOOLS) };
+
+        let res = patched_client.chat_completion(
+            IterableOrSingle::Single(Number::default()),
+            (),
+            2,
+            chat_request("fake", "give me a number", false),
+        ).await.unwrap();
+        assert_eq!(res.unwrap().unwrap().value, 3);
+
+        let requests = backend.requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].tools.as_ref().unwrap()[0].function.name, "Number");
+        // the second request carries the re-ask
+        assert_eq!(requests[1].messages.len(), 3);
+    }
+
+    #[tokio::test]
+    async fn openai_backend_sends_with_its_http_client() {
+        let body = format!("data: {}\n\ndata: [DONE]\n\n", json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion.chunk",
+            "created": 0,
+            "model": "gpt-4o",
+            "choices": [{"index": 0, "delta": {"content": "[{\"value\": 2}]"}, "finish_reason": "stop", "logprobs": null}],
+        }));
+        let server = MockServer::start(vec![
+            MockResponse::json(200, json!({
+                "id": "chatcmpl-1",
+                "object": "chat.completion",
+                "created": 0,
+                "model": "gpt-4o",
+                "choices": [{"index": 0, "message": {"role": "assistant", "content": "{\"value\": 1}"}, "finish_reason": "stop", "logprobs": null}],
+            })),
+            MockResponse { status: 200, content_type: "text/event-stream".to_string(), body, headers: Vec::new() },
+        ]).await;
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert("x-gateway-token", "secret".parse().unwrap());
+        let http_client = reqwest::Client::builder().default_headers(headers).build().unwrap();
+        let client = Client::with_config(OpenAIConfig::new().with_api_key("test").with_api_base(&server.url));
+        let patched_client = Patch { client: OpenAIBackend::new(client).with_http_client(http_client), mode: Some(Mode::JSON) };
+
+        let res = patched_client.chat_completion(IterableOrSingle::Single(Number::default()), (), 1, chat_request("fake", "give me a number", false)).await.unwrap();
+        assert_eq!(res.unwrap().unwrap().value, 1);
+        let res = patched_client.chat_completion(IterableOrSingle::Iterable(Number::default()), (), 1, chat_request("fake", "give me a number", true)).await.unwrap();
+        let values: Vec<i64> = match res {
+            InstructorResponse::Stream(stream) => stream.map(|number| number.unwrap().value).collect().await,
+            _ => panic!("expected a stream"),
+        };
+        assert_eq!(values, vec![2]);
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert!(requests.iter().all(|request| request.header("x-gateway-token") == Some("secret")));
+    }
+
+    #[tokio::test]
+    async fn client_sends_a_plain_request_with_its_http_client() {
+        let server = MockServer::start(vec![chat_completion("gpt-4o", "{\"value\": 1}")]).await;
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert("x-gateway-token", "secret".parse().unwrap());
+        let http_client = reqwest::Client::builder().default_headers(headers).build().unwrap();
+        let client = Client::with_config(OpenAIConfig::new().with_api_key("test").with_api_base(&server.url)).with_http_client(http_client);
+        let patched_client = Patch { client, mode: Some(Mode::JSON) };
+
+        let res = patched_client.chat_completion(IterableOrSingle::Single(Number::default()), (), 1, chat_request("fake", "give me a number", false)).await.unwrap();
+        assert_eq!(res.unwrap().unwrap().value, 1);
+        assert_eq!(server.requests()[0].header("x-gateway-token"), Some("secret"));
+    }
+
+    #[tokio::test]
+    async fn streaming_is_not_implemented_by_default() {
+        let patched_client = Patch { client: FakeBackend::default(), mode: Some(Mode::JSON) };
+        let res = patched_client.chat_completion(
+            IterableOrSingle::Iterable(Number::default()),
+            (),
+            1,
+            chat_request("fake", "give me a number", true),
+        ).await;
+        match res {
+            Err(Error::NotImplementedError(_)) => {},
+            other => panic!("expected NotImplementedError, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,200 @@
+#![allow(dead_code)]
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use async_openai::types::{
+    CreateChatCompletionRequest, CreateChatCompletionRequestArgs, ChatCompletionRequestMessage,
+    ChatCompletionRequestUserMessage, ChatCompletionRequestUserMessageContent, Role, CompletionUsage,
+    ChatChoiceStream, ChatCompletionStreamResponseDelta, CreateChatCompletionStreamResponse
+};
+use serde_json::json;
+
+///a canned http response returned by the MockServer
+#[derive(Debug, Clone)]
+pub struct MockResponse {
+    pub status: u16,
+    pub content_type: String,
+    pub body: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl MockResponse {
+    pub fn json(status: u16, body: serde_json::Value) -> Self {
+        MockResponse {
+            status,
+            content_type: "application/json".to_string(),
+            body: body.to_string(),
+            headers: Vec::new(),
+        }
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    ///adds the usage field to a json response
+    pub fn with_usage(mut self, usage: &CompletionUsage) -> Self {
+        let mut body: serde_json::Value = serde_json::from_str(&self.body).unwrap();
+        body["usage"] = json!(usage);
+        self.body = body.to_string();
+        self
+    }
+}
+
+///a chat request of `model` with `prompt` as its only (user) message
+pub fn chat_request(model: &str, prompt: &str, stream: bool) -> CreateChatCompletionRequest {
+    CreateChatCompletionRequestArgs::default()
+        .model(model)
+        .messages(vec![
+            ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage{
+                role: Role::User,
+                content: ChatCompletionRequestUserMessageContent::Text(prompt.to_string()),
+                name: None,
+            })
+        ])
+        .stream(stream)
+        .build()
+        .unwrap()
+}
+
+///an openai chat completion of `model` answering with the assistant message `content`
+pub fn chat_completion(model: &str, content: &str) -> MockResponse {
+    chat_completion_message(model, json!({"role": "assistant", "content": content}))
+}
+
+///an openai chat completion of `model` answering with `message`
+pub fn chat_completion_message(model: &str, message: serde_json::Value) -> MockResponse {
+    MockResponse::json(200, json!({
+        "id": "chatcmpl-1",
+        "object": "chat.completion",
+        "created": 0,
+        "model": model,
+        "choices": [{"index": 0, "message": message, "finish_reason": "stop", "logprobs": null}],
+    }))
+}
+
+///a chunk of a streamed chat completion of `model` with the text `content`
+#[allow(deprecated)]
+pub fn chunk(model: &str, content: &str) -> CreateChatCompletionStreamResponse {
+    CreateChatCompletionStreamResponse {
+        id: "chatcmpl-1".to_string(),
+        object: "chat.completion.chunk".to_string(),
+        created: 0,
+        model: model.to_string(),
+        system_fingerprint: None,
+        choices: vec![ChatChoiceStream {
+            index: 0,
+            finish_reason: None,
+            logprobs: None,
+            delta: ChatCompletionStreamResponseDelta {
+                content: Some(content.to_string()),
+                function_call: None,
+                tool_calls: None,
+                role: None,
+            },
+        }],
+    }
+}
+
+///a request recorded by the MockServer
+#[derive(Debug, Clone)]
+pub struct MockRequest {
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: serde_json::Value,
+}
+
+impl MockRequest {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+}
+
+///a minimal http server on localhost that answers each request with the next canned response,
+/// this lets us test the provider clients without any network access
+pub struct MockServer {
+    pub url: String,
+    requests: Arc<Mutex<Vec<MockRequest>>>,
+}
+
+impl MockServer {
+    pub async fn start(responses: Vec<MockResponse>) -> MockServer {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let recorded = requests.clone();
+
+        tokio::spawn(async move {
+            let mut responses = responses.into_iter();
+            loop {
+                let (mut socket, _) = match listener.accept().await {
+                    Ok(conn) => conn,
+                    Err(_) => return,
+                };
+                let request = match read_request(&mut socket).await {
+                    Some(request) => request,
+                    None => continue,
+                };
+                recorded.lock().unwrap().push(request);
+
+                let response = responses.next().unwrap_or_else(|| MockResponse::json(
+                    500, serde_json::json!({"error": {"message": "no more mock responses"}})
+                ));
+                let headers: String = response.headers.iter().map(|(name, value)| format!("{}: {}\r\n", name, value)).collect();
+                let head = format!(
+                    "HTTP/1.1 {} OK\r\ncontent-type: {}\r\ncontent-length: {}\r\n{}connection: close\r\n\r\n",
+                    response.status, response.content_type, response.body.len(), headers
+                );
+                let _ = socket.write_all(head.as_bytes()).await;
+                let _ = socket.write_all(response.body.as_bytes()).await;
+                let _ = socket.shutdown().await;
+            }
+        });
+
+        MockServer { url, requests }
+    }
+
+    pub fn requests(&self) -> Vec<MockRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+async fn read_request(socket: &mut tokio::net::TcpStream) -> Option<MockRequest> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0u8; 4096];
+    let header_end = loop {
+        let n = socket.read(&mut chunk).await.ok()?;
+        if n == 0 {
+            return None;
+        }
+        buffer.extend_from_slice(&chunk[..n]);
+        if let Some(index) = buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+            break index + 4;
+        }
+    };
+
+    let head = String::from_utf8_lossy(&buffer[..header_end]).to_string();
+    let mut lines = head.lines();
+    let path = lines.next()?.split_whitespace().nth(1)?.to_string();
+    let headers: Vec<(String, String)> = lines
+        .filter_map(|line| line.split_once(':'))
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .collect();
+    let content_length = headers.iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.parse::<usize>().ok())
+        .unwrap_or(0);
+
+    while buffer.len() < header_end + content_length {
+        let n = socket.read(&mut chunk).await.ok()?;
+        if n == 0 {
+            break;
+        }
+        buffer.extend_from_slice(&chunk[..n]);
+    }
+    let body = serde_json::from_slice(&buffer[header_end..]).unwrap_or(serde_json::Value::Null);
+    Some(MockRequest { path, headers, body })
+}
@@ -0,0 +1,96 @@
+use schemars::JsonSchema;
+use validator::Validate;
+use serde::{Deserialize, Serialize};
+use model_traits_macro::derive_all;
+use instructor_rs::backend::ChatRequest;
+use instructor_rs::completion::completion_prompt;
+use instructor_rs::enums::IterableOrSingle;
+use instructor_rs::error::Error;
+use instructor_rs::mode::Mode;
+use instructor_rs::patch::Patch;
+use instructor_rs::process_response::handle_response_model;
+use instructor_rs::common::GPT3_5_TURBO_INSTRUCT;
+use async_openai::Client;
+use async_openai::config::OpenAIConfig;
+use async_openai::types::CreateChatCompletionRequest;
+use serde_json::json;
+use crate::common::mock_server::{MockServer, MockResponse, chat_request};
+
+#[derive_all]
+struct UserInfo {
+    name: String,
+    #[validate(range(min = 0, max = 150))]
+    age: u8,
+}
+
+fn request() -> CreateChatCompletionRequest {
+    CreateChatCompletionRequest {
+        max_tokens: Some(200),
+        ..chat_request(GPT3_5_TURBO_INSTRUCT, "John Doe is 30 years old", false)
+    }
+}
+
+fn completion(text: &str) -> MockResponse {
+    MockResponse::json(200, json!({
+        "id": "cmpl-1",
+        "object": "text_completion",
+        "created": 0,
+        "model": GPT3_5_TURBO_INSTRUCT,
+        "choices": [{"text": text, "index": 0, "logprobs": null, "finish_reason": "stop"}],
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flattens_messages_into_prompt() {
+        let mut kwargs = ChatRequest::new(request());
+        handle_response_model(&IterableOrSingle::Single(UserInfo::default()), Mode::COMPLETION, &mut kwargs).unwrap();
+        assert!(kwargs.request.response_format.is_none());
+
+        let prompt = completion_prompt(&kwargs.request.messages).unwrap();
+        assert!(prompt.starts_with("As a genius expert"));
+        assert!(prompt.contains("UserInfo"));
+        assert!(prompt.contains("\n\nUser: John Doe is 30 years old\n\nAssistant:"));
+        assert!(prompt.ends_with("Assistant:"));
+
+        let mut kwargs = ChatRequest::new(request());
+        kwargs.request.stream = Some(true);
+        let res = handle_response_model(&IterableOrSingle::Iterable(UserInfo::default()), Mode::COMPLETION, &mut kwargs);
+        assert!(matches!(res, Err(Error::NotImplementedError(_))));
+    }
+
+    #[tokio::test]
+    async fn reasks_in_prompt_text() {
+        let server = MockServer::start(vec![
+            completion(" Sure: {\"name\": \"John Doe\", \"age\": 300}"),
+            completion(" {\"name\": \"John Doe\", \"age\": 30}"),
+        ]).await;
+
+        let client = Client::with_config(OpenAIConfig::new().with_api_key("test").with_api_base(&server.url));
+        let patched_client = Patch { client, mode: Some(Mode::COMPLETION) };
+        let res = patched_client.chat_completion(
+            IterableOrSingle::Single(UserInfo::default()),
+            (),
+            2,
+            request(),
+        ).await.unwrap();
+        let user = res.unwrap().unwrap();
+        assert_eq!(user.name, "John Doe");
+        assert_eq!(user.age, 30);
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].path, "/completions");
+        assert_eq!(requests[0].body["model"], GPT3_5_TURBO_INSTRUCT);
+        assert_eq!(requests[0].body["max_tokens"], 200);
+        assert!(requests[0].body.get("messages").is_none());
+
+        let prompt = requests[1].body["prompt"].as_str().unwrap();
+        assert!(prompt.contains("Assistant:  Sure: {\"name\": \"John Doe\", \"age\": 300}"));
+        assert!(prompt.contains("User: Recall the function correctly, fix the errors"));
+        assert!(prompt.ends_with("Assistant:"));
+    }
+}
@@ -0,0 +1,145 @@
+use schemars::JsonSchema;
+use validator::Validate;
+use serde::{Deserialize, Serialize};
+use model_traits_macro::derive_all;
+use instructor_rs::enums::IterableOrSingle;
+use instructor_rs::error::{Error, DeserializeError};
+use instructor_rs::json_stream::json_pointer_at;
+use instructor_rs::mode::Mode;
+use instructor_rs::openai_schema::OpenAISchema;
+use instructor_rs::utils::create_chat_completion_response;
+use instructor_rs::retry::reask_messages;
+use async_openai::types::{ChatCompletionRequestMessage, ChatCompletionRequestUserMessage, ChatCompletionRequestUserMessageContent};
+use serde_json::json;
+
+#[derive(JsonSchema, Serialize, Debug, Default, Deserialize, Clone, PartialEq)]
+enum Priority {
+    #[default]
+    Low,
+    High,
+}
+
+#[derive_all]
+struct Address {
+    city: String,
+    zip: u32,
+}
+
+#[derive_all]
+struct Task {
+    #[validate(length(min = 1))]
+    title: String,
+    priority: Priority,
+    address: Address,
+}
+
+fn errors(result: Result<impl std::fmt::Debug, Error>) -> Vec<DeserializeError> {
+    match result {
+        Err(Error::DeserializeErrors(errors)) => errors,
+        other => panic!("expected deserialization errors, got {:?}", other),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pointer_of_a_nested_value() {
+        let text = "{\"a\": [1, {\"b/c\": \"x\"}], \"d\": {}}";
+        assert_eq!(json_pointer_at(text, text.find("\"x\"").unwrap() + 3), "/a/1/b~1c");
+        assert_eq!(json_pointer_at(text, text.find('[').unwrap() + 1), "/a");
+        assert_eq!(json_pointer_at(text, text.len() - 1), "/d");
+        assert_eq!(json_pointer_at(text, text.len()), "");
+    }
+
+    #[test]
+    fn single_reports_the_path_value_and_expected_type() {
+        let data = "{\"title\": \"write\", \"priority\": \"Low\", \"address\": {\"city\": \"Oslo\", \"zip\": \"0150\"}}";
+        let result = Task::model_validate_json(&IterableOrSingle::Single(Task::default()), data, &());
+        let errors = errors(result);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].item, None);
+        assert_eq!(errors[0].path, "/address/zip");
+        assert_eq!(errors[0].value, Some(json!("0150")));
+        assert_eq!(errors[0].expected.as_deref(), Some("u32"));
+    }
+
+    #[test]
+    fn every_invalid_item_is_reported() {
+        let data = concat!(
+            "{\"title\": \"a\", \"priority\": \"Low\", \"address\": {\"city\": \"Oslo\", \"zip\": 150}},",
+            "{\"title\": \"b\", \"priority\": \"Urgent\", \"address\": {\"city\": \"Oslo\", \"zip\": 150}},",
+            "{\"title\": \"c\", \"priority\": \"High\", \"address\": {\"city\": \"Oslo\"}}",
+        );
+        let result = Task::model_validate_json(&IterableOrSingle::Iterable(Task::default()), data, &());
+        let errors = errors(result);
+        assert_eq!(errors.len(), 2);
+        assert_eq!(errors[0].item, Some(1));
+        assert_eq!(errors[0].path, "/1/priority");
+        assert_eq!(errors[0].value, Some(json!("Urgent")));
+        assert_eq!(errors[0].expected.as_deref(), Some("`Low` or `High`"));
+        // a missing field has no value
+        assert_eq!(errors[1].item, Some(2));
+        assert_eq!(errors[1].path, "/2/address/zip");
+        assert_eq!(errors[1].value, None);
+        assert_eq!(errors[1].message, "missing field `zip`");
+    }
+
+    #[test]
+    fn validation_errors_of_every_item_are_reported() {
+        let data = concat!(
+            "{\"title\": \"\", \"priority\": \"Low\", \"address\": {\"city\": \"Oslo\", \"zip\": 150}},",
+            "{\"title\": \"b\", \"priority\": \"Low\", \"address\": {\"city\": \"Oslo\", \"zip\": 150}},",
+            "{\"title\": \"\", \"priority\": \"High\", \"address\": {\"city\": \"Oslo\", \"zip\": 150}}",
+        );
+        let result = Task::model_validate_json(&IterableOrSingle::Iterable(Task::default()), data, &());
+        match result {
+            Err(Error::ValidationErrors(errors)) => {
+                let message = errors.to_string();
+                assert!(message.contains("items[0].title"), "{}", message);
+                assert!(message.contains("items[2].title"), "{}", message);
+                assert!(!message.contains("items[1]"), "{}", message);
+            }
+            other => panic!("expected validation errors, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn items_wrapper_of_a_json_schema_response_reports_the_path() {
+        let response = create_chat_completion_response(None, Some("{\"items\": {\"title\": \"a\"}}".to_string()));
+        let result = Task::from_response(&IterableOrSingle::Iterable(Task::default()), &response, &(), Mode::JSON_SCHEMA);
+        let wrapper_errors = errors(result);
+        assert_eq!(wrapper_errors.len(), 1);
+        assert_eq!(wrapper_errors[0].path, "/items");
+        assert_eq!(wrapper_errors[0].expected.as_deref(), Some("a sequence"));
+
+        let response = create_chat_completion_response(None, Some(
+            "{\"items\": [{\"title\": \"a\", \"priority\": \"Low\", \"address\": {\"city\": \"Oslo\", \"zip\": -1}}]}".to_string()
+        ));
+        let result = Task::from_response(&IterableOrSingle::Iterable(Task::default()), &response, &(), Mode::JSON_SCHEMA);
+        let errors = errors(result);
+        assert_eq!(errors[0].item, Some(0));
+        assert_eq!(errors[0].path, "/0/address/zip");
+    }
+
+    #[test]
+    fn invalid_json_stays_a_serde_error() {
+        let result = Task::model_validate_json(&IterableOrSingle::Single(Task::default()), "{\"title\": ", &());
+        assert!(matches!(result, Err(Error::SerdeError(_))));
+    }
+
+    #[test]
+    fn reask_names_the_field_and_the_item() {
+        let data = "{\"title\": \"a\", \"priority\": \"Low\", \"address\": {\"city\": \"Oslo\", \"zip\": 1}}, {\"title\": 2, \"priority\": \"Low\", \"address\": {\"city\": \"Oslo\", \"zip\": 1}}";
+        let result = Task::model_validate_json(&IterableOrSingle::Iterable(Task::default()), data, &());
+        let exception = result.err().unwrap().to_string();
+        let messages = reask_messages(data.to_string(), None, Mode::JSON, &exception);
+        match &messages[1] {
+            ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage { content: ChatCompletionRequestUserMessageContent::Text(text), .. }) => {
+                assert!(text.contains("the field `/1/title` of item 2: invalid type: integer `2`, expected a string, the value was 2"), "{}", text);
+            }
+            other => panic!("expected a user message, got {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use schemars::JsonSchema;
+use validator::Validate;
+use serde::{Deserialize, Serialize};
+use model_traits_macro::derive_all;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use instructor_rs::backend::{ChatBackend, BackendFuture, ChatRequest};
+use instructor_rs::enums::{IterableOrSingle, InstructorResponse, ChatCompletionResponseWrapper};
+use instructor_rs::error::Error;
+use instructor_rs::mode::Mode;
+use instructor_rs::patch::Patch;
+use instructor_rs::timeout::Timeouts;
+use async_openai::types::ChatCompletionResponseStream;
+use async_stream::stream;
+use futures::stream::StreamExt;
+use crate::common::mock_server::{chat_request, chunk};
+
+#[derive_all]
+struct Record {
+    #[validate(range(min = 0))]
+    id: i64,
+    name: String,
+}
+
+///sets the flag when the stream of the backend is dropped, i.e. when the connection would be closed
+struct DropFlag(Arc<AtomicBool>);
+
+impl Drop for DropFlag {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+///a backend that streams the given chunks and then keeps the connection open forever,
+/// the stream only ends if it is cancelled
+#[derive(Clone)]
+struct EndlessBackend {
+    chunks: Vec<&'static str>,
+    dropped: Arc<AtomicBool>,
+}
+
+impl EndlessBackend {
+    fn new(chunks: Vec<&'static str>) -> Self {
+        EndlessBackend { chunks, dropped: Arc::new(AtomicBool::new(false)) }
+    }
+}
+
+impl ChatBackend for EndlessBackend {
+    fn create(&self, _request: ChatRequest) -> BackendFuture<ChatCompletionResponseWrapper> {
+        Box::pin(async move { Err(Error::NotImplementedError("only streaming".to_string())) })
+    }
+
+    fn create_stream(&self, _request: ChatRequest) -> BackendFuture<ChatCompletionResponseStream> {
+        let this = self.clone();
+        Box::pin(async move {
+            let stream = stream! {
+                let _flag = DropFlag(this.dropped.clone());
+                for content in this.chunks.iter() {
+                    yield Ok(chunk("fake", content));
+                }
+                futures::future::pending::<()>().await;
+            };
+            Ok(Box::pin(stream) as ChatCompletionResponseStream)
+        })
+    }
+}
+
+const CHUNKS: [&str; 4] = [
+    "[{\"id\": 1, \"name\": \"ada\"}, ",
+    "{\"id\": 2, \"name\": \"bob\"}, ",
+    "{\"id\": 3, \"name\": \"cy\"}, ",
+    "{\"id\": 4, \"name\": \"dee\"}, ",
+];
+
+fn records(records: Vec<Result<Record, Error>>) -> Vec<Result<i64, ()>> {
+    records.into_iter().map(|record| record.map(|record| record.id).map_err(|_| ())).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn take_validated_cancels_the_request() {
+        let backend = EndlessBackend::new(CHUNKS.to_vec());
+        let patched_client = Patch { client: backend.clone(), mode: Some(Mode::JSON) };
+        let response = patched_client.chat_completion(
+            IterableOrSingle::Iterable(Record::default()),
+            (),
+            0,
+            chat_request("fake", "find the records", true),
+        ).await.unwrap().take_validated(2);
+        let mut stream = match response {
+            InstructorResponse::Stream(stream) => stream,
+            _ => panic!("expected a stream"),
+        };
+
+        assert_eq!(stream.next().await.unwrap().unwrap().id, 1);
+        assert_eq!(stream.next().await.unwrap().unwrap().id, 2);
+        // the request is dropped before the last item is handed out
+        assert!(backend.dropped.load(Ordering::SeqCst));
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn until_sees_every_valid_item() {
+        let backend = EndlessBackend::new(CHUNKS.to_vec());
+        let patched_client = Patch { client: backend.clone(), mode: Some(Mode::JSON) };
+        let mut short_names = 0;
+        let response = patched_client.chat_completion(
+            IterableOrSingle::Iterable(Record::default()),
+            (),
+            0,
+            chat_request("fake", "find the records", true),
+        ).await.unwrap().until(move |record: &Record| {
+            short_names += (record.name.len() <= 2) as usize;
+            short_names == 1
+        });
+        let outputs = match response {
+            InstructorResponse::Stream(stream) => records(stream.collect().await),
+            _ => panic!("expected a stream"),
+        };
+        assert_eq!(outputs, vec![Ok(1), Ok(2), Ok(3)]);
+        assert!(backend.dropped.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn cancelled_stream_is_reported_in_metadata() {
+        let backend = EndlessBackend::new(CHUNKS.to_vec());
+        let patched_client = Patch { client: backend.clone(), mode: Some(Mode::JSON) };
+        let (response, metadata) = patched_client.chat_completion_with_metadata(
+            IterableOrSingle::Iterable(Record::default()),
+            (),
+            0,
+            chat_request("fake", "find the records", true),
+            Timeouts::default(),
+        ).await;
+        let outputs = match response.unwrap().take_validated(1) {
+            InstructorResponse::Stream(stream) => records(stream.collect().await),
+            _ => panic!("expected a stream"),
+        };
+        assert_eq!(outputs, vec![Ok(1)]);
+
+        let metadata = metadata.finished().await;
+        assert!(metadata.usage.is_empty());
+        assert_eq!(metadata.unreported_requests, 1);
+        assert!(metadata.unreported_chunks >= 1);
+        assert_eq!(metadata.id.as_deref(), Some("chatcmpl-1"));
+    }
+
+    #[test]
+    fn adapters_truncate_many() {
+        let many = |ids: &[i64]| InstructorResponse::Many(ids.iter().map(|id| Record { id: *id, name: String::new() }).collect());
+        let ids = |response: InstructorResponse<Record>| match response {
+            InstructorResponse::Many(records) => records.into_iter().map(|record| record.id).collect::<Vec<_>>(),
+            _ => panic!("expected many"),
+        };
+        assert_eq!(ids(many(&[1, 2, 3]).take_validated(2)), vec![1, 2]);
+        assert_eq!(ids(many(&[1, 2, 3]).take_validated(0)), Vec::<i64>::new());
+        assert_eq!(ids(many(&[1, 2, 3]).until(|record| record.id == 2)), vec![1, 2]);
+        assert_eq!(ids(many(&[1, 2, 3]).until(|_| false)), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn take_validated_zero_keeps_one_and_unwraps_to_an_error() {
+        let record = Record { id: 1, name: String::new() };
+        let response = InstructorResponse::One(record).take_validated(0);
+        assert_eq!(response.unwrap().unwrap().id, 1);
+
+        let response = InstructorResponse::Many(vec![Record { id: 1, name: String::new() }]).take_validated(0);
+        assert!(matches!(response.unwrap(), Err(Error::Generic(_))));
+    }
+}
@@ -0,0 +1,162 @@
+use schemars::JsonSchema;
+use validator::Validate;
+use serde::{Deserialize, Serialize};
+use model_traits_macro::derive_all;
+use instructor_rs::backend::{ChatBackend, BackendFuture, ChatRequest};
+use instructor_rs::dsl::events::StreamEvent;
+use instructor_rs::enums::{IterableOrSingle, ChatCompletionResponseWrapper};
+use instructor_rs::error::Error;
+use instructor_rs::json_stream::{JsonEvent, JsonEventParser};
+use instructor_rs::mode::Mode;
+use instructor_rs::patch::Patch;
+use instructor_rs::retry_policy::RetryPolicy;
+use instructor_rs::timeout::Timeouts;
+use async_openai::error::OpenAIError;
+use async_openai::types::ChatCompletionResponseStream;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use futures::stream::{self, StreamExt};
+use serde_json::json;
+use crate::common::mock_server::{chat_request, chunk};
+
+#[derive_all]
+struct Report {
+    #[validate(length(min = 1))]
+    title: String,
+    summary: String,
+    tags: Vec<String>,
+}
+
+///a backend that streams the given chunks as content
+#[derive(Clone)]
+struct ChunkBackend {
+    chunks: Vec<&'static str>,
+}
+
+impl ChatBackend for ChunkBackend {
+    fn create(&self, _request: ChatRequest) -> BackendFuture<ChatCompletionResponseWrapper> {
+        Box::pin(async move { Err(Error::NotImplementedError("only streaming".to_string())) })
+    }
+
+    fn create_stream(&self, _request: ChatRequest) -> BackendFuture<ChatCompletionResponseStream> {
+        let chunks = self.chunks.iter().map(|content| Ok(chunk("fake", content))).collect::<Vec<_>>();
+        Box::pin(async move { Ok(stream::iter(chunks).boxed()) })
+    }
+}
+
+///a backend whose first stream request fails, the next one streams the chunks and then hangs
+#[derive(Clone)]
+struct HangingBackend {
+    chunks: Vec<&'static str>,
+    requests: Arc<Mutex<usize>>,
+}
+
+impl ChatBackend for HangingBackend {
+    fn create(&self, _request: ChatRequest) -> BackendFuture<ChatCompletionResponseWrapper> {
+        Box::pin(async move { Err(Error::NotImplementedError("only streaming".to_string())) })
+    }
+
+    fn create_stream(&self, _request: ChatRequest) -> BackendFuture<ChatCompletionResponseStream> {
+        let mut requests = self.requests.lock().unwrap();
+        *requests += 1;
+        if *requests == 1 {
+            return Box::pin(async move { Err(Error::OpenAIError(OpenAIError::StreamError("connection reset".to_string()))) });
+        }
+        let chunks = self.chunks.iter().map(|content| Ok(chunk("fake", content))).collect::<Vec<_>>();
+        Box::pin(async move { Ok(stream::iter(chunks).chain(stream::pending()).boxed()) })
+    }
+}
+
+const TEXT: &str = r#"Here you go: [{"title": "a \"quoted\" title", "meta": {"pages": 12, "draft": false},
+"sections": [{"title": "intro"}, {"title": "caf\u00e9 \ud83d\ude00"}], "summary": "line one\nline two"}]"#;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_field_events() {
+        // the events do not depend on where the chunks are split, apart from how the text of a field is split into deltas
+        for chunk_size in [1, 3, 10, TEXT.len()] {
+            let mut parser = JsonEventParser::new();
+            let chars: Vec<char> = TEXT.chars().collect();
+            let events: Vec<JsonEvent> = chars.chunks(chunk_size)
+                .flat_map(|chunk| parser.push(&chunk.iter().collect::<String>()))
+                .collect();
+
+            let completed: Vec<(String, serde_json::Value)> = events.iter().filter_map(|event| match event {
+                JsonEvent::FieldCompleted { path, value } => Some((path.clone(), value.clone())),
+                _ => None,
+            }).collect();
+            assert_eq!(completed, vec![
+                ("title".to_string(), json!("a \"quoted\" title")),
+                ("meta.pages".to_string(), json!(12)),
+                ("meta.draft".to_string(), json!(false)),
+                ("meta".to_string(), json!({"pages": 12, "draft": false})),
+                ("sections[0].title".to_string(), json!("intro")),
+                ("sections[0]".to_string(), json!({"title": "intro"})),
+                ("sections[1].title".to_string(), json!("café 😀")),
+                ("sections[1]".to_string(), json!({"title": "café 😀"})),
+                ("sections".to_string(), json!([{"title": "intro"}, {"title": "café 😀"}])),
+                ("summary".to_string(), json!("line one\nline two")),
+            ], "chunk size {}", chunk_size);
+
+            let summary: String = events.iter().filter_map(|event| match event {
+                JsonEvent::FieldDelta { path, text } if path == "summary" => Some(text.as_str()),
+                _ => None,
+            }).collect();
+            assert_eq!(summary, "line one\nline two");
+            assert_eq!(events.first(), Some(&JsonEvent::ObjectStarted { index: 0 }));
+            assert!(matches!(events.last(), Some(JsonEvent::ObjectEnded { index: 0, json }) if json.starts_with("{\"title\"")));
+        }
+    }
+
+    #[tokio::test]
+    async fn streams_events_of_every_object() {
+        let backend = ChunkBackend { chunks: vec![
+            "[{\"title\": \"first\", \"summary\": \"a long",
+            " summary that ",
+            "arrives in pieces\", \"tags\": [\"x\"]}, ",
+            "{\"title\": \"\", \"summary\": \"\", \"tags\": []}]",
+        ] };
+        let patched_client = Patch { client: backend, mode: Some(Mode::JSON) };
+        let events: Vec<StreamEvent<Report>> = patched_client.chat_completion_events(
+            IterableOrSingle::Iterable(Report::default()),
+            (),
+            chat_request("fake", "write the reports", false),
+        ).await.unwrap().map(|event| event.unwrap()).collect().await;
+
+        let deltas: Vec<&str> = events.iter().filter_map(|event| match event {
+            StreamEvent::FieldDelta { path, text } if path == "summary" => Some(text.as_str()),
+            _ => None,
+        }).collect();
+        assert_eq!(deltas, vec!["a long", " summary that ", "arrives in pieces"]);
+
+        assert!(matches!(events[0], StreamEvent::ObjectStarted { index: 0 }));
+        let ends: Vec<&StreamEvent<Report>> = events.iter()
+            .filter(|event| matches!(event, StreamEvent::ObjectCompleted { .. } | StreamEvent::ValidationFailed { .. }))
+            .collect();
+        assert!(matches!(ends[0], StreamEvent::ObjectCompleted { index: 0, object } if object.tags == vec!["x".to_string()]));
+        assert!(matches!(ends[1], StreamEvent::ValidationFailed { index: 1, errors: Error::ValidationErrors(_) }));
+        assert!(matches!(events.last(), Some(StreamEvent::ValidationFailed { .. })));
+    }
+
+    #[tokio::test]
+    async fn events_with_policy_retry_the_request_and_end_with_idle_timeout() {
+        let backend = HangingBackend { chunks: vec!["{\"title\": \"first\", \"summary\": \"cut"], requests: Arc::new(Mutex::new(0)) };
+        let patched_client = Patch { client: backend.clone(), mode: Some(Mode::JSON) };
+        let policy = RetryPolicy { max_transport_retries: 1, initial_backoff: Duration::from_millis(10), jitter: false, ..Default::default() };
+        let timeouts = Timeouts { deadline: None, idle_timeout: Some(Duration::from_millis(50)) };
+        let events: Vec<Result<StreamEvent<Report>, Error>> = patched_client.chat_completion_events_with_policy(
+            IterableOrSingle::Single(Report::default()),
+            (),
+            policy,
+            chat_request("fake", "write the report", false),
+            timeouts,
+        ).await.unwrap().collect().await;
+
+        assert_eq!(*backend.requests.lock().unwrap(), 2);
+        assert!(matches!(events[0], Ok(StreamEvent::ObjectStarted { index: 0 })));
+        assert!(matches!(events.last(), Some(Err(Error::IdleTimeout(_)))));
+    }
+}
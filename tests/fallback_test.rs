@@ -0,0 +1,224 @@
+use schemars::JsonSchema;
+use validator::Validate;
+use serde::{Deserialize, Serialize};
+use model_traits_macro::derive_all;
+use instructor_rs::enums::{IterableOrSingle, InstructorResponse};
+use instructor_rs::error::Error;
+use instructor_rs::fallback::{Fallbacks, FallbackModel, FallbackConversation};
+use instructor_rs::mode::Mode;
+use instructor_rs::patch::Patch;
+use instructor_rs::backend::{ChatBackend, BackendFuture, ChatRequest};
+use instructor_rs::enums::ChatCompletionResponseWrapper;
+use instructor_rs::timeout::Timeouts;
+use instructor_rs::utils::create_chat_completion_response;
+use std::time::{Duration, Instant};
+use async_openai::Client;
+use async_openai::config::OpenAIConfig;
+use async_openai::error::OpenAIError;
+use serde_json::json;
+use crate::common::mock_server::{MockServer, MockResponse, chat_request, chat_completion};
+
+#[derive_all]
+struct Number {
+    #[validate(range(min = 0))]
+    value: i64,
+}
+
+fn patch(server: &MockServer) -> Patch<Client<OpenAIConfig>> {
+    let client = Client::with_config(OpenAIConfig::new().with_api_key("test").with_api_base(&server.url));
+    Patch { client, mode: Some(Mode::JSON) }
+}
+
+fn tool_call(arguments: &str) -> MockResponse {
+    MockResponse::json(200, json!({
+        "id": "chatcmpl-1",
+        "object": "chat.completion",
+        "created": 0,
+        "model": "gpt-4o",
+        "choices": [{
+            "index": 0,
+            "message": {"role": "assistant", "content": null, "tool_calls": [
+                {"id": "call_1", "type": "function", "function": {"name": "Number", "arguments": arguments}}
+            ]},
+            "finish_reason": "tool_calls",
+            "logprobs": null
+        }],
+    }))
+}
+
+///answers every request with an invalid number after a delay
+#[derive(Clone)]
+struct SlowBackend {
+    delay: Duration,
+}
+
+impl ChatBackend for SlowBackend {
+    fn create(&self, _request: ChatRequest) -> BackendFuture<ChatCompletionResponseWrapper> {
+        let delay = self.delay;
+        Box::pin(async move {
+            tokio::time::sleep(delay).await;
+            Ok(ChatCompletionResponseWrapper::AtOnce(create_chat_completion_response(None, Some("{\"value\": -1}".to_string()))))
+        })
+    }
+}
+
+fn value(response: InstructorResponse<Number>) -> i64 {
+    response.unwrap().unwrap().value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn next_model_answers_in_its_own_mode() {
+        let server = MockServer::start(vec![
+            chat_completion("gpt-4o", "{\"value\": -1}"),
+            chat_completion("gpt-4o", "{\"value\": -2}"),
+            tool_call("{\"value\": 5}"),
+        ]).await;
+        let fallbacks = Fallbacks::new(vec![
+            FallbackModel::new("cheap-model"),
+            FallbackModel::with_mode("smart-model", Mode::TOOLS),
+        ], 2);
+        let response = patch(&server).chat_completion_with_fallbacks(
+            IterableOrSingle::Single(Number::default()),
+            (),
+            fallbacks,
+            chat_request("ignored", "give me a number", false),
+            Timeouts::default(),
+        ).await.unwrap();
+        assert_eq!(response.model, "smart-model");
+        assert_eq!(response.mode, Mode::TOOLS);
+        assert_eq!(response.index, 1);
+        assert_eq!(value(response.response), 5);
+
+        let requests = server.requests();
+        let models: Vec<&str> = requests.iter().map(|request| request.body["model"].as_str().unwrap()).collect();
+        assert_eq!(models, vec!["cheap-model", "cheap-model", "smart-model"]);
+        // the request is prepared for the mode of the model
+        assert_eq!(requests[0].body["response_format"]["type"], "json_object");
+        assert!(requests[2].body.get("response_format").is_none());
+        assert_eq!(requests[2].body["tools"][0]["function"]["name"], "Number");
+        // the conversation is reset by default
+        assert_eq!(requests[2].body["messages"].as_array().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn carry_over_sends_the_previous_outputs() {
+        let server = MockServer::start(vec![
+            chat_completion("gpt-4o", "{\"value\": -1}"),
+            chat_completion("gpt-4o", "{\"value\": 4}"),
+        ]).await;
+        let fallbacks = Fallbacks {
+            conversation: FallbackConversation::CarryOver,
+            ..Fallbacks::new(vec!["cheap-model".into(), "smart-model".into()], 1)
+        };
+        let response = patch(&server).chat_completion_with_fallbacks(
+            IterableOrSingle::Single(Number::default()),
+            (),
+            fallbacks,
+            chat_request("ignored", "give me a number", false),
+            Timeouts::default(),
+        ).await.unwrap();
+        assert_eq!(response.model, "smart-model");
+        assert_eq!(value(response.response), 4);
+
+        let requests = server.requests();
+        let first = requests[0].body["messages"].as_array().unwrap().clone();
+        let second = requests[1].body["messages"].as_array().unwrap();
+        assert_eq!(second.len(), first.len() + 2);
+        assert_eq!(second[..first.len()], first[..]);
+        assert_eq!(second[first.len()]["content"], "{\"value\": -1}");
+        assert_eq!(second[first.len() + 1]["role"], "user");
+    }
+
+    #[tokio::test]
+    async fn every_attempt_is_reported_when_all_models_fail() {
+        let server = MockServer::start(vec![
+            chat_completion("gpt-4o", "{\"value\": -1}"),
+            chat_completion("gpt-4o", "{\"value\": -2}"),
+        ]).await;
+        let fallbacks = Fallbacks::new(vec!["cheap-model".into(), "smart-model".into()], 1);
+        let response = patch(&server).chat_completion_with_fallbacks(
+            IterableOrSingle::Single(Number::default()),
+            (),
+            fallbacks,
+            chat_request("ignored", "give me a number", false),
+            Timeouts::default(),
+        ).await;
+        match response {
+            Err(Error::RetryError(error)) => {
+                let models: Vec<&str> = error.attempts.iter().map(|attempt| attempt.model.as_str()).collect();
+                assert_eq!(models, vec!["cheap-model", "smart-model"]);
+            }
+            _ => panic!("expected a retry error"),
+        }
+    }
+
+    #[tokio::test]
+    async fn other_errors_end_the_chain() {
+        let server = MockServer::start(vec![
+            MockResponse::json(401, json!({"error": {"message": "Invalid API key", "type": "invalid_request_error", "param": null, "code": null}})),
+        ]).await;
+        let fallbacks = Fallbacks::new(vec!["cheap-model".into(), "smart-model".into()], 1);
+        let response = patch(&server).chat_completion_with_fallbacks(
+            IterableOrSingle::Single(Number::default()),
+            (),
+            fallbacks,
+            chat_request("ignored", "give me a number", false),
+            Timeouts::default(),
+        ).await;
+        assert!(matches!(response, Err(Error::OpenAIError(OpenAIError::ApiError(ref e))) if e.message == "Invalid API key"));
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn carry_over_turns_tool_messages_into_text() {
+        let server = MockServer::start(vec![
+            tool_call("{\"value\": -1}"),
+            chat_completion("gpt-4o", "{\"value\": 4}"),
+        ]).await;
+        let fallbacks = Fallbacks {
+            conversation: FallbackConversation::CarryOver,
+            ..Fallbacks::new(vec![
+                FallbackModel::with_mode("tool-model", Mode::TOOLS),
+                FallbackModel::with_mode("json-model", Mode::JSON),
+            ], 1)
+        };
+        let response = patch(&server).chat_completion_with_fallbacks(
+            IterableOrSingle::Single(Number::default()),
+            (),
+            fallbacks,
+            chat_request("ignored", "give me a number", false),
+            Timeouts::default(),
+        ).await.unwrap();
+        assert_eq!(value(response.response), 4);
+
+        let requests = server.requests();
+        let messages = requests[1].body["messages"].as_array().unwrap();
+        assert!(messages.iter().all(|message| message["role"] != "tool" && message.get("tool_calls").is_none()), "{:?}", messages);
+        let carried = &messages[messages.len() - 2..];
+        assert_eq!(carried[0]["role"], "assistant");
+        assert_eq!(carried[0]["content"], "{\"value\": -1}");
+        assert_eq!(carried[1]["role"], "user");
+        assert!(carried[1]["content"].as_str().unwrap().contains("Validation Error found"), "{:?}", carried);
+    }
+
+    #[tokio::test]
+    async fn deadline_covers_the_whole_chain() {
+        let patched_client = Patch { client: SlowBackend { delay: Duration::from_millis(150) }, mode: Some(Mode::JSON) };
+        let fallbacks = Fallbacks::new(vec!["first".into(), "second".into(), "third".into()], 1);
+        let timeouts = Timeouts { deadline: Some(Duration::from_millis(250)), idle_timeout: None };
+        let start = Instant::now();
+        let response = patched_client.chat_completion_with_fallbacks(
+            IterableOrSingle::Single(Number::default()),
+            (),
+            fallbacks,
+            chat_request("ignored", "give me a number", false),
+            timeouts,
+        ).await;
+        assert!(matches!(response, Err(Error::DeadlineExceeded(deadline)) if deadline == Duration::from_millis(250)));
+        assert!(start.elapsed() < Duration::from_millis(400));
+    }
+}
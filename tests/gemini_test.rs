@@ -0,0 +1,187 @@
+use schemars::JsonSchema;
+use validator::Validate;
+use serde::{Deserialize, Serialize};
+use model_traits_macro::derive_all;
+use instructor_rs::backend::ChatRequest;
+use instructor_rs::enums::{IterableOrSingle, InstructorResponse};
+use instructor_rs::error::Error;
+use instructor_rs::gemini::{gemini_schema, GeminiClient, GenerateContentRequest, GenerateContentResponse};
+use instructor_rs::mode::Mode;
+use instructor_rs::openai_schema::OpenAISchema;
+use instructor_rs::patch::Patch;
+use instructor_rs::process_response::handle_response_model;
+use serde_json::json;
+use crate::common::mock_server::{MockServer, MockResponse, chat_request};
+
+#[derive_all]
+struct Address {
+    city: String,
+    zip: Option<String>,
+}
+
+#[derive_all]
+struct Weather {
+    #[validate(range(min = 1, max = 12))]
+    time: u8,
+    #[schemars(description = "where to look up the weather")]
+    address: Address,
+}
+
+#[derive_all]
+struct Node {
+    value: i64,
+    next: Option<Box<Node>>,
+}
+
+fn function_call_response(args: Vec<serde_json::Value>) -> serde_json::Value {
+    let parts: Vec<serde_json::Value> = args.into_iter().map(|args| {
+        json!({"functionCall": {"name": "Weather", "args": args}})
+    }).collect();
+    json!({
+        "candidates": [{"content": {"role": "model", "parts": parts}, "finishReason": "STOP"}],
+        "usageMetadata": {"promptTokenCount": 10, "candidatesTokenCount": 5, "totalTokenCount": 15}
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn translates_schema_to_openapi_subset() {
+        let schema = serde_json::to_value(schemars::schema_for!(Weather)).unwrap();
+        let translated = gemini_schema(&schema).unwrap();
+        assert_eq!(translated, json!({
+            "type": "OBJECT",
+            "properties": {
+                "address": {
+                    "type": "OBJECT",
+                    "description": "where to look up the weather",
+                    "properties": {
+                        "city": {"type": "STRING"},
+                        "zip": {"type": "STRING", "nullable": true},
+                    },
+                    "required": ["city"],
+                },
+                "time": {"type": "INTEGER"},
+            },
+            "required": ["address", "time"],
+        }));
+
+        let schema = serde_json::to_value(schemars::schema_for!(Node)).unwrap();
+        assert!(matches!(gemini_schema(&schema), Err(Error::NotImplementedError(_))));
+    }
+
+    #[test]
+    fn renders_function_declaration() {
+        let mut kwargs = ChatRequest::new(chat_request("gemini-1.5-flash", "what is the weather at 10 in Paris?", false));
+        handle_response_model(&IterableOrSingle::Single(Weather::default()), Mode::GEMINI_TOOLS, &mut kwargs).unwrap();
+        let body = serde_json::to_value(GenerateContentRequest::from_chat_request(&kwargs).unwrap()).unwrap();
+
+        let declaration = &body["tools"][0]["functionDeclarations"][0];
+        assert_eq!(declaration["name"], "Weather");
+        assert_eq!(declaration["parameters"]["properties"]["address"]["properties"]["city"]["type"], "STRING");
+        assert!(!body.to_string().contains("$ref"));
+        assert_eq!(body["toolConfig"], json!({
+            "functionCallingConfig": {"mode": "ANY", "allowedFunctionNames": ["Weather"]}
+        }));
+        assert_eq!(body["contents"][0]["role"], "user");
+        assert!(body.get("model").is_none());
+
+        let mut kwargs = ChatRequest::new(chat_request("gemini-1.5-flash", "what is the weather at 10 in Paris?", false));
+        kwargs.request.stream = Some(true);
+        let res = handle_response_model(&IterableOrSingle::Single(Weather::default()), Mode::GEMINI_TOOLS, &mut kwargs);
+        assert!(matches!(res, Err(Error::NotImplementedError(_))));
+    }
+
+    #[test]
+    fn json_mode_sets_the_response_schema() {
+        let mut kwargs = ChatRequest::new(chat_request("gemini-1.5-flash", "what is the weather at 10 in Paris?", false));
+        handle_response_model(&IterableOrSingle::Iterable(Weather::default()), Mode::GEMINI_JSON, &mut kwargs).unwrap();
+        let body = serde_json::to_value(GenerateContentRequest::from_chat_request(&kwargs).unwrap()).unwrap();
+
+        let config = &body["generationConfig"];
+        assert_eq!(config["responseMimeType"], "application/json");
+        assert_eq!(config["responseSchema"]["required"], json!(["items"]));
+        assert_eq!(config["responseSchema"]["properties"]["items"]["items"]["properties"]["address"]["type"], "OBJECT");
+        assert!(body.get("tools").is_none());
+
+        let response: GenerateContentResponse = serde_json::from_value(json!({
+            "candidates": [{"content": {"role": "model", "parts": [
+                {"text": "{\"items\": [{\"time\": 10, \"address\": {\"city\": \"Paris\", \"zip\": null}}]}"}
+            ]}}]
+        })).unwrap();
+        match Weather::parse_gemini(&IterableOrSingle::Iterable(Weather::default()), &response, &(), Mode::GEMINI_JSON).unwrap() {
+            InstructorResponse::Many(items) => assert_eq!(items[0].address.city, "Paris"),
+            _ => panic!("expected many"),
+        }
+    }
+
+    #[test]
+    fn parses_function_calls() {
+        let response: GenerateContentResponse = serde_json::from_value(function_call_response(vec![
+            json!({"time": 10, "address": {"city": "Paris"}}),
+            json!({"time": 11, "address": {"city": "Lyon"}}),
+        ])).unwrap();
+        match Weather::parse_gemini(&IterableOrSingle::Iterable(Weather::default()), &response, &(), Mode::GEMINI_TOOLS).unwrap() {
+            InstructorResponse::Many(items) => assert_eq!(items.len(), 2),
+            _ => panic!("expected many"),
+        }
+        let single = Weather::parse_gemini(&IterableOrSingle::Single(Weather::default()), &response, &(), Mode::GEMINI_TOOLS);
+        assert!(single.is_err());
+    }
+
+    #[tokio::test]
+    async fn reasks_with_function_response() {
+        let server = MockServer::start(vec![
+            MockResponse::json(200, function_call_response(vec![json!({"time": 22, "address": {"city": "Paris"}})])),
+            MockResponse::json(200, function_call_response(vec![json!({"time": 10, "address": {"city": "Paris"}})])),
+        ]).await;
+
+        let client = GeminiClient::new()
+            .with_api_key("test-key")
+            .with_api_base(format!("{}/v1beta", server.url));
+        let patched_client = Patch { client, mode: Some(Mode::GEMINI_TOOLS) };
+        let res = patched_client.chat_completion(
+            IterableOrSingle::Single(Weather::default()),
+            (),
+            2,
+            chat_request("gemini-1.5-flash", "what is the weather at 10 in Paris?", false),
+        ).await.unwrap();
+        assert_eq!(res.unwrap().unwrap().time, 10);
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].path, "/v1beta/models/gemini-1.5-flash:generateContent");
+        assert_eq!(requests[0].header("x-goog-api-key"), Some("test-key"));
+
+        let contents = requests[1].body["contents"].as_array().unwrap();
+        assert_eq!(contents.len(), 3);
+        assert_eq!(contents[1]["role"], "model");
+        assert_eq!(contents[1]["parts"][0]["functionCall"]["name"], "Weather");
+        assert_eq!(contents[2]["role"], "user");
+        let function_response = &contents[2]["parts"][0]["functionResponse"];
+        assert_eq!(function_response["name"], "Weather");
+        assert!(function_response["response"]["error"].as_str().unwrap().contains("Validation Error found"));
+    }
+
+    #[tokio::test]
+    async fn surfaces_api_errors() {
+        let server = MockServer::start(vec![
+            MockResponse::json(400, json!({
+                "error": {"code": 400, "message": "Invalid JSON payload received.", "status": "INVALID_ARGUMENT"}
+            })),
+        ]).await;
+
+        let client = GeminiClient::new().with_api_base(server.url.clone());
+        let patched_client = Patch { client, mode: Some(Mode::GEMINI_TOOLS) };
+        let res = patched_client.chat_completion(
+            IterableOrSingle::Single(Weather::default()),
+            (),
+            1,
+            chat_request("gemini-1.5-flash", "what is the weather at 10 in Paris?", false),
+        ).await;
+        let err = res.unwrap_err().to_string();
+        assert!(err.contains("Invalid JSON payload received."), "{}", err);
+    }
+}
@@ -0,0 +1,194 @@
+use schemars::JsonSchema;
+use validator::Validate;
+use serde::{Deserialize, Serialize};
+use model_traits_macro::derive_all;
+use instructor_rs::backend::ChatRequest;
+use instructor_rs::enums::{IterableOrSingle, InstructorResponse};
+use instructor_rs::error::Error;
+use instructor_rs::json_schema::strict_json_schema;
+use instructor_rs::mode::Mode;
+use instructor_rs::openai_schema::OpenAISchema;
+use instructor_rs::patch::Patch;
+use instructor_rs::process_response::handle_response_model;
+use async_openai::Client;
+use async_openai::config::OpenAIConfig;
+use futures::stream::StreamExt;
+use serde_json::json;
+use crate::common::mock_server::{MockServer, MockResponse, chat_request, chat_completion_message};
+
+#[derive(JsonSchema, Serialize, Debug, Default, Deserialize, Clone)]
+enum Unit {
+    #[default]
+    Celsius,
+    Fahrenheit,
+}
+
+#[derive_all]
+struct Location {
+    city: String,
+    country: Option<String>,
+}
+
+#[derive_all]
+struct Forecast {
+    #[validate(range(min = -100, max = 100))]
+    temperature: i64,
+    unit: Unit,
+    location: Location,
+    #[schemars(description = "where the forecast was measured")]
+    station: Option<Location>,
+    note: Option<String>,
+}
+
+fn client(server: &MockServer) -> Client<OpenAIConfig> {
+    Client::with_config(OpenAIConfig::new().with_api_key("test").with_api_base(&server.url))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strict_schema_requires_every_property() {
+        let schema = Forecast::strict_json_schema();
+        assert_eq!(schema["additionalProperties"], json!(false));
+        assert_eq!(schema["required"], json!(["location", "note", "station", "temperature", "unit"]));
+        assert_eq!(schema["properties"]["note"]["type"], json!(["string", "null"]));
+        assert_eq!(schema["properties"]["station"]["anyOf"][1], json!({"type": "null"}));
+        assert_eq!(schema["properties"]["station"]["description"], json!("where the forecast was measured"));
+        assert!(schema["properties"]["temperature"].get("format").is_none());
+        assert!(schema.get("$schema").is_none());
+
+        let location = &schema["definitions"]["Location"];
+        assert_eq!(location["additionalProperties"], json!(false));
+        assert_eq!(location["required"], json!(["city", "country"]));
+    }
+
+    #[test]
+    fn strict_schema_rewrites_one_of() {
+        let schema = strict_json_schema(json!({
+            "oneOf": [{"type": "object", "properties": {"a": {"type": "integer", "minimum": 0}}}],
+        }));
+        assert_eq!(schema, json!({
+            "anyOf": [{
+                "type": "object",
+                "properties": {"a": {"type": ["integer", "null"]}},
+                "required": ["a"],
+                "additionalProperties": false,
+            }],
+        }));
+    }
+
+    #[test]
+    fn json_schema_mode_sends_response_format() {
+        let mut kwargs = ChatRequest::new(chat_request("gpt-4o", "it is 21 degrees in Paris", false));
+        handle_response_model(&IterableOrSingle::Single(Forecast::default()), Mode::JSON_SCHEMA, &mut kwargs).unwrap();
+        assert!(kwargs.request.response_format.is_none());
+        // the schema is enforced by the api so no system prompt is added
+        assert_eq!(kwargs.request.messages.len(), 1);
+
+        let body = kwargs.body().unwrap();
+        assert_eq!(body["response_format"]["type"], json!("json_schema"));
+        assert_eq!(body["response_format"]["json_schema"]["name"], json!("Forecast"));
+        assert_eq!(body["response_format"]["json_schema"]["strict"], json!(true));
+        assert_eq!(body["response_format"]["json_schema"]["schema"], Forecast::strict_json_schema());
+
+        let mut kwargs = ChatRequest::new(chat_request("gpt-4o", "it is 21 degrees in Paris", true));
+        handle_response_model(&IterableOrSingle::Iterable(Forecast::default()), Mode::JSON_SCHEMA, &mut kwargs).unwrap();
+        let schema = &kwargs.extra_body["response_format"]["json_schema"]["schema"];
+        assert_eq!(schema["required"], json!(["items"]));
+        assert_eq!(schema["properties"]["items"]["items"]["required"][0], json!("location"));
+        assert!(schema["definitions"].get("Location").is_some());
+    }
+
+    #[tokio::test]
+    async fn json_schema_round_trip() {
+        let server = MockServer::start(vec![
+            chat_completion_message("gpt-4o", json!({
+                "role": "assistant",
+                "content": "{\"items\": [{\"temperature\": 21, \"unit\": \"Celsius\", \"location\": {\"city\": \"Paris\", \"country\": null}, \"station\": null, \"note\": null}]}",
+            })),
+        ]).await;
+        let patched_client = Patch { client: client(&server), mode: Some(Mode::JSON_SCHEMA) };
+
+        let res = patched_client.chat_completion(
+            IterableOrSingle::Iterable(Forecast::default()),
+            (),
+            1,
+            chat_request("gpt-4o", "it is 21 degrees in Paris", false),
+        ).await.unwrap();
+        match res {
+            InstructorResponse::Many(forecasts) => {
+                assert_eq!(forecasts.len(), 1);
+                assert_eq!(forecasts[0].location.city, "Paris");
+                assert_eq!(forecasts[0].location.country, None);
+            },
+            _ => panic!("expected many"),
+        }
+
+        let requests = server.requests();
+        assert_eq!(requests[0].path, "/chat/completions");
+        assert_eq!(requests[0].header("authorization"), Some("Bearer test"));
+        assert_eq!(requests[0].body["response_format"]["type"], json!("json_schema"));
+    }
+
+    #[tokio::test]
+    async fn refusal_is_a_distinct_error() {
+        let server = MockServer::start(vec![
+            chat_completion_message("gpt-4o", json!({
+                "role": "assistant",
+                "content": null,
+                "refusal": "I'm sorry, I can't help with that.",
+            })),
+        ]).await;
+        let patched_client = Patch { client: client(&server), mode: Some(Mode::JSON_SCHEMA) };
+
+        let res = patched_client.chat_completion(
+            IterableOrSingle::Single(Forecast::default()),
+            (),
+            3,
+            chat_request("gpt-4o", "it is 21 degrees in Paris", false),
+        ).await;
+        match res {
+            Err(Error::Refusal(refusal)) => assert_eq!(refusal, "I'm sorry, I can't help with that."),
+            other => panic!("expected a refusal, got {:?}", other.map(|_| ())),
+        }
+        // a refusal is not re-asked
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn streamed_refusal_is_surfaced() {
+        let chunk = |delta: serde_json::Value| json!({
+            "id": "chatcmpl-1",
+            "object": "chat.completion.chunk",
+            "created": 0,
+            "model": "gpt-4o",
+            "choices": [{"index": 0, "delta": delta, "finish_reason": null, "logprobs": null}],
+        });
+        let body = [
+            chunk(json!({"role": "assistant", "refusal": "I'm sorry, "})),
+            chunk(json!({"refusal": "I can't help with that."})),
+        ].iter().map(|chunk| format!("data: {}\n\n", chunk)).collect::<String>() + "data: [DONE]\n\n";
+        let server = MockServer::start(vec![
+            MockResponse { status: 200, content_type: "text/event-stream".to_string(), body, headers: Vec::new() },
+        ]).await;
+        let patched_client = Patch { client: client(&server), mode: Some(Mode::JSON_SCHEMA) };
+
+        let res = patched_client.chat_completion(
+            IterableOrSingle::Iterable(Forecast::default()),
+            (),
+            1,
+            chat_request("gpt-4o", "it is 21 degrees in Paris", true),
+        ).await.unwrap();
+        let items: Vec<_> = match res {
+            InstructorResponse::Stream(stream) => stream.collect().await,
+            _ => panic!("expected a stream"),
+        };
+        assert_eq!(items.len(), 1);
+        match &items[0] {
+            Err(Error::Refusal(refusal)) => assert_eq!(refusal, "I'm sorry, I can't help with that."),
+            other => panic!("expected a refusal, got {:?}", other.as_ref().map(|_| ())),
+        }
+    }
+}
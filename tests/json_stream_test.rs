@@ -0,0 +1,199 @@
+use schemars::JsonSchema;
+use validator::Validate;
+use serde::{Deserialize, Serialize};
+use model_traits_macro::derive_all;
+use instructor_rs::dsl::iterable::IterableBase;
+use instructor_rs::enums::{IterableOrSingle, InstructorResponse};
+use instructor_rs::json_stream::{CodeFenceFilter, ItemsWrapperFilter, JsonObjectSplitter, skip_to_first_array};
+use instructor_rs::mode::Mode;
+use instructor_rs::utils::{create_chat_completion_stream, extract_json_from_stream_async, string_to_stream};
+use futures::stream::{self, StreamExt, TryStreamExt};
+
+#[derive_all]
+struct Snippet {
+    language: String,
+    code: String,
+    lines: Vec<Vec<i64>>,
+}
+
+fn snippets() -> Vec<Snippet> {
+    vec![
+        Snippet { language: "rust".to_string(), code: "fn main() { println!(\"{}\", \"}\"); }".to_string(), lines: vec![vec![1], vec![]] },
+        Snippet { language: "json".to_string(), code: "{\"a\": [1, {\"b\": \"\\\\\"}]}".to_string(), lines: vec![] },
+        Snippet { language: "text".to_string(), code: "unbalanced ]]} {{ \\".to_string(), lines: vec![vec![2, 3]] },
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ignores_braces_in_strings() {
+        let text = format!("Here is the \"json\": [{}]", snippets().iter()
+            .map(|snippet| serde_json::to_string(snippet).unwrap())
+            .collect::<Vec<String>>()
+            .join(", "));
+
+        // the result does not depend on where the chunks are split
+        for chunk_size in [1, 2, 3, 7, text.len()] {
+            let mut splitter = JsonObjectSplitter::new();
+            let chars: Vec<char> = text.chars().collect();
+            let objects: Vec<String> = chars.chunks(chunk_size)
+                .flat_map(|chunk| splitter.push(&chunk.iter().collect::<String>()))
+                .collect();
+            let parsed: Vec<Snippet> = objects.iter().map(|object| serde_json::from_str(object).unwrap()).collect();
+            assert_eq!(parsed.len(), 3, "chunk size {}", chunk_size);
+            for (parsed, snippet) in parsed.iter().zip(snippets()) {
+                assert_eq!(parsed.code, snippet.code);
+                assert_eq!(parsed.lines, snippet.lines);
+            }
+        }
+    }
+
+    #[test]
+    fn returns_objects_as_soon_as_they_close() {
+        let mut splitter = JsonObjectSplitter::new();
+        assert!(splitter.push("[{\"code\": \"fn a() {\"").is_empty());
+        assert_eq!(splitter.push("}, {\"co"), vec!["{\"code\": \"fn a() {\"}".to_string()]);
+        assert_eq!(splitter.push("de\": \"\\\"}\"}]"), vec!["{\"code\": \"\\\"}\"}".to_string()]);
+    }
+
+    #[test]
+    fn get_object_skips_braces_in_strings() {
+        let text = "} stray {\"code\": \"fn a() {\"} and then {\"b\": 1}";
+        let (object, rest) = Snippet::get_object(text, 0);
+        assert_eq!(object.as_deref(), Some("{\"code\": \"fn a() {\"}"));
+        assert_eq!(rest, " and then {\"b\": 1}");
+        // the search starts at index
+        let (object, rest) = Snippet::get_object(text, text.find("and").unwrap());
+        assert_eq!(object.as_deref(), Some("{\"b\": 1}"));
+        assert_eq!(rest, "");
+        assert_eq!(Snippet::get_object("{\"a\": ", 0), (None, "{\"a\": ".to_string()));
+    }
+
+    #[test]
+    fn runs_in_linear_time() {
+        // a quadratic rescan of the buffer on every chunk does not finish in reasonable time here
+        let code = "x".repeat(500_000);
+        let object = format!("{{\"language\": \"rust\", \"code\": \"{}\", \"lines\": []}}", code);
+        let mut splitter = JsonObjectSplitter::new();
+        let mut objects = Vec::new();
+        for c in object.chars() {
+            objects.extend(splitter.push(c.encode_utf8(&mut [0; 4])));
+        }
+        assert_eq!(objects, vec![object]);
+    }
+
+    #[tokio::test]
+    async fn skips_the_items_wrapper() {
+        let chunks = stream::iter(vec![
+            Ok("{\"it".to_string()),
+            Ok("ems\": [{\"a\": \"[\"}, ".to_string()),
+            Ok("{\"a\": 1}]}".to_string()),
+        ]).boxed();
+        let text: Vec<String> = skip_to_first_array(chunks).try_collect().await.unwrap();
+        assert_eq!(text.concat(), "{\"a\": \"[\"}, {\"a\": 1}]}");
+    }
+
+    #[test]
+    fn drops_only_an_items_wrapper() {
+        let mut filter = ItemsWrapperFilter::new();
+        assert_eq!(filter.push(" {\"it"), "");
+        assert_eq!(filter.push("ems\": [ "), "");
+        assert_eq!(filter.push("{\"a\": 1}, {\"a\": 2}]}"), "{\"a\": 1}, {\"a\": 2}]}");
+        assert_eq!(filter.finish(), "");
+
+        // objects one after the other and bare arrays are passed on
+        let mut filter = ItemsWrapperFilter::new();
+        assert_eq!(filter.push("{\"a\": 1} {\"a\": 2}"), "{\"a\": 1} {\"a\": 2}");
+        let mut filter = ItemsWrapperFilter::new();
+        assert_eq!(filter.push("[{\"a\": 1}]"), "[{\"a\": 1}]");
+        // an item whose first field is a list of numbers is not a wrapper
+        let mut filter = ItemsWrapperFilter::new();
+        assert_eq!(filter.push("{\"lines\": ["), "");
+        assert_eq!(filter.push("1]}"), "{\"lines\": [1]}");
+        let mut filter = ItemsWrapperFilter::new();
+        assert_eq!(filter.push("{\"a\""), "");
+        assert_eq!(filter.finish(), "{\"a\"");
+    }
+
+    #[tokio::test]
+    async fn streams_wrapped_items_in_json_modes() {
+        let items = snippets().iter()
+            .map(|snippet| serde_json::to_string(snippet).unwrap())
+            .collect::<Vec<String>>()
+            .join(", ");
+        for (mode, text) in [
+            (Mode::JSON, format!("{{\"snippets\": [{}]}}", items)),
+            (Mode::MD_JSON, format!("```json\n{{\"items\": [\n{}\n]}}\n```", items)),
+        ] {
+            // string_to_stream sends the text one character at a time
+            let stream = create_chat_completion_stream(string_to_stream(text).await).await;
+            let response = Snippet::from_streaming_response_async(IterableOrSingle::Iterable(Snippet::default()), stream, &(), mode).await;
+            let outputs: Vec<Snippet> = match response {
+                InstructorResponse::Stream(stream) => stream.try_collect().await.unwrap(),
+                _ => panic!("expected a stream"),
+            };
+            assert_eq!(outputs.iter().map(|snippet| snippet.code.clone()).collect::<Vec<_>>(),
+                snippets().iter().map(|snippet| snippet.code.clone()).collect::<Vec<_>>(), "{}", mode);
+        }
+    }
+
+    #[tokio::test]
+    async fn streams_snippets_with_braces_in_code() {
+        let text = snippets().iter()
+            .map(|snippet| serde_json::to_string(snippet).unwrap())
+            .collect::<Vec<String>>()
+            .join(",\n");
+
+        let response = Snippet::tasks_from_chunks_async(
+            IterableOrSingle::Iterable(Snippet::default()),
+            string_to_stream(text.clone()).await,
+            ()
+        ).await;
+        let outputs: Vec<Snippet> = match response {
+            InstructorResponse::Stream(stream) => stream.try_collect().await.unwrap(),
+            _ => panic!("expected a stream"),
+        };
+        assert_eq!(outputs.iter().map(|snippet| snippet.code.clone()).collect::<Vec<_>>(),
+            snippets().iter().map(|snippet| snippet.code.clone()).collect::<Vec<_>>());
+
+        let objects: Vec<String> = extract_json_from_stream_async(string_to_stream(text).await).await.try_collect().await.unwrap();
+        assert_eq!(objects.len(), 3);
+        assert!(objects[0].ends_with("\"lines\":[[1],[]]}"));
+    }
+
+    #[test]
+    fn filters_code_fence_across_chunks() {
+        let mut filter = CodeFenceFilter::new();
+        assert_eq!(filter.push("Sure, here is the `json` you asked for:\n`"), "");
+        assert_eq!(filter.push("``js"), "");
+        assert_eq!(filter.push("on\n[{\"code\": \"```rust\"}]\n`"), "[{\"code\": \"```rust\"}]\n");
+        assert_eq!(filter.push("`` \nI hope this helps! ```json\n{}\n```"), "");
+        assert_eq!(filter.finish(), "");
+
+        // a reply without a fence is used as is
+        let mut filter = CodeFenceFilter::new();
+        assert_eq!(filter.push("\n [{\"a\": 1}]"), "[{\"a\": 1}]");
+        let mut filter = CodeFenceFilter::new();
+        assert_eq!(filter.push("the answer is {\"a\": 1}"), "");
+        assert_eq!(filter.finish(), "the answer is {\"a\": 1}");
+    }
+
+    #[tokio::test]
+    async fn streams_md_json_items() {
+        let text = format!(
+            "Here are the snippets, note the {{braces}} in the code:\n```json\n[\n{}\n]\n```\nLet me know if you need {{more}}!",
+            snippets().iter().map(|snippet| serde_json::to_string(snippet).unwrap()).collect::<Vec<String>>().join(",\n")
+        );
+        let stream = create_chat_completion_stream(string_to_stream(text).await).await;
+        let response = Snippet::from_streaming_response_async(IterableOrSingle::Iterable(Snippet::default()), stream, &(), Mode::MD_JSON).await;
+        let outputs: Vec<Snippet> = match response {
+            InstructorResponse::Stream(stream) => stream.try_collect().await.unwrap(),
+            _ => panic!("expected a stream"),
+        };
+        assert_eq!(outputs.len(), 3);
+        assert_eq!(outputs[2].code, snippets()[2].code);
+    }
+}
@@ -0,0 +1,113 @@
+use schemars::JsonSchema;
+use validator::Validate;
+use serde::{Deserialize, Serialize};
+use model_traits_macro::derive_all;
+use instructor_rs::backend::ChatRequest;
+use instructor_rs::enums::{IterableOrSingle, InstructorResponse};
+use instructor_rs::gbnf::json_schema_to_gbnf;
+use instructor_rs::mode::Mode;
+use instructor_rs::patch::Patch;
+use instructor_rs::process_response::handle_response_model;
+use async_openai::Client;
+use async_openai::config::OpenAIConfig;
+use async_openai::types::ChatCompletionRequestMessage;
+use serde_json::json;
+use crate::common::mock_server::{MockServer, chat_request, chat_completion};
+
+#[derive_all]
+struct Pet {
+    name: String,
+    age: Option<u8>,
+    #[validate(length(max = 3))]
+    tags: Vec<String>,
+}
+
+#[derive_all]
+struct Tree {
+    value: i64,
+    children: Vec<Tree>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn converts_schema_to_gbnf() {
+        let schema = serde_json::to_value(schemars::schema_for!(Pet)).unwrap();
+        let grammar = json_schema_to_gbnf(&schema).unwrap();
+        let rules: Vec<&str> = grammar.lines().collect();
+        assert_eq!(rules[0], "root ::= \"{\" ws \"\\\"name\\\"\" ws \":\" ws string \",\" ws \"\\\"tags\\\"\" ws \":\" ws root-tags (\",\" ws \"\\\"age\\\"\" ws \":\" ws root-age)? \"}\" ws");
+        assert!(rules.contains(&"root-age ::= integer | null"));
+        assert!(rules.contains(&"root-tags ::= \"[\" ws ( string (\",\" ws string)* )? \"]\" ws"));
+        assert!(rules.contains(&"ws ::= ([ \\t\\n] ws)?"));
+        assert!(rules.iter().any(|rule| rule.starts_with("integer ::= ")));
+        // only the primitives that are used are emitted
+        assert!(!rules.iter().any(|rule| rule.starts_with("boolean ::= ")));
+    }
+
+    #[test]
+    fn converts_recursive_and_optional_only_schemas() {
+        let schema = serde_json::to_value(schemars::schema_for!(Tree)).unwrap();
+        let grammar = json_schema_to_gbnf(&schema).unwrap();
+        assert!(grammar.contains("root-children ::= \"[\" ws ( Tree (\",\" ws Tree)* )? \"]\" ws"));
+        assert!(grammar.contains("Tree ::= \"{\" ws \"\\\"children\\\"\" ws \":\" ws Tree-children"));
+
+        let grammar = json_schema_to_gbnf(&json!({
+            "type": "object",
+            "properties": {"a": {"type": "boolean"}, "b": {"enum": ["x", "y"]}},
+        })).unwrap();
+        let root = grammar.lines().next().unwrap();
+        assert_eq!(root, "root ::= \"{\" ws ( \"\\\"a\\\"\" ws \":\" ws boolean (\",\" ws \"\\\"b\\\"\" ws \":\" ws root-b)? | \"\\\"b\\\"\" ws \":\" ws root-b )? \"}\" ws");
+        assert!(grammar.contains("root-b ::= (\"\\\"x\\\"\" | \"\\\"y\\\"\") ws"));
+    }
+
+    #[test]
+    fn local_modes_send_the_native_constraint() {
+        let mut kwargs = ChatRequest::new(chat_request("llama3", "Rex is a 3 year old good boy", false));
+        handle_response_model(&IterableOrSingle::Single(Pet::default()), Mode::OLLAMA_JSON_SCHEMA, &mut kwargs).unwrap();
+        assert_eq!(kwargs.extra_body["format"]["required"], json!(["name", "tags"]));
+        assert!(matches!(kwargs.request.messages[0], ChatCompletionRequestMessage::System(_)));
+
+        let mut kwargs = ChatRequest::new(chat_request("llama3", "Rex is a 3 year old good boy", false));
+        handle_response_model(&IterableOrSingle::Iterable(Pet::default()), Mode::LLAMA_CPP_JSON_SCHEMA, &mut kwargs).unwrap();
+        assert_eq!(kwargs.extra_body["json_schema"]["required"], json!(["items"]));
+        assert!(kwargs.extra_body.get("format").is_none());
+
+        let mut kwargs = ChatRequest::new(chat_request("llama3", "Rex is a 3 year old good boy", false));
+        handle_response_model(&IterableOrSingle::Single(Pet::default()), Mode::LLAMA_CPP_GRAMMAR, &mut kwargs).unwrap();
+        assert!(kwargs.extra_body["grammar"].as_str().unwrap().starts_with("root ::= \"{\""));
+    }
+
+    #[tokio::test]
+    async fn round_trip_against_a_stub_server() {
+        let server = MockServer::start(vec![
+            chat_completion("llama3", "{\"name\": \"Rex\", \"age\": 3, \"tags\": [\"good\", \"boy\", \"dog\", \"loud\"]}"),
+            chat_completion("llama3", "{\"name\": \"Rex\", \"age\": 3, \"tags\": [\"good\", \"boy\"]}"),
+        ]).await;
+        let client = Client::with_config(OpenAIConfig::new().with_api_key("ollama").with_api_base(&server.url));
+        let patched_client = Patch { client, mode: Some(Mode::OLLAMA_JSON_SCHEMA) };
+
+        let res = patched_client.chat_completion(
+            IterableOrSingle::Single(Pet::default()),
+            (),
+            2,
+            chat_request("llama3", "Rex is a 3 year old good boy", false),
+        ).await.unwrap();
+        match res {
+            InstructorResponse::One(pet) => {
+                assert_eq!(pet.name, "Rex");
+                assert_eq!(pet.tags.len(), 2);
+            },
+            _ => panic!("expected one"),
+        }
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].body["format"]["title"], json!("Pet"));
+        assert!(requests[0].body.get("response_format").is_none());
+        // the validation error is re-asked with the same constraint
+        assert_eq!(requests[1].body["format"], requests[0].body["format"]);
+        assert_eq!(requests[1].body["messages"].as_array().unwrap().len(), 4);
+    }
+}
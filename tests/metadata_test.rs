@@ -0,0 +1,234 @@
+use schemars::JsonSchema;
+use validator::Validate;
+use serde::{Deserialize, Serialize};
+use model_traits_macro::derive_all;
+use instructor_rs::anthropic::AnthropicClient;
+use instructor_rs::backend::ReconnectingBackend;
+use instructor_rs::enums::{IterableOrSingle, InstructorResponse};
+use instructor_rs::mode::Mode;
+use instructor_rs::patch::Patch;
+use instructor_rs::timeout::Timeouts;
+use async_openai::Client;
+use async_openai::config::OpenAIConfig;
+use async_openai::types::{
+    CompletionUsage, FinishReason
+};
+use futures::stream::StreamExt;
+use serde_json::json;
+use crate::common::mock_server::{MockServer, MockResponse, chat_request, chat_completion};
+
+#[derive_all]
+struct Number {
+    #[validate(range(min = 0))]
+    value: i64,
+}
+
+fn client(server: &MockServer) -> Client<OpenAIConfig> {
+    Client::with_config(OpenAIConfig::new().with_api_key("test").with_api_base(&server.url))
+}
+
+fn usage(prompt_tokens: u32, completion_tokens: u32) -> CompletionUsage {
+    CompletionUsage { prompt_tokens, completion_tokens, total_tokens: prompt_tokens + completion_tokens }
+}
+
+///a streamed response of `contents` followed by the chunk carrying its usage, as sent with stream_options.include_usage
+fn streamed(id: &str, contents: &[&str], usage: &CompletionUsage) -> MockResponse {
+    let chunk = |choices: serde_json::Value, usage: serde_json::Value| json!({
+        "id": id,
+        "object": "chat.completion.chunk",
+        "created": 1700000000,
+        "model": "gpt-4o-2024-08-06",
+        "system_fingerprint": "fp_123",
+        "choices": choices,
+        "usage": usage,
+    });
+    let mut chunks: Vec<serde_json::Value> = contents.iter().map(|content| chunk(
+        json!([{"index": 0, "delta": {"content": content}, "finish_reason": null, "logprobs": null}]),
+        json!(null),
+    )).collect();
+    chunks.push(chunk(json!([{"index": 0, "delta": {}, "finish_reason": "stop", "logprobs": null}]), json!(null)));
+    chunks.push(chunk(json!([]), json!(usage)));
+    let body = chunks.iter().map(|chunk| format!("data: {}\n\n", chunk)).collect::<String>() + "data: [DONE]\n\n";
+    MockResponse { status: 200, content_type: "text/event-stream".to_string(), body, headers: Vec::new() }
+}
+
+///a streamed response that is cut off after `contents`, without a finish_reason or [DONE]
+fn cut_off(id: &str, contents: &[&str]) -> MockResponse {
+    let body = contents.iter().map(|content| format!("data: {}\n\n", json!({
+        "id": id,
+        "object": "chat.completion.chunk",
+        "created": 1700000000,
+        "model": "gpt-4o-2024-08-06",
+        "choices": [{"index": 0, "delta": {"content": content}, "finish_reason": null, "logprobs": null}],
+    }))).collect::<String>();
+    MockResponse { status: 200, content_type: "text/event-stream".to_string(), body, headers: Vec::new() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn stream_metadata_resolves_at_the_end() {
+        let server = MockServer::start(vec![
+            streamed("chatcmpl-1", &["[{\"value\": 1}, ", "{\"value\": 2}]"], &usage(31, 12)),
+        ]).await;
+        let patched_client = Patch { client: client(&server), mode: Some(Mode::JSON) };
+        let (response, metadata) = patched_client.chat_completion_with_metadata(
+            IterableOrSingle::Iterable(Number::default()),
+            (),
+            1,
+            chat_request("gpt-4o", "give me numbers", true),
+            Timeouts::default(),
+        ).await;
+        let items: Vec<i64> = match response.unwrap() {
+            InstructorResponse::Stream(stream) => stream.map(|item| item.unwrap().value).collect().await,
+            _ => panic!("expected a stream"),
+        };
+        assert_eq!(items, vec![1, 2]);
+
+        let metadata = metadata.finished().await;
+        assert_eq!(metadata.id.as_deref(), Some("chatcmpl-1"));
+        assert_eq!(metadata.model.as_deref(), Some("gpt-4o-2024-08-06"));
+        assert_eq!(metadata.system_fingerprint.as_deref(), Some("fp_123"));
+        assert_eq!(metadata.created, Some(1700000000));
+        assert_eq!(metadata.finish_reason, Some(FinishReason::Stop));
+        assert_eq!(metadata.usage, vec![usage(31, 12)]);
+        assert!(metadata.complete);
+        assert_eq!(server.requests()[0].body["stream_options"], json!({"include_usage": true}));
+    }
+
+    #[tokio::test]
+    async fn usage_of_every_request_is_counted() {
+        // the second item is invalid and re-asked, both requests are billed
+        let server = MockServer::start(vec![
+            streamed("chatcmpl-1", &["[{\"value\": 1}, {\"valu\": 2}]"], &usage(40, 15)),
+            streamed("chatcmpl-2", &["[{\"value\": 2}]"], &usage(90, 6)),
+        ]).await;
+        let patched_client = Patch { client: client(&server), mode: Some(Mode::JSON) };
+        let (response, metadata) = patched_client.chat_completion_with_metadata(
+            IterableOrSingle::Iterable(Number::default()),
+            (),
+            2,
+            chat_request("gpt-4o", "give me numbers", true),
+            Timeouts::default(),
+        ).await;
+        let items: Vec<i64> = match response.unwrap() {
+            InstructorResponse::Stream(stream) => stream.map(|item| item.unwrap().value).collect().await,
+            _ => panic!("expected a stream"),
+        };
+        assert_eq!(items, vec![1, 2]);
+
+        let metadata = metadata.finished().await;
+        assert_eq!(metadata.id.as_deref(), Some("chatcmpl-2"));
+        assert_eq!(metadata.usage, vec![usage(40, 15), usage(90, 6)]);
+        assert_eq!(metadata.total_usage(), Some(usage(130, 21)));
+        assert_eq!(server.requests()[1].body["stream_options"], json!({"include_usage": true}));
+    }
+
+    #[tokio::test]
+    async fn failed_call_keeps_its_usage() {
+        let server = MockServer::start(vec![
+            chat_completion("gpt-4o-2024-08-06", "{\"value\": -1}").with_usage(&usage(20, 5)),
+            chat_completion("gpt-4o-2024-08-06", "{\"value\": -3}").with_usage(&usage(45, 5)),
+        ]).await;
+        let patched_client = Patch { client: client(&server), mode: Some(Mode::JSON) };
+        let (response, metadata) = patched_client.chat_completion_with_metadata(
+            IterableOrSingle::Single(Number::default()),
+            (),
+            2,
+            chat_request("gpt-4o", "give me numbers", false),
+            Timeouts::default(),
+        ).await;
+        assert!(response.is_err());
+
+        let metadata = metadata.finished().await;
+        assert_eq!(metadata.usage.len(), server.requests().len());
+        assert_eq!(metadata.total_usage(), Some(usage(65, 10)));
+        assert_eq!(metadata.finish_reason, Some(FinishReason::Stop));
+        // stream_options is only allowed on streamed requests
+        assert!(server.requests()[0].body.get("stream_options").is_none());
+    }
+
+    #[tokio::test]
+    async fn dropped_stream_resolves_the_handle() {
+        let server = MockServer::start(vec![
+            streamed("chatcmpl-1", &["[{\"value\": 1}, ", "{\"value\": 2}]"], &usage(31, 12)),
+        ]).await;
+        let patched_client = Patch { client: client(&server), mode: Some(Mode::JSON) };
+        let (response, metadata) = patched_client.chat_completion_with_metadata(
+            IterableOrSingle::Iterable(Number::default()),
+            (),
+            1,
+            chat_request("gpt-4o", "give me numbers", true),
+            Timeouts::default(),
+        ).await;
+        let mut stream = match response.unwrap() {
+            InstructorResponse::Stream(stream) => stream,
+            _ => panic!("expected a stream"),
+        };
+        assert_eq!(stream.next().await.unwrap().unwrap().value, 1);
+        drop(stream);
+
+        let metadata = metadata.finished().await;
+        assert!(!metadata.complete);
+        assert_eq!(metadata.id.as_deref(), Some("chatcmpl-1"));
+    }
+
+    #[tokio::test]
+    async fn reconnected_segment_without_usage_is_unreported() {
+        let server = MockServer::start(vec![
+            cut_off("chatcmpl-1", &["[{\"value\": 1}, ", "{\"val"]),
+            streamed("chatcmpl-2", &["ue\": 2}]"], &usage(60, 4)),
+        ]).await;
+        let patched_client = Patch { client: ReconnectingBackend::new(client(&server), 1), mode: Some(Mode::JSON) };
+        let (response, metadata) = patched_client.chat_completion_with_metadata(
+            IterableOrSingle::Iterable(Number::default()),
+            (),
+            1,
+            chat_request("gpt-4o", "give me numbers", true),
+            Timeouts::default(),
+        ).await;
+        let items: Vec<i64> = match response.unwrap() {
+            InstructorResponse::Stream(stream) => stream.map(|item| item.unwrap().value).collect().await,
+            _ => panic!("expected a stream"),
+        };
+        assert_eq!(items, vec![1, 2]);
+
+        let metadata = metadata.finished().await;
+        assert_eq!(server.requests().len(), 2);
+        assert_eq!(metadata.usage, vec![usage(60, 4)]);
+        // the cut off request is billed but never reported its usage
+        assert_eq!(metadata.unreported_requests, 1);
+        assert_eq!(metadata.unreported_chunks, 2);
+    }
+
+    #[tokio::test]
+    async fn usage_of_other_providers_is_recorded() {
+        let server = MockServer::start(vec![
+            MockResponse::json(200, json!({
+                "id": "msg_1",
+                "type": "message",
+                "role": "assistant",
+                "content": [{"type": "tool_use", "id": "toolu_1", "name": "Number", "input": {"value": 3}}],
+                "model": "claude-3-opus-20240229",
+                "stop_reason": "tool_use",
+                "stop_sequence": null,
+                "usage": {"input_tokens": 10, "output_tokens": 20}
+            })),
+        ]).await;
+        let client = AnthropicClient::new().with_api_key("test").with_api_base(format!("{}/v1", server.url));
+        let patched_client = Patch { client, mode: Some(Mode::ANTHROPIC_TOOLS) };
+        let (response, metadata) = patched_client.chat_completion_with_metadata(
+            IterableOrSingle::Single(Number::default()),
+            (),
+            1,
+            chat_request("gpt-4o", "give me numbers", false),
+            Timeouts::default(),
+        ).await;
+        assert_eq!(response.unwrap().unwrap().unwrap().value, 3);
+
+        let metadata = metadata.finished().await;
+        assert_eq!(metadata.usage, vec![usage(10, 20)]);
+    }
+}
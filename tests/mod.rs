@@ -1,5 +1,28 @@
+mod common;
 mod utils_test;
 mod enums;
 mod openai_schema_test;
 mod test_iterable;
-
+mod anthropic_test;
+mod backend_test;
+mod test_partial;
+mod test_parallel;
+mod json_schema_test;
+mod local_server_test;
+mod gemini_test;
+mod completion_test;
+mod yaml_test;
+mod xml_test;
+mod json_stream_test;
+mod tool_stream_test;
+mod stream_errors_test;
+mod timeout_test;
+mod events_test;
+mod metadata_test;
+mod early_stop_test;
+mod speculative_test;
+mod retry_policy_test;
+mod reask_test;
+mod retry_error_test;
+mod fallback_test;
+mod deserialize_errors_test;
@@ -0,0 +1,183 @@
+use schemars::JsonSchema;
+use validator::Validate;
+use serde::{Deserialize, Serialize};
+use model_traits_macro::derive_all;
+use std::sync::{Arc, Mutex};
+use instructor_rs::backend::{ChatBackend, BackendFuture, ChatRequest};
+use instructor_rs::enums::{IterableOrSingle, ChatCompletionResponseWrapper};
+use instructor_rs::mode::Mode;
+use instructor_rs::patch::Patch;
+use instructor_rs::retry::{reask_messages, DefaultReask, ReaskStrategy};
+use instructor_rs::retry_policy::RetryPolicy;
+use instructor_rs::timeout::Timeouts;
+use instructor_rs::utils::{create_chat_completion_response, create_tool_call};
+use async_openai::types::{
+    CreateChatCompletionRequest, ChatCompletionRequestMessage,
+    ChatCompletionRequestUserMessage, ChatCompletionRequestUserMessageContent, ChatCompletionMessageToolCall
+};
+use crate::common::mock_server::chat_request;
+
+#[derive_all]
+struct Number {
+    #[validate(range(min = 0))]
+    value: i64,
+}
+
+///a reply of the FakeBackend, either tool calls or text
+#[derive(Clone)]
+enum Reply {
+    Tools(Vec<ChatCompletionMessageToolCall>),
+    Text(&'static str),
+}
+
+///an in-process backend that answers with canned replies and records every request
+#[derive(Clone, Default)]
+struct FakeBackend {
+    replies: Arc<Mutex<Vec<Reply>>>,
+    requests: Arc<Mutex<Vec<CreateChatCompletionRequest>>>,
+}
+
+impl FakeBackend {
+    fn new(replies: Vec<Reply>) -> Self {
+        FakeBackend { replies: Arc::new(Mutex::new(replies)), requests: Arc::new(Mutex::new(Vec::new())) }
+    }
+}
+
+impl ChatBackend for FakeBackend {
+    fn create(&self, request: ChatRequest) -> BackendFuture<ChatCompletionResponseWrapper> {
+        self.requests.lock().unwrap().push(request.request);
+        let reply = self.replies.lock().unwrap().remove(0);
+        Box::pin(async move {
+            let response = match reply {
+                Reply::Tools(tool_calls) => create_chat_completion_response(Some(tool_calls), None),
+                Reply::Text(text) => create_chat_completion_response(None, Some(text.to_string())),
+            };
+            Ok(ChatCompletionResponseWrapper::AtOnce(response))
+        })
+    }
+}
+
+fn tool_call(id: &str, arguments: &str) -> ChatCompletionMessageToolCall {
+    let mut tool_call = create_tool_call("Number".to_string(), arguments.to_string());
+    tool_call.id = id.to_string();
+    tool_call
+}
+
+fn user_text(message: &ChatCompletionRequestMessage) -> String {
+    match message {
+        ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage { content: ChatCompletionRequestUserMessageContent::Text(text), .. }) => text.clone(),
+        other => panic!("expected a user message, got {:?}", other),
+    }
+}
+
+///overrides the wording of the json modes only
+struct Terse;
+
+impl ReaskStrategy for Terse {
+    fn message(&self, mode: Mode, exception: &str) -> String {
+        match mode {
+            Mode::JSON => format!("Wrong: {}", exception),
+            _ => DefaultReask.message(mode, exception),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn tools_reask_answers_the_tool_call() {
+        let backend = FakeBackend::new(vec![
+            Reply::Tools(vec![tool_call("call_1", "{\"value\": -1}")]),
+            Reply::Tools(vec![tool_call("call_2", "{\"value\": 1}")]),
+        ]);
+        let patched_client = Patch { client: backend.clone(), mode: Some(Mode::TOOLS) };
+        let number = patched_client.chat_completion(IterableOrSingle::Single(Number::default()), (), 2, chat_request("fake", "give me a number", false))
+            .await.unwrap().unwrap().unwrap();
+        assert_eq!(number.value, 1);
+
+        let requests = backend.requests.lock().unwrap();
+        let messages = &requests[1].messages;
+        assert_eq!(messages.len(), 3);
+        match &messages[1] {
+            ChatCompletionRequestMessage::Assistant(message) => {
+                assert!(message.content.is_none());
+                assert_eq!(message.tool_calls.as_ref().unwrap(), &vec![tool_call("call_1", "{\"value\": -1}")]);
+            }
+            other => panic!("expected an assistant message, got {:?}", other),
+        }
+        match &messages[2] {
+            ChatCompletionRequestMessage::Tool(message) => {
+                assert_eq!(message.tool_call_id, "call_1");
+                assert!(message.content.contains("Validation Error found"), "{}", message.content);
+                assert!(message.content.contains("value"), "{}", message.content);
+            }
+            other => panic!("expected a tool message, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn text_answer_in_tools_mode_is_reasked_as_text() {
+        // with tool_choice auto the llm can answer with text, there is no tool call to answer
+        let backend = FakeBackend::new(vec![
+            Reply::Text("I can not call that function"),
+            Reply::Tools(vec![tool_call("call_1", "{\"value\": 1}")]),
+        ]);
+        let patched_client = Patch { client: backend.clone(), mode: Some(Mode::TOOLS) };
+        let _ = patched_client.chat_completion(IterableOrSingle::Single(Number::default()), (), 2, chat_request("fake", "give me a number", false)).await;
+
+        let requests = backend.requests.lock().unwrap();
+        let messages = &requests[1].messages;
+        assert_eq!(messages.len(), 3);
+        match &messages[1] {
+            ChatCompletionRequestMessage::Assistant(message) => {
+                assert_eq!(message.content.as_deref(), Some("I can not call that function"));
+                assert!(message.tool_calls.is_none());
+            }
+            other => panic!("expected an assistant message, got {:?}", other),
+        }
+        assert!(user_text(&messages[2]).contains("Recall the function correctly"));
+    }
+
+    #[tokio::test]
+    async fn reask_strategy_overrides_the_wording() {
+        let backend = FakeBackend::new(vec![
+            Reply::Text("{\"value\": -1}"),
+            Reply::Text("{\"value\": 1}"),
+        ]);
+        let patched_client = Patch { client: backend.clone(), mode: Some(Mode::JSON) };
+        let policy = RetryPolicy { max_retries: 2, max_transport_retries: 0, reask: Arc::new(Terse), ..Default::default() };
+        let number = patched_client.chat_completion_with_policy(
+            IterableOrSingle::Single(Number::default()),
+            (),
+            policy,
+            chat_request("fake", "give me a number", false),
+            Timeouts::default(),
+        ).await.unwrap().unwrap().unwrap();
+        assert_eq!(number.value, 1);
+
+        let requests = backend.requests.lock().unwrap();
+        let reask = user_text(requests[1].messages.last().unwrap());
+        assert!(reask.starts_with("Wrong: "), "{}", reask);
+    }
+
+    #[test]
+    fn every_mode_alternates_roles() {
+        let tool_calls = Some(vec![tool_call("call_1", "{}"), tool_call("call_2", "{}")]);
+        for mode in [Mode::JSON, Mode::MD_JSON, Mode::MD_YAML, Mode::XML, Mode::JSON_SCHEMA, Mode::COMPLETION] {
+            let messages = reask_messages("{}".to_string(), tool_calls.clone(), mode, "invalid");
+            assert_eq!(messages.len(), 2, "{}", mode);
+            assert!(matches!(messages[0], ChatCompletionRequestMessage::Assistant(_)), "{}", mode);
+            assert!(user_text(&messages[1]).contains("invalid"), "{}", mode);
+        }
+        for mode in [Mode::TOOLS, Mode::ANTHROPIC_TOOLS, Mode::GEMINI_TOOLS] {
+            let messages = reask_messages("{}, {}".to_string(), tool_calls.clone(), mode, "invalid");
+            let ids: Vec<String> = messages[1..].iter().map(|message| match message {
+                ChatCompletionRequestMessage::Tool(message) => message.tool_call_id.clone(),
+                other => panic!("expected a tool message, got {:?}", other),
+            }).collect();
+            assert_eq!(ids, vec!["call_1".to_string(), "call_2".to_string()], "{}", mode);
+        }
+    }
+}
@@ -0,0 +1,87 @@
+use schemars::JsonSchema;
+use validator::Validate;
+use serde::{Deserialize, Serialize};
+use model_traits_macro::derive_all;
+use std::time::Instant;
+use instructor_rs::enums::IterableOrSingle;
+use instructor_rs::error::Error;
+use instructor_rs::mode::Mode;
+use instructor_rs::patch::Patch;
+use async_openai::Client;
+use async_openai::config::OpenAIConfig;
+use async_openai::types::{
+    ChatCompletionRequestMessage, CompletionUsage
+};
+use crate::common::mock_server::{MockServer, chat_request, chat_completion};
+
+#[derive_all]
+struct Number {
+    #[validate(range(min = 0))]
+    value: i64,
+}
+
+fn patch(server: &MockServer) -> Patch<Client<OpenAIConfig>> {
+    let client = Client::with_config(OpenAIConfig::new().with_api_key("test").with_api_base(&server.url));
+    Patch { client, mode: Some(Mode::JSON) }
+}
+
+fn usage(prompt_tokens: u32, completion_tokens: u32) -> CompletionUsage {
+    CompletionUsage { prompt_tokens, completion_tokens, total_tokens: prompt_tokens + completion_tokens }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn exhaustion_lists_every_attempt() {
+        let server = MockServer::start(vec![
+            chat_completion("gpt-4o", "{\"value\": -1}").with_usage(&usage(20, 5)),
+            chat_completion("gpt-4o", "{\"valu\": 2}").with_usage(&usage(45, 6)),
+        ]).await;
+        let start = Instant::now();
+        let response = patch(&server).chat_completion(IterableOrSingle::Single(Number::default()), (), 2, chat_request("gpt-4o", "give me a number", false)).await;
+        let elapsed = start.elapsed();
+        let error = match response {
+            Err(Error::RetryError(error)) => error,
+            _ => panic!("expected a retry error"),
+        };
+        assert_eq!(error.attempts.len(), 2);
+
+        let (first, second) = (&error.attempts[0], &error.attempts[1]);
+        assert_eq!(first.output.as_deref(), Some("{\"value\": -1}"));
+        assert!(matches!(first.error, Error::ValidationErrors(_)));
+        assert_eq!(first.usage, Some(usage(20, 5)));
+        assert_eq!(second.output.as_deref(), Some("{\"valu\": 2}"));
+        assert!(matches!(second.error, Error::DeserializeErrors(ref errors) if errors[0].path == "/value"));
+        assert_eq!(second.usage, Some(usage(45, 6)));
+        assert!(first.latency + second.latency <= elapsed);
+
+        // the messages are those that were sent, the second request carries the re-ask of the first
+        let requests = server.requests();
+        assert_eq!(first.messages.len(), requests[0].body["messages"].as_array().unwrap().len());
+        assert_eq!(second.messages.len(), first.messages.len() + 2);
+        match &second.messages[first.messages.len()] {
+            ChatCompletionRequestMessage::Assistant(message) => assert_eq!(message.content.as_deref(), Some("{\"value\": -1}")),
+            other => panic!("expected the first output, got {:?}", other),
+        }
+
+        let message = Error::RetryError(error).to_string();
+        assert!(message.starts_with("Max retries exceeded after 2 attempts"), "{}", message);
+        assert!(message.contains("missing field `value`"), "{}", message);
+    }
+
+    #[tokio::test]
+    async fn no_attempt_without_retries() {
+        let server = MockServer::start(vec![]).await;
+        let response = patch(&server).chat_completion(IterableOrSingle::Single(Number::default()), (), 0, chat_request("gpt-4o", "give me a number", false)).await;
+        match response {
+            Err(Error::RetryError(error)) => {
+                assert!(error.attempts.is_empty());
+                assert!(error.last_error().is_none());
+            }
+            _ => panic!("expected a retry error"),
+        }
+        assert!(server.requests().is_empty());
+    }
+}
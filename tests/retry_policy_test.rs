@@ -0,0 +1,266 @@
+use schemars::JsonSchema;
+use validator::Validate;
+use serde::{Deserialize, Serialize};
+use model_traits_macro::derive_all;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use instructor_rs::enums::{IterableOrSingle, InstructorResponse};
+use instructor_rs::error::{Error, HttpError};
+use instructor_rs::backend::OpenAIBackend;
+use instructor_rs::mode::Mode;
+use instructor_rs::patch::Patch;
+use instructor_rs::retry_policy::RetryPolicy;
+use instructor_rs::timeout::Timeouts;
+use async_openai::Client;
+use async_openai::config::OpenAIConfig;
+use async_openai::error::ApiError;
+use futures::stream::StreamExt;
+use serde_json::json;
+use crate::common::mock_server::{MockServer, MockResponse, chat_request, chat_completion};
+
+#[derive_all]
+struct Number {
+    #[validate(range(min = 0))]
+    value: i64,
+}
+
+///a bare Client retries rate limits with the backoff of async_openai and drops the status of an error,
+/// an OpenAIBackend leaves them to the RetryPolicy
+fn patch(server: &MockServer) -> Patch<OpenAIBackend<OpenAIConfig>> {
+    let client = OpenAIBackend::new(Client::with_config(OpenAIConfig::new().with_api_key("test").with_api_base(&server.url)));
+    Patch { client, mode: Some(Mode::JSON) }
+}
+
+fn failure(status: u16, message: &str) -> MockResponse {
+    MockResponse::json(status, json!({"error": {"message": message, "type": "server_error", "param": null, "code": null}}))
+}
+
+fn streamed(contents: &[&str]) -> MockResponse {
+    let body = contents.iter().map(|content| format!("data: {}\n\n", json!({
+        "id": "chatcmpl-1",
+        "object": "chat.completion.chunk",
+        "created": 0,
+        "model": "gpt-4o",
+        "choices": [{"index": 0, "delta": {"content": content}, "finish_reason": null, "logprobs": null}],
+    }))).collect::<String>() + "data: [DONE]\n\n";
+    MockResponse { status: 200, content_type: "text/event-stream".to_string(), body, headers: Vec::new() }
+}
+
+///retries quickly and predictably
+fn policy(max_retries: usize, max_transport_retries: usize) -> RetryPolicy {
+    RetryPolicy {
+        max_retries,
+        max_transport_retries,
+        initial_backoff: Duration::from_millis(10),
+        jitter: false,
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn rate_limit_waits_for_retry_after() {
+        let server = MockServer::start(vec![
+            failure(429, "Rate limit reached").with_header("retry-after", "0.3"),
+            chat_completion("gpt-4o", "{\"value\": 7}"),
+        ]).await;
+        let policy = RetryPolicy { initial_backoff: Duration::from_secs(20), ..policy(1, 1) };
+        let start = Instant::now();
+        let response = patch(&server).chat_completion_with_policy(
+            IterableOrSingle::Single(Number::default()),
+            (),
+            policy,
+            chat_request("gpt-4o", "give me a number", false),
+            Timeouts::default(),
+        ).await;
+        match response.unwrap() {
+            InstructorResponse::One(number) => assert_eq!(number.value, 7),
+            _ => panic!("expected one"),
+        }
+        // the delay of the header is used instead of the backoff
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn failed_stream_request_is_retried() {
+        let server = MockServer::start(vec![
+            failure(503, "The server is overloaded"),
+            streamed(&["[{\"value\": 1}, ", "{\"value\": 2}]"]),
+        ]).await;
+        let response = patch(&server).chat_completion_with_policy(
+            IterableOrSingle::Iterable(Number::default()),
+            (),
+            policy(1, 2),
+            chat_request("gpt-4o", "give me a number", true),
+            Timeouts::default(),
+        ).await;
+        let values: Vec<i64> = match response.unwrap() {
+            InstructorResponse::Stream(stream) => stream.map(|number| number.unwrap().value).collect().await,
+            _ => panic!("expected a stream"),
+        };
+        assert_eq!(values, vec![1, 2]);
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn budgets_are_separate() {
+        // the invalid response uses the validation budget, the server error the transport budget
+        let server = MockServer::start(vec![
+            chat_completion("gpt-4o", "{\"value\": -1}"),
+            failure(502, "Bad gateway"),
+            chat_completion("gpt-4o", "{\"value\": 3}"),
+        ]).await;
+        let response = patch(&server).chat_completion_with_policy(
+            IterableOrSingle::Single(Number::default()),
+            (),
+            policy(2, 1),
+            chat_request("gpt-4o", "give me a number", false),
+            Timeouts::default(),
+        ).await;
+        match response.unwrap() {
+            InstructorResponse::One(number) => assert_eq!(number.value, 3),
+            _ => panic!("expected one"),
+        }
+        assert_eq!(server.requests().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn gives_up_once_the_transport_budget_is_used() {
+        let server = MockServer::start(vec![
+            failure(500, "first"),
+            failure(500, "second"),
+            failure(500, "third"),
+        ]).await;
+        let response = patch(&server).chat_completion_with_policy(
+            IterableOrSingle::Single(Number::default()),
+            (),
+            policy(1, 1),
+            chat_request("gpt-4o", "give me a number", false),
+            Timeouts::default(),
+        ).await;
+        match response {
+            Err(Error::HttpError(e)) => {
+                assert_eq!(e.status, 500);
+                assert_eq!(e.error.message, "second");
+            }
+            _ => panic!("expected an http error"),
+        }
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn only_classified_errors_are_retried() {
+        let server = MockServer::start(vec![
+            failure(400, "Invalid request"),
+            chat_completion("gpt-4o", "{\"value\": 3}"),
+        ]).await;
+        let response = patch(&server).chat_completion_with_policy(
+            IterableOrSingle::Single(Number::default()),
+            (),
+            policy(1, 3),
+            chat_request("gpt-4o", "give me a number", false),
+            Timeouts::default(),
+        ).await;
+        assert!(matches!(response, Err(Error::HttpError(ref e)) if e.status == 400));
+        assert_eq!(server.requests().len(), 1);
+
+        // a classifier of our own retries the 400 as well
+        let server = MockServer::start(vec![
+            failure(400, "Invalid request"),
+            chat_completion("gpt-4o", "{\"value\": 3}"),
+        ]).await;
+        let policy = RetryPolicy { classifier: Arc::new(|error| matches!(error, Error::HttpError(_))), ..policy(1, 3) };
+        let response = patch(&server).chat_completion_with_policy(
+            IterableOrSingle::Single(Number::default()),
+            (),
+            policy,
+            chat_request("gpt-4o", "give me a number", false),
+            Timeouts::default(),
+        ).await;
+        assert!(response.is_ok());
+        assert_eq!(server.requests().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn chat_completion_does_not_retry_requests() {
+        let server = MockServer::start(vec![
+            failure(429, "Rate limit reached"),
+            chat_completion("gpt-4o", "{\"value\": 3}"),
+        ]).await;
+        let response = patch(&server).chat_completion(
+            IterableOrSingle::Single(Number::default()),
+            (),
+            3,
+            chat_request("gpt-4o", "give me a number", false),
+        ).await;
+        assert!(matches!(response, Err(Error::HttpError(ref e)) if e.status == 429));
+        assert_eq!(server.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn retry_after_can_be_an_http_date() {
+        // a date in the past asks for no delay, the backoff of 20s is not used
+        let server = MockServer::start(vec![
+            failure(503, "The server is overloaded").with_header("retry-after", "Wed, 21 Oct 2015 07:28:00 GMT"),
+            chat_completion("gpt-4o", "{\"value\": 7}"),
+        ]).await;
+        let slow = RetryPolicy { initial_backoff: Duration::from_secs(20), ..policy(1, 1) };
+        let start = Instant::now();
+        let response = patch(&server).chat_completion_with_policy(
+            IterableOrSingle::Single(Number::default()), (), slow, chat_request("gpt-4o", "give me a number", false), Timeouts::default(),
+        ).await;
+        assert!(response.is_ok());
+        assert!(start.elapsed() < Duration::from_secs(10));
+
+        // a date far in the future waits for max_backoff, a value that can not be read for the backoff
+        let server = MockServer::start(vec![
+            failure(429, "Rate limit reached").with_header("retry-after", "Fri, 01 Jan 2100 00:00:00 GMT"),
+            failure(429, "Rate limit reached").with_header("retry-after", "soon"),
+            chat_completion("gpt-4o", "{\"value\": 7}"),
+        ]).await;
+        let capped = RetryPolicy { max_backoff: Duration::from_millis(300), ..policy(1, 2) };
+        let start = Instant::now();
+        let response = patch(&server).chat_completion_with_policy(
+            IterableOrSingle::Single(Number::default()), (), capped, chat_request("gpt-4o", "give me a number", false), Timeouts::default(),
+        ).await;
+        assert!(response.is_ok());
+        assert!(start.elapsed() >= Duration::from_millis(310));
+        assert!(start.elapsed() < Duration::from_secs(10));
+        assert_eq!(server.requests().len(), 3);
+    }
+
+    #[test]
+    fn retry_after_is_capped_by_max_backoff() {
+        let policy = RetryPolicy { max_backoff: Duration::from_secs(1), ..Default::default() };
+        let error = |retry_after: u64| Error::HttpError(Box::new(HttpError {
+            status: 429,
+            retry_after: Some(Duration::from_secs(retry_after)),
+            error: ApiError { message: "Rate limit reached".to_string(), r#type: None, param: None, code: None },
+        }));
+        assert_eq!(policy.delay(0, &error(3600)), Duration::from_secs(1));
+        assert_eq!(policy.delay(0, &error(0)), Duration::ZERO);
+    }
+
+    #[test]
+    fn backoff_grows_up_to_the_maximum() {
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_millis(100),
+            multiplier: 3.0,
+            max_backoff: Duration::from_secs(1),
+            ..Default::default()
+        };
+        assert_eq!(policy.backoff(0), Duration::from_millis(100));
+        assert_eq!(policy.backoff(1), Duration::from_millis(300));
+        assert_eq!(policy.backoff(2), Duration::from_millis(900));
+        assert_eq!(policy.backoff(3), Duration::from_secs(1));
+        assert_eq!(policy.backoff(usize::MAX), Duration::from_secs(1));
+        // with jitter the delay is anywhere up to the backoff
+        let error = Error::Generic("reset".to_string());
+        assert!((0..20).all(|_| policy.delay(1, &error) <= Duration::from_millis(300)));
+    }
+}
@@ -0,0 +1,219 @@
+use schemars::JsonSchema;
+use validator::{Validate, ValidateArgs};
+use serde::{Deserialize, Serialize};
+use model_traits_macro::derive_all;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use instructor_rs::backend::{ChatBackend, BackendFuture, ChatRequest};
+use instructor_rs::enums::{IterableOrSingle, InstructorResponse, ChatCompletionResponseWrapper};
+use instructor_rs::error::Error;
+use instructor_rs::openai_schema::BaseSchema;
+use instructor_rs::mode::Mode;
+use instructor_rs::patch::Patch;
+use async_openai::types::{
+    CreateChatCompletionRequest, ChatCompletionRequestMessage, ChatCompletionResponseStream
+};
+use async_stream::stream;
+use futures::stream::StreamExt;
+use crate::common::mock_server::{chat_request, chunk};
+
+#[derive(JsonSchema, Serialize, Debug, Default, Deserialize, Clone, PartialEq)]
+enum Priority {
+    #[default]
+    Low,
+    High,
+}
+
+#[derive_all]
+struct Ticket {
+    #[validate(length(min = 3))]
+    title: String,
+    priority: Priority,
+    #[validate(range(min = 1, max = 5))]
+    severity: i64,
+    description: String,
+}
+
+#[derive_all]
+#[serde(rename_all = "camelCase")]
+struct Incident {
+    #[validate(range(min = 1, max = 5))]
+    severity_level: i64,
+    summary: String,
+}
+
+#[derive_all]
+struct Alert {
+    #[serde(rename = "headline")]
+    #[validate(length(min = 5))]
+    title: String,
+    #[validate(range(min = 1, max = 5))]
+    level: i64,
+}
+
+///sets the flag when the stream of the backend is dropped, i.e. when the connection would be closed
+struct DropFlag(Arc<AtomicBool>);
+
+impl Drop for DropFlag {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+///a backend that streams the next list of chunks for every request, a list ending with None
+/// keeps the connection open forever after its chunks, so the test only finishes if the stream is cancelled
+#[derive(Clone)]
+struct ScriptedBackend {
+    responses: Vec<Vec<Option<&'static str>>>,
+    requests: Arc<Mutex<Vec<CreateChatCompletionRequest>>>,
+    dropped: Arc<AtomicBool>,
+}
+
+impl ScriptedBackend {
+    fn new(responses: Vec<Vec<Option<&'static str>>>) -> Self {
+        ScriptedBackend { responses, requests: Arc::new(Mutex::new(Vec::new())), dropped: Arc::new(AtomicBool::new(false)) }
+    }
+}
+
+impl ChatBackend for ScriptedBackend {
+    fn create(&self, _request: ChatRequest) -> BackendFuture<ChatCompletionResponseWrapper> {
+        Box::pin(async move { Err(Error::NotImplementedError("only streaming".to_string())) })
+    }
+
+    fn create_stream(&self, request: ChatRequest) -> BackendFuture<ChatCompletionResponseStream> {
+        let mut requests = self.requests.lock().unwrap();
+        let chunks = self.responses.get(requests.len()).cloned().unwrap_or_default();
+        requests.push(request.request);
+        let dropped = self.dropped.clone();
+        Box::pin(async move {
+            let stream = stream! {
+                let _flag = DropFlag(dropped);
+                for content in chunks {
+                    match content {
+                        Some(content) => yield Ok(chunk("fake", content)),
+                        None => futures::future::pending::<()>().await,
+                    }
+                }
+            };
+            Ok(Box::pin(stream) as ChatCompletionResponseStream)
+        })
+    }
+}
+
+async fn collect<T>(backend: ScriptedBackend, model: IterableOrSingle<T>, max_retries: usize) -> Vec<Result<T, Error>>
+where
+    T: ValidateArgs<'static, Args = ()> + BaseSchema + 'static,
+{
+    let patched_client = Patch { client: backend, mode: Some(Mode::JSON) };
+    match patched_client.chat_completion(model, (), max_retries, chat_request("fake", "file the tickets", true)).await.unwrap() {
+        InstructorResponse::Stream(stream) => stream.collect().await,
+        _ => panic!("expected a stream"),
+    }
+}
+
+fn content(message: &ChatCompletionRequestMessage) -> String {
+    serde_json::to_value(message).unwrap()["content"].as_str().unwrap().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn invalid_field_cancels_the_generation() {
+        let backend = ScriptedBackend::new(vec![
+            vec![Some("{\"title\": \"Disk full\", \"severity\": 9, "), Some("\"description\": \"the disk"), None],
+            vec![Some("{\"title\": \"Disk full\", \"priority\": \"High\", \"severity\": 4, \"description\": \"no space left\"}")],
+        ]);
+        let outputs = collect(backend.clone(), IterableOrSingle::Single(Ticket::default()), 2).await;
+        assert_eq!(outputs.len(), 1);
+        assert_eq!(outputs[0].as_ref().unwrap().severity, 4);
+        assert!(backend.dropped.load(Ordering::SeqCst));
+
+        // the re-ask goes through reask_messages with the text received so far
+        let requests = backend.requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        let messages = &requests[1].messages;
+        assert_eq!(messages.len(), requests[0].messages.len() + 2);
+        assert_eq!(content(&messages[messages.len() - 2]), "{\"title\": \"Disk full\", \"severity\": 9, ");
+        let reask = content(&messages[messages.len() - 1]);
+        assert!(reask.contains("The field `severity` is invalid"), "{}", reask);
+    }
+
+    #[tokio::test]
+    async fn unknown_variant_of_an_item_is_reasked_right_away() {
+        let backend = ScriptedBackend::new(vec![
+            vec![
+                Some("[{\"title\": \"Printer\", \"priority\": \"Low\", \"severity\": 1, \"description\": \"paper jam\"}, "),
+                Some("{\"title\": \"Disk full\", \"priority\": \"Urgent\", "),
+                None,
+            ],
+            vec![Some("[{\"title\": \"Disk full\", \"priority\": \"High\", \"severity\": 3, \"description\": \"\"}]")],
+        ]);
+        let outputs = collect(backend.clone(), IterableOrSingle::Iterable(Ticket::default()), 2).await;
+        let titles: Vec<String> = outputs.into_iter().map(|ticket| ticket.unwrap().title).collect();
+        assert_eq!(titles, vec!["Printer".to_string(), "Disk full".to_string()]);
+
+        let requests = backend.requests.lock().unwrap();
+        let reask = content(requests[1].messages.last().unwrap());
+        assert!(reask.contains("The field `priority` of item 2 is invalid"), "{}", reask);
+        assert!(reask.contains("unknown variant `Urgent`"), "{}", reask);
+        assert!(reask.contains("the items that follow them"), "{}", reask);
+    }
+
+    #[tokio::test]
+    async fn renamed_field_cancels_the_generation() {
+        let backend = ScriptedBackend::new(vec![
+            vec![Some("{\"severityLevel\": 9, "), Some("\"summary\": \"the disk"), None],
+            vec![Some("{\"severityLevel\": 2, \"summary\": \"disk full\"}")],
+        ]);
+        let outputs = collect(backend.clone(), IterableOrSingle::Single(Incident::default()), 2).await;
+        assert_eq!(outputs.len(), 1);
+        assert_eq!(outputs[0].as_ref().unwrap().severity_level, 2);
+        assert!(backend.dropped.load(Ordering::SeqCst));
+        assert_eq!(backend.requests.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn field_renamed_to_another_word_cancels_the_generation() {
+        let backend = ScriptedBackend::new(vec![
+            vec![Some("{\"headline\": \"Disk\", "), Some("\"level\": 2"), None],
+            vec![Some("{\"headline\": \"Disk full\", \"level\": 2}")],
+        ]);
+        let outputs = collect(backend.clone(), IterableOrSingle::Single(Alert::default()), 2).await;
+        assert_eq!(outputs.len(), 1);
+        assert_eq!(outputs[0].as_ref().unwrap().title, "Disk full");
+        assert!(backend.dropped.load(Ordering::SeqCst));
+        let requests = backend.requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        let reask = format!("{:?}", requests[1].messages.last().unwrap());
+        assert!(reask.contains("The field `headline` is invalid"), "{}", reask);
+    }
+
+    #[tokio::test]
+    async fn fields_are_checked_once_complete() {
+        // "Di" is too short but the title is not complete yet, the fields that were not generated are not checked
+        let backend = ScriptedBackend::new(vec![
+            vec![Some("{\"title\": \"Di"), Some("sk full\", \"severity\""), Some(": 2, \"priority\": \"Low\", \"description\": \"\"}")],
+        ]);
+        let outputs = collect(backend.clone(), IterableOrSingle::Single(Ticket::default()), 2).await;
+        assert_eq!(outputs.len(), 1);
+        assert_eq!(outputs[0].as_ref().unwrap().title, "Disk full");
+        assert_eq!(backend.requests.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn gives_up_with_the_errors_of_the_field() {
+        let backend = ScriptedBackend::new(vec![
+            vec![Some("{\"severity\": 0, "), None],
+        ]);
+        let outputs = collect(backend.clone(), IterableOrSingle::Single(Ticket::default()), 1).await;
+        assert_eq!(outputs.len(), 1);
+        // the title of the template is too short as well, but it was not generated
+        match &outputs[0] {
+            Err(Error::ValidationErrors(errors)) => assert_eq!(errors.errors().keys().collect::<Vec<_>>(), vec![&"severity"]),
+            other => panic!("expected validation errors, got {:?}", other.as_ref().map(|_| ())),
+        }
+        assert_eq!(backend.requests.lock().unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,180 @@
+use schemars::JsonSchema;
+use validator::Validate;
+use serde::{Deserialize, Serialize};
+use model_traits_macro::derive_all;
+use std::sync::{Arc, Mutex};
+use instructor_rs::backend::{ChatBackend, BackendFuture, ChatRequest, ReconnectingBackend};
+use instructor_rs::enums::{IterableOrSingle, InstructorResponse, ChatCompletionResponseWrapper};
+use instructor_rs::error::Error;
+use instructor_rs::mode::Mode;
+use instructor_rs::patch::Patch;
+use async_openai::error::OpenAIError;
+use async_openai::types::{
+    CreateChatCompletionRequest, ChatCompletionResponseStream
+};
+use futures::stream::{self, StreamExt};
+use crate::common::mock_server::{chat_request, chunk};
+
+#[derive_all]
+struct Number {
+    #[validate(range(min = 0))]
+    value: i64,
+}
+
+///the content of the chunks of a stream, an Err is a dropped connection
+type Chunks = Vec<Result<&'static str, &'static str>>;
+
+///an in-process backend that replays canned streams and records every request
+#[derive(Clone, Default)]
+struct FlakyBackend {
+    streams: Arc<Mutex<Vec<Chunks>>>,
+    requests: Arc<Mutex<Vec<CreateChatCompletionRequest>>>,
+}
+
+impl FlakyBackend {
+    fn new(streams: Vec<Chunks>) -> Self {
+        FlakyBackend { streams: Arc::new(Mutex::new(streams)), ..Default::default() }
+    }
+}
+
+impl ChatBackend for FlakyBackend {
+    fn create(&self, _request: ChatRequest) -> BackendFuture<ChatCompletionResponseWrapper> {
+        Box::pin(async move { Err(Error::NotImplementedError("only streaming".to_string())) })
+    }
+
+    fn create_stream(&self, request: ChatRequest) -> BackendFuture<ChatCompletionResponseStream> {
+        self.requests.lock().unwrap().push(request.request);
+        let chunks = self.streams.lock().unwrap().remove(0);
+        Box::pin(async move {
+            let chunks = chunks.into_iter().map(|chunk_result| match chunk_result {
+                Ok(content) => Ok(chunk("fake", content)),
+                Err(e) => Err(OpenAIError::StreamError(e.to_string())),
+            }).collect::<Vec<_>>();
+            Ok(stream::iter(chunks).boxed())
+        })
+    }
+}
+
+async fn collect<B: ChatBackend>(client: B) -> Vec<Result<Number, Error>> {
+    collect_with(client, Mode::JSON, 1).await
+}
+
+async fn collect_with<B: ChatBackend>(client: B, mode: Mode, max_retries: usize) -> Vec<Result<Number, Error>> {
+    let patched_client = Patch { client, mode: Some(mode) };
+    let response = patched_client.chat_completion(
+        IterableOrSingle::Iterable(Number::default()),
+        (),
+        max_retries,
+        chat_request("fake", "give me two numbers", true),
+    ).await.unwrap();
+    match response {
+        InstructorResponse::Stream(stream) => stream.collect().await,
+        _ => panic!("expected a stream"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn dropped_connection_is_an_error_item() {
+        let backend = FlakyBackend::new(vec![
+            vec![Ok("[{\"value\": 1}, "), Ok("{\"val"), Err("connection reset")],
+        ]);
+        let outputs = collect(backend).await;
+        assert_eq!(outputs.len(), 2);
+        assert_eq!(outputs[0].as_ref().unwrap().value, 1);
+        assert!(matches!(&outputs[1], Err(Error::OpenAIError(OpenAIError::StreamError(e))) if e == "connection reset"));
+    }
+
+    #[tokio::test]
+    async fn reconnects_and_requests_the_tail() {
+        let backend = FlakyBackend::new(vec![
+            vec![Ok("[{\"value\": 1}, "), Ok("{\"val"), Err("connection reset")],
+            vec![Ok("ue\": 2}]")],
+        ]);
+        let outputs = collect(ReconnectingBackend::new(backend.clone(), 1)).await;
+        assert_eq!(outputs.iter().map(|number| number.as_ref().unwrap().value).collect::<Vec<_>>(), vec![1, 2]);
+
+        let requests = backend.requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        let messages = serde_json::to_value(&requests[1].messages).unwrap();
+        let messages = messages.as_array().unwrap();
+        assert_eq!(messages.len(), requests[0].messages.len() + 2);
+        assert_eq!(messages[messages.len() - 2]["content"], "[{\"value\": 1}, {\"val");
+        assert!(messages[messages.len() - 1]["content"].as_str().unwrap().contains("Continue exactly where it stopped"));
+    }
+
+    #[tokio::test]
+    async fn gives_up_after_max_reconnects() {
+        let backend = FlakyBackend::new(vec![
+            vec![Ok("[{\"value\": 1}, "), Err("connection reset")],
+            vec![Err("connection reset again")],
+        ]);
+        let outputs = collect(ReconnectingBackend::new(backend.clone(), 1)).await;
+        assert_eq!(outputs.len(), 2);
+        assert!(matches!(&outputs[1], Err(Error::OpenAIError(OpenAIError::StreamError(e))) if e == "connection reset again"));
+        assert_eq!(backend.requests.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn reasks_only_the_invalid_items() {
+        let backend = FlakyBackend::new(vec![
+            // a missing field can only be told once the item is complete, so these are re-asked at the end of the stream
+            vec![Ok("[{\"value\": 1}, {\"valu\": 2}, "), Ok("{\"number\": 3}, {\"value\": 4}]")],
+            vec![Ok("[{\"value\": 2}, {\"value\": 3}]")],
+        ]);
+        let outputs = collect_with(backend.clone(), Mode::JSON, 2).await;
+        // the fixed items are appended to the valid ones
+        assert_eq!(outputs.iter().map(|number| number.as_ref().unwrap().value).collect::<Vec<_>>(), vec![1, 4, 2, 3]);
+
+        let requests = backend.requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1].messages.len(), requests[0].messages.len() + 2);
+        let answer = serde_json::to_value(&requests[1].messages[requests[1].messages.len() - 2]).unwrap();
+        assert_eq!(answer["content"], "{\"value\": 1}\n{\"valu\": 2}\n{\"number\": 3}\n{\"value\": 4}");
+        let reask = serde_json::to_value(requests[1].messages.last().unwrap()).unwrap();
+        let reask = reask["content"].as_str().unwrap();
+        assert!(reask.contains("{\"valu\": 2}\nErrors: Deserialization error: the field `/value`: missing field `value`"), "{}", reask);
+        assert!(reask.contains("{\"number\": 3}\nErrors: Deserialization error: the field `/value`: missing field `value`"), "{}", reask);
+        assert!(!reask.contains("{\"value\": 4}"), "{}", reask);
+    }
+
+    #[tokio::test]
+    async fn yields_items_that_stay_invalid_as_errors() {
+        let backend = FlakyBackend::new(vec![
+            vec![Ok("```yaml\n- value: 1\n- value: -2\n```")],
+            vec![Ok("```yaml\n- value: -3\n```")],
+        ]);
+        let outputs = collect_with(backend.clone(), Mode::MD_YAML, 2).await;
+        assert_eq!(outputs.len(), 2);
+        assert_eq!(outputs[0].as_ref().unwrap().value, 1);
+        assert!(matches!(outputs[1], Err(Error::ValidationErrors(_))));
+
+        let requests = backend.requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        let reask = serde_json::to_value(requests[1].messages.last().unwrap()).unwrap();
+        assert!(reask["content"].as_str().unwrap().contains("- value: -2\nErrors:"));
+    }
+
+    #[tokio::test]
+    async fn keeps_the_earlier_reasks() {
+        let backend = FlakyBackend::new(vec![
+            vec![Ok("```yaml\n- value: -1\n```")],
+            vec![Ok("```yaml\n- value: -2\n```")],
+            vec![Ok("```yaml\n- value: 3\n```")],
+        ]);
+        let outputs = collect_with(backend.clone(), Mode::MD_YAML, 3).await;
+        assert_eq!(outputs.len(), 1);
+        assert_eq!(outputs[0].as_ref().unwrap().value, 3);
+
+        // the second re-ask is added to the first one instead of replacing it
+        let requests = backend.requests.lock().unwrap();
+        assert_eq!(requests.len(), 3);
+        assert_eq!(requests[2].messages.len(), requests[0].messages.len() + 4);
+        assert_eq!(requests[2].messages[..requests[1].messages.len()], requests[1].messages[..]);
+        let reask = serde_json::to_value(requests[2].messages.last().unwrap()).unwrap();
+        assert!(reask["content"].as_str().unwrap().contains("- value: -2\nErrors:"));
+    }
+}
@@ -0,0 +1,199 @@
+use schemars::JsonSchema;
+use validator::Validate;
+use serde::{Deserialize, Serialize};
+use model_traits_macro::{derive_all, ParallelBase};
+use std::sync::{Arc, Mutex};
+use instructor_rs::backend::{ChatBackend, BackendFuture, ChatRequest, OpenAIBackend};
+use instructor_rs::dsl::parallel::{ParallelBase, parse_parallel_tools};
+use instructor_rs::enums::ChatCompletionResponseWrapper;
+use instructor_rs::error::Error;
+use instructor_rs::mode::Mode;
+use instructor_rs::patch::Patch;
+use instructor_rs::retry_policy::RetryPolicy;
+use instructor_rs::timeout::Timeouts;
+use instructor_rs::utils::{create_chat_completion_response, create_tool_call};
+use async_openai::types::{
+    CreateChatCompletionRequest, ChatCompletionRequestMessage,
+    ChatCompletionMessageToolCall, ChatCompletionToolChoiceOption
+};
+use async_openai::Client;
+use async_openai::config::OpenAIConfig;
+use serde_json::json;
+use std::time::Duration;
+use crate::common::mock_server::{MockServer, MockResponse, chat_request, chat_completion_message};
+
+#[derive_all]
+struct Person {
+    #[validate(length(min = 1))]
+    name: String,
+    age: u8,
+}
+
+#[derive_all]
+struct Company {
+    name: String,
+    #[validate(range(min = 1))]
+    employees: u32,
+}
+
+#[derive(ParallelBase, Debug)]
+enum Entity {
+    Person(Person),
+    Company(Company),
+}
+
+///an in-process backend that answers with canned tool calls and records every request
+#[derive(Clone, Default)]
+struct FakeBackend {
+    replies: Arc<Mutex<Vec<Vec<ChatCompletionMessageToolCall>>>>,
+    requests: Arc<Mutex<Vec<CreateChatCompletionRequest>>>,
+}
+
+impl ChatBackend for FakeBackend {
+    fn create(&self, request: ChatRequest) -> BackendFuture<ChatCompletionResponseWrapper> {
+        self.requests.lock().unwrap().push(request.request);
+        let tool_calls = self.replies.lock().unwrap().remove(0);
+        Box::pin(async move {
+            Ok(ChatCompletionResponseWrapper::AtOnce(
+                create_chat_completion_response(Some(tool_calls), None)
+            ))
+        })
+    }
+}
+
+///a backend that never answers
+#[derive(Clone)]
+struct SilentBackend;
+
+impl ChatBackend for SilentBackend {
+    fn create(&self, _request: ChatRequest) -> BackendFuture<ChatCompletionResponseWrapper> {
+        Box::pin(futures::future::pending())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn derive_lists_every_model() {
+        let names: Vec<String> = Entity::tool_schemas().into_iter().map(|schema| schema.name).collect();
+        assert_eq!(names, vec!["Person".to_string(), "Company".to_string()]);
+    }
+
+    #[test]
+    fn dispatches_tool_calls_by_name() {
+        let response = ChatCompletionResponseWrapper::AtOnce(create_chat_completion_response(
+            Some(vec![
+                create_tool_call("Person".to_string(), "{\"name\": \"Ada\", \"age\": 36}".to_string()),
+                create_tool_call("Company".to_string(), "{\"name\": \"Engines Ltd\", \"employees\": 12}".to_string()),
+                create_tool_call("Person".to_string(), "{\"name\": \"Charles\", \"age\": 40}".to_string()),
+            ]),
+            None,
+        ));
+        let entities = parse_parallel_tools::<Entity>(&response, &()).unwrap();
+        assert_eq!(entities.len(), 3);
+        match &entities[0] {
+            Entity::Person(person) => assert_eq!(person.name, "Ada"),
+            other => panic!("expected a person, got {:?}", other),
+        }
+        match &entities[1] {
+            Entity::Company(company) => assert_eq!(company.employees, 12),
+            other => panic!("expected a company, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn each_model_is_validated_and_unknown_names_fail() {
+        let invalid = Entity::from_tool_call("Company", "{\"name\": \"Empty\", \"employees\": 0}", &());
+        assert!(matches!(invalid, Err(Error::ValidationErrors(_))));
+
+        let unknown = Entity::from_tool_call("Product", "{}", &());
+        match unknown {
+            Err(Error::Generic(message)) => assert!(message.contains("Person, Company")),
+            other => panic!("expected a generic error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn arguments_that_do_not_fit_report_their_tool_call() {
+        let response = ChatCompletionResponseWrapper::AtOnce(create_chat_completion_response(
+            Some(vec![
+                create_tool_call("Person".to_string(), "{\"name\": \"Ada\", \"age\": 36}".to_string()),
+                create_tool_call("Company".to_string(), "{\"name\": \"Engines Ltd\", \"employees\": \"12\"}".to_string()),
+                create_tool_call("Person".to_string(), "{\"name\": \"Charles\"}".to_string()),
+            ]),
+            None,
+        ));
+        match parse_parallel_tools::<Entity>(&response, &()) {
+            Err(Error::DeserializeErrors(errors)) => {
+                assert_eq!(errors.len(), 2);
+                assert_eq!(errors[0].item, Some(1));
+                assert_eq!(errors[0].path, "/1/employees");
+                assert_eq!(errors[1].item, Some(2));
+                assert_eq!(errors[1].path, "/2/age");
+            }
+            other => panic!("expected deserialization errors, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn parallel_chat_completion_reasks_failed_calls() {
+        let backend = FakeBackend::default();
+        backend.replies.lock().unwrap().extend(vec![
+            vec![
+                create_tool_call("Person".to_string(), "{\"name\": \"\", \"age\": 36}".to_string()),
+                create_tool_call("Company".to_string(), "{\"name\": \"Engines Ltd\", \"employees\": 12}".to_string()),
+            ],
+            vec![
+                create_tool_call("Person".to_string(), "{\"name\": \"Ada\", \"age\": 36}".to_string()),
+                create_tool_call("Company".to_string(), "{\"name\": \"Engines Ltd\", \"employees\": 12}".to_string()),
+            ],
+        ]);
+        let patched_client = Patch { client: backend.clone(), mode: Some(Mode::TOOLS) };
+
+        let entities = patched_client.parallel_chat_completion::<Entity>((), 2, chat_request("fake", "Ada, 36, founded Engines Ltd with 12 employees", false)).await.unwrap();
+        assert_eq!(entities.len(), 2);
+
+        let requests = backend.requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].tools.as_ref().unwrap().len(), 2);
+        assert!(matches!(requests[0].tool_choice, Some(ChatCompletionToolChoiceOption::Auto)));
+        // the assistant message with both tool calls, then a tool result for each of them
+        let messages = &requests[1].messages;
+        assert_eq!(messages.len(), 4);
+        match &messages[1] {
+            ChatCompletionRequestMessage::Assistant(message) => assert_eq!(message.tool_calls.as_ref().unwrap().len(), 2),
+            other => panic!("expected an assistant message, got {:?}", other),
+        }
+        assert!(messages[2..].iter().all(|message| matches!(message, ChatCompletionRequestMessage::Tool(_))));
+    }
+
+    #[tokio::test]
+    async fn parallel_chat_completion_rejects_json_modes() {
+        let patched_client = Patch { client: FakeBackend::default(), mode: Some(Mode::JSON) };
+        let res = patched_client.parallel_chat_completion::<Entity>((), 1, chat_request("fake", "Ada, 36, founded Engines Ltd with 12 employees", false)).await;
+        assert!(matches!(res, Err(Error::NotImplementedError(_))));
+    }
+
+    #[tokio::test]
+    async fn parallel_chat_completion_with_policy_retries_requests_and_keeps_the_deadline() {
+        let server = MockServer::start(vec![
+            MockResponse::json(503, json!({"error": {"message": "The server is overloaded", "type": "server_error", "param": null, "code": null}})),
+            chat_completion_message("gpt-4o", json!({"role": "assistant", "content": null, "tool_calls": [
+                {"id": "call_1", "type": "function", "function": {"name": "Person", "arguments": "{\"name\": \"Ada\", \"age\": 36}"}},
+            ]})),
+        ]).await;
+        let client = OpenAIBackend::new(Client::with_config(OpenAIConfig::new().with_api_key("test").with_api_base(&server.url)));
+        let patched_client = Patch { client, mode: Some(Mode::TOOLS) };
+        let policy = RetryPolicy { max_retries: 1, max_transport_retries: 1, initial_backoff: Duration::from_millis(10), jitter: false, ..Default::default() };
+        let entities = patched_client.parallel_chat_completion_with_policy::<Entity>((), policy, chat_request("gpt-4o", "Ada, 36", false), Timeouts::default()).await.unwrap();
+        assert_eq!(entities.len(), 1);
+        assert_eq!(server.requests().len(), 2);
+
+        let patched_client = Patch { client: SilentBackend, mode: Some(Mode::TOOLS) };
+        let timeouts = Timeouts { deadline: Some(Duration::from_millis(50)), idle_timeout: None };
+        let res = patched_client.parallel_chat_completion_with_policy::<Entity>((), RetryPolicy::new(1), chat_request("fake", "Ada, 36", false), timeouts).await;
+        assert!(matches!(res, Err(Error::DeadlineExceeded(_))));
+    }
+}
@@ -0,0 +1,171 @@
+use schemars::JsonSchema;
+use std::clone::Clone;
+use instructor_rs::enums::IterableOrSingle;
+use instructor_rs::utils::{string_to_stream, create_chat_completion_stream};
+use validator::Validate;
+use serde::{Deserialize, Serialize};
+use model_traits_macro::derive_all;
+use instructor_rs::dsl::iterable::IterableBase;
+use instructor_rs::dsl::partial::PartialBase;
+use instructor_rs::enums::InstructorResponse;
+use instructor_rs::mode::Mode;
+use futures::stream::{self, StreamExt};
+
+#[derive_all]
+struct Report {
+    title: String,
+    #[validate(length(min = 10))]
+    summary: String,
+    pages: i64,
+    tags: Vec<String>,
+    author: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_partial_fills_defaults() {
+        let report = Report::parse_partial(&Report::default(), "{\"title\": \"Q3\", \"tags\": [\"fin").unwrap();
+        assert_eq!(report.title, "Q3");
+        assert_eq!(report.tags, vec!["fin".to_string()]);
+        assert_eq!(report.pages, 0);
+        assert_eq!(report.author, None);
+    }
+
+    #[tokio::test]
+    async fn parse_partial_matches_the_stream() {
+        // the tags of the template are replaced, not merged, like the stream replaces them
+        let template = Report { tags: vec!["draft".to_string(), "old".to_string()], ..Report::default() };
+        let text = "{\"title\": \"use {braces}\", \"tags\": [\"fin";
+        let report = Report::parse_partial(&template, text).unwrap();
+        assert_eq!(report.title, "use {braces}");
+        assert_eq!(report.tags, vec!["fin".to_string()]);
+
+        let response = Report::partials_from_chunks(
+            IterableOrSingle::Partial(template),
+            stream::iter(vec![Ok(text.to_string())]).boxed(),
+            (),
+        );
+        let items: Vec<_> = match response {
+            InstructorResponse::Stream(stream) => stream.collect().await,
+            _ => panic!("expected a stream"),
+        };
+        let first = items[0].as_ref().unwrap();
+        assert_eq!(first.title, report.title);
+        assert_eq!(first.tags, report.tags);
+    }
+
+    #[tokio::test]
+    async fn partials_from_chunks_yields_snapshots() {
+        let report = Report {
+            title: "Quarterly report".to_string(),
+            summary: "revenue went up and costs went down".to_string(),
+            pages: 12,
+            tags: vec!["finance".to_string(), "q3".to_string()],
+            author: Some("Ada".to_string()),
+        };
+        let text = serde_json::to_string(&report).unwrap();
+
+        let response = Report::partials_from_chunks(
+            IterableOrSingle::Partial(Report::default()),
+            string_to_stream(text).await,
+            (),
+        );
+        let items: Vec<_> = match response {
+            InstructorResponse::Stream(stream) => stream.collect().await,
+            _ => panic!("expected a stream"),
+        };
+
+        assert!(items.len() > 10);
+        let snapshots: Vec<Report> = items.into_iter().map(|item| item.unwrap()).collect();
+        // the summary is streamed character by character
+        assert!(snapshots.iter().any(|s| s.summary == "revenue"));
+        assert!(snapshots.iter().any(|s| !s.summary.is_empty() && s.pages == 0));
+        let last = snapshots.last().unwrap();
+        assert_eq!(last.pages, 12);
+        assert_eq!(last.author, Some("Ada".to_string()));
+    }
+
+    #[tokio::test]
+    async fn only_the_final_object_is_validated() {
+        let text = "{\"title\": \"t\", \"summary\": \"short\", \"pages\": 1, \"tags\": []}".to_string();
+        let response = Report::partials_from_chunks(
+            IterableOrSingle::Partial(Report::default()),
+            string_to_stream(text).await,
+            (),
+        );
+        let items: Vec<_> = match response {
+            InstructorResponse::Stream(stream) => stream.collect().await,
+            _ => panic!("expected a stream"),
+        };
+        let (last, snapshots) = items.split_last().unwrap();
+        assert!(snapshots.iter().all(|item| item.is_ok()));
+        assert!(last.is_err());
+    }
+
+    #[tokio::test]
+    async fn text_after_the_object_is_ignored() {
+        let chunks = vec![
+            "{\"title\": \"t\", \"summary\": \"a long enough summary\", \"pages\": 3, \"tags\": []}",
+            "\nthe object follows the schema {title, summary}",
+        ];
+        let response = Report::partials_from_chunks(
+            IterableOrSingle::Partial(Report::default()),
+            stream::iter(chunks.into_iter().map(|chunk| Ok(chunk.to_string()))).boxed(),
+            (),
+        );
+        let items: Vec<_> = match response {
+            InstructorResponse::Stream(stream) => stream.collect().await,
+            _ => panic!("expected a stream"),
+        };
+        let last = items.last().unwrap().as_ref().unwrap();
+        assert_eq!(last.pages, 3);
+    }
+
+    #[tokio::test]
+    async fn complete_object_is_yielded_once() {
+        let chunks = vec![
+            "{\"title\": \"Q3\", \"summary\": \"costs went", " down a lot\", \"pages\": 4",
+            ", \"tags\": [\"fin", "ance\", \"q3\"], \"author\": \"Ada\"}",
+        ];
+        let template = Report { tags: vec!["draft".to_string()], ..Report::default() };
+        let response = Report::partials_from_chunks(
+            IterableOrSingle::Partial(template),
+            stream::iter(chunks.into_iter().map(|chunk| Ok(chunk.to_string()))).boxed(),
+            (),
+        );
+        let items: Vec<Report> = match response {
+            InstructorResponse::Stream(stream) => stream.map(|item| item.unwrap()).collect().await,
+            _ => panic!("expected a stream"),
+        };
+        assert_eq!(items.len(), 4);
+        assert_eq!(items[0].summary, "costs went");
+        assert_eq!(items[1].pages, 0);
+        // the tags of the template are replaced as soon as the first tag arrives
+        assert_eq!(items[2].pages, 4);
+        assert_eq!(items[2].tags, vec!["fin".to_string()]);
+        assert_eq!(items[3].tags, vec!["finance".to_string(), "q3".to_string()]);
+        assert_eq!(items[3].author, Some("Ada".to_string()));
+    }
+
+    #[tokio::test]
+    async fn from_streaming_response_async_partial() {
+        let text = "{\"title\": \"t\", \"summary\": \"a long enough summary\", \"pages\": 3, \"tags\": [\"a\"]}".to_string();
+        let response_stream = create_chat_completion_stream(string_to_stream(text).await).await;
+        let response = Report::from_streaming_response_async(
+            IterableOrSingle::Partial(Report::default()),
+            response_stream,
+            &(),
+            Mode::TOOLS
+        ).await;
+        let items: Vec<_> = match response {
+            InstructorResponse::Stream(stream) => stream.collect().await,
+            _ => panic!("expected a stream"),
+        };
+        let last = items.last().unwrap().as_ref().unwrap();
+        assert_eq!(last.pages, 3);
+        assert_eq!(last.summary, "a long enough summary");
+    }
+}
@@ -0,0 +1,150 @@
+use schemars::JsonSchema;
+use validator::Validate;
+use serde::{Deserialize, Serialize};
+use model_traits_macro::derive_all;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use instructor_rs::backend::{ChatBackend, BackendFuture, ChatRequest};
+use instructor_rs::enums::{IterableOrSingle, InstructorResponse, ChatCompletionResponseWrapper};
+use instructor_rs::error::Error;
+use instructor_rs::mode::Mode;
+use instructor_rs::patch::Patch;
+use instructor_rs::timeout::Timeouts;
+use instructor_rs::utils::{create_chat_completion_response, create_tool_call};
+use async_openai::types::ChatCompletionResponseStream;
+use async_stream::stream;
+use futures::stream::StreamExt;
+use crate::common::mock_server::{chat_request, chunk};
+
+#[derive_all]
+struct Number {
+    #[validate(range(min = 0))]
+    value: i64,
+}
+
+///sets the flag when the stream of the backend is dropped, i.e. when the connection would be closed
+struct DropFlag(Arc<AtomicBool>);
+
+impl Drop for DropFlag {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
+
+///a backend that sends every chunk after `delay` and then hangs forever
+#[derive(Clone)]
+struct SlowBackend {
+    chunks: Vec<&'static str>,
+    delay: Duration,
+    dropped: Arc<AtomicBool>,
+}
+
+impl SlowBackend {
+    fn new(chunks: Vec<&'static str>, delay: Duration) -> Self {
+        SlowBackend { chunks, delay, dropped: Arc::new(AtomicBool::new(false)) }
+    }
+}
+
+impl ChatBackend for SlowBackend {
+    fn create(&self, _request: ChatRequest) -> BackendFuture<ChatCompletionResponseWrapper> {
+        let delay = self.delay;
+        Box::pin(async move {
+            tokio::time::sleep(delay).await;
+            let tool_call = create_tool_call("Number".to_string(), "{\"value\": -1}".to_string());
+            Ok(ChatCompletionResponseWrapper::AtOnce(create_chat_completion_response(Some(vec![tool_call]), None)))
+        })
+    }
+
+    fn create_stream(&self, _request: ChatRequest) -> BackendFuture<ChatCompletionResponseStream> {
+        let this = self.clone();
+        Box::pin(async move {
+            let stream = stream! {
+                let _flag = DropFlag(this.dropped.clone());
+                for content in this.chunks.iter() {
+                    tokio::time::sleep(this.delay).await;
+                    yield Ok(chunk("fake", content));
+                }
+                futures::future::pending::<()>().await;
+            };
+            Ok(Box::pin(stream) as ChatCompletionResponseStream)
+        })
+    }
+}
+
+async fn stream_numbers(backend: SlowBackend, timeouts: Timeouts) -> Vec<Result<Number, Error>> {
+    let patched_client = Patch { client: backend, mode: Some(Mode::JSON) };
+    let response = patched_client.chat_completion_with_timeouts(
+        IterableOrSingle::Iterable(Number::default()),
+        (),
+        1,
+        chat_request("fake", "give me numbers", true),
+        timeouts,
+    ).await.unwrap();
+    match response {
+        InstructorResponse::Stream(stream) => stream.collect().await,
+        _ => panic!("expected a stream"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn deadline_covers_all_retries() {
+        // every attempt is invalid and takes 40ms, the third one does not start before the deadline
+        let backend = SlowBackend::new(vec![], Duration::from_millis(40));
+        let patched_client = Patch { client: backend, mode: Some(Mode::TOOLS) };
+        let start = Instant::now();
+        let res = patched_client.chat_completion_with_timeouts(
+            IterableOrSingle::Single(Number::default()),
+            (),
+            5,
+            chat_request("fake", "give me numbers", false),
+            Timeouts { deadline: Some(Duration::from_millis(100)), idle_timeout: None },
+        ).await;
+        assert!(matches!(res, Err(Error::DeadlineExceeded(deadline)) if deadline == Duration::from_millis(100)));
+        assert!(start.elapsed() < Duration::from_millis(200));
+    }
+
+    #[tokio::test]
+    async fn idle_stream_ends_with_idle_timeout() {
+        let backend = SlowBackend::new(vec!["[{\"value\": 1}, ", "{\"value\": 2}"], Duration::from_millis(10));
+        let outputs = stream_numbers(backend.clone(), Timeouts { deadline: None, idle_timeout: Some(Duration::from_millis(100)) }).await;
+        assert_eq!(outputs.len(), 3);
+        assert_eq!(outputs[1].as_ref().unwrap().value, 2);
+        assert!(matches!(outputs[2], Err(Error::IdleTimeout(_))));
+        assert!(backend.dropped.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn slow_chunks_are_not_idle() {
+        // an item spread over chunks that arrive every 50ms is not idle with a 120ms idle timeout,
+        // the deadline still ends the stream
+        let backend = SlowBackend::new(vec!["[{\"val", "ue\": ", "1}, ", "{\"value\"", ": 2}, ", "{\"value\": 3}"], Duration::from_millis(50));
+        let outputs = stream_numbers(backend.clone(), Timeouts {
+            deadline: Some(Duration::from_millis(275)),
+            idle_timeout: Some(Duration::from_millis(120)),
+        }).await;
+        assert_eq!(outputs.len(), 3);
+        assert_eq!(outputs[1].as_ref().unwrap().value, 2);
+        assert!(matches!(outputs[2], Err(Error::DeadlineExceeded(_))));
+        assert!(backend.dropped.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn dropping_the_stream_drops_the_request() {
+        let backend = SlowBackend::new(vec!["[{\"value\": 1}, ", "{\"value\": 2}"], Duration::from_millis(1));
+        let patched_client = Patch { client: backend.clone(), mode: Some(Mode::JSON) };
+        let response = patched_client.chat_completion(IterableOrSingle::Iterable(Number::default()), (), 1, chat_request("fake", "give me numbers", true)).await.unwrap();
+        let mut stream = match response {
+            InstructorResponse::Stream(stream) => stream,
+            _ => panic!("expected a stream"),
+        };
+        assert_eq!(stream.next().await.unwrap().unwrap().value, 1);
+        assert!(!backend.dropped.load(Ordering::SeqCst));
+        drop(stream);
+        assert!(backend.dropped.load(Ordering::SeqCst));
+    }
+}
@@ -0,0 +1,171 @@
+use schemars::JsonSchema;
+use validator::Validate;
+use serde::{Deserialize, Serialize};
+use model_traits_macro::derive_all;
+use instructor_rs::dsl::iterable::IterableBase;
+use instructor_rs::enums::{IterableOrSingle, InstructorResponse};
+use instructor_rs::error::Error;
+use instructor_rs::json_stream::ToolCallSplitter;
+use instructor_rs::mode::Mode;
+use async_openai::types::{
+    ChatChoiceStream, ChatCompletionMessageToolCallChunk, ChatCompletionResponseStream,
+    ChatCompletionStreamResponseDelta, CreateChatCompletionStreamResponse, FunctionCallStream
+};
+use futures::stream::{self, StreamExt};
+
+#[derive_all]
+struct Person {
+    #[validate(length(min = 1))]
+    name: String,
+    age: u8,
+}
+
+fn fragment(index: i32, name: Option<&str>, arguments: &str) -> ChatCompletionMessageToolCallChunk {
+    ChatCompletionMessageToolCallChunk {
+        index,
+        id: name.map(|_| format!("call_{}", index)),
+        r#type: None,
+        function: Some(FunctionCallStream {
+            name: name.map(|name| name.to_string()),
+            arguments: Some(arguments.to_string()),
+        }),
+    }
+}
+
+#[allow(deprecated)]
+fn tool_call_stream(fragments: Vec<Vec<ChatCompletionMessageToolCallChunk>>) -> ChatCompletionResponseStream {
+    let chunks = fragments.into_iter().map(|tool_calls| Ok(CreateChatCompletionStreamResponse {
+        id: "chatcmpl-1".to_string(),
+        object: "chat.completion.chunk".to_string(),
+        created: 0,
+        model: "gpt-4o".to_string(),
+        system_fingerprint: None,
+        choices: vec![ChatChoiceStream {
+            index: 0,
+            finish_reason: None,
+            logprobs: None,
+            delta: ChatCompletionStreamResponseDelta {
+                content: None,
+                function_call: None,
+                tool_calls: Some(tool_calls),
+                role: None,
+            },
+        }],
+    })).collect::<Vec<_>>();
+    stream::iter(chunks).boxed()
+}
+
+///three parallel calls whose fragments are interleaved, the last one names another function
+fn interleaved() -> Vec<Vec<ChatCompletionMessageToolCallChunk>> {
+    vec![
+        vec![fragment(0, Some("Person"), "{\"name\": \"A")],
+        vec![fragment(1, Some("Person"), "{\"name\": \"Bob\", "), fragment(0, None, "da\", ")],
+        vec![fragment(2, Some("Company"), "{\"name\": \"Engines Ltd\"}")],
+        vec![fragment(1, None, "\"age\": 41}")],
+        vec![fragment(0, None, "\"age\": 36}")],
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn assembles_calls_by_index() {
+        let mut splitter = ToolCallSplitter::new();
+        let completed: Vec<Vec<String>> = interleaved().iter()
+            .map(|fragments| splitter.push(fragments).into_iter().map(|call| call.function.arguments).collect())
+            .collect();
+        assert_eq!(completed, vec![
+            vec![],
+            vec![],
+            vec!["{\"name\": \"Engines Ltd\"}".to_string()],
+            vec!["{\"name\": \"Bob\", \"age\": 41}".to_string()],
+            vec!["{\"name\": \"Ada\", \"age\": 36}".to_string()],
+        ]);
+        assert!(splitter.finish().is_empty());
+
+        // a call that never closes is returned by finish()
+        let mut splitter = ToolCallSplitter::new();
+        assert!(splitter.push(&[fragment(3, Some("Person"), "{\"name\": ")]).is_empty());
+        let rest = splitter.finish();
+        assert_eq!(rest.len(), 1);
+        assert_eq!((rest[0].id.as_str(), rest[0].function.name.as_str()), ("call_3", "Person"));
+    }
+
+    #[tokio::test]
+    async fn streams_every_parallel_tool_call() {
+        let response = Person::from_streaming_response_async(
+            IterableOrSingle::Iterable(Person::default()),
+            tool_call_stream(interleaved()),
+            &(),
+            Mode::TOOLS
+        ).await;
+        let outputs: Vec<Result<Person, Error>> = match response {
+            InstructorResponse::Stream(stream) => stream.collect().await,
+            _ => panic!("expected a stream"),
+        };
+        assert_eq!(outputs.len(), 3);
+        assert!(matches!(&outputs[0], Err(Error::Generic(e)) if e.contains("Company")));
+        assert_eq!(outputs[1].as_ref().unwrap().name, "Bob");
+        assert_eq!(outputs[2].as_ref().unwrap().age, 36);
+    }
+
+    #[tokio::test]
+    async fn validates_each_call_separately() {
+        let fragments = vec![
+            vec![fragment(0, Some("Person"), "{\"name\": \"\", \"age\": 1}"), fragment(1, Some("Person"), "{\"name\": \"Ada\", ")],
+            vec![fragment(1, None, "\"age\": 3")],
+        ];
+        let response = Person::from_streaming_response_async(
+            IterableOrSingle::Iterable(Person::default()),
+            tool_call_stream(fragments),
+            &(),
+            Mode::TOOLS
+        ).await;
+        let outputs: Vec<Result<Person, Error>> = match response {
+            InstructorResponse::Stream(stream) => stream.collect().await,
+            _ => panic!("expected a stream"),
+        };
+        assert_eq!(outputs.len(), 2);
+        assert!(matches!(outputs[0], Err(Error::ValidationErrors(_))));
+        // the arguments of the call that was cut off are incomplete
+        assert!(outputs[1].is_err());
+    }
+
+    #[tokio::test]
+    async fn single_model_rejects_parallel_calls() {
+        let response = Person::from_streaming_response_async(
+            IterableOrSingle::Single(Person::default()),
+            tool_call_stream(interleaved()),
+            &(),
+            Mode::TOOLS
+        ).await;
+        let outputs: Vec<Result<Person, Error>> = match response {
+            InstructorResponse::Stream(stream) => stream.collect().await,
+            _ => panic!("expected a stream"),
+        };
+        assert_eq!(outputs.len(), 3);
+        assert!(matches!(&outputs[0], Err(Error::Generic(e)) if e.contains("Company")));
+        assert!(matches!(&outputs[1], Err(Error::Generic(e)) if e.contains("parallel tool calls")));
+        assert!(matches!(&outputs[2], Err(Error::Generic(e)) if e.contains("parallel tool calls")));
+    }
+
+    #[tokio::test]
+    async fn partial_model_stops_at_a_parallel_call() {
+        let response = Person::from_streaming_response_async(
+            IterableOrSingle::Partial(Person::default()),
+            tool_call_stream(interleaved()),
+            &(),
+            Mode::TOOLS
+        ).await;
+        let outputs: Vec<Result<Person, Error>> = match response {
+            InstructorResponse::Stream(stream) => stream.collect().await,
+            _ => panic!("expected a stream"),
+        };
+        // the fragment of the first call before the parallel one is still parsed
+        assert_eq!(outputs[0].as_ref().unwrap().name, "A");
+        assert!(outputs.iter().any(|output| matches!(output, Err(Error::Generic(e)) if e.contains("parallel call with index 1"))));
+        assert!(!outputs.iter().any(|output| matches!(output, Ok(person) if person.name == "Bob")));
+    }
+}
@@ -0,0 +1,153 @@
+use schemars::JsonSchema;
+use validator::Validate;
+use serde::{Deserialize, Serialize};
+use model_traits_macro::derive_all;
+use instructor_rs::enums::{IterableOrSingle, InstructorResponse};
+use instructor_rs::error::Error;
+use instructor_rs::mode::Mode;
+use instructor_rs::openai_schema::OpenAISchema;
+use instructor_rs::patch::Patch;
+use instructor_rs::xml::{xml_template, xml_to_values};
+use async_openai::Client;
+use async_openai::config::OpenAIConfig;
+use serde_json::json;
+use crate::common::mock_server::{MockServer, chat_request, chat_completion};
+
+#[derive(JsonSchema, Serialize, Debug, Default, Deserialize, Clone, PartialEq)]
+enum Priority {
+    #[default]
+    Low,
+    High,
+}
+
+#[derive(JsonSchema, Serialize, Debug, Default, Deserialize, Clone, PartialEq)]
+enum Assignee {
+    #[default]
+    Nobody,
+    Person { name: String },
+    Team(String),
+}
+
+#[derive_all]
+struct Subtask {
+    title: String,
+    done: bool,
+}
+
+#[derive_all]
+struct Ticket {
+    #[validate(length(min = 1))]
+    title: String,
+    #[schemars(description = "the estimate in hours")]
+    estimate: Option<f64>,
+    priority: Priority,
+    assignee: Assignee,
+    labels: Vec<String>,
+    subtasks: Vec<Subtask>,
+}
+
+fn schema() -> serde_json::Value {
+    serde_json::to_value(schemars::schema_for!(Ticket)).unwrap()
+}
+
+const TICKET: &str = "Here is the ticket:
+<Ticket>
+  <title>Fix login when a < b &amp; c</title>
+  <priority>High</priority>
+  <assignee><Person><name>Ada</name></Person></assignee>
+  <labels><item>bug</item><item>auth</item></labels>
+  <subtasks>
+    <item><title>reproduce</title><done>true</done></item>
+    <item><title>patch</title><done>false</done></item>
+  </subtasks>
+</Ticket>";
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_tag_template() {
+        let template = xml_template(&schema()).unwrap();
+        assert!(template.starts_with("<Ticket>\n  "));
+        assert!(template.ends_with("</Ticket>\n"));
+        assert!(template.contains("<title>string</title>"));
+        assert!(template.contains("<estimate>number</estimate> <!-- optional, the estimate in hours, can be empty -->"));
+        assert!(template.contains("<priority>one of: Low, High</priority>"));
+        assert!(template.contains("<Person>\n      <name>string</name>\n    </Person>"));
+        assert!(template.contains("<Team>string</Team>"));
+        assert!(template.contains("<labels>\n    <item>string</item>\n    ...\n  </labels>"));
+        assert!(template.contains("<done>true or false</done>"));
+    }
+
+    #[test]
+    fn parses_nested_tags() {
+        let values = xml_to_values(TICKET, &schema()).unwrap();
+        assert_eq!(values, vec![json!({
+            "title": "Fix login when a < b & c",
+            "priority": "High",
+            "assignee": {"Person": {"name": "Ada"}},
+            "labels": ["bug", "auth"],
+            "subtasks": [{"title": "reproduce", "done": true}, {"title": "patch", "done": false}],
+        })]);
+
+        let ticket = Ticket::model_validate_xml(&IterableOrSingle::Single(Ticket::default()), TICKET, &()).unwrap().unwrap().unwrap();
+        assert_eq!(ticket.assignee, Assignee::Person { name: "Ada".to_string() });
+        assert_eq!(ticket.estimate, None);
+
+        let two = format!("{}\n{}", TICKET, TICKET.replace("<assignee><Person><name>Ada</name></Person></assignee>", "<assignee>Nobody</assignee>"));
+        match Ticket::model_validate_xml(&IterableOrSingle::Iterable(Ticket::default()), &two, &()).unwrap() {
+            InstructorResponse::Many(tickets) => {
+                assert_eq!(tickets.len(), 2);
+                assert_eq!(tickets[1].assignee, Assignee::Nobody);
+            }
+            _ => panic!("expected many"),
+        }
+        assert!(Ticket::model_validate_xml(&IterableOrSingle::Single(Ticket::default()), &two, &()).is_err());
+    }
+
+    #[test]
+    fn errors_quote_the_malformed_tag() {
+        let res = xml_to_values(&TICKET.replace("<done>true</done>", "<done>yes</done>"), &schema());
+        assert_eq!(res.unwrap_err().to_string(), "Xml error: <done>yes</done> is not true or false");
+
+        let res = xml_to_values(&TICKET.replace("<priority>High</priority>", "<priority>Urgent</priority>"), &schema());
+        assert_eq!(res.unwrap_err().to_string(), "Xml error: <priority>Urgent</priority> is not one of: Low, High");
+
+        let res = xml_to_values(&TICKET.replace("</labels>", ""), &schema());
+        assert!(res.unwrap_err().to_string().contains("the tag <labels> is not closed"));
+
+        let res = xml_to_values(&TICKET.replace("<priority>High</priority>", ""), &schema());
+        assert!(matches!(res, Err(Error::XmlError(e)) if e == "missing the tag <priority> in <Ticket>"));
+
+        let res = Ticket::model_validate_xml(&IterableOrSingle::Single(Ticket::default()), &TICKET.replace("Fix login when a < b &amp; c", ""), &());
+        assert!(matches!(res, Err(Error::ValidationErrors(_))));
+    }
+
+    #[tokio::test]
+    async fn reasks_with_the_malformed_tag() {
+        let server = MockServer::start(vec![
+            chat_completion("gpt-4o", &TICKET.replace("<done>false</done>", "<done>not yet</done>")),
+            chat_completion("gpt-4o", TICKET),
+        ]).await;
+        let client = Client::with_config(OpenAIConfig::new().with_api_key("test").with_api_base(&server.url));
+        let patched_client = Patch { client, mode: Some(Mode::XML) };
+
+        let ticket = patched_client.chat_completion(
+            IterableOrSingle::Single(Ticket::default()),
+            (),
+            2,
+            chat_request("gpt-4o", "fix the login page, it is urgent", false),
+        ).await.unwrap().unwrap().unwrap();
+        assert_eq!(ticket.priority, Priority::High);
+
+        let requests = server.requests();
+        assert_eq!(requests.len(), 2);
+        let system = requests[0].body["messages"][0]["content"].as_str().unwrap();
+        assert!(system.contains("<Ticket>") && system.contains("Return a single <Ticket> tag"));
+        assert!(requests[0].body.get("response_format").is_none());
+
+        let reask = requests[1].body["messages"].as_array().unwrap().last().unwrap()["content"].as_str().unwrap();
+        assert!(reask.contains("<done>not yet</done> is not true or false"), "{}", reask);
+    }
+}
@@ -0,0 +1,94 @@
+use schemars::JsonSchema;
+use validator::Validate;
+use serde::{Deserialize, Serialize};
+use model_traits_macro::derive_all;
+use instructor_rs::backend::ChatRequest;
+use instructor_rs::dsl::iterable::IterableBase;
+use instructor_rs::dsl::yaml::YamlListSplitter;
+use instructor_rs::enums::{IterableOrSingle, InstructorResponse};
+use instructor_rs::error::Error;
+use instructor_rs::mode::Mode;
+use instructor_rs::openai_schema::OpenAISchema;
+use instructor_rs::process_response::handle_response_model;
+use instructor_rs::utils::{create_chat_completion_response, create_chat_completion_stream, extract_yaml_from_codeblock, string_to_stream};
+use async_openai::types::{CreateChatCompletionRequestArgs, ChatCompletionRequestMessage};
+use futures::stream::StreamExt;
+
+#[derive_all]
+struct Note {
+    #[validate(length(min = 1))]
+    title: String,
+    body: String,
+    tags: Vec<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_list_items_as_they_complete() {
+        let mut splitter = YamlListSplitter::new();
+        assert_eq!(splitter.push("Here you go:\n```yaml\n- title: a\n  tags:\n  - x\n"), Vec::<String>::new());
+        assert_eq!(splitter.push("  - y\n- ti"), vec!["- title: a\n  tags:\n  - x\n  - y\n".to_string()]);
+        assert_eq!(splitter.push("tle: b\n```\nsome trailing text\n- title: c\n"), vec!["- title: b\n".to_string()]);
+        assert!(splitter.finish().is_empty());
+
+        // without a fence or a trailing newline the last item is returned by finish
+        let mut splitter = YamlListSplitter::new();
+        assert_eq!(splitter.push("- title: a\n- title: b"), vec!["- title: a\n".to_string()]);
+        assert_eq!(splitter.finish(), vec!["- title: b".to_string()]);
+    }
+
+    #[test]
+    fn extracts_and_validates_yaml() {
+        let text = "Sure!\n```yaml\ntitle: Release notes\nbody: |\n  first line with \"quotes\"\n  second line: with a colon\ntags: [release]\n```\n";
+        let yaml = extract_yaml_from_codeblock(text).unwrap();
+        assert!(yaml.starts_with("title: Release notes\n") && yaml.ends_with("tags: [release]\n"));
+        let response = create_chat_completion_response(None, Some(text.to_string()));
+        let note = Note::from_response(&IterableOrSingle::Single(Note::default()), &response, &(), Mode::MD_YAML)
+            .unwrap().unwrap().unwrap();
+        assert_eq!(note.body, "first line with \"quotes\"\nsecond line: with a colon\n");
+
+        let res = Note::model_validate_yaml(&IterableOrSingle::Single(Note::default()), "title: ''\nbody: b\ntags: []", &());
+        assert!(matches!(res, Err(Error::ValidationErrors(_))));
+        let res = Note::model_validate_yaml(&IterableOrSingle::Single(Note::default()), "title: [a\n", &());
+        assert!(matches!(res, Err(Error::YamlError(_))));
+    }
+
+    #[test]
+    fn asks_for_a_yaml_codeblock() {
+        let mut kwargs = ChatRequest::new(CreateChatCompletionRequestArgs::default()
+            .model("gpt-4o")
+            .messages(vec![])
+            .stream(true)
+            .build()
+            .unwrap());
+        handle_response_model(&IterableOrSingle::Iterable(Note::default()), Mode::MD_YAML, &mut kwargs).unwrap();
+        match (&kwargs.request.messages[0], kwargs.request.messages.last().unwrap()) {
+            (ChatCompletionRequestMessage::System(system), ChatCompletionRequestMessage::User(_)) => {
+                assert!(system.content.contains("YAML list"));
+            }
+            _ => panic!("expected a system and a user message"),
+        }
+        assert!(kwargs.request.response_format.is_none());
+
+        let res = handle_response_model(&IterableOrSingle::Single(Note::default()), Mode::MD_YAML, &mut kwargs);
+        assert!(res.is_err());
+    }
+
+    #[tokio::test]
+    async fn streams_list_items() {
+        let text = "```yaml\n- title: a\n  body: |\n    x: 1\n  tags: [t]\n- title: ''\n  body: b\n  tags: []\n- title: c\n  body: c\n  tags: []\n```".to_string();
+        let stream = create_chat_completion_stream(string_to_stream(text).await).await;
+        let res = Note::from_streaming_response_async(IterableOrSingle::Iterable(Note::default()), stream, &(), Mode::MD_YAML).await;
+        let items: Vec<Result<Note, Error>> = match res {
+            InstructorResponse::Stream(stream) => stream.collect().await,
+            _ => panic!("expected a stream"),
+        };
+        assert_eq!(items.len(), 3);
+        assert_eq!(items[0].as_ref().unwrap().body, "x: 1\n");
+        assert!(matches!(items[1], Err(Error::ValidationErrors(_))));
+        assert_eq!(items[2].as_ref().unwrap().title, "c");
+    }
+}